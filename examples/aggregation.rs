@@ -0,0 +1,35 @@
+//! Summarise readings with aggregates: the sensors with the highest readings, and a moving
+//! average along time.
+
+use matdb::api::{Aggregate, Criteria, Database, MemoryBackend, Schema};
+
+fn main() {
+    let schema = Schema::builder()
+        .dimension("time", 100)
+        .dimension("sensor_id", 10)
+        .value("reading")
+        .build().unwrap();
+    let mut database = Database::create_with_backend(schema, Box::new(MemoryBackend::new())).unwrap();
+
+    let mut txn = database.new_transaction().unwrap();
+    for time in 0..200 {
+        for sensor_id in 0..10 {
+            txn.add_row(&[time, sensor_id, (time * sensor_id) % 97]).unwrap();
+        }
+    }
+    txn.commit().unwrap();
+
+    let txn = database.new_transaction().unwrap();
+
+    /* The three sensors with the highest total readings in the first hundred times */
+    let criteria = Criteria::new().range(0, 0..=99);
+    for (sensor_id, total) in txn.query_with(&criteria).unwrap().top_k(1, 0, Aggregate::Sum, 3).unwrap() {
+        println!("sensor {} total {}", sensor_id, total);
+    }
+
+    /* A moving average of sensor 3 over its last ten readings */
+    let criteria = Criteria::new().range(0, 190..=199).values(1, &[3]);
+    for row in txn.query_with(&criteria).unwrap().window(10).mean() {
+        println!("time {} mean {}", row[0], row[2]);
+    }
+}
@@ -0,0 +1,32 @@
+//! Back a database up to a dump, restore it elsewhere, and check the copy matches.
+
+use matdb::api::{Database, MemoryBackend, Schema};
+
+fn main() {
+    let schema = Schema::builder()
+        .dimension("x", 10)
+        .dimension("y", 10)
+        .value("value")
+        .build().unwrap();
+    let mut database = Database::create_with_backend(schema, Box::new(MemoryBackend::new())).unwrap();
+
+    let mut txn = database.new_transaction().unwrap();
+    for x in 0..30 {
+        for y in (0..30).step_by(x % 4 + 1) {
+            txn.add_row(&[x, y, x * y]).unwrap();
+        }
+    }
+    txn.set_checkpoint("generator", "done");
+    txn.commit().unwrap();
+
+    /* A dump could go to a file or over the network; here it stays in memory */
+    let mut dump = Vec::new();
+    let num_rows = database.dump(&mut dump).unwrap();
+    println!("Dumped {} rows in {} bytes", num_rows, dump.len());
+
+    let mut copy = Database::restore_with_backend(&mut dump.as_slice(), Box::new(MemoryBackend::new())).unwrap();
+    let original: Vec<_> = database.new_transaction().unwrap().query().map(|row| format!("{:?}", row)).collect();
+    let restored: Vec<_> = copy.new_transaction().unwrap().query().map(|row| format!("{:?}", row)).collect();
+    assert_eq!(original, restored);
+    println!("Restored copy has the same {} rows", restored.len());
+}
@@ -0,0 +1,43 @@
+//! Load a large number of rows in batches, recording a checkpoint with each batch so that an
+//! interrupted load can carry on where it left off.
+
+use matdb::api::{Database, Datum, MemoryBackend, Schema};
+
+const NUM_ROWS: usize = 100_000;
+const BATCH_SIZE: usize = 10_000;
+const SOURCE: &str = "readings.csv";
+
+/* One reading per minute from each of 20 sensors */
+fn reading(row_no: usize) -> [Datum; 3] {
+    let time = row_no / 20;
+    let sensor_id = row_no % 20;
+    [time, sensor_id, (time * 7 + sensor_id * 13) % 1000]
+}
+
+fn main() {
+    let schema = Schema::builder()
+        .dimension("time", 1000)
+        .dimension("sensor_id", 20)
+        .value("reading")
+        .build().unwrap();
+    let mut database = Database::create_with_backend(schema, Box::new(MemoryBackend::new())).unwrap();
+
+    /* Start after the last batch recorded, if any */
+    let mut row_no = database.checkpoints().get(SOURCE).map_or(0, |pos| pos.parse().unwrap());
+    while row_no < NUM_ROWS {
+        let end = (row_no + BATCH_SIZE).min(NUM_ROWS);
+        let rows: Vec<[Datum; 3]> = (row_no..end).map(reading).collect();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(&rows).unwrap();
+        txn.set_checkpoint(SOURCE, &end.to_string());
+        txn.commit().unwrap();
+        row_no = end;
+    }
+
+    let stats = database.stats();
+    println!("Loaded {} rows into {} segments, {} bytes", NUM_ROWS, stats.num_segments, stats.size);
+    if let Some(amplification) = stats.write_amplification() {
+        println!("Write amplification: {:.2}", amplification);
+    }
+}
@@ -0,0 +1,44 @@
+//! Inspect how a database's data is laid out over its segments, and keep it in shape: late
+//! data goes to segments of its own, and `compact` brings old segments up to date.
+
+use matdb::api::{Database, MemoryBackend, Schema};
+
+fn main() {
+    let schema = Schema::builder()
+        .dimension("time", 100)
+        .dimension("sensor_id", 10)
+        .value("reading")
+        .build().unwrap();
+    let mut database = Database::create_with_backend(schema, Box::new(MemoryBackend::new())).unwrap();
+
+    /* Rows more than 200 behind the newest ones are late */
+    database.set_late_data(Some((0, 200)));
+
+    /* Many small transactions, each spread over several chunks */
+    for batch in 0..20 {
+        let mut txn = database.new_transaction().unwrap();
+        for time in batch * 50..batch * 50 + 50 {
+            txn.add_row(&[time, batch % 10, time]).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+
+    /* A sensor whose clock was behind catches up */
+    let mut txn = database.new_transaction().unwrap();
+    for time in 100..150 {
+        txn.add_row(&[time, 9, time]).unwrap();
+    }
+    txn.commit().unwrap();
+
+    let block_stats = database.block_stats().unwrap();
+    let mean_fill = block_stats.iter().map(|stats| stats.fill_ratio()).sum::<f64>() / block_stats.len() as f64;
+    println!("Blocks: {}, mean fill ratio {:.2}", block_stats.len(), mean_fill);
+    println!("Overlap depth: {}", database.overlap_depth().unwrap());
+    println!("Segments holding late data: {:?}", database.late_segments());
+
+    let num_rewritten = database.compact().unwrap();
+    println!("Compaction rewrote {} segments", num_rewritten);
+    for entry in database.history().unwrap() {
+        println!("{} {}", entry.action, entry.detail);
+    }
+}
@@ -0,0 +1,43 @@
+//! Query part of a database with criteria, and page through the results with a cursor.
+
+use matdb::api::{Criteria, Database, MemoryBackend, Schema};
+
+fn main() {
+    let schema = Schema::builder()
+        .dimension("time", 100)
+        .dimension("sensor_id", 10)
+        .value("reading")
+        .build().unwrap();
+    let mut database = Database::create_with_backend(schema, Box::new(MemoryBackend::new())).unwrap();
+
+    let mut txn = database.new_transaction().unwrap();
+    for time in 0..1000 {
+        for sensor_id in 0..10 {
+            txn.add_row(&[time, sensor_id, time + sensor_id]).unwrap();
+        }
+    }
+    txn.commit().unwrap();
+
+    /* Sensors 2 and 7 between times 500 and 509 */
+    let criteria = Criteria::new()
+        .range(0, 500..=509)
+        .values(1, &[2, 7]);
+    let txn = database.new_transaction().unwrap();
+    for row in txn.query_with(&criteria).unwrap() {
+        println!("time {} sensor {} reading {}", row[0], row[1], row[2]);
+    }
+
+    /* The same rows, five at a time */
+    let mut cursor = None;
+    let mut page_no = 1;
+    loop {
+        let page = txn.query_paged(&criteria, cursor.as_ref(), 5).unwrap();
+        println!("Page {}: {:?}", page_no, page.rows);
+        match page.cursor {
+            Some(next) => cursor = Some(next),
+            None => break
+        }
+        page_no += 1;
+    }
+    txn.rollback();
+}
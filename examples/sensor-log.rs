@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io;
-use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
@@ -13,8 +13,8 @@ use std::time::Instant;
 use chrono::prelude::*;
 use serde::{Serialize, Deserialize};
 
-use matdb::{Dimension, Value, Schema, Transaction, Database, Error, Datum};
-use matdb::Error::DataError;
+use matdb::api::{Aggregate, Criteria, Schema, Transaction, Database, Error, Datum, OpenMode, OpenOptions};
+use matdb::api::Error::DataError;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Sensor {
@@ -24,6 +24,12 @@ struct Sensor {
     kind: String
 }
 
+impl Sensor {
+    fn full_name(&self) -> String {
+        format!("{}/{}/{}", self.component, self.sensor, self.kind)
+    }
+}
+
 struct Sensors<'s> {
     sensor_array: Vec<Sensor>,
     name_to_pos: HashMap<(&'s str, &'s str, &'s str), usize>,
@@ -82,6 +88,19 @@ impl<'s> Sensors<'s> {
         Ok(())
     }
 
+    /**
+     * Find sensors by full name (`component/sensor/kind`), or by just the sensor part.
+     */
+    fn find(&self, name: &str) -> Vec<&Sensor> {
+        self.sensor_array.iter()
+            .filter(|s| s.full_name() == name || s.sensor == name)
+            .collect()
+    }
+
+    fn get_by_id(&self, id: usize) -> Option<&Sensor> {
+        self.sensor_array.iter().find(|s| s.id == id)
+    }
+
     fn get(&mut self, component: &str, sensor: &str, kind: &str) -> usize {
         if let Some(&pos) = self.name_to_pos.get(&(component, sensor, kind)) {
             return self.sensor_array[pos].id;
@@ -105,28 +124,44 @@ impl<'s> Sensors<'s> {
 }
 
 fn open_database(database_path: &Path) -> Result<Database, Error> {
-    if database_path.exists() {
-        Database::open(database_path)
-    } else {
-        Database::create(Schema {
-            dimensions: vec![
-                Dimension { name: String::from("time"), chunk_size: 24*60*60*1000 },
-                Dimension { name: String::from("sensor_id"), chunk_size: 100 },
-            ],
-            values: vec![
-                Value { name: String::from("value")}
-            ]
-        }, database_path)
-    }
+    let schema = Schema::builder()
+        /* Anything before 2000 is the result of a bad parse. */
+        .dimension("time", 24*60*60*1000).bounds(946684800000, Datum::MAX)
+        .dimension("sensor_id", 100)
+        .value("value")
+        .build()?;
+    Database::open_with(database_path, OpenOptions::new().mode(OpenMode::CreateIfMissing).schema(schema))
 }
 
 fn parse_time(s: &str) -> Result<usize, Error> {
     let s = s.replace("a.m.", "am").replace("p.m.", "pm");
-    let parsed = Utc.datetime_from_str(s.as_str(), "%d/%m/%Y %I:%M:%S %p")
+    let naive = NaiveDateTime::parse_from_str(s.as_str(), "%d/%m/%Y %I:%M:%S %p")
         .map_err(|_e| DataError)?;
+    let parsed = Utc.from_utc_datetime(&naive);
     Ok(parsed.timestamp_millis() as usize)
 }
 
+/**
+ * Parse a time given on the command line, as `YYYY-MM-DD`, `YYYY-MM-DD HH:MM:SS`, or a number of
+ * milliseconds since the epoch.
+ */
+fn parse_query_time(s: &str) -> Result<usize, Error> {
+    if let Ok(time_ms) = s.parse::<usize>() {
+        return Ok(time_ms);
+    }
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_hms_opt(0, 0, 0).unwrap()))
+        .map_err(|_e| DataError)?;
+    Ok(Utc.from_utc_datetime(&naive).timestamp_millis() as usize)
+}
+
+fn format_time(time_ms: usize) -> String {
+    match Utc.timestamp_millis_opt(time_ms as i64) {
+        chrono::LocalResult::Single(dt) => dt.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+        _ => time_ms.to_string()
+    }
+}
+
 fn parse_value(s: &str) -> Result<usize, Error> {
     if s.is_empty() {
         return Ok(0);
@@ -174,7 +209,12 @@ fn parse_line(
     Ok(Item { time_ms, sensor_id, value })
 }
 
-fn parse_reader<R: BufRead>(reader: &mut R, file_size: usize, sensors_shared: &Arc<Mutex<&mut Sensors>>) -> Result<Vec<Item>, Error> {
+/**
+ * Parse lines from the reader, returning the items and the number of bytes consumed.  Unless the
+ * input is `complete`, a final line with no newline is assumed to be still being written, and is
+ * left for next time.
+ */
+fn parse_reader<R: BufRead>(reader: &mut R, file_size: usize, complete: bool, sensors_shared: &Arc<Mutex<&mut Sensors>>) -> Result<(Vec<Item>, usize), Error> {
     let mut bytes_read = 0;
     let mut last_pct = 0;
     let mut line_buffer = String::new();
@@ -185,7 +225,7 @@ fn parse_reader<R: BufRead>(reader: &mut R, file_size: usize, sensors_shared: &A
     for line_num in 1.. {
         line_buffer.clear();
         let nr = reader.read_line(&mut line_buffer)?;
-        if nr == 0 {
+        if nr == 0 || (!complete && !line_buffer.ends_with('\n')) {
             break;
         }
 
@@ -206,7 +246,7 @@ fn parse_reader<R: BufRead>(reader: &mut R, file_size: usize, sensors_shared: &A
 
         //println!("{} {} {}", time_ms, sensor_id, value);
 
-        let pct = bytes_read * 10 / file_size;
+        let pct = bytes_read * 10 / max(1, file_size);
         if pct > last_pct {
             //print!("{} ", pct*10);
             io::stdout().flush().unwrap();
@@ -214,57 +254,109 @@ fn parse_reader<R: BufRead>(reader: &mut R, file_size: usize, sensors_shared: &A
         }
     }
     //println!("Done ({} rows)", items.len());
-    Ok(items)
+    Ok((items, bytes_read))
 }
 
-fn parse_file(filename: &Path, sensors_shared: &Arc<Mutex<&mut Sensors>>) -> Result<Vec<Item>, Error> {
-    let file_size = std::fs::metadata(filename)?.len() as usize;
-    let file = File::open(filename)?;
+fn is_gzipped(filename: &Path) -> bool {
+    filename.to_str().unwrap().ends_with(".gz")
+}
+
+/**
+ * Parse a file starting at the given offset, returning the items and the offset to start from
+ * next time.  Gzipped files are always parsed in full, and their offset is their size.
+ */
+fn parse_file(filename: &Path, start_offset: u64, sensors_shared: &Arc<Mutex<&mut Sensors>>) -> Result<(Vec<Item>, u64), Error> {
+    let file_size = std::fs::metadata(filename)?.len();
+    let mut file = File::open(filename)?;
 
-    if filename.to_str().unwrap().ends_with(".gz") {
+    if is_gzipped(filename) {
         const COMPRESSION_RATIO : usize = 16;
         let gz_reader = flate2::read::GzDecoder::new(file);
         let mut reader = BufReader::new(gz_reader);
-        parse_reader(&mut reader, file_size * COMPRESSION_RATIO, sensors_shared)
+        let (items, _) = parse_reader(&mut reader, file_size as usize * COMPRESSION_RATIO, true, sensors_shared)?;
+        Ok((items, file_size))
     } else {
+        file.seek(SeekFrom::Start(start_offset))?;
         let mut reader = BufReader::new(file);
-        parse_reader(&mut reader, file_size, sensors_shared)
+        let (items, consumed) = parse_reader(&mut reader, (file_size - start_offset) as usize, false, sensors_shared)?;
+        Ok((items, start_offset + consumed as u64))
+    }
+}
+
+/**
+ * A file with new data to be loaded, and the offset to start reading it from.
+ */
+struct LoadJob {
+    filename: PathBuf,
+    source: String,
+    start_offset: u64
+}
+
+/**
+ * Work out which files have new data since they were last loaded, using the checkpoints in the
+ * database.  Files that have shrunk are assumed to have been replaced, and are loaded again in
+ * full; that is harmless, as reloaded rows replace identical ones.
+ */
+fn get_load_jobs(matdb: &Database, filenames: &[PathBuf]) -> Vec<LoadJob> {
+    let mut jobs = Vec::new();
+    for filename in filenames {
+        let source = std::fs::canonicalize(filename).unwrap_or_else(|_| filename.clone());
+        let source = source.to_string_lossy().to_string();
+        let file_size = std::fs::metadata(filename).map(|m| m.len()).unwrap_or(0);
+        let checkpoint = matdb.checkpoints().get(&source)
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        if checkpoint == file_size {
+            continue;
+        }
+        let start_offset = if is_gzipped(filename) || checkpoint > file_size { 0 } else { checkpoint };
+        jobs.push(LoadJob { filename: filename.clone(), source, start_offset });
     }
+    jobs
 }
 
 fn load_data(items: &Vec<Item>, txn: &mut Transaction) {
     for item in items {
-        txn.add_row(&[item.time_ms, item.sensor_id, item.value]);
+        if let Err(err) = txn.add_row(&[item.time_ms, item.sensor_id, item.value]) {
+            println!("Skipping row at time {} for sensor {}: {err:?}", item.time_ms, item.sensor_id);
+        }
     }
 }
 
 fn load(sensors: &mut Sensors, matdb: &mut Database, filenames: &[PathBuf]) {
+    let jobs = get_load_jobs(matdb, filenames);
+    if jobs.is_empty() {
+        println!("No new data in {} files", filenames.len());
+        return;
+    }
+
     let num_parser_threads = max(1, thread::available_parallelism().map(|x| x.get()).unwrap_or(1) - 1);
 
     let (sender, receiver) = channel();
 
     let sensors_shared = &Arc::new(Mutex::new(sensors));
 
-    let chunk_size = max(1, (filenames.len() - 1) / num_parser_threads + 1);
-    assert!(chunk_size * num_parser_threads >= filenames.len());
+    let chunk_size = max(1, (jobs.len() - 1) / num_parser_threads + 1);
+    assert!(chunk_size * num_parser_threads >= jobs.len());
 
-    println!("Loading {} files using {} parser threads", filenames.len(), num_parser_threads);
+    println!("Loading {} of {} files using {} parser threads", jobs.len(), filenames.len(), num_parser_threads);
 
     thread::scope(|s| {
         let start_of_load = Instant::now();
 
-        for (i, chunk) in filenames.chunks(chunk_size).enumerate() {
+        for (i, chunk) in jobs.chunks(chunk_size).enumerate() {
             let sender = sender.clone();
             let sensors_shared = sensors_shared.clone();
 
             thread::Builder::new()
                 .name(format!("Worker {i}"))
                 .spawn_scoped(s, move || {
-                    for filename in chunk {
+                    for job in chunk {
                         let now = Instant::now();
-                        let items = parse_file(filename.as_path(), &sensors_shared).unwrap();
+                        let (items, end_offset) = parse_file(job.filename.as_path(), job.start_offset, &sensors_shared).unwrap();
                         let parse_ms = now.elapsed();
-                        sender.send((filename, parse_ms, items)).unwrap();
+                        sender.send((job, parse_ms, items, end_offset)).unwrap();
                     }
                 }).unwrap();
         }
@@ -278,18 +370,19 @@ fn load(sensors: &mut Sensors, matdb: &mut Database, filenames: &[PathBuf]) {
         /* Start a transaction */
         let mut txn = matdb.new_transaction().unwrap();
 
-        for (filename, parse_ms, items) in receiver {
-            println!("Parsed {filename:?} in {parse_ms:?}");
+        for (job, parse_ms, items, end_offset) in receiver {
+            println!("Parsed {:?} from offset {} in {parse_ms:?}", job.filename, job.start_offset);
 
-            /* Insert the data */
+            /* Insert the data, and remember how far we got */
             let now = Instant::now();
             load_data(&items, &mut txn);
+            txn.set_checkpoint(&job.source, &end_offset.to_string());
             println!("Inserted in {:?}", now.elapsed());
             item_count += items.len();
 
             if item_count >= last_item_count + FLUSH_SIZE {
                 let now = Instant::now();
-                txn.flush();
+                txn.flush().unwrap();
                 println!("Flushed transaction in {:?}", now.elapsed());
                 last_item_count = item_count;
             }
@@ -304,6 +397,66 @@ fn load(sensors: &mut Sensors, matdb: &mut Database, filenames: &[PathBuf]) {
     });
 }
 
+/**
+ * Print the rows for the sensors and time range given in the query arguments.
+ */
+fn query(sensors: &Sensors, matdb: &mut Database, args: &[String]) {
+    let mut from_time = 0;
+    let mut to_time = Datum::MAX;
+    let mut sensor_ids = Vec::new();
+    let mut sensor_name = None;
+    let mut top = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(param) = args.next() else { panic!("Missing value for {arg}") };
+        match arg.as_str() {
+            "--from" => { from_time = parse_query_time(param).expect("Invalid --from time"); }
+            "--to" => { to_time = parse_query_time(param).expect("Invalid --to time"); }
+            "--sensor" => { sensor_name = Some(param); }
+            "--top" => { top = Some(param.parse::<usize>().expect("Invalid --top count")); }
+            _ => panic!("Unknown query option {arg}")
+        }
+    }
+
+    if let Some(name) = sensor_name {
+        sensor_ids.extend(sensors.find(name).iter().map(|s| s.id));
+        if sensor_ids.is_empty() {
+            println!("No sensor called {name}");
+            return;
+        }
+    }
+
+    let mut criteria = Criteria::new().range(0, from_time..=to_time);
+    if !sensor_ids.is_empty() {
+        criteria = criteria.values(1, &sensor_ids);
+    }
+
+    let now = Instant::now();
+    let txn = matdb.new_transaction().unwrap();
+
+    /* Show only the sensors with the highest readings */
+    if let Some(k) = top {
+        let results = txn.query_with(&criteria).unwrap().top_k(1, 0, Aggregate::Max, k).unwrap();
+        for (id, value) in &results {
+            let name = sensors.get_by_id(*id).map(|s| s.full_name()).unwrap_or_else(|| id.to_string());
+            println!("{} {:.3}", name, *value as f64 / 1000f64);
+        }
+        txn.rollback();
+        println!("Found top {} sensors in {:?}", results.len(), now.elapsed());
+        return;
+    }
+
+    let mut count = 0;
+    for row in txn.query_with(&criteria).unwrap() {
+        let name = sensors.get_by_id(row[1]).map(|s| s.full_name()).unwrap_or_else(|| row[1].to_string());
+        println!("{} {} {:.3}", format_time(row[0]), name, row[2] as f64 / 1000f64);
+        count += 1;
+    }
+    txn.rollback();
+    println!("Queried {} rows in {:?}", count, now.elapsed());
+}
+
 fn main() {
     env_logger::init();
 
@@ -337,6 +490,9 @@ fn main() {
         }
         txn.commit().unwrap();
         println!("Queried {} rows in {:?}", count, now.elapsed());
+    } else if first_arg == "query" {
+        /* List the rows for some sensors in a time range */
+        query(&sensors, &mut matdb, &args[2..]);
     } else if first_arg == "count" {
         /* Count the number of rows in the database */
         let now = Instant::now();
@@ -0,0 +1,186 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use log::error;
+
+use crate::{Datum, Error};
+use crate::query::QueryRow;
+use crate::scan::Scan;
+
+/**
+ * An aggregate function over a set of values.  Integer division is used for `Mean`, so its
+ * result is rounded down.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Mean
+}
+
+/**
+ * Running state for computing any `Aggregate` over a stream of values.
+ */
+#[derive(Clone, Debug)]
+pub(crate) struct Accumulator {
+    count: u64,
+    sum: u128,
+    min: Datum,
+    max: Datum
+}
+
+impl Accumulator {
+    pub(crate) fn new() -> Accumulator {
+        Accumulator { count: 0, sum: 0, min: Datum::MAX, max: Datum::MIN }
+    }
+
+    pub(crate) fn add(&mut self, value: Datum) {
+        self.count += 1;
+        self.sum += value as u128;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub(crate) fn result(&self, aggregate: Aggregate) -> Datum {
+        if self.count == 0 {
+            return 0;
+        }
+        match aggregate {
+            Aggregate::Count => self.count as Datum,
+            Aggregate::Sum => self.sum.min(Datum::MAX as u128) as Datum,
+            Aggregate::Min => self.min,
+            Aggregate::Max => self.max,
+            Aggregate::Mean => (self.sum / self.count as u128) as Datum
+        }
+    }
+}
+
+/**
+ * Every aggregate of one value over the rows of a query, from `AggregateQuery::run`.  All are 0
+ * when there are no rows.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AggregateResult {
+    pub count: Datum,
+    pub sum: Datum,
+    pub min: Datum,
+    pub max: Datum,
+    pub mean: Datum
+}
+
+impl AggregateResult {
+    pub fn get(&self, aggregate: Aggregate) -> Datum {
+        match aggregate {
+            Aggregate::Count => self.count,
+            Aggregate::Sum => self.sum,
+            Aggregate::Min => self.min,
+            Aggregate::Max => self.max,
+            Aggregate::Mean => self.mean
+        }
+    }
+}
+
+/**
+ * A query aggregating one value over all the rows of a scan, from `Scan::aggregate`.  The rows
+ * are folded into a single accumulator as the scan merges them, so none are kept.  Values are
+ * aggregated as unsigned integers, as `Scan::top_k` does.
+ */
+pub struct AggregateQuery<'txn> {
+    scan: Scan<'txn>,
+    num_dims: usize,
+    value_no: usize
+}
+
+impl<'txn> AggregateQuery<'txn> {
+    pub(crate) fn new(scan: Scan<'txn>, num_dims: usize, value_no: usize) -> AggregateQuery<'txn> {
+        AggregateQuery { scan, num_dims, value_no }
+    }
+
+    /**
+     * Run the scan to completion.  Fails with `SchemaError` if the rows have no value
+     * `value_no`, or as `Scan::try_next` does if a block can't be read.
+     */
+    pub fn run(mut self) -> Result<AggregateResult, Error> {
+        let mut acc = Accumulator::new();
+        while let Some(row) = self.scan.try_next()? {
+            let Some(&value) = row.values_array.get(self.num_dims + self.value_no) else {
+                error!("Can't aggregate value {:?} of a row with {:?} values", self.value_no, row.values_array.len() - self.num_dims);
+                return Err(Error::SchemaError);
+            };
+            acc.add(value);
+        }
+        Ok(AggregateResult {
+            count: acc.result(Aggregate::Count),
+            sum: acc.result(Aggregate::Sum),
+            min: acc.result(Aggregate::Min),
+            max: acc.result(Aggregate::Max),
+            mean: acc.result(Aggregate::Mean)
+        })
+    }
+}
+
+/**
+ * Group rows by the coordinate in `group_dim`, aggregate the value at `value_no` in each group,
+ * and return the `k` groups with the largest aggregates, largest first.  Ties are broken in
+ * favour of the lower group coordinate.
+ *
+ * Only one accumulator per group is kept while scanning, and only `k` candidates while
+ * selecting, so the rows themselves are never collected.
+ */
+pub(crate) fn top_k<I: Iterator<Item=QueryRow>>(rows: I, num_dims: usize, group_dim: usize, value_no: usize, aggregate: Aggregate, k: usize) -> Vec<(Datum, Datum)> {
+    let mut groups: HashMap<Datum, Accumulator> = HashMap::new();
+    for row in rows {
+        groups.entry(row[group_dim])
+            .or_insert_with(Accumulator::new)
+            .add(row[num_dims + value_no]);
+    }
+
+    let mut heap = BinaryHeap::with_capacity(k + 1);
+    for (group, acc) in groups {
+        heap.push(Reverse((acc.result(aggregate), Reverse(group))));
+        if heap.len() > k {
+            heap.pop();
+        }
+    }
+
+    heap.into_sorted_vec().into_iter()
+        .map(|Reverse((value, Reverse(group)))| (group, value))
+        .collect()
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use super::*;
+
+    fn rows(data: &[[Datum; 3]]) -> impl Iterator<Item=QueryRow> + '_ {
+        data.iter().map(|r| QueryRow { txn_id: 1, segment: None, values_array: r.to_vec() })
+    }
+
+    #[test]
+    fn accumulator() {
+        let mut acc = Accumulator::new();
+        assert_eq!(acc.result(Aggregate::Max), 0);
+        for v in [4, 9, 2] {
+            acc.add(v);
+        }
+        assert_eq!(acc.result(Aggregate::Count), 3);
+        assert_eq!(acc.result(Aggregate::Sum), 15);
+        assert_eq!(acc.result(Aggregate::Min), 2);
+        assert_eq!(acc.result(Aggregate::Max), 9);
+        assert_eq!(acc.result(Aggregate::Mean), 5);
+    }
+
+    #[test]
+    fn top_groups() {
+        let data = [
+            [0, 1, 10], [0, 2, 50], [0, 3, 30], [0, 4, 5],
+            [1, 1, 70], [1, 2, 20], [1, 3, 30], [1, 4, 6],
+        ];
+        assert_eq!(top_k(rows(&data), 2, 1, 0, Aggregate::Max, 2), vec![(1, 70), (2, 50)]);
+        assert_eq!(top_k(rows(&data), 2, 1, 0, Aggregate::Min, 3), vec![(3, 30), (2, 20), (1, 10)]);
+        assert_eq!(top_k(rows(&data), 2, 1, 0, Aggregate::Sum, 10).len(), 4);
+        assert_eq!(top_k(rows(&data), 2, 1, 0, Aggregate::Count, 0), vec![]);
+    }
+}
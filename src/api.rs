@@ -0,0 +1,32 @@
+pub use crate::{Datum, DictionaryId, Error, SegmentId, TransactionId};
+pub use crate::aggregate::{Aggregate, AggregateQuery, AggregateResult};
+pub use crate::backend::{Backend, MemoryBackend, ReadSeek};
+#[cfg(feature = "fs")]
+pub use crate::backend::FileBackend;
+pub use crate::backup::BackupStats;
+pub use crate::cache::CacheHint;
+pub use crate::checkpoint::Checkpoints;
+pub use crate::column_stats::{ColumnStats, DataStats};
+pub use crate::compression::Compression;
+pub use crate::cursor::{Cursor, Page};
+pub use crate::database::{Database, DatabaseStats, ValueColumnStats};
+pub use crate::export::{ExportManifest, ExportPartition};
+pub use crate::flush::FlushStats;
+pub use crate::freeze::Frozen;
+pub use crate::import::ImportLease;
+pub use crate::manifest::{BlockDivergence, BlockManifest};
+pub use crate::merge::{KeepAllVersions, MergeStrategy, NewestWins, SumValues};
+pub use crate::merged::MergedScan;
+pub use crate::open::{OpenMode, OpenOptions};
+pub use crate::pool::Layout;
+pub use crate::prepared::PreparedQuery;
+pub use crate::query::{Criteria, QueryRow};
+pub use crate::retry::RetryPolicy;
+pub use crate::scan::{MissingRows, Scan};
+pub use crate::schema::{CellOrder, Dimension, Schema, SchemaBuilder, SchemaChange, SchemaDiff, SchemaDifference, Value, ValueType};
+pub use crate::sharded::{ShardBy, ShardedDatabase, ShardedTransaction};
+pub use crate::snapshot::Snapshot;
+pub use crate::time::{CalendarFilter, TimeResolution, TimeScale};
+pub use crate::trace::QueryTrace;
+pub use crate::transaction::{CommitProgress, CommitSummary, CountStats, DryRunSegment, MemoryAction, MemoryHook, Transaction};
+pub use crate::typed::{Field, TypedValue};
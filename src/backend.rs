@@ -0,0 +1,328 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::HashMap;
+#[cfg(feature = "fs")]
+use std::fs::File;
+#[cfg(feature = "fs")]
+use std::io::Write;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+#[cfg(feature = "fs")]
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use log::error;
+
+use crate::Error;
+
+/**
+ * A readable, seekable file opened from a backend.
+ */
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/**
+ * Where a database keeps its files.  All of the database's file IO goes through a backend,
+ * so it can run without a filesystem, such as in a browser or in tests.
+ *
+ * Files are identified by name only; a backend has no subdirectories.  Writes replace the
+ * whole file and must be durable when they return, and renames must be atomic: the database
+ * relies on renaming to make each change visible in a single step.
+ */
+pub trait Backend {
+    /**
+     * The names of all files in the backend.
+     */
+    fn list(&self) -> Result<Vec<String>, Error>;
+
+    fn exists(&self, name: &str) -> bool;
+
+    fn open(&self, name: &str) -> Result<Box<dyn ReadSeek>, Error>;
+
+    /**
+     * Create or replace a file with the given contents.
+     */
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error>;
+
+    /**
+     * Rename a file, replacing any existing file with the new name.
+     */
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error>;
+
+    fn remove(&self, name: &str) -> Result<(), Error>;
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        self.open(name)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /**
+     * The size of a file in bytes.
+     */
+    fn size(&self, name: &str) -> Result<u64, Error> {
+        Ok(self.open(name)?.seek(SeekFrom::End(0))?)
+    }
+
+    /**
+     * Make the renames and removals done so far durable, for backends where they aren't as
+     * soon as they return.
+     */
+    fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/**
+ * A shared backend, for example so the same in-memory files can be opened again after the
+ * database using them is dropped.
+ */
+impl<B: Backend + ?Sized> Backend for Rc<B> {
+    fn list(&self) -> Result<Vec<String>, Error> { self.as_ref().list() }
+    fn exists(&self, name: &str) -> bool { self.as_ref().exists(name) }
+    fn open(&self, name: &str) -> Result<Box<dyn ReadSeek>, Error> { self.as_ref().open(name) }
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> { self.as_ref().write(name, data) }
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> { self.as_ref().rename(from, to) }
+    fn remove(&self, name: &str) -> Result<(), Error> { self.as_ref().remove(name) }
+    fn size(&self, name: &str) -> Result<u64, Error> { self.as_ref().size(name) }
+    fn sync(&self) -> Result<(), Error> { self.as_ref().sync() }
+}
+
+/**
+ * A backend keeping files in a directory.
+ */
+#[cfg(feature = "fs")]
+pub struct FileBackend {
+    path: PathBuf
+}
+
+#[cfg(feature = "fs")]
+impl FileBackend {
+    /**
+     * Use an existing directory.
+     */
+    pub fn new(path: &Path) -> FileBackend {
+        FileBackend { path: path.to_path_buf() }
+    }
+
+    /**
+     * Create a new directory, failing if it already exists.
+     */
+    pub fn create(path: &Path) -> Result<FileBackend, Error> {
+        std::fs::create_dir(path)?;
+        Ok(FileBackend::new(path))
+    }
+}
+
+#[cfg(feature = "fs")]
+impl Backend for FileBackend {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            match entry.file_name().into_string() {
+                Ok(name) => names.push(name),
+                Err(name) => error!("Ignoring file with non-UTF-8 name {:?}", name)
+            }
+        }
+        Ok(names)
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.path.join(name).exists()
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ReadSeek>, Error> {
+        Ok(Box::new(File::open(self.path.join(name))?))
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        let mut file = File::create(self.path.join(name))?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        std::fs::rename(self.path.join(from), self.path.join(to))?;
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        std::fs::remove_file(self.path.join(name))?;
+        Ok(())
+    }
+
+    fn size(&self, name: &str) -> Result<u64, Error> {
+        Ok(std::fs::metadata(self.path.join(name))?.len())
+    }
+
+    /**
+     * Sync the directory, which is where renames and removals are recorded.  Directories
+     * can only be opened for syncing on Unix.
+     */
+    fn sync(&self) -> Result<(), Error> {
+        #[cfg(unix)]
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+}
+
+/**
+ * A backend keeping files in memory, for databases that don't need to outlive the process or
+ * that are loaded from elsewhere, such as an exported dataset fetched by a browser.
+ */
+#[derive(Default)]
+pub struct MemoryBackend {
+    files: RefCell<HashMap<String, Rc<[u8]>>>
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        Ok(self.files.borrow().keys().cloned().collect())
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.files.borrow().contains_key(name)
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ReadSeek>, Error> {
+        let files = self.files.borrow();
+        let Some(data) = files.get(name) else {
+            error!("No file {name:?} in memory backend");
+            return Err(Error::IoError);
+        };
+        Ok(Box::new(Cursor::new(data.clone())))
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        self.files.borrow_mut().insert(name.to_string(), Rc::from(data));
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        let mut files = self.files.borrow_mut();
+        let Some(data) = files.remove(from) else {
+            error!("No file {from:?} in memory backend");
+            return Err(Error::IoError);
+        };
+        files.insert(to.to_string(), data);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        if self.files.borrow_mut().remove(name).is_none() {
+            error!("No file {name:?} in memory backend");
+            return Err(Error::IoError);
+        }
+        Ok(())
+    }
+}
+
+/**
+ * A backend that fails on demand, for testing how the database copes with IO errors.  A
+ * failed write leaves half of the file behind, as a full disk might.  Renames start failing
+ * once `renames_before_failure` more have succeeded, and the next `failed_opens` opens fail.
+ */
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct FaultyBackend {
+    inner: MemoryBackend,
+    pub fail_writes: Cell<bool>,
+    pub renames_before_failure: Cell<Option<usize>>,
+    pub fail_removes: Cell<bool>,
+    pub failed_opens: Cell<usize>
+}
+
+#[cfg(test)]
+impl Backend for FaultyBackend {
+    fn list(&self) -> Result<Vec<String>, Error> { self.inner.list() }
+    fn exists(&self, name: &str) -> bool { self.inner.exists(name) }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ReadSeek>, Error> {
+        if self.failed_opens.get() > 0 {
+            self.failed_opens.set(self.failed_opens.get() - 1);
+            return Err(Error::IoError);
+        }
+        self.inner.open(name)
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        if self.fail_writes.get() {
+            self.inner.write(name, &data[..data.len() / 2])?;
+            return Err(Error::IoError);
+        }
+        self.inner.write(name, data)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        match self.renames_before_failure.get() {
+            Some(0) => return Err(Error::IoError),
+            Some(n) => self.renames_before_failure.set(Some(n - 1)),
+            None => {}
+        }
+        self.inner.rename(from, to)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        if self.fail_removes.get() {
+            return Err(Error::IoError);
+        }
+        self.inner.remove(name)
+    }
+}
+
+#[cfg(test)]
+mod backend_tests {
+    use super::*;
+
+    fn exercise(backend: &dyn Backend) {
+        backend.write("a.tmp", b"hello").unwrap();
+        assert!(backend.exists("a.tmp"));
+        backend.rename("a.tmp", "a").unwrap();
+        assert!(!backend.exists("a.tmp"));
+        assert_eq!(backend.read("a").unwrap(), b"hello");
+        assert_eq!(backend.size("a").unwrap(), 5);
+
+        let mut file = backend.open("a").unwrap();
+        file.seek(std::io::SeekFrom::Start(3)).unwrap();
+        let mut rest = String::new();
+        file.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "lo");
+
+        backend.write("b", b"").unwrap();
+        let mut names = backend.list().unwrap();
+        names.sort();
+        assert_eq!(names, vec!["a", "b"]);
+
+        backend.remove("a").unwrap();
+        assert!(backend.open("a").is_err());
+        assert!(backend.remove("a").is_err());
+    }
+
+    #[test]
+    fn memory() {
+        exercise(&MemoryBackend::new());
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn file() {
+        let path = std::env::temp_dir().join("backend-file");
+        if path.exists() {
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+        exercise(&FileBackend::create(&path).unwrap());
+        assert!(FileBackend::create(&path).is_err());
+    }
+}
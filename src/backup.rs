@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use log::debug;
+
+use crate::{Error, SegmentId};
+use crate::backend::Backend;
+use crate::storage::{decode_dictionary_name, decode_import_name, decode_pool_name, decode_segment_path, LABELS_FILENAME};
+
+/**
+ * What `Database::backup_to` did.  Files already identical in the destination are skipped,
+ * and segments the database no longer has are removed from it.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupStats {
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+    pub files_skipped: usize,
+    pub files_removed: usize
+}
+
+/**
+ * Make `dest` a copy of the committed state of the database in `source`.
+ *
+ * Pool objects and dictionaries never change once written, so they are copied only if the
+ * destination doesn't have them; other files are copied unless the destination's copy is
+ * identical.  They are copied in an order that leaves the destination a consistent database
+ * if the backup is interrupted: pool objects and dictionaries before the segments that refer
+ * to them, the labels, which only grow, before the segments using them, and the first segment
+ * of each transaction, which marks it as committed, last.
+ * Imports in progress are left out.
+ */
+pub(crate) fn backup(
+    source: &dyn Backend,
+    committed_segments: &HashSet<SegmentId>,
+    dest: &dyn Backend
+) -> Result<BackupStats, Error> {
+    let mut stats = BackupStats::default();
+
+    let mut immutable = Vec::new();
+    let mut labels = Vec::new();
+    let mut segments = Vec::new();
+    let mut others = Vec::new();
+    for name in source.list()? {
+        if decode_pool_name(&name).is_some() || decode_dictionary_name(&name).is_some() {
+            immutable.push(name);
+        } else if name == LABELS_FILENAME {
+            labels.push(name);
+        } else if let Some((txn_id, seg_num, committed)) = decode_segment_path(Path::new(&name)) {
+            if committed && committed_segments.contains(&(txn_id, seg_num)) {
+                segments.push(((seg_num == 0, txn_id, seg_num), name));
+            }
+        } else if !name.ends_with(".tmp") && decode_import_name(&name).is_none() {
+            others.push(name);
+        }
+    }
+    segments.sort();
+
+    for name in immutable {
+        if dest.exists(&name) {
+            stats.files_skipped += 1;
+        } else {
+            copy_file(source, dest, &name, &mut stats)?;
+        }
+    }
+    for name in labels.into_iter().chain(segments.into_iter().map(|(_, name)| name)).chain(others) {
+        let data = source.read(&name)?;
+        if dest.exists(&name) && dest.size(&name)? == data.len() as u64 && dest.read(&name)? == data {
+            stats.files_skipped += 1;
+        } else {
+            write_file(dest, &name, &data, &mut stats)?;
+        }
+    }
+
+    for name in dest.list()? {
+        if let Some((txn_id, seg_num, committed)) = decode_segment_path(Path::new(&name)) {
+            if !committed || !committed_segments.contains(&(txn_id, seg_num)) {
+                dest.remove(&name)?;
+                debug!("Removed segment file {:?} from backup", name);
+                stats.files_removed += 1;
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+fn copy_file(source: &dyn Backend, dest: &dyn Backend, name: &str, stats: &mut BackupStats) -> Result<(), Error> {
+    let data = source.read(name)?;
+    write_file(dest, name, &data, stats)
+}
+
+/**
+ * Write a file under a temporary name and rename it into place, so an interrupted backup never
+ * leaves a damaged file that a later one would take to be complete.
+ */
+fn write_file(dest: &dyn Backend, name: &str, data: &[u8], stats: &mut BackupStats) -> Result<(), Error> {
+    let temp_name = format!("{name}.tmp");
+    dest.write(&temp_name, data)?;
+    dest.rename(&temp_name, name)?;
+    stats.files_copied += 1;
+    stats.bytes_copied += data.len() as u64;
+    Ok(())
+}
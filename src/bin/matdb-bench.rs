@@ -0,0 +1,203 @@
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use matdb::{Backend, Criteria, Database, Datum, FileBackend, MemoryBackend, Schema};
+use serde::Serialize;
+
+const DATASETS: [&str; 4] = ["dense", "sensors", "late", "overlapping"];
+const DEFAULT_NUM_ROWS: usize = 1_000_000;
+const BATCH_SIZE: usize = 100_000;
+const NUM_SENSORS: usize = 200;
+/* A tenth of the late dataset's rows are backfilled from up to this far behind */
+const LATE_WINDOW: Datum = 3600;
+
+fn usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!("    matdb-bench [--dataset NAME] [--rows N] [--dir DIRECTORY] [--output FILENAME]");
+    eprintln!("Datasets: {}", DATASETS.join(", "));
+    std::process::exit(1);
+}
+
+/**
+ * The results of a run, for comparing builds of MatDB over time.
+ */
+#[derive(Serialize)]
+struct Report {
+    version: &'static str,
+    num_rows: usize,
+    results: Vec<ScenarioResult>
+}
+
+#[derive(Serialize)]
+struct ScenarioResult {
+    dataset: String,
+    scenario: &'static str,
+    rows: usize,
+    seconds: f64,
+    rows_per_second: f64,
+    bytes: u64,
+    segments: usize
+}
+
+/**
+ * A small deterministic generator, so every run loads exactly the same data.
+ */
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/**
+ * The schema and rows of a synthetic dataset, in the order they are loaded.
+ */
+fn generate(dataset: &str, num_rows: usize) -> (Schema, Vec<[Datum; 3]>) {
+    match dataset {
+        /* A fully populated matrix, 1000 columns wide */
+        "dense" => {
+            let schema = Schema::builder().dimension("x", 100).dimension("y", 100).value("value").build().unwrap();
+            let rows = (0..num_rows).map(|i| [i / 1000, i % 1000, (i * 31) % 1000]).collect();
+            (schema, rows)
+        },
+        /* Sensors reporting at different intervals, each from once a second to once a minute */
+        "sensors" => {
+            let schema = Schema::builder().dimension("time", 3600).dimension("sensor_id", 16).value("reading").build().unwrap();
+            let mut rows = Vec::with_capacity(num_rows);
+            let mut time = 0;
+            while rows.len() < num_rows {
+                for sensor_id in 0..NUM_SENSORS {
+                    if time % (1 + sensor_id % 60) == 0 && rows.len() < num_rows {
+                        rows.push([time, sensor_id, (time + sensor_id * 7) % 1000]);
+                    }
+                }
+                time += 1;
+            }
+            (schema, rows)
+        },
+        /* Readings from 20 sensors that mostly arrive in order, with some backfilled later */
+        "late" => {
+            let schema = Schema::builder().dimension("time", 600).dimension("sensor_id", 20).value("reading").build().unwrap();
+            let mut random = Random(0x9e3779b97f4a7c15);
+            let rows = (0..num_rows).map(|i| {
+                let mut time = i / 20;
+                if random.next().is_multiple_of(10) {
+                    time = time.saturating_sub((random.next() % LATE_WINDOW as u64) as Datum);
+                }
+                [time, i % 20, (time * 13) % 1000]
+            }).collect();
+            (schema, rows)
+        },
+        /* The dense matrix, loaded in interleaved passes, so every chunk has a block from each
+           batch and the scans before compaction merge them all, comparing points at every row */
+        "overlapping" => {
+            let schema = Schema::builder().dimension("x", 100).dimension("y", 100).value("value").build().unwrap();
+            let passes = num_rows.div_ceil(BATCH_SIZE);
+            let mut indexes: Vec<usize> = (0..num_rows).collect();
+            indexes.sort_by_key(|&i| i % passes);
+            let rows = indexes.into_iter().map(|i| [i / 1000, i % 1000, (i * 31) % 1000]).collect();
+            (schema, rows)
+        },
+        _ => usage()
+    }
+}
+
+/**
+ * Run the load, query and compact scenarios on a dataset in a new database.
+ */
+fn run(dataset: &str, num_rows: usize, backend: Box<dyn Backend>, results: &mut Vec<ScenarioResult>) {
+    let (schema, rows) = generate(dataset, num_rows);
+    let mut database = Database::create_with_backend(schema, backend).unwrap();
+    if dataset == "late" {
+        database.set_late_data(Some((0, LATE_WINDOW)));
+    }
+    let mut record = |database: &Database, scenario: &'static str, rows: usize, started: Instant| {
+        let seconds = started.elapsed().as_secs_f64();
+        let stats = database.stats();
+        let result = ScenarioResult {
+            dataset: dataset.to_string(),
+            scenario,
+            rows,
+            seconds,
+            rows_per_second: rows as f64 / seconds.max(f64::EPSILON),
+            bytes: stats.size,
+            segments: stats.num_segments
+        };
+        eprintln!("{:8} {:12} {:10} rows in {:8.3} s", dataset, scenario, rows, seconds);
+        results.push(result);
+    };
+
+    let started = Instant::now();
+    for batch in rows.chunks(BATCH_SIZE) {
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(batch).unwrap();
+        txn.commit().unwrap();
+    }
+    record(&database, "load", rows.len(), started);
+
+    let started = Instant::now();
+    let count = database.query_snapshot().query().count();
+    record(&database, "query-all", count, started);
+
+    /* The middle tenth of the first dimension */
+    let max_first = rows.iter().map(|row| row[0]).max().unwrap_or(0);
+    let criteria = Criteria::new().range(0, max_first * 9 / 20..=max_first * 11 / 20);
+    let started = Instant::now();
+    let count = database.query_snapshot().query_with(&criteria).unwrap().count();
+    record(&database, "query-range", count, started);
+
+    let started = Instant::now();
+    database.compact().unwrap();
+    record(&database, "compact", rows.len(), started);
+}
+
+fn main() {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut datasets: Vec<&str> = DATASETS.to_vec();
+    let mut num_rows = DEFAULT_NUM_ROWS;
+    let mut dir: Option<PathBuf> = None;
+    let mut output: Option<PathBuf> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(param) = args.next() else { usage() };
+        match arg.as_str() {
+            "--dataset" if DATASETS.contains(&param.as_str()) => datasets = vec![param],
+            "--rows" => num_rows = param.parse().unwrap_or_else(|_| usage()),
+            "--dir" => dir = Some(PathBuf::from(param)),
+            "--output" => output = Some(PathBuf::from(param)),
+            _ => usage()
+        }
+    }
+
+    let mut results = Vec::new();
+    for dataset in datasets {
+        /* In memory, unless a directory is given to measure a real filesystem */
+        let backend: Box<dyn Backend> = match &dir {
+            Some(dir) => {
+                let path = dir.join(format!("bench-{dataset}"));
+                if path.exists() {
+                    std::fs::remove_dir_all(&path).unwrap();
+                }
+                Box::new(FileBackend::create(&path).unwrap())
+            },
+            None => Box::new(MemoryBackend::new())
+        };
+        run(dataset, num_rows, backend, &mut results);
+    }
+
+    let report = Report { version: env!("CARGO_PKG_VERSION"), num_rows, results };
+    let json = serde_json::to_string_pretty(&report).unwrap();
+    match output {
+        Some(path) => writeln!(File::create(path).unwrap(), "{json}").unwrap(),
+        None => println!("{json}")
+    }
+}
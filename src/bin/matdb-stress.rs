@@ -0,0 +1,305 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::Cursor;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use matdb::{Backend, Criteria, Database, Datum, Error, ReadSeek, Schema};
+
+const DEFAULT_TRANSACTIONS: u64 = 1000;
+const MAX_X: u64 = 200;
+const MAX_Y: u64 = 50;
+const MAX_OPS_PER_TRANSACTION: u64 = 200;
+/* Every commit adds segments, so the database is rebuilt from a dump to keep queries quick */
+const REBUILD_INTERVAL: u64 = 50;
+
+fn usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!("    matdb-stress [--seed N] [--transactions N] [--seconds N]");
+    std::process::exit(1);
+}
+
+/**
+ * A small deterministic generator, so a failing run can be reproduced from its seed.
+ */
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/**
+ * An in-memory backend that can be made to crash after a number of operations: the write in
+ * progress leaves part of its file behind, and every later operation fails, until the
+ * database is dropped and the surviving files opened again.
+ */
+struct CrashBackend {
+    files: RefCell<HashMap<String, Vec<u8>>>,
+    ops_before_crash: Cell<Option<u64>>,
+    crashed: Cell<bool>
+}
+
+impl CrashBackend {
+    fn new() -> CrashBackend {
+        CrashBackend { files: RefCell::default(), ops_before_crash: Cell::new(None), crashed: Cell::new(false) }
+    }
+
+    fn crash_now(&self) -> bool {
+        if self.crashed.get() {
+            return true;
+        }
+        match self.ops_before_crash.get() {
+            Some(0) => {
+                self.crashed.set(true);
+                true
+            },
+            Some(n) => {
+                self.ops_before_crash.set(Some(n - 1));
+                false
+            },
+            None => false
+        }
+    }
+
+    fn restart(&self) {
+        self.ops_before_crash.set(None);
+        self.crashed.set(false);
+    }
+}
+
+impl Backend for CrashBackend {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        Ok(self.files.borrow().keys().cloned().collect())
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.files.borrow().contains_key(name)
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ReadSeek>, Error> {
+        let data = self.files.borrow().get(name).cloned().ok_or(Error::IoError)?;
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        let first_failure = !self.crashed.get();
+        if self.crash_now() {
+            if first_failure {
+                self.files.borrow_mut().insert(name.to_string(), data[..data.len() / 2].to_vec());
+            }
+            return Err(Error::IoError);
+        }
+        self.files.borrow_mut().insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        if self.crash_now() {
+            return Err(Error::IoError);
+        }
+        let mut files = self.files.borrow_mut();
+        let data = files.remove(from).ok_or(Error::IoError)?;
+        files.insert(to.to_string(), data);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        if self.crash_now() {
+            return Err(Error::IoError);
+        }
+        self.files.borrow_mut().remove(name).map(|_| ()).ok_or(Error::IoError)
+    }
+}
+
+/**
+ * The contents the database should have, by point.
+ */
+type Model = BTreeMap<(Datum, Datum), Datum>;
+
+#[derive(Default)]
+struct Counts {
+    transactions: u64,
+    commits: u64,
+    rollbacks: u64,
+    crashes: u64,
+    inserts: u64,
+    queries: u64,
+    flushes: u64,
+    compactions: u64,
+    rebuilds: u64
+}
+
+/**
+ * Check that a query returns exactly the rows of the model in a range of the first dimension.
+ */
+fn check_query<I: Iterator<Item = matdb::QueryRow>>(rows: I, model: &Model, x_range: (Datum, Datum), context: &str) {
+    let mut found: Vec<(Datum, Datum, Datum)> = rows.map(|r| (r[0], r[1], r[2])).collect();
+    found.sort();
+    let expected: Vec<(Datum, Datum, Datum)> = model.range((x_range.0, 0)..=(x_range.1, Datum::MAX))
+        .map(|(&(x, y), &value)| (x, y, value))
+        .collect();
+    if found != expected {
+        eprintln!("{context}: query of x in {x_range:?} returned {} rows, expected {}", found.len(), expected.len());
+        for (f, e) in found.iter().zip(&expected).filter(|(f, e)| f != e).take(5) {
+            eprintln!("    found {f:?}, expected {e:?}");
+        }
+        std::process::exit(2);
+    }
+}
+
+fn random_range(random: &mut Random) -> (Datum, Datum) {
+    let start = random.below(MAX_X);
+    let end = start + random.below(MAX_X - start);
+    (start as Datum, end as Datum)
+}
+
+/**
+ * Run one transaction of random operations against the database, keeping the model of what
+ * it holds up to date.  If a crash interrupts the commit, the transaction may or may not have
+ * committed, so the model it would have left is returned for recovery to decide.
+ */
+fn run_transaction(database: &mut Database, backend: &CrashBackend, random: &mut Random, committed: &mut Model, counts: &mut Counts, context: &str) -> Option<Model> {
+    let mut pending = committed.clone();
+    let mut txn = database.new_transaction().unwrap();
+    let num_ops = 1 + random.below(MAX_OPS_PER_TRANSACTION);
+    for _ in 0..num_ops {
+        match random.below(100) {
+            0..=89 => {
+                let (x, y, value) = (random.below(MAX_X) as Datum, random.below(MAX_Y) as Datum, random.below(1000) as Datum);
+                txn.add_row(&[x, y, value]).unwrap();
+                pending.insert((x, y), value);
+                counts.inserts += 1;
+            },
+            90..=98 => {
+                let range = random_range(random);
+                let scan = txn.query_with(&Criteria::new().range(0, range.0..=range.1)).unwrap();
+                check_query(scan, &pending, range, context);
+                counts.queries += 1;
+            },
+            _ => {
+                txn.flush().unwrap();
+                counts.flushes += 1;
+            }
+        }
+    }
+
+    match random.below(10) {
+        0..=1 => {
+            txn.rollback();
+            counts.rollbacks += 1;
+            None
+        },
+        2 => {
+            /* Crash while committing, or just after */
+            backend.ops_before_crash.set(Some(random.below(8)));
+            counts.crashes += 1;
+            match txn.commit() {
+                Ok(_) => {
+                    *committed = pending;
+                    None
+                },
+                Err(_) => Some(pending)
+            }
+        },
+        _ => {
+            txn.commit().unwrap();
+            *committed = pending;
+            counts.commits += 1;
+            None
+        }
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut seed = 1;
+    let mut max_transactions = DEFAULT_TRANSACTIONS;
+    let mut max_duration: Option<Duration> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(param) = args.next() else { usage() };
+        let Ok(number) = param.parse::<u64>() else { usage() };
+        match arg.as_str() {
+            "--seed" => seed = number,
+            "--transactions" => max_transactions = number,
+            "--seconds" => {
+                max_duration = Some(Duration::from_secs(number));
+                max_transactions = u64::MAX;
+            },
+            _ => usage()
+        }
+    }
+
+    let schema = Schema::builder().dimension("x", 16).dimension("y", 16).value("value").build().unwrap();
+    let mut backend = Rc::new(CrashBackend::new());
+    let mut database = Database::create_with_backend(schema, Box::new(backend.clone())).unwrap();
+    let mut random = Random::new(seed);
+    let mut committed = Model::new();
+    let mut counts = Counts::default();
+    let started = Instant::now();
+
+    while counts.transactions < max_transactions && max_duration.is_none_or(|duration| started.elapsed() < duration) {
+        let context = format!("seed {seed} transaction {}", counts.transactions);
+        counts.transactions += 1;
+        let in_flight = run_transaction(&mut database, &backend, &mut random, &mut committed, &mut counts, &context);
+
+        if backend.ops_before_crash.get().is_some() {
+            /* Restart from the files that survived; a commit in flight is all there or not at all */
+            drop(database);
+            backend.restart();
+            database = Database::open_with_backend(Box::new(backend.clone()))
+                .unwrap_or_else(|err| panic!("{context}: open after crash failed with {err:?}"));
+            let found: Model = database.query_snapshot().query().map(|r| ((r[0], r[1]), r[2])).collect();
+            match in_flight {
+                Some(pending) if found == pending => committed = pending,
+                _ => {}
+            }
+            if found != committed {
+                eprintln!("{context}: recovered {} rows, expected {}", found.len(), committed.len());
+                std::process::exit(2);
+            }
+        }
+
+        let range = random_range(&mut random);
+        let snapshot = database.query_snapshot();
+        check_query(snapshot.query_with(&Criteria::new().range(0, range.0..=range.1)).unwrap(), &committed, range, &context);
+        counts.queries += 1;
+
+        if random.below(20) == 0 {
+            database.compact().unwrap();
+            counts.compactions += 1;
+        }
+
+        if counts.transactions % REBUILD_INTERVAL == 0 {
+            let mut dump = Vec::new();
+            database.dump(&mut dump).unwrap();
+            drop(database);
+            backend = Rc::new(CrashBackend::new());
+            database = Database::restore_with_backend(&mut dump.as_slice(), Box::new(backend.clone())).unwrap();
+            check_query(database.query_snapshot().query(), &committed, (0, MAX_X as Datum), &context);
+            counts.rebuilds += 1;
+        }
+    }
+
+    println!("Seed {seed}: {} transactions in {:?}", counts.transactions, started.elapsed());
+    println!("    {} commits, {} rollbacks, {} crashes, {} compactions, {} rebuilds",
+        counts.commits, counts.rollbacks, counts.crashes, counts.compactions, counts.rebuilds);
+    println!("    {} inserts, {} queries, {} flushes", counts.inserts, counts.queries, counts.flushes);
+    println!("    {} rows at the end", committed.len());
+}
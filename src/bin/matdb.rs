@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use matdb::{BlockStats, Database, Datum, FileBackend, SegmentId};
+
+fn usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!("    matdb dump DATABASE FILENAME");
+    eprintln!("    matdb restore FILENAME DATABASE");
+    eprintln!("    matdb export DATABASE DIRECTORY PARTITION_SIZE");
+    eprintln!("    matdb compact DATABASE");
+    eprintln!("    matdb ingest DATABASE SEGMENTFILE");
+    eprintln!("    matdb serve DATABASE ADDRESS");
+    eprintln!("    matdb stats DATABASE [--detail]");
+    eprintln!("    matdb column-stats DATABASE");
+    eprintln!("    matdb history DATABASE");
+    std::process::exit(1);
+}
+
+fn main() {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        usage();
+    }
+
+    let first_arg = &args[1];
+
+    if first_arg == "dump" {
+        /* Dump the database to a file */
+        if args.len() != 4 { usage(); }
+        let now = Instant::now();
+        let mut matdb = Database::open(Path::new(&args[2])).unwrap();
+        let mut writer = BufWriter::new(File::create(&args[3]).unwrap());
+        let count = matdb.dump(&mut writer).unwrap();
+        writer.flush().unwrap();
+        println!("Dumped {} rows in {:?}", count, now.elapsed());
+    } else if first_arg == "restore" {
+        /* Create a new database from a dump file */
+        if args.len() != 4 { usage(); }
+        let now = Instant::now();
+        let mut reader = BufReader::new(File::open(&args[2]).unwrap());
+        Database::restore(&mut reader, Path::new(&args[3])).unwrap();
+        println!("Restored in {:?}", now.elapsed());
+    } else if first_arg == "export" {
+        /* Export to CSV files by partition, resuming an interrupted export to the same directory */
+        if args.len() != 5 { usage(); }
+        let Ok(partition_size) = args[4].parse::<Datum>() else { usage() };
+        let now = Instant::now();
+        let matdb = Database::open(Path::new(&args[2])).unwrap();
+        let dest = Path::new(&args[3]);
+        let backend = if dest.exists() { FileBackend::new(dest) } else { FileBackend::create(dest).unwrap() };
+        let manifest = matdb.export_partitions(&backend, partition_size).unwrap();
+        println!("Exported {} partitions in {:?}", manifest.partitions.len(), now.elapsed());
+    } else if first_arg == "compact" {
+        /* Rewrite old segments in the current format, and remove duplicate blocks */
+        if args.len() != 3 { usage(); }
+        let now = Instant::now();
+        let mut matdb = Database::open(Path::new(&args[2])).unwrap();
+        let count = matdb.compact().unwrap();
+        /* No other reader has the database open, so every transaction is before the horizon */
+        let horizon = matdb.next_transaction_id;
+        let count = count + matdb.remove_duplicate_blocks(horizon).unwrap();
+        println!("Rewrote {} segments in {:?}", count, now.elapsed());
+    } else if first_arg == "ingest" {
+        /* Adopt a segment file built by a SegmentEncoder */
+        if args.len() != 4 { usage(); }
+        let now = Instant::now();
+        let mut matdb = Database::open(Path::new(&args[2])).unwrap();
+        let seg_id = matdb.ingest_segment_file(Path::new(&args[3])).unwrap();
+        println!("Ingested segment {:?} in {:?}", seg_id, now.elapsed());
+    } else if first_arg == "serve" {
+        /* Serve blocks to remote readers */
+        if args.len() != 4 { usage(); }
+        serve(Path::new(&args[2]), &args[3]);
+    } else if first_arg == "stats" {
+        /* Report the database's size, and optionally how well its blocks are filled */
+        let detail = match args.len() {
+            3 => false,
+            4 if args[3] == "--detail" => true,
+            _ => usage()
+        };
+        let matdb = Database::open(Path::new(&args[2])).unwrap();
+        let stats = matdb.stats();
+        println!("Segments: {}", stats.num_segments);
+        println!("Size: {} bytes", stats.size);
+        for column in &stats.value_columns {
+            println!("Missing cells in {}: {:.1}%", column.name, column.missing_fraction() * 100.0);
+        }
+        if detail {
+            print_block_stats(&matdb.block_stats().unwrap(), &matdb.late_segments());
+        }
+    } else if first_arg == "column-stats" {
+        /* Print the statistics gathered by the last compaction, for other tools to read */
+        if args.len() != 3 { usage(); }
+        let matdb = Database::open(Path::new(&args[2])).unwrap();
+        match matdb.data_stats().unwrap() {
+            Some(stats) => println!("{}", serde_json::to_string_pretty(&stats).unwrap()),
+            None => eprintln!("No statistics; run compact to gather them")
+        }
+    } else if first_arg == "history" {
+        /* List the maintenance actions recorded in the journal */
+        if args.len() != 3 { usage(); }
+        let matdb = Database::open(Path::new(&args[2])).unwrap();
+        for entry in matdb.history().unwrap() {
+            let time = chrono::DateTime::from_timestamp(entry.time as i64, 0).unwrap_or_default();
+            println!("{} {:8} {}", time.format("%Y-%m-%d %H:%M:%S"), entry.action, entry.detail);
+        }
+    } else {
+        usage();
+    }
+}
+
+const NUM_COMPACTION_CANDIDATES: usize = 10;
+
+/**
+ * Print histograms of block fill ratios and of the number of segments with blocks in each
+ * chunk, followed by the segments holding late data and the chunks spread over the most
+ * segments, which gain the most from compaction.
+ */
+fn print_block_stats(block_stats: &[BlockStats], late_segments: &[SegmentId]) {
+    let mut fill_counts = [0; 10];
+    let mut chunk_segments: BTreeMap<&Vec<Datum>, BTreeSet<SegmentId>> = BTreeMap::new();
+    for stats in block_stats {
+        let bucket = ((stats.fill_ratio() * 10.0) as usize).min(9);
+        fill_counts[bucket] += 1;
+        chunk_segments.entry(&stats.chunk).or_default().insert((stats.block_id.0, stats.block_id.1));
+    }
+
+    println!("Blocks: {}", block_stats.len());
+    println!("Fill ratio histogram:");
+    for (bucket, count) in fill_counts.iter().enumerate() {
+        println!("    {:3}-{:3}%  {}", bucket * 10, bucket * 10 + 10, count);
+    }
+
+    let mut overlap_counts: BTreeMap<usize, usize> = BTreeMap::new();
+    for segments in chunk_segments.values() {
+        *overlap_counts.entry(segments.len()).or_default() += 1;
+    }
+    println!("Chunks: {}", chunk_segments.len());
+    println!("Segments per chunk histogram:");
+    for (num_segments, count) in &overlap_counts {
+        println!("    {:6}  {}", num_segments, count);
+    }
+
+    let mut candidates: Vec<_> = chunk_segments.into_iter()
+        .map(|(chunk, segments)| (chunk, segments.len()))
+        .filter(|&(_, num_segments)| num_segments > 1)
+        .collect();
+    candidates.sort_by_key(|&(_, num_segments)| std::cmp::Reverse(num_segments));
+    if !candidates.is_empty() || !late_segments.is_empty() {
+        println!("Compaction candidates:");
+        for seg_id in late_segments {
+            println!("    segment {:?} holds late data", seg_id);
+        }
+        for (chunk, num_segments) in candidates.into_iter().take(NUM_COMPACTION_CANDIDATES) {
+            println!("    chunk {:?} is in {} segments", chunk, num_segments);
+        }
+    }
+}
+
+/**
+ * Serve one connection at a time, opening the database again for each so that every client
+ * sees the latest committed data.
+ */
+#[cfg(feature = "remote")]
+fn serve(path: &Path, address: &str) {
+    let listener = std::net::TcpListener::bind(address).unwrap();
+    println!("Serving {:?} on {}", path, address);
+    for stream in listener.incoming() {
+        let stream = stream.unwrap();
+        let matdb = Database::open(path).unwrap();
+        if let Err(err) = matdb.serve(stream) {
+            eprintln!("Error serving client: {:?}", err);
+        }
+    }
+}
+
+#[cfg(not(feature = "remote"))]
+fn serve(_path: &Path, _address: &str) {
+    eprintln!("matdb was built without the remote feature");
+    std::process::exit(1);
+}
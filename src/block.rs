@@ -1,13 +1,31 @@
+use std::collections::BTreeSet;
 use std::io::{Read, Write};
 use std::io;
 use std::rc::Rc;
 
 use byteorder::{BE, ReadBytesExt, WriteBytesExt};
 use crate::{Datum};
-
+use crate::format::FormatVersion;
+use crate::schema::CellOrder;
+use crate::storage::read_bytes;
+use crate::query::Criteria;
+
+/**
+ * A chunk's rows, stored as a grid with a cell for every combination of the dimension values
+ * present.  Each value column has its own array with an entry per cell, and a cell holds a row
+ * if any of them has a value there.  A row without every value is a partial version, written
+ * by `Transaction::update`; a scan takes the missing values from the version before it.  The
+ * cells are in row-major order unless the block was loaded from a segment that saved them in
+ * another `CellOrder`.
+ */
+#[derive(Clone)]
 pub struct Block {
     pub(crate) dimension_values: Vec<Vec<Datum>>,
     pub(crate) values: Vec<Option<Datum>>,
+    pub(crate) extra_values: Vec<Vec<Option<Datum>>>,
+    /* The cells holding a row, if the block was loaded with only some of its value columns */
+    rows: Option<Vec<bool>>,
+    pub(crate) order: CellOrder
 }
 
 #[derive(Debug)]
@@ -23,33 +41,104 @@ struct SliceInsertionParams {
 pub struct BlockIter {
     block: Rc<Block>,
     indexes: Vec<usize>,
-    value_index: usize
+    value_index: usize,
+    /* The value columns to return, in order, if not all of them */
+    selected: Option<Vec<usize>>,
+    /* The range of indexes of each dimension to visit, if not all of them */
+    window: Option<Vec<(usize, usize)>>
 }
 
 impl Block {
     pub(crate) fn new(num_dimensions: usize) -> Self {
         Block {
             dimension_values: vec![Vec::new(); num_dimensions],
-            values: Vec::new()
+            values: Vec::new(),
+            extra_values: Vec::new(),
+            rows: None,
+            order: CellOrder::RowMajor
+        }
+    }
+
+    /**
+     * A block with the given values for each dimension, which must be in increasing order, and
+     * one array per value column with an entry per cell, in the order `iter` visits them.
+     */
+    pub(crate) fn from_dense(dimension_values: Vec<Vec<Datum>>, columns: &[Vec<Option<Datum>>]) -> Self {
+        let (first, extra) = columns.split_first().map_or((Vec::new(), &[][..]), |(first, extra)| (first.clone(), extra));
+        Block {
+            dimension_values,
+            values: first,
+            extra_values: extra.to_vec(),
+            rows: None,
+            order: CellOrder::RowMajor
         }
     }
 
     pub(crate) fn add_row(&mut self, values: &[Datum]) {
+        self.add_partial_row(values, &[]);
+    }
+
+    /**
+     * Add a row without the values numbered in `missing`, whose entries in `values` are
+     * ignored.  A row already at the point keeps its own values for those.
+     */
+    pub(crate) fn add_partial_row(&mut self, values: &[Datum], missing: &[usize]) {
+        /* Inserting dimension values moves whole slices of a row-major grid */
+        if self.order != CellOrder::RowMajor {
+            *self = self.with_order(CellOrder::RowMajor);
+        }
+
         let mut dim_idxs = Vec::new();
-        for dim_no in 0..self.dimension_values.len() {
-            let dim_value = values[dim_no];
+        let num_dims = self.dimension_values.len();
+        for (dim_no, &dim_value) in values.iter().take(num_dims).enumerate() {
             let dim_idx = self.add_dimension_value(dim_no, dim_value);
             dim_idxs.push(dim_idx);
         }
 
-        for value_no in self.dimension_values.len()..values.len() {
-            let value = values[value_no];
-            let idx = self.get_index(&dim_idxs);
-            self.values[idx] = Some(value);
+        let row_values = &values[num_dims.min(values.len())..];
+        let Some((&first, extra)) = row_values.split_first() else { return };
+        if self.extra_values.len() < extra.len() {
+            self.extra_values.resize(extra.len(), vec![None; self.values.len()]);
+        }
+        let idx = self.get_index(&dim_idxs);
+        if !missing.contains(&0) {
+            self.values[idx] = Some(first);
         }
+        for (value_no, (column, &value)) in (1..).zip(self.extra_values.iter_mut().zip(extra)) {
+            if !missing.contains(&value_no) {
+                column[idx] = Some(value);
+            }
+        }
+    }
+
+    fn has_row(&self, idx: usize) -> bool {
+        if let Some(rows) = &self.rows {
+            return rows[idx];
+        }
+        self.values[idx].is_some() || self.extra_values.iter().any(|column| column[idx].is_some())
+    }
+
+    /**
+     * Whether every row has every value, so that none is a partial version.  A block loaded
+     * with only some of its value columns is never complete.
+     */
+    pub(crate) fn is_complete(&self) -> bool {
+        self.rows.is_none() && (0..self.values.len()).all(|idx| !self.has_row(idx)
+            || (self.values[idx].is_some() && self.extra_values.iter().all(|column| column[idx].is_some())))
     }
 
     fn get_index(&self, dim_indexes: &[usize]) -> usize {
+        if let CellOrder::Tiled(side) = self.order {
+            /* Tiles fill bands of `side` rows, and are cut short at the bottom and right edges */
+            let side = side as usize;
+            let (num_rows, num_cols) = (self.dimension_values[0].len(), self.dimension_values[1].len());
+            let (row, col) = (dim_indexes[0], dim_indexes[1]);
+            let (top, left) = (row - row % side, col - col % side);
+            let height = side.min(num_rows - top);
+            let width = side.min(num_cols - left);
+            return top * num_cols + left * height + (row - top) * width + (col - left);
+        }
+
         let mut idx = 0;
 
         let num_dims = self.dimension_values.len();
@@ -64,6 +153,43 @@ impl Block {
         idx
     }
 
+    /**
+     * A copy of the block with its cells in another order.
+     */
+    pub(crate) fn with_order(&self, order: CellOrder) -> Block {
+        let mut block = Block::new(0);
+        block.dimension_values = self.dimension_values.clone();
+        block.order = order;
+
+        /* Find the cell each cell of the copy comes from, visiting them in row-major order */
+        let sizes: Vec<usize> = self.dimension_values.iter().map(Vec::len).collect();
+        let mut sources = vec![0; self.num_cells()];
+        let mut indexes = vec![0; sizes.len()];
+        for _ in 0..sources.len() {
+            sources[block.get_index(&indexes)] = self.get_index(&indexes);
+            for pos in (0..indexes.len()).rev() {
+                indexes[pos] += 1;
+                if indexes[pos] < sizes[pos] {
+                    break;
+                }
+                indexes[pos] = 0;
+            }
+        }
+
+        /* Columns that weren't loaded stay empty */
+        let reorder = |column: &Vec<Option<Datum>>| -> Vec<Option<Datum>> {
+            if column.len() != sources.len() {
+                return Vec::new();
+            }
+            sources.iter().map(|&idx| column[idx]).collect()
+        };
+        let mut columns = std::iter::once(&self.values).chain(&self.extra_values).map(reorder);
+        block.values = columns.next().unwrap_or_default();
+        block.extra_values = columns.collect();
+        block.rows = self.rows.as_ref().map(|rows| sources.iter().map(|&idx| rows[idx]).collect());
+        block
+    }
+
     fn add_dimension_value(&mut self, dim_no: usize, value: Datum) -> usize {
         match self.dimension_values[dim_no].binary_search(&value) {
             Ok(idx) => idx,
@@ -79,6 +205,9 @@ impl Block {
         let params = self.get_slice_insertion_params(dim_no, idx);
 
         self.values.resize(params.new_size, None);
+        for column in &mut self.extra_values {
+            column.resize(params.new_size, None);
+        }
 
         for i in (0..params.moves).rev() {
             let from_offset = i * params.len + params.offset;
@@ -95,12 +224,18 @@ impl Block {
         }
         let value_slice = self.values.as_mut_slice();
         value_slice.copy_within(from_idx..from_idx+num, to_idx);
+        for column in &mut self.extra_values {
+            column.copy_within(from_idx..from_idx+num, to_idx);
+        }
     }
 
     fn clear_elements(&mut self, from_idx: usize, to_idx: usize) {
         for i in from_idx..to_idx {
             self.values[i] = None;
         }
+        for column in &mut self.extra_values {
+            column[from_idx..to_idx].fill(None);
+        }
     }
 
     fn get_slice_insertion_params(&self, dim_no: usize, index: usize) -> SliceInsertionParams {
@@ -133,11 +268,32 @@ impl Block {
         }
     }
 
-    pub(crate) fn load<R: Read>(&mut self, src: &mut R) -> io::Result<()> {
-        let mut num_values = 1;
+    /**
+     * Load a block saved by `save`.  Corrupt data fails with an `InvalidData` error; memory is
+     * only allocated for data actually present, however large the sizes in it claim to be.
+     */
+    pub(crate) fn load<R: Read>(&mut self, src: &mut R, version: FormatVersion) -> io::Result<()> {
+        self.decode(src, version, None)
+    }
+
+    /**
+     * Load a block saved by `save`, decoding only the value columns numbered in `values`.  From
+     * version 10 the others are skipped without being decoded and left empty, and the block
+     * remembers which cells hold a row; such a block can be iterated with those columns
+     * selected, but not saved.  Earlier versions always load every column.
+     */
+    pub(crate) fn load_values<R: Read>(&mut self, src: &mut R, version: FormatVersion, values: &[usize]) -> io::Result<()> {
+        self.decode(src, version, Some(values))
+    }
+
+    fn decode<R: Read>(&mut self, src: &mut R, version: FormatVersion, values: Option<&[usize]>) -> io::Result<()> {
+        let mut num_values: usize = 1;
 
         /* Read the dimensions */
         let num_dimensions = src.read_u16::<BE>()?;
+        if num_dimensions == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "block has no dimensions"));
+        }
         self.dimension_values.clear();
         for _ in 0..num_dimensions {
             let mut dim_vals: Vec<Datum> = Vec::new();
@@ -146,30 +302,112 @@ impl Block {
                 let dim_idx = src.read_u64::<BE>()?;
                 dim_vals.push(dim_idx as Datum);
             }
+            if !dim_vals.windows(2).all(|w| w[0] < w[1]) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "block dimension values are not in order"));
+            }
             self.dimension_values.push(dim_vals);
-            num_values *= dim_size;
+            num_values = num_values.checked_mul(dim_size)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "block is too large"))?;
+        }
+
+        /* Version 11 follows with the order of the cells */
+        self.order = CellOrder::RowMajor;
+        if version >= 11 {
+            self.order = match src.read_u8()? {
+                0 => CellOrder::RowMajor,
+                1 => CellOrder::Tiled(src.read_u16::<BE>()?),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "block has an unknown cell order"))
+            };
+            if matches!(self.order, CellOrder::Tiled(side) if side == 0 || num_dimensions != 2) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "block has tiles that don't fit its dimensions"));
+            }
         }
 
         /* Read the values */
         self.values.clear();
-        self.values.reserve(num_values);
+        self.extra_values.clear();
+        self.rows = None;
+
+        let rows: Vec<bool>;
+        if version == 0 {
+            /* Version 0 has one byte per value, which is 1 if the value is missing. */
+            let missing_bytes = read_bytes(src, num_values)?;
+
+            for &missing in &missing_bytes {
+                if missing == 1 {
+                    self.values.push(None);
+                } else {
+                    let val = src.read_u64::<BE>()? as Datum;
+                    self.values.push(Some(val));
+                }
+            }
+            rows = self.values.iter().map(Option::is_some).collect();
+        } else {
+            /* Later versions have a bitmap, with a bit set for each cell holding a row. */
+            let present_bits = read_bytes(src, num_values.div_ceil(8))?;
+            rows = (0..num_values).map(|i| present_bits[i / 8] & (1 << (i % 8)) != 0).collect();
+            if version >= 10 {
+                return self.load_sized_columns(src, rows, version, values);
+            }
+            self.values = load_column(src, &rows, version)?;
+        }
+
+        /* Version 6 follows with the other value columns, with entries only for cells with rows */
+        if version >= 6 {
+            let num_extra = src.read_u16::<BE>()?;
+            for _ in 0..num_extra {
+                self.extra_values.push(load_column(src, &rows, version)?);
+            }
+        }
+
+        if rows.iter().enumerate().any(|(idx, &row)| row && !self.has_row(idx)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "block has a row without values"));
+        }
 
-        let mut missing_bytes: Vec<u8> = vec![1; num_values];
-        src.read_exact(&mut missing_bytes)?;
+        Ok(())
+    }
 
-        for &missing in &missing_bytes {
-            if missing == 1 {
-                self.values.push(None);
+    /**
+     * Load the value columns of a version 10 block, each prefixed with its length so that
+     * those not in `values` can be skipped.
+     */
+    fn load_sized_columns<R: Read>(&mut self, src: &mut R, rows: Vec<bool>, version: FormatVersion, values: Option<&[usize]>) -> io::Result<()> {
+        let num_columns = src.read_u16::<BE>()? as usize;
+        if num_columns == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "block has no value columns"));
+        }
+        let mut columns = Vec::with_capacity(num_columns);
+        for value_no in 0..num_columns {
+            let len = src.read_u32::<BE>()? as u64;
+            let mut section = src.take(len);
+            if values.is_some_and(|values| !values.contains(&value_no)) {
+                io::copy(&mut section, &mut io::sink())?;
+                columns.push(Vec::new());
             } else {
-                let val = src.read_u64::<BE>()? as Datum;
-                self.values.push(Some(val));
+                columns.push(load_column(&mut section, &rows, version)?);
+            }
+            if section.limit() != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "block value column has the wrong length"));
             }
         }
+        let mut columns = columns.into_iter();
+        self.values = columns.next().unwrap_or_default();
+        self.extra_values = columns.collect();
+
+        if values.is_some() {
+            self.rows = Some(rows);
+        } else if rows.iter().enumerate().any(|(idx, &row)| row && !self.has_row(idx)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "block has a row without values"));
+        }
 
         Ok(())
     }
 
-    pub(crate) fn save<W: Write>(&self, dest: &mut W) -> io::Result<()> {
+    pub(crate) fn save<W: Write>(&self, dest: &mut W, version: FormatVersion) -> io::Result<()> {
+        if version < 11 && self.order != CellOrder::RowMajor {
+            return self.with_order(CellOrder::RowMajor).save(dest, version);
+        }
+
         /* Write the dimensions */
         dest.write_u16::<BE>(self.dimension_values.len() as u16)?;
         for dim in &self.dimension_values {
@@ -178,26 +416,142 @@ impl Block {
                 dest.write_u64::<BE>(dim_val as u64)?;
             }
         }
+        if version >= 11 {
+            match self.order {
+                CellOrder::RowMajor => dest.write_u8(0)?,
+                CellOrder::Tiled(side) => {
+                    dest.write_u8(1)?;
+                    dest.write_u16::<BE>(side)?;
+                }
+            }
+        }
 
         /* Write the values */
-        let mut missing_bytes: Vec<u8> = Vec::new();
-        let mut values_bytes: Vec<u8> = Vec::new();
+        if !self.has_all_values() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "block wasn't loaded with all of its value columns"));
+        }
+        if version < 6 && !self.extra_values.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "format version can't hold more than one value column"));
+        }
+        if version < 8 && !self.is_complete() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "format version can't hold partial rows"));
+        }
 
-        for &val in &self.values {
-            if let Some(value) = val {
-                missing_bytes.push(0);
-                values_bytes.extend(usize::to_be_bytes(value));
-            } else {
-                missing_bytes.push(1);
+        if version == 0 {
+            let mut flag_bytes: Vec<u8> = Vec::new();
+            let mut values_bytes: Vec<u8> = Vec::new();
+            for &val in &self.values {
+                if let Some(value) = val {
+                    flag_bytes.push(0);
+                    values_bytes.extend(usize::to_be_bytes(value));
+                } else {
+                    flag_bytes.push(1);
+                }
+            }
+            dest.write_all(flag_bytes.as_slice())?;
+            dest.write_all(values_bytes.as_slice())?;
+        } else {
+            let rows: Vec<bool> = (0..self.values.len()).map(|idx| self.has_row(idx)).collect();
+            dest.write_all(&to_bitmap(rows.iter().copied()))?;
+            if version >= 10 {
+                dest.write_u16::<BE>(1 + self.extra_values.len() as u16)?;
+                for column in std::iter::once(&self.values).chain(&self.extra_values) {
+                    let mut data = Vec::new();
+                    save_column(&mut data, &rows, column, version)?;
+                    dest.write_u32::<BE>(data.len() as u32)?;
+                    dest.write_all(&data)?;
+                }
+                return Ok(());
+            }
+            save_column(dest, &rows, &self.values, version)?;
+            if version >= 6 {
+                dest.write_u16::<BE>(self.extra_values.len() as u16)?;
+                for column in &self.extra_values {
+                    save_column(dest, &rows, column, version)?;
+                }
             }
         }
 
-        dest.write_all(missing_bytes.as_slice())?;
-        dest.write_all(values_bytes.as_slice())?;
-
         Ok(())
     }
 
+    pub(crate) fn num_rows(&self) -> usize {
+        (0..self.num_cells()).filter(|&idx| self.has_row(idx)).count()
+    }
+
+    /**
+     * The number of rows with each value.  They differ only if some rows are partial versions.
+     */
+    pub(crate) fn column_counts(&self) -> Vec<usize> {
+        std::iter::once(&self.values).chain(&self.extra_values)
+            .map(|column| column.iter().filter(|value| value.is_some()).count())
+            .collect()
+    }
+
+    /**
+     * The number of cells in the block's grid, whether they hold a row or not.
+     */
+    pub(crate) fn num_cells(&self) -> usize {
+        self.rows.as_ref().map_or(self.values.len(), Vec::len)
+    }
+
+    /**
+     * Whether the block was loaded with all of its value columns; see `load_values`.
+     */
+    pub(crate) fn has_all_values(&self) -> bool {
+        self.rows.is_none()
+    }
+
+    /**
+     * Roughly how many bytes the block occupies in memory.
+     */
+    pub(crate) fn memory_size(&self) -> usize {
+        let dims_size: usize = self.dimension_values.iter().map(|dim| size_of::<Vec<Datum>>() + dim.capacity() * size_of::<Datum>()).sum();
+        let extra_size: usize = self.extra_values.iter().map(|column| size_of::<Vec<Option<Datum>>>() + column.capacity() * size_of::<Option<Datum>>()).sum();
+        size_of::<Block>() + dims_size + self.values.capacity() * size_of::<Option<Datum>>() + extra_size
+    }
+
+    /**
+     * Roughly how many bytes the block takes to save, before compression.
+     */
+    pub(crate) fn saved_size(&self) -> usize {
+        let dims_size: usize = self.dimension_values.iter().map(|dim| 4 + dim.len() * 8).sum();
+        2 + dims_size + self.num_cells().div_ceil(8) + 2 + self.num_rows() * 8 * (1 + self.extra_values.len())
+    }
+
+    /**
+     * Split a block into smaller ones, each saving to at most `max_size` bytes if possible.
+     * The block is halved on the first dimension with more than one value, repeatedly, so the
+     * pieces are smaller in sparse regions and the empty cells between their rows disappear.
+     * A block with only one value in every dimension can't be split.
+     */
+    pub(crate) fn split(this: &Rc<Self>, max_size: usize) -> Vec<Rc<Block>> {
+        let split_dim = this.dimension_values.iter().position(|dim| dim.len() > 1);
+        let Some(dim_no) = split_dim.filter(|_| this.saved_size() > max_size) else {
+            return vec![this.clone()];
+        };
+
+        let dim_values = &this.dimension_values[dim_no];
+        let split_value = dim_values[dim_values.len() / 2];
+        let mut lower = Block::new(this.dimension_values.len());
+        let mut upper = Block::new(this.dimension_values.len());
+        for (row, missing) in Block::iter(this).partial() {
+            if row[dim_no] < split_value {
+                lower.add_partial_row(&row, &missing);
+            } else {
+                upper.add_partial_row(&row, &missing);
+            }
+        }
+
+        let mut pieces = Vec::new();
+        for piece in [lower, upper] {
+            if piece.get_start_point().is_some() {
+                pieces.extend(Block::split(&Rc::new(piece), max_size));
+            }
+        }
+        pieces
+    }
+
     pub(crate) fn get_start_point(&self) -> Option<Vec<Datum>> {
         let mut point = Vec::with_capacity(self.dimension_values.len());
         for dimvals in &self.dimension_values {
@@ -231,16 +585,213 @@ impl Block {
         point
     }
 
+    /**
+     * Add the values of one dimension present in rows matching the criteria.  If the block lies
+     * entirely within the criteria on the other dimensions, this is just the block's own list of
+     * values for that dimension; otherwise each row has to be checked.
+     */
+    pub(crate) fn add_distinct_values(this: &Rc<Self>, dim_no: usize, criteria: &Criteria, values: &mut BTreeSet<Datum>) {
+        if criteria.without(dim_no).covers_bounds(&this.get_min_bounds(), &this.get_max_bounds()) {
+            values.extend(this.dimension_values[dim_no].iter()
+                .filter(|&&v| criteria.matches_value(dim_no, v)));
+        } else {
+            values.extend(Block::iter(this)
+                .filter(|row| criteria.matches_point(row))
+                .map(|row| row[dim_no]));
+        }
+    }
+
     pub(crate) fn iter(this: &Rc<Self>) -> BlockIter {
         BlockIter {
             block: this.clone(),
             indexes: vec![0; this.dimension_values.len()],
-            value_index: 0
+            value_index: 0,
+            selected: None,
+            window: None
+        }
+    }
+
+    /**
+     * Value column `value_no`, with an entry for every cell, unless it wasn't loaded.
+     */
+    fn column(&self, value_no: usize) -> Option<&Vec<Option<Datum>>> {
+        let column = match value_no {
+            0 => Some(&self.values),
+            _ => self.extra_values.get(value_no - 1)
+        };
+        column.filter(|column| column.len() == self.num_cells())
+    }
+}
+
+/**
+ * Load a value column saved by `save_column`, with an entry for every cell.
+ */
+fn load_column<R: Read>(src: &mut R, rows: &[bool], version: FormatVersion) -> io::Result<Vec<Option<Datum>>> {
+    /* Version 8 records whether the column has a value in every row, or else a bitmap of the rows that do */
+    let mut present = None;
+    if version >= 8 && src.read_u8()? == 0 {
+        let num_rows = rows.iter().filter(|&&row| row).count();
+        present = Some(read_bytes(src, num_rows.div_ceil(8))?);
+    }
+
+    let mut column = Vec::with_capacity(rows.len());
+    let mut row_no = 0;
+    for &row in rows {
+        let mut value = None;
+        if row {
+            if present.as_ref().is_none_or(|bits| bits[row_no / 8] & (1 << (row_no % 8)) != 0) {
+                value = Some(src.read_u64::<BE>()? as Datum);
+            }
+            row_no += 1;
+        }
+        column.push(value);
+    }
+    Ok(column)
+}
+
+/**
+ * Save a value column's entries for the cells holding rows.
+ */
+fn save_column<W: Write>(dest: &mut W, rows: &[bool], column: &[Option<Datum>], version: FormatVersion) -> io::Result<()> {
+    let entries = || rows.iter().zip(column).filter(|(&row, _)| row).map(|(_, value)| value);
+    if version >= 8 {
+        if entries().all(Option::is_some) {
+            dest.write_u8(1)?;
+        } else {
+            dest.write_u8(0)?;
+            dest.write_all(&to_bitmap(entries().map(Option::is_some)))?;
+        }
+    }
+    for &value in entries().flatten() {
+        dest.write_u64::<BE>(value as u64)?;
+    }
+    Ok(())
+}
+
+fn to_bitmap(bits: impl Iterator<Item = bool>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (i, bit) in bits.enumerate() {
+        if i % 8 == 0 {
+            bytes.push(0);
+        }
+        if bit {
+            bytes[i / 8] |= 1 << (i % 8);
         }
     }
+    bytes
 }
 
 impl BlockIter {
+    /**
+     * Return only the value columns numbered in `values`, in that order, so the others are
+     * never copied out of the block.  The missing values of a partial row are then numbered by
+     * their position in `values`.  A cell still holds a row if it has any values, selected or
+     * not.
+     */
+    pub(crate) fn select_values(&mut self, values: &[usize]) {
+        self.selected = Some(values.to_vec());
+    }
+
+    /**
+     * Visit only the cells within the smallest box around the criteria, skipping the rest of
+     * the block without looking at them.  The rows in the box still have to be matched against
+     * the criteria.
+     */
+    pub(crate) fn restrict(&mut self, criteria: &Criteria) {
+        let window = self.block.dimension_values.iter().enumerate().map(|(dim_no, values)| {
+            let Some(ranges) = criteria.get_ranges(dim_no) else { return (0, values.len()) };
+            let min = ranges.iter().map(|range| *range.start()).min().unwrap_or(Datum::MAX);
+            let max = ranges.iter().map(|range| *range.end()).max().unwrap_or(0);
+            (values.partition_point(|&v| v < min), values.partition_point(|&v| v <= max))
+        }).collect();
+        self.window = Some(window);
+    }
+
+    /**
+     * Move the indexes forward to the first cell at or after them within the window, if any.
+     */
+    fn enter_window(&mut self) {
+        let Some(window) = &self.window else { return };
+        let end = self.block.dimension_values[0].len();
+        if window.iter().any(|&(start, stop)| start >= stop) {
+            self.indexes[0] = end;
+            return;
+        }
+
+        let num_dims = self.indexes.len();
+        let mut dim_no = 0;
+        while dim_no < num_dims && self.indexes[0] < end {
+            let (start, stop) = window[dim_no];
+            if self.indexes[dim_no] < start {
+                self.indexes[dim_no] = start;
+                for (index, &(start, _)) in self.indexes.iter_mut().zip(window).skip(dim_no + 1) {
+                    *index = start;
+                }
+                break;
+            }
+            if self.indexes[dim_no] >= stop {
+                /* Carry into the previous dimension, and check it again */
+                if dim_no == 0 {
+                    self.indexes[0] = end;
+                    break;
+                }
+                for (index, &(start, _)) in self.indexes.iter_mut().zip(window).skip(dim_no) {
+                    *index = start;
+                }
+                dim_no -= 1;
+                self.indexes[dim_no] += 1;
+                continue;
+            }
+            dim_no += 1;
+        }
+    }
+
+    /**
+     * Move forward to the first row at or after `point`, found by a binary search of each
+     * dimension's values, rather than stepping through every cell in between.  The iterator
+     * never moves backward, so seeking to a point it has already passed does nothing.
+     */
+    pub(crate) fn seek_to(&mut self, point: &[Datum]) {
+        let dimension_values = &self.block.dimension_values;
+        let num_dims = dimension_values.len();
+        let mut target = vec![0; num_dims];
+        for dim_no in 0..num_dims {
+            let values = &dimension_values[dim_no];
+            let idx = values.partition_point(|&v| v < point[dim_no]);
+            if idx < values.len() {
+                target[dim_no] = idx;
+                if values[idx] == point[dim_no] {
+                    continue;
+                }
+                break;
+            }
+
+            /* Every value of this dimension is before the point, so carry into the previous one */
+            let mut carry_pos = dim_no;
+            loop {
+                if carry_pos == 0 {
+                    target[0] = dimension_values[0].len();
+                    break;
+                }
+                carry_pos -= 1;
+                target[carry_pos] += 1;
+                if target[carry_pos] < dimension_values[carry_pos].len() {
+                    break;
+                }
+                target[carry_pos] = 0;
+            }
+            break;
+        }
+
+        if target <= self.indexes {
+            return;
+        }
+        if target[0] < dimension_values[0].len() {
+            self.value_index = self.block.get_index(&target);
+        }
+        self.indexes = target;
+    }
+
     fn increment_indexes(&mut self) {
         self.value_index += 1;
         let mut incr_pos = self.indexes.len() - 1;
@@ -255,15 +806,15 @@ impl BlockIter {
             break;
         }
     }
-}
-
-impl Iterator for BlockIter {
-    type Item = Vec<Datum>;
 
-    fn next(&mut self) -> Option<Vec<Datum>>
-    {
+    /**
+     * Return the next row, with 0 for any values it is missing, along with the numbers of
+     * those values.  A row missing values is a partial version; see `Block`.
+     */
+    pub(crate) fn next_partial(&mut self) -> Option<(Vec<Datum>, Vec<usize>)> {
         loop {
-            // Check if indexes are already past the size of the block
+            // Skip any cells outside the window, then check if indexes are already past the size of the block
+            self.enter_window();
             if self.indexes[0] >= self.block.dimension_values[0].len() {
                 return None;
             }
@@ -271,25 +822,226 @@ impl Iterator for BlockIter {
             // Turn this index into a single number and get the result
             //let calculated_idx = self.block.get_index(&self.indexes);
             //assert_eq!(self.value_index, calculated_idx);
-            let value: Option<Datum> = self.block.values[self.value_index];
+
+            // Cells in any other order, or after skipping some, are found from the indexes each time
+            if self.window.is_some() || self.block.order != CellOrder::RowMajor {
+                self.value_index = self.block.get_index(&self.indexes);
+            }
 
             // If it's empty, increment and try the next one
-            if value.is_none() {
+            if !self.block.has_row(self.value_index) {
                 self.increment_indexes();
                 continue;
             }
 
-            let value = value.unwrap();
             let mut va = Vec::new();
             for i in 0..self.indexes.len() {
                 va.push(self.block.dimension_values[i][self.indexes[i]]);
             }
-            va.push(value);
+            let mut missing = Vec::new();
+            let num_values = self.selected.as_ref().map_or(1 + self.block.extra_values.len(), Vec::len);
+            for pos in 0..num_values {
+                let value_no = self.selected.as_ref().map_or(pos, |selected| selected[pos]);
+                let value = self.block.column(value_no).and_then(|column| column[self.value_index]);
+                if value.is_none() {
+                    missing.push(pos);
+                }
+                va.push(value.unwrap_or(0));
+            }
 
             // Move to to the next index and return the row
             self.increment_indexes();
-            return Some(va);
+            return Some((va, missing));
+        }
+    }
+
+    /**
+     * Return the rows along with the numbers of the values each is missing, as `next_partial`.
+     */
+    pub(crate) fn partial(self) -> PartialRows {
+        PartialRows(self)
+    }
+}
+
+impl Iterator for BlockIter {
+    type Item = Vec<Datum>;
+
+    /**
+     * Return the next row, with 0 for any values it is missing.
+     */
+    fn next(&mut self) -> Option<Vec<Datum>> {
+        self.next_partial().map(|(row, _)| row)
+    }
+}
+
+/**
+ * An iterator over a block's rows that returns the numbers of the values missing from each,
+ * from `BlockIter::partial`.
+ */
+pub(crate) struct PartialRows(BlockIter);
+
+impl Iterator for PartialRows {
+    type Item = (Vec<Datum>, Vec<usize>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_partial()
+    }
+}
+
+#[cfg(test)]
+mod split_tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let mut b = Block::new(2);
+        for i in 0..10 {
+            b.add_row(&[i, i, i * 10]);
         }
+        let b = Rc::new(b);
+        assert_eq!(Block::split(&b, b.saved_size()).len(), 1);
+
+        let pieces = Block::split(&b, 100);
+        assert!(pieces.len() > 1);
+        assert!(pieces.iter().all(|p| p.saved_size() <= 100));
+        assert!(pieces.iter().map(|p| p.num_cells()).sum::<usize>() < b.num_cells());
+        let rows: Vec<_> = pieces.iter().flat_map(Block::iter).collect();
+        assert_eq!(rows, Block::iter(&b).collect::<Vec<_>>());
+
+        let mut single = Block::new(2);
+        single.add_row(&[3, 4, 5]);
+        assert_eq!(Block::split(&Rc::new(single), 1).len(), 1);
+    }
+
+    #[test]
+    fn value_columns() {
+        let mut b = Block::new(1);
+        b.add_row(&[5, 50, 500]);
+        b.add_row(&[1, 10, 100]);
+        b.add_row(&[3, 30, 300]);
+        b.add_row(&[1, 11, 101]);
+        let rows: Vec<_> = Block::iter(&Rc::new(b.clone())).collect();
+        assert_eq!(rows, vec![vec![1, 11, 101], vec![3, 30, 300], vec![5, 50, 500]]);
+
+        let mut data = Vec::new();
+        b.save(&mut data, 6).unwrap();
+        let mut loaded = Block::new(0);
+        loaded.load(&mut data.as_slice(), 6).unwrap();
+        assert_eq!(Block::iter(&Rc::new(loaded)).collect::<Vec<_>>(), rows);
+
+        assert!(b.save(&mut Vec::new(), 5).is_err());
+    }
+
+    #[test]
+    fn partial_rows() {
+        let mut b = Block::new(1);
+        for i in 0..10 {
+            b.add_partial_row(&[i, i * 10, i * 100], &[i % 2]);
+        }
+        b.add_partial_row(&[4, 0, 400], &[0]);
+        b.add_row(&[20, 200, 2000]);
+        assert!(!b.is_complete());
+        assert_eq!(b.num_rows(), 11);
+        let rows: Vec<_> = Block::iter(&Rc::new(b.clone())).partial().collect();
+        assert_eq!(rows[0], (vec![0, 0, 0], vec![0]));
+        assert_eq!(rows[1], (vec![1, 10, 0], vec![1]));
+        assert_eq!(rows[4], (vec![4, 0, 400], vec![0]));
+        assert_eq!(rows[10], (vec![20, 200, 2000], vec![]));
+
+        let mut data = Vec::new();
+        b.save(&mut data, 8).unwrap();
+        let mut loaded = Block::new(0);
+        loaded.load(&mut data.as_slice(), 8).unwrap();
+        assert_eq!(Block::iter(&Rc::new(loaded)).partial().collect::<Vec<_>>(), rows);
+        let pieces = Block::split(&Rc::new(b.clone()), 100);
+        assert_eq!(pieces.iter().flat_map(|piece| Block::iter(piece).partial()).collect::<Vec<_>>(), rows);
+
+        assert!(b.save(&mut Vec::new(), 7).is_err());
+    }
+
+    #[test]
+    fn selected_values() {
+        let mut b = Block::new(1);
+        for i in 0..10 {
+            b.add_partial_row(&[i, i * 10, i * 100, i * 1000], &[i % 3]);
+        }
+        b.add_partial_row(&[20, 200, 0, 0], &[1, 2]);
+        let selected = |block: Block| {
+            let mut iter = Block::iter(&Rc::new(block));
+            iter.select_values(&[2, 0]);
+            iter.partial().collect::<Vec<_>>()
+        };
+        let rows = selected(b.clone());
+        assert_eq!(rows[0], (vec![0, 0, 0], vec![1]));
+        assert_eq!(rows[10], (vec![20, 0, 200], vec![0]));
+
+        let mut data = Vec::new();
+        b.save(&mut data, 10).unwrap();
+        let mut loaded = Block::new(0);
+        loaded.load_values(&mut data.as_slice(), 10, &[2, 0]).unwrap();
+        assert!(!loaded.has_all_values());
+        assert!(loaded.extra_values[0].is_empty());
+        assert_eq!((loaded.num_rows(), loaded.num_cells()), (b.num_rows(), b.num_cells()));
+        assert!(loaded.save(&mut Vec::new(), 10).is_err());
+        assert_eq!(selected(loaded), rows);
+
+        /* A row is still found when none of the selected values are loaded */
+        let mut loaded = Block::new(0);
+        loaded.load_values(&mut data.as_slice(), 10, &[]).unwrap();
+        assert_eq!(Block::iter(&Rc::new(loaded)).count(), 11);
+
+        /* Earlier versions have to load every value */
+        let mut data = Vec::new();
+        b.save(&mut data, 9).unwrap();
+        let mut loaded = Block::new(0);
+        loaded.load_values(&mut data.as_slice(), 9, &[2, 0]).unwrap();
+        assert!(loaded.has_all_values());
+        assert_eq!(selected(loaded), rows);
+    }
+
+    #[test]
+    fn tiled() {
+        let mut b = Block::new(2);
+        for i in 0..7 {
+            for j in 0..5 {
+                if (i + j) % 4 != 0 {
+                    b.add_partial_row(&[i, j, i * 10 + j, i], &[j % 2]);
+                }
+            }
+        }
+        let rows: Vec<_> = Block::iter(&Rc::new(b.clone())).partial().collect();
+        let tiled = b.with_order(CellOrder::Tiled(3));
+
+        /* The first tile is the first nine cells, and the tiles at the edges are cut short */
+        assert_eq!((0..3).flat_map(|i| (0..3).map(move |j| [i, j])).map(|idx| tiled.get_index(&idx)).collect::<Vec<_>>(), (0..9).collect::<Vec<_>>());
+        assert_eq!(tiled.get_index(&[0, 3]), 9);
+        assert_eq!(tiled.get_index(&[6, 4]), 34);
+        let tiled = Rc::new(tiled);
+        assert_eq!(Block::iter(&tiled).partial().collect::<Vec<_>>(), rows);
+        let mut iter = Block::iter(&tiled);
+        iter.seek_to(&[3, 4]);
+        assert_eq!(iter.next(), Some(vec![3, 4, 0, 3]));
+
+        let mut data = Vec::new();
+        tiled.save(&mut data, 11).unwrap();
+        let mut loaded = Block::new(0);
+        loaded.load(&mut data.as_slice(), 11).unwrap();
+        assert_eq!(loaded.order, CellOrder::Tiled(3));
+        assert_eq!(loaded.values, tiled.values);
+        assert_eq!(Block::iter(&Rc::new(loaded.clone())).partial().collect::<Vec<_>>(), rows);
+
+        /* Earlier versions save the rows in row-major order */
+        let mut data = Vec::new();
+        tiled.save(&mut data, 10).unwrap();
+        let mut earlier = Block::new(0);
+        earlier.load(&mut data.as_slice(), 10).unwrap();
+        assert_eq!(earlier.order, CellOrder::RowMajor);
+        assert_eq!(earlier.values, b.values);
+
+        /* Adding a row puts the cells back in row-major order */
+        loaded.add_row(&[9, 9, 99, 9]);
+        assert_eq!(loaded.order, CellOrder::RowMajor);
+        assert_eq!(Block::iter(&Rc::new(loaded)).count(), rows.len() + 1);
     }
 }
 
@@ -478,7 +1230,7 @@ mod iterate_tests {
 
     #[test]
     fn empty_block() {
-        let mut b = Rc::new(Block::new(1));
+        let b = Rc::new(Block::new(1));
 
         let count = Block::iter(&b).count();
         assert_eq!(count, 0);
@@ -509,4 +1261,66 @@ mod iterate_tests {
         let count = Block::iter(&b).count();
         assert_eq!(count, 0);
     }
+
+    #[test]
+    fn seek_to() {
+        let mut b = Block::new(2);
+        for i in 0..10 {
+            for j in 0..5 {
+                if (i + j) % 3 != 0 {
+                    b.add_row(&[i * 10, j * 2, i * 100 + j]);
+                }
+            }
+        }
+        let b = Rc::new(b);
+        let all: Vec<_> = Block::iter(&b).collect();
+
+        for x in 0..=100 {
+            for y in 0..=10 {
+                let expected: Vec<_> = all.iter().filter(|row| (row[0], row[1]) >= (x, y)).cloned().collect();
+                let mut iter = Block::iter(&b);
+                iter.seek_to(&[x, y]);
+                assert_eq!(iter.collect::<Vec<_>>(), expected, "seeking to {:?}", (x, y));
+            }
+        }
+
+        let mut iter = Block::iter(&b);
+        iter.seek_to(&[50, 0]);
+        assert_eq!(iter.next().unwrap()[0], 50);
+        iter.seek_to(&[20, 0]);
+        assert_eq!(iter.next().unwrap()[0], 50);
+    }
+
+    #[test]
+    fn restrict() {
+        let mut b = Block::new(2);
+        for i in 0..10 {
+            for j in 0..8 {
+                if (i * j) % 5 != 1 {
+                    b.add_row(&[i * 10, j * 2, i * 100 + j]);
+                }
+            }
+        }
+        let all: Vec<_> = Block::iter(&Rc::new(b.clone())).collect();
+
+        for order in [CellOrder::RowMajor, CellOrder::Tiled(3)] {
+            let b = Rc::new(b.with_order(order));
+            for (xs, ys) in [(15..=45, 3..=9), (0..=0, 0..=100), (95..=200, 0..=14), (31..=39, 0..=14), (0..=90, 15..=20)] {
+                let criteria = Criteria::new().range(0, xs.clone()).range(1, ys.clone());
+                let expected: Vec<_> = all.iter().filter(|row| criteria.matches_point(row)).cloned().collect();
+                let mut iter = Block::iter(&b);
+                iter.restrict(&criteria);
+                assert_eq!(iter.collect::<Vec<_>>(), expected, "{:?} restricted to {:?}", order, (xs, ys));
+            }
+
+            let criteria = Criteria::new().ranges(0, [10..=20, 60..=70]).range(1, 4..=6);
+            let mut iter = Block::iter(&b);
+            iter.restrict(&criteria);
+            iter.seek_to(&[20, 0]);
+            assert_eq!(iter.next(), Some(vec![20, 4, 202]));
+            iter.seek_to(&[30, 0]);
+            assert_eq!(iter.next(), Some(vec![30, 6, 303]));
+            assert_eq!(iter.last(), Some(vec![70, 4, 702]));
+        }
+    }
 }
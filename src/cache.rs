@@ -1,11 +1,41 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::rc::Rc;
 use log::{debug, warn};
 
+/* The fraction of the cache given over to probationary entries */
+const PROBATION_FRACTION: usize = 4;
+
+/* Hot items survive this many eviction sweeps without being used */
+const HOT_USE_COUNT: usize = 10;
+
+/**
+ * How a query wants the blocks it reads to be cached, from `Scan::cache_hint`.  This lets
+ * applications choose which workloads populate the block cache.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheHint {
+    /** Blocks are cached on probation, and join the main part of the cache if read again. */
+    #[default]
+    Normal,
+    /**
+     * The blocks are unlikely to be read again soon, as in an export or a backfill check.
+     * Blocks that aren't cached are not added, and cached ones aren't promoted, so the query
+     * leaves the cache as it found it.
+     */
+    OneShot,
+    /**
+     * The blocks will be read often, as for a dashboard.  They join the main part of the cache
+     * straight away, and are kept in preference to other blocks until they go unused for a
+     * while.
+     */
+    Hot
+}
+
 struct Entry<V> {
     use_count: usize,
+    probationary: bool,
     rc: Rc<V>
 }
 
@@ -15,17 +45,30 @@ struct Entry<V> {
  *
  * Values are added and borrowed wrapped in Rc: eviction from the cache
  * will not interfere with current users of an item.
+ *
+ * New items start out on probation, and only join the main part of the cache
+ * when they are used again.  Probationary items are evicted first, oldest first,
+ * so a large scan that touches each item once can only displace other
+ * probationary items, not the working set.
  */
 pub struct Cache<K, V> {
     entries: HashMap<K, Entry<V>>,
     max_entries: usize,
-    evictables: Vec<K>
+    evictables: Vec<K>,
+    probation: VecDeque<K>,
+    max_probation: usize
 }
 
 impl<K, V> Cache<K, V>
 where K: Hash + Eq + Clone + Debug, V: Sized {
     pub fn new(max_entries: usize) -> Cache<K, V> {
-        Cache { entries: HashMap::new(), max_entries, evictables: Vec::new() }
+        Cache {
+            entries: HashMap::new(),
+            max_entries,
+            evictables: Vec::new(),
+            probation: VecDeque::new(),
+            max_probation: (max_entries / PROBATION_FRACTION).max(1)
+        }
     }
 
     /**
@@ -35,17 +78,94 @@ where K: Hash + Eq + Clone + Debug, V: Sized {
      * to the cache.
      */
     pub fn add(&mut self, key: K, rc: Rc<V>) {
+        self.trim_probation(self.max_probation.saturating_sub(1));
         self.check_capacity();
-        debug!("Key {key:?} added");
-        self.entries.insert(key, Entry { use_count: 1, rc });
+        debug!("Key {key:?} added on probation");
+        self.probation.push_back(key.clone());
+        self.entries.insert(key, Entry { use_count: 1, probationary: true, rc });
     }
 
     pub fn get(&mut self, key: &K) -> Option<Rc<V>> {
-        let mut entry = self.entries.get_mut(key)?;
+        let entry = self.entries.get_mut(key)?;
         entry.use_count += 1;
+        if entry.probationary {
+            debug!("Key {key:?} promoted from probation");
+            entry.probationary = false;
+            self.probation.retain(|k| k != key);
+        }
         Some(entry.rc.clone())
     }
 
+    /**
+     * Add a value to the cache as `add` does, or as the hint asks.
+     */
+    pub fn add_with_hint(&mut self, key: K, rc: Rc<V>, hint: CacheHint) {
+        match hint {
+            CacheHint::Normal => self.add(key, rc),
+            CacheHint::OneShot => debug!("Key {key:?} not added for one-shot query"),
+            CacheHint::Hot => {
+                self.check_capacity();
+                debug!("Key {key:?} added as hot");
+                self.entries.insert(key, Entry { use_count: HOT_USE_COUNT, probationary: false, rc });
+            }
+        }
+    }
+
+    /**
+     * Get a value from the cache as `get` does, or as the hint asks.
+     */
+    pub fn get_with_hint(&mut self, key: &K, hint: CacheHint) -> Option<Rc<V>> {
+        match hint {
+            CacheHint::Normal => self.get(key),
+            CacheHint::OneShot => self.entries.get(key).map(|entry| entry.rc.clone()),
+            CacheHint::Hot => {
+                let rc = self.get(key)?;
+                let entry = self.entries.get_mut(key)?;
+                entry.use_count = entry.use_count.max(HOT_USE_COUNT);
+                Some(rc)
+            }
+        }
+    }
+
+    /**
+     * The keys of items in the main part of the cache, most used first.
+     */
+    pub fn hot_keys(&self) -> Vec<K> {
+        let mut hot: Vec<(&K, usize)> = self.entries.iter()
+            .filter(|(_, entry)| !entry.probationary)
+            .map(|(key, entry)| (key, entry.use_count))
+            .collect();
+        hot.sort_by_key(|&(_, use_count)| std::cmp::Reverse(use_count));
+        hot.into_iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    /**
+     * The total of some measure of the cached items, such as their size in bytes, and the
+     * number of them that are pinned by being in use outside the cache.
+     */
+    pub fn measure<F: Fn(&V) -> usize>(&self, measure: F) -> (usize, usize) {
+        let total = self.entries.values().map(|entry| measure(&entry.rc)).sum();
+        let num_pinned = self.entries.values().filter(|entry| Rc::strong_count(&entry.rc) > 1).count();
+        (total, num_pinned)
+    }
+
+    /**
+     * Evict the oldest probationary items that aren't in use, until at most `max_len` remain.
+     */
+    fn trim_probation(&mut self, max_len: usize) {
+        let mut in_use = Vec::new();
+        while self.probation.len() > max_len {
+            let Some(key) = self.probation.pop_front() else { break; };
+            if !self.evict(&key) {
+                in_use.push(key);
+            }
+        }
+        /* Items in use stay on probation, at the front so they are tried first next time. */
+        for key in in_use.into_iter().rev() {
+            self.probation.push_front(key);
+        }
+    }
+
     /**
      * Evict an item from the cache.  The result is `true` if the item
      * was successfully evicted, or `false` if the item was pinned or there
@@ -61,11 +181,20 @@ where K: Hash + Eq + Clone + Debug, V: Sized {
         }
 
         debug!("Key {key:?} evicted");
+        if entry.probationary {
+            self.probation.retain(|k| k != key);
+        }
         self.entries.remove(key);
         true
     }
 
     pub fn check_capacity(&mut self) {
+        /* Probationary items go first. */
+        if self.entries.len() >= self.max_entries {
+            let excess = self.entries.len() + 1 - self.max_entries;
+            self.trim_probation(self.probation.len().saturating_sub(excess));
+        }
+
         const MAX_FIND_ATTEMPTS: usize = 10;
         let mut find_attempts = 0;
         while self.entries.len() >= self.max_entries {
@@ -79,6 +208,7 @@ where K: Hash + Eq + Clone + Debug, V: Sized {
                 if find_attempts >= MAX_FIND_ATTEMPTS {
                     warn!("Too many attempts to find evictables, forcibly emptying cache");
                     self.entries.clear();
+                    self.probation.clear();
                     return;
                 } else {
                     find_attempts += 1;
@@ -128,6 +258,18 @@ mod cache_tests {
         assert_eq!(Rc::strong_count(&cache.entries.get(&5).unwrap().rc), 1);
     }
 
+    #[test]
+    fn measure() {
+        let mut cache: Cache<u32, u32> = Cache::new(100);
+        cache.add(5, Rc::new(42));
+        cache.add(6, Rc::new(43));
+        let item = cache.get(&6);
+
+        assert_eq!(cache.measure(|&v| v as usize), (85, 1));
+        drop(item);
+        assert_eq!(cache.measure(|_| 1), (2, 0));
+    }
+
     #[test]
     fn borrow_and_use() {
         let mut cache: Cache<u32, u32> = Cache::new(100);
@@ -214,7 +356,7 @@ mod eviction_tests {
         let mut cache: Cache<u32, u32> = Cache::new(100);
         cache.add(5, Rc::new(42));
 
-        assert_eq!(cache.evict(&5), true);
+        assert!(cache.evict(&5));
 
         assert_eq!(cache.entries.len(), 0);
     }
@@ -223,7 +365,59 @@ mod eviction_tests {
     fn evict_something_not_there() {
         let mut cache: Cache<u32, u32> = Cache::new(100);
 
-        assert_eq!(cache.evict(&5), false);
+        assert!(!cache.evict(&5));
+    }
+
+    #[test]
+    fn scan_does_not_evict_working_set() {
+        let mut cache: Cache<u32, u32> = Cache::new(100);
+        for i in 0..50 {
+            cache.add(i, Rc::new(i));
+            cache.get(&i);
+        }
+
+        /* A scan touches many more items than fit in the cache, each only once */
+        for i in 1000..5000 {
+            cache.add(i, Rc::new(i));
+        }
+
+        for i in 0..50 {
+            assert!(cache.get(&i).is_some());
+        }
+        assert!(cache.entries.len() <= 100);
+        assert!(cache.probation.len() <= 25);
+    }
+
+    #[test]
+    fn hot_keys() {
+        let mut cache: Cache<u32, u32> = Cache::new(100);
+        for i in 0..5 {
+            cache.add(i, Rc::new(i));
+        }
+        for _ in 0..3 { cache.get(&3); }
+        cache.get(&1);
+
+        assert_eq!(cache.hot_keys(), vec![3, 1]);
+    }
+
+    #[test]
+    fn hints() {
+        let mut cache: Cache<u32, u32> = Cache::new(100);
+        cache.add_with_hint(1, Rc::new(1), CacheHint::OneShot);
+        assert!(cache.get(&1).is_none());
+
+        cache.add(2, Rc::new(2));
+        assert!(cache.get_with_hint(&2, CacheHint::OneShot).is_some());
+        assert!(cache.hot_keys().is_empty());
+
+        /* Hot items outlast a working set used more recently */
+        cache.add_with_hint(3, Rc::new(3), CacheHint::Hot);
+        assert_eq!(cache.hot_keys(), vec![3]);
+        for i in 100..300 {
+            cache.add(i, Rc::new(i));
+            cache.get(&i);
+        }
+        assert!(cache.get(&3).is_some());
     }
 
     #[test]
@@ -231,9 +425,9 @@ mod eviction_tests {
         let mut cache: Cache<u32, u32> = Cache::new(100);
         cache.add(5, Rc::new(42));
 
-        let item = cache.get(&5);
+        let _item = cache.get(&5);
 
-        assert_eq!(cache.evict(&5), false);
+        assert!(!cache.evict(&5));
 
         assert_eq!(cache.entries.len(), 1);
     }
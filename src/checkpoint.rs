@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, HashSet};
+
+use log::{debug, info};
+use serde::{Serialize, Deserialize};
+
+use crate::{Error, SegmentId, TransactionId};
+use crate::backend::Backend;
+use crate::storage::CHECKPOINTS_FILENAME;
+
+/**
+ * A small persistent map from source names to positions in those sources, recorded by loaders
+ * so they can tell what has already been ingested.  The positions are opaque to the database:
+ * they might be file offsets, hashes, or anything else the loader wants to record.
+ *
+ * Checkpoints are set on a `Transaction` and saved as part of its commit: the new checkpoints
+ * are staged in the file, along with the ones they replace, before the transaction's first
+ * segment is made visible, and take effect only if it is.
+ */
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(transparent)]
+pub struct Checkpoints {
+    entries: BTreeMap<String, String>
+}
+
+impl Checkpoints {
+    pub fn get(&self, source: &str) -> Option<&str> {
+        self.entries.get(source).map(|s| s.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn set(&mut self, source: String, position: String) {
+        self.entries.insert(source, position);
+    }
+
+    /**
+     * Load the checkpoints in effect.  Staged checkpoints are in effect if their transaction
+     * committed, which it did if any committed segment is from it or a later transaction:
+     * uncommitted segments have been removed by then, and a transaction that fails to commit
+     * without a crash restores the checkpoints it replaced.  Otherwise the replaced checkpoints
+     * are saved back, so the transaction id can be used again.
+     */
+    pub(crate) fn load(backend: &dyn Backend, committed_segments: &HashSet<SegmentId>) -> Result<Checkpoints, Error> {
+        if !backend.exists(CHECKPOINTS_FILENAME) {
+            return Ok(Checkpoints::default());
+        }
+
+        let json = backend.read(CHECKPOINTS_FILENAME)?;
+        match serde_json::from_slice(&json)? {
+            CheckpointsFile::Saved(checkpoints) => Ok(checkpoints),
+            CheckpointsFile::Staged { txn_id, checkpoints, .. } if committed_segments.iter().any(|seg_id| seg_id.0 >= txn_id) => {
+                debug!("Checkpoints of transaction {:?} are in effect", txn_id);
+                Ok(checkpoints)
+            },
+            CheckpointsFile::Staged { txn_id, previous, .. } => {
+                info!("Restoring the checkpoints replaced by transaction {:?}, which didn't commit", txn_id);
+                previous.save(backend)?;
+                Ok(previous)
+            }
+        }
+    }
+
+    /**
+     * Save the checkpoints, replacing the existing file in a single rename so that a crash
+     * never leaves a partially written file behind.
+     */
+    pub(crate) fn save(&self, backend: &dyn Backend) -> Result<(), Error> {
+        write_file(backend, &CheckpointsFile::Saved(self.clone()))
+    }
+
+    /**
+     * Save the checkpoints as staged for a transaction that is about to commit, keeping the
+     * ones they replace in case it doesn't.
+     */
+    pub(crate) fn save_staged(&self, backend: &dyn Backend, txn_id: TransactionId, previous: &Checkpoints) -> Result<(), Error> {
+        write_file(backend, &CheckpointsFile::Staged { txn_id, checkpoints: self.clone(), previous: previous.clone() })
+    }
+}
+
+/**
+ * The contents of the checkpoints file: either the checkpoints themselves, or checkpoints
+ * staged by a transaction along with the ones in effect before it.
+ */
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum CheckpointsFile {
+    Staged { txn_id: TransactionId, checkpoints: Checkpoints, previous: Checkpoints },
+    Saved(Checkpoints)
+}
+
+fn write_file(backend: &dyn Backend, file: &CheckpointsFile) -> Result<(), Error> {
+    let temp_filename = format!("{CHECKPOINTS_FILENAME}.tmp");
+    let json = serde_json::to_string_pretty(file)?;
+    backend.write(&temp_filename, json.as_bytes())?;
+    backend.rename(&temp_filename, CHECKPOINTS_FILENAME)?;
+    Ok(())
+}
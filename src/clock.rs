@@ -0,0 +1,47 @@
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/*
+ * The standard library has no clock on wasm32-unknown-unknown, where reading the time or
+ * sleeping panics.  There, times are 0 and nothing is measured: query traces and commit
+ * summaries report no duration, journal entries no time, and fetch retries don't wait
+ * between attempts.
+ */
+const HAS_CLOCK: bool = !cfg!(all(target_arch = "wasm32", target_os = "unknown"));
+
+/**
+ * The current time, in seconds since 1970.
+ */
+pub(crate) fn unix_seconds() -> u64 {
+    if !HAS_CLOCK {
+        return 0;
+    }
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/**
+ * Measures how long something takes, from when it was started.
+ */
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Stopwatch {
+    started: Option<Instant>
+}
+
+impl Stopwatch {
+    pub(crate) fn start() -> Stopwatch {
+        Stopwatch { started: HAS_CLOCK.then(Instant::now) }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.started.map_or(Duration::ZERO, |started| started.elapsed())
+    }
+}
+
+/**
+ * Wait before trying something again.
+ */
+pub(crate) fn sleep(delay: Duration) {
+    if HAS_CLOCK {
+        thread::sleep(delay);
+    }
+}
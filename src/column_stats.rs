@@ -0,0 +1,224 @@
+use std::collections::BTreeSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Datum, Error, TransactionId};
+use crate::backend::Backend;
+use crate::query::QueryRow;
+use crate::schema::Schema;
+use crate::storage::COLUMN_STATS_FILENAME;
+
+/* Hashes kept for estimating the number of distinct values; the estimate is exact below this */
+const DISTINCT_SAMPLE_SIZE: usize = 1024;
+/* Values sampled for the quantiles */
+const QUANTILE_SAMPLE_SIZE: usize = 4096;
+const NUM_QUANTILES: usize = 10;
+
+/**
+ * The distribution of one dimension or value, as part of `DataStats`.
+ *
+ * `distinct_values` estimates how many different values the column has; it is exact for up to
+ * about a thousand.  `quantiles` divides the rows into ten groups of equal size by the column's
+ * value, starting with the smallest value and ending with the largest, which are exact; the
+ * ones between are estimated from a sample.  It is empty if there are no rows.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ColumnStats {
+    pub name: String,
+    pub distinct_values: u64,
+    pub quantiles: Vec<Datum>
+}
+
+impl ColumnStats {
+    pub fn min(&self) -> Option<Datum> {
+        self.quantiles.first().copied()
+    }
+
+    pub fn max(&self) -> Option<Datum> {
+        self.quantiles.last().copied()
+    }
+}
+
+/**
+ * Statistics on the data in a database, from `Database::data_stats`, for query planners and
+ * other tools that want to know the shape of the data without reading it.  They are gathered
+ * by `Database::compact` and saved with the database, so they describe the data committed
+ * before `horizon`, and become out of date as more is added.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DataStats {
+    pub horizon: TransactionId,
+    pub num_rows: u64,
+    pub dimensions: Vec<ColumnStats>,
+    pub values: Vec<ColumnStats>
+}
+
+/**
+ * Gathers the statistics on one column in a single pass.  Distinct values are estimated from
+ * the smallest hashes seen, and quantiles from a reservoir sample.
+ */
+struct ColumnCollector {
+    smallest_hashes: BTreeSet<u64>,
+    sample: Vec<Datum>,
+    min: Datum,
+    max: Datum,
+    num_seen: u64,
+    random_state: u64
+}
+
+impl ColumnCollector {
+    fn new(seed: u64) -> ColumnCollector {
+        ColumnCollector {
+            smallest_hashes: BTreeSet::new(),
+            sample: Vec::new(),
+            min: Datum::MAX,
+            max: Datum::MIN,
+            num_seen: 0,
+            random_state: seed | 1
+        }
+    }
+
+    fn add(&mut self, value: Datum) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.num_seen += 1;
+
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.smallest_hashes.len() < DISTINCT_SAMPLE_SIZE {
+            self.smallest_hashes.insert(hash);
+        } else if hash < *self.smallest_hashes.last().unwrap() && self.smallest_hashes.insert(hash) {
+            self.smallest_hashes.pop_last();
+        }
+
+        if self.sample.len() < QUANTILE_SAMPLE_SIZE {
+            self.sample.push(value);
+        } else {
+            let slot = self.next_random() % self.num_seen;
+            if let Some(sampled) = self.sample.get_mut(slot as usize) {
+                *sampled = value;
+            }
+        }
+    }
+
+    /* xorshift64, which is plenty for choosing a sample */
+    fn next_random(&mut self) -> u64 {
+        self.random_state ^= self.random_state << 13;
+        self.random_state ^= self.random_state >> 7;
+        self.random_state ^= self.random_state << 17;
+        self.random_state
+    }
+
+    fn finish(mut self, name: &str) -> ColumnStats {
+        /* With fewer hashes than the sample size, every distinct value has been seen */
+        let distinct_values = if self.smallest_hashes.len() < DISTINCT_SAMPLE_SIZE {
+            self.smallest_hashes.len() as u64
+        } else {
+            let largest = *self.smallest_hashes.last().unwrap() as f64 / u64::MAX as f64;
+            (((DISTINCT_SAMPLE_SIZE - 1) as f64 / largest) as u64).min(self.num_seen)
+        };
+
+        let mut quantiles = Vec::new();
+        if !self.sample.is_empty() {
+            self.sample.sort();
+            quantiles.push(self.min);
+            for i in 1..NUM_QUANTILES {
+                quantiles.push(self.sample[i * (self.sample.len() - 1) / NUM_QUANTILES]);
+            }
+            quantiles.push(self.max);
+        }
+
+        ColumnStats { name: name.to_string(), distinct_values, quantiles }
+    }
+}
+
+/**
+ * Gathers `DataStats` from the rows of a query.
+ */
+pub(crate) struct StatsCollector {
+    columns: Vec<ColumnCollector>,
+    num_rows: u64
+}
+
+impl StatsCollector {
+    pub(crate) fn new(schema: &Schema) -> StatsCollector {
+        let num_columns = schema.dimensions.len() + schema.values.len();
+        StatsCollector {
+            columns: (0..num_columns).map(|col_no| ColumnCollector::new(col_no as u64 + 1)).collect(),
+            num_rows: 0
+        }
+    }
+
+    pub(crate) fn add(&mut self, row: &QueryRow) {
+        for (column, &value) in self.columns.iter_mut().zip(row.values_array.iter()) {
+            column.add(value);
+        }
+        self.num_rows += 1;
+    }
+
+    pub(crate) fn finish(self, schema: &Schema, horizon: TransactionId) -> DataStats {
+        let names = schema.dimensions.iter().map(|dim| &dim.name)
+            .chain(schema.values.iter().map(|value| &value.name));
+        let mut columns: Vec<ColumnStats> = self.columns.into_iter().zip(names)
+            .map(|(column, name)| column.finish(name))
+            .collect();
+        let values = columns.split_off(schema.dimensions.len());
+        DataStats { horizon, num_rows: self.num_rows, dimensions: columns, values }
+    }
+}
+
+pub(crate) fn save(backend: &dyn Backend, stats: &DataStats) -> Result<(), Error> {
+    let temp_filename = format!("{COLUMN_STATS_FILENAME}.tmp");
+    backend.write(&temp_filename, serde_json::to_string(stats)?.as_bytes())?;
+    backend.rename(&temp_filename, COLUMN_STATS_FILENAME)?;
+    Ok(())
+}
+
+pub(crate) fn load(backend: &dyn Backend) -> Result<Option<DataStats>, Error> {
+    if !backend.exists(COLUMN_STATS_FILENAME) {
+        return Ok(None);
+    }
+    let json = backend.read(COLUMN_STATS_FILENAME)?;
+    Ok(Some(serde_json::from_slice(&json)?))
+}
+
+#[cfg(test)]
+mod column_stats_tests {
+    use super::ColumnCollector;
+
+    #[test]
+    fn distinct_values() {
+        let mut column = ColumnCollector::new(1);
+        for i in 0..500 {
+            column.add(i % 100);
+        }
+        assert_eq!(column.finish("x").distinct_values, 100);
+
+        let mut column = ColumnCollector::new(1);
+        for i in 0..100000 {
+            column.add(i / 2);
+        }
+        let estimate = column.finish("x").distinct_values;
+        assert!((45000..=55000).contains(&estimate), "estimated {estimate}");
+    }
+
+    #[test]
+    fn quantiles() {
+        let mut column = ColumnCollector::new(1);
+        for i in (0..=100000).rev() {
+            column.add(i);
+        }
+        let stats = column.finish("x");
+        assert_eq!(stats.min(), Some(0));
+        assert_eq!(stats.max(), Some(100000));
+        assert_eq!(stats.quantiles.len(), 11);
+        for (i, &quantile) in stats.quantiles.iter().enumerate() {
+            assert!(quantile.abs_diff(i * 10000) < 3000, "quantile {i} is {quantile}");
+        }
+
+        let stats = ColumnCollector::new(1).finish("x");
+        assert_eq!((stats.distinct_values, stats.min()), (0, None));
+    }
+}
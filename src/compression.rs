@@ -0,0 +1,176 @@
+#[cfg(feature = "zstd")]
+use std::io::Read;
+
+use log::error;
+
+use crate::Error;
+use crate::Error::DataError;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 1;
+
+/* Recorded for sections compressed with zstd and a dictionary, followed by the dictionary id */
+pub(crate) const ZSTD_DICTIONARY_ID: u8 = 4;
+
+/**
+ * How a section of a segment file is compressed.  From format version 3 each section records
+ * its compression, so a segment can be read by any build with that codec compiled in; older
+ * versions always use zstd.  Builds without the `zstd` feature save sections uncompressed.
+ *
+ * `Lz4` and `Snappy` need the features of the same names.  They compress less than zstd but
+ * are much faster, which suits deployments where query latency matters more than space.  `None`
+ * is always available.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Lz4,
+    Snappy
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        if cfg!(feature = "zstd") { Compression::Zstd } else { Compression::None }
+    }
+}
+
+impl Compression {
+    /**
+     * Whether this build can compress and decompress with this codec.
+     */
+    pub fn is_available(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Snappy => cfg!(feature = "snappy")
+        }
+    }
+
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+            Compression::Snappy => 3
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Compression, Error> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Lz4),
+            3 => Ok(Compression::Snappy),
+            _ => {
+                error!("Unknown compression {id:?}");
+                Err(DataError)
+            }
+        }
+    }
+
+    pub(crate) fn compress(self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(payload.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::stream::encode_all(payload, ZSTD_LEVEL)?),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(payload)),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => snap::raw::Encoder::new().compress_vec(payload).map_err(|err| {
+                error!("Snappy compression failed: {err}");
+                DataError
+            }),
+            #[allow(unreachable_patterns)]
+            _ => Err(self.unavailable())
+        }
+    }
+
+    pub(crate) fn decompress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::stream::decode_all(data)?),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(data).map_err(|err| {
+                error!("Lz4 decompression failed: {err}");
+                DataError
+            }),
+            #[cfg(feature = "snappy")]
+            Compression::Snappy => snap::raw::Decoder::new().decompress_vec(data).map_err(|err| {
+                error!("Snappy decompression failed: {err}");
+                DataError
+            }),
+            #[allow(unreachable_patterns)]
+            _ => Err(self.unavailable())
+        }
+    }
+
+    fn unavailable(self) -> Error {
+        error!("Compression {self:?} is not available in this build");
+        DataError
+    }
+}
+
+/**
+ * Compress with zstd, using a dictionary trained on similar payloads.
+ */
+#[cfg(feature = "zstd")]
+pub(crate) fn compress_with_dictionary(payload: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut compressor = zstd::bulk::Compressor::with_dictionary(ZSTD_LEVEL, dictionary)?;
+    Ok(compressor.compress(payload)?)
+}
+
+#[cfg(feature = "zstd")]
+pub(crate) fn decompress_with_dictionary(data: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    let mut decoder = zstd::stream::read::Decoder::with_dictionary(data, dictionary)?;
+    decoder.read_to_end(&mut payload)?;
+    Ok(payload)
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn compress_with_dictionary(_payload: &[u8], _dictionary: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Compression::Zstd.unavailable())
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn decompress_with_dictionary(_data: &[u8], _dictionary: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Compression::Zstd.unavailable())
+}
+
+#[cfg(test)]
+mod compression_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let payload: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let all = [Compression::None, Compression::Zstd, Compression::Lz4, Compression::Snappy];
+        for compression in all.into_iter().filter(|c| c.is_available()) {
+            let data = compression.compress(&payload).unwrap();
+            assert_eq!(compression.decompress(&data).unwrap(), payload);
+            assert_eq!(Compression::from_id(compression.id()).unwrap(), compression);
+        }
+        assert!(Compression::from_id(99).is_err());
+    }
+
+    #[test]
+    fn unavailable() {
+        for compression in [Compression::Zstd, Compression::Lz4, Compression::Snappy] {
+            if !compression.is_available() {
+                assert!(compression.compress(b"data").is_err());
+                assert!(compression.decompress(b"data").is_err());
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn corrupt_lz4() {
+        let mut data = Compression::Lz4.compress(&[1; 1000]).unwrap();
+        data.truncate(data.len() - 1);
+        assert!(Compression::Lz4.decompress(&data).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt::Write;
+
+use log::error;
+
+use crate::{compare_points, Datum, Error, TransactionId};
+use crate::Error::DataError;
+use crate::query::{Criteria, QueryRow};
+use crate::transaction::Transaction;
+
+/**
+ * A position in the results of a query, from which the next page of results can be fetched.
+ *
+ * It records the horizon of the transaction it came from, so the following pages see the same
+ * data as the first one, and the last point returned.  It can be turned into an opaque token for
+ * handing to a client, such as in an HTTP response, so that no state has to be kept between
+ * requests.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    horizon: TransactionId,
+    last_point: Vec<Datum>
+}
+
+/**
+ * One page of query results, and a cursor for the next page if there might be one.
+ */
+pub struct Page {
+    pub rows: Vec<QueryRow>,
+    pub cursor: Option<Cursor>
+}
+
+impl Cursor {
+    pub fn horizon(&self) -> TransactionId {
+        self.horizon
+    }
+
+    pub fn to_token(&self) -> String {
+        let mut token = format!("{:x}", self.horizon);
+        for val in &self.last_point {
+            write!(token, ".{val:x}").unwrap();
+        }
+        token
+    }
+
+    /**
+     * Parse a token made by `to_token`, failing with `DataError` if it is malformed.
+     */
+    pub fn from_token(token: &str) -> Result<Cursor, Error> {
+        let mut parts = token.split('.');
+        let horizon = parts.next()
+            .and_then(|s| TransactionId::from_str_radix(s, 16).ok())
+            .ok_or(DataError)?;
+        let last_point = parts.map(|s| Datum::from_str_radix(s, 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| DataError)?;
+        if last_point.is_empty() {
+            return Err(DataError);
+        }
+        Ok(Cursor { horizon, last_point })
+    }
+}
+
+/**
+ * Fetch up to `page_size` rows matching the criteria, starting after the cursor's position.
+ */
+pub(crate) fn query_page<'db>(txn: &'db Transaction<'db>, criteria: &Criteria, cursor: Option<&Cursor>, page_size: usize) -> Result<Page, Error> {
+    let num_dims = txn.database.schema.dimensions.len();
+
+    let mut criteria = criteria.clone();
+    if let Some(cursor) = cursor {
+        if cursor.horizon != txn.horizon || cursor.last_point.len() != num_dims {
+            error!("Cursor {:?} does not belong to a transaction with horizon {:?}", cursor, txn.horizon);
+            return Err(DataError);
+        }
+        /* Only scan blocks that can contain points after the cursor */
+        criteria = criteria.and(&Criteria::new().range(0, cursor.last_point[0]..=Datum::MAX));
+    }
+
+    let mut scan = txn.query_with(&criteria)?;
+    if let Some(cursor) = cursor {
+        scan.seek_to(&cursor.last_point);
+    }
+    let mut rows = Vec::with_capacity(page_size);
+    while rows.len() < page_size {
+        let Some(row) = scan.next() else { break };
+        if let Some(cursor) = cursor {
+            if compare_points(num_dims, &row.values_array, &cursor.last_point).is_le() {
+                continue;
+            }
+        }
+        rows.push(row);
+    }
+
+    let cursor = if rows.len() < page_size || page_size == 0 {
+        None
+    } else {
+        rows.last().map(|row| Cursor {
+            horizon: txn.horizon,
+            last_point: row.values_array[0..num_dims].to_vec()
+        })
+    };
+    Ok(Page { rows, cursor })
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod cursor_tests {
+    use crate::{Criteria, Cursor};
+    use crate::test_util::create_database;
+
+    #[test]
+    fn token_round_trip() {
+        let cursor = Cursor { horizon: 42, last_point: vec![1000, 0, 7] };
+        assert_eq!(Cursor::from_token(&cursor.to_token()).unwrap(), cursor);
+        assert!(Cursor::from_token("").is_err());
+        assert!(Cursor::from_token("12").is_err());
+        assert!(Cursor::from_token("12.zz").is_err());
+    }
+
+    #[test]
+    fn pages() {
+        let mut database = create_database("cursor-pages");
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..25 {
+            txn.add_row(&[i / 4, i % 4, i]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let criteria = Criteria::new().range(1, 1..=2);
+        let mut token: Option<String> = None;
+        let mut values = Vec::new();
+        loop {
+            /* Each page is fetched by a new transaction, as if by a separate request */
+            let cursor = token.map(|t| Cursor::from_token(&t).unwrap());
+            let txn = match &cursor {
+                Some(cursor) => database.resume_transaction(cursor).unwrap(),
+                None => database.new_transaction().unwrap()
+            };
+            let page = txn.query_paged(&criteria, cursor.as_ref(), 5).unwrap();
+            values.extend(page.rows.iter().map(|r| r[2]));
+            token = page.cursor.map(|c| c.to_token());
+            txn.rollback();
+
+            if token.is_none() { break; }
+
+            /* Rows added after the first page are not seen by later pages */
+            let mut txn = database.new_transaction().unwrap();
+            txn.add_row(&[100, 1, 100]).unwrap();
+            txn.commit().unwrap();
+        }
+
+        assert_eq!(values, vec![1, 2, 5, 6, 9, 10, 13, 14, 17, 18, 21, 22]);
+    }
+}
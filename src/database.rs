@@ -1,192 +1,3428 @@
-use std::cell::RefCell;
-use std::collections::{HashSet};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::Duration;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
-use crate::{BlockId, Error, SegmentId, TransactionId};
+use crate::{BlockId, BlockNum, Datum, DictionaryId, Error, SegmentId, SegmentNum, TransactionId};
+use crate::backend::Backend;
+use crate::backup::{self, BackupStats};
+#[cfg(feature = "fs")]
+use crate::backend::FileBackend;
 use crate::block::Block;
-use crate::cache::Cache;
-use crate::scan::ScanSource;
-use crate::schema::Schema;
-use crate::segment::Segment;
-use crate::storage::decode_segment_path;
+use crate::cache::{Cache, CacheHint};
+use crate::checkpoint::Checkpoints;
+use crate::column_stats::{self, DataStats, StatsCollector};
+use crate::compression::Compression;
+use crate::cursor::Cursor;
+use crate::dictionary::{Dictionaries, Dictionary};
+use crate::Error::{DataError, IoError, OutOfBounds, QuotaExceeded, ReadOnly, SchemaError};
+use crate::dump::{read_dump_rows, read_dump_schema, write_dump};
+use crate::export::{self, ExportManifest};
+use crate::format::{CURRENT_FORMAT_VERSION, FormatVersion};
+use crate::freeze::Frozen;
+use crate::import::{self, ImportLease};
+use crate::journal::{self, JournalEntry};
+use crate::labels::Labels;
+use crate::manifest::{BlockDivergence, BlockManifest};
+use crate::open::{OpenMode, OpenOptions};
+use crate::pool::{self, Layout, PoolHash};
+use crate::query::{Criteria, QueryRow};
+use crate::retry::RetryPolicy;
+use crate::scan::{Scan, ScanSource};
+use crate::schema::{self, Dimension, Schema, SchemaChange, SchemaDiff, Value};
+use crate::segment::{ColumnOccupancy, Segment};
+use crate::snapshot::Snapshot;
+use crate::storage::{decode_pool_name, decode_segment_path, get_segment_name, HOT_BLOCKS_FILENAME, LATE_SEGMENTS_FILENAME, SCHEMA_FILENAME};
+use crate::tombstone::Tombstones;
+use crate::typed::{Field, TypedValue};
+use crate::trace::{self, QueryTrace};
 use crate::transaction::Transaction;
 
 const SEGMENT_CACHE_SIZE: usize = 100;
 const BLOCK_CACHE_SIZE: usize = 100;
+const MAX_DICTIONARY_SAMPLES: usize = 1000;
+/* Large enough for few files, small enough for backup tools and object store uploads */
+const DEFAULT_MAX_SEGMENT_SIZE: u64 = 256 * 1024 * 1024;
 
 pub struct Database {
     pub path: PathBuf,
+    pub(crate) backend: Box<dyn Backend>,
     pub schema: Schema,
+    renames: Vec<SchemaChange>,
+    pub(crate) labels: Labels,
     pub next_transaction_id: TransactionId,
     pub committed_segments: HashSet<SegmentId>,
     pub cached_segments: RefCell<Cache<SegmentId, Segment>>,
-    pub cached_blocks: RefCell<Cache<BlockId, Block>>
+    pub cached_blocks: RefCell<Cache<BlockId, Block>>,
+    pub(crate) checkpoints: Checkpoints,
+    max_size: Option<u64>,
+    used_size: u64,
+    pub(crate) max_block_size: Option<usize>,
+    pub(crate) max_segment_size: Option<u64>,
+    pub(crate) auto_flush: Option<u64>,
+    late_data: Option<(usize, Datum)>,
+    late_segments: HashSet<SegmentId>,
+    pub(crate) compression: Compression,
+    pub(crate) dictionaries: Dictionaries,
+    pub(crate) dictionary: Option<Rc<Dictionary>>,
+    layout: Layout,
+    pub(crate) counters: WriteCounters,
+    next_query_id: Cell<u64>,
+    slow_query_threshold: Option<Duration>,
+    open_scans: Cell<usize>,
+    peak_open_scans: Cell<usize>,
+    max_open_scans: Option<usize>,
+    /* Each committed segment's `Segment::column_occupancy`, as read for `stats` */
+    segment_occupancy: RefCell<HashMap<SegmentId, Option<ColumnOccupancy>>>,
+    verify_block_bounds: bool,
+    fetch_retry: RetryPolicy,
+    pub(crate) frozen: bool,
+    /* The format version recorded in the schema file */
+    format_version: FormatVersion,
+    health: Health
+}
+
+/**
+ * Running totals of the writing done since the database was opened.
+ */
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WriteCounters {
+    pub logical_bytes: u64,
+    pub bytes_written: u64,
+    pub segments_created: u64,
+    pub segments_rewritten: u64,
+    pub segments_deleted: u64
+}
+
+/**
+ * Whether a database can still be written to.  After a write error that leaves files on disk
+ * that the database can't account for, such as a partly committed transaction or a temporary
+ * file that couldn't be removed, it becomes read-only: queries still work, but transactions
+ * can't save anything and fail with `ReadOnly`.  Opening the database again cleans up and
+ * clears the condition.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    ReadOnly
+}
+
+/**
+ * How much space a database is using, from `Database::stats`.  Sizes are of the segment files
+ * and pool objects, which hold all of the data; the few small metadata files are not counted.
+ *
+ * The remaining fields count the work done since the database was opened.  `logical_bytes` is
+ * the size of the rows added, at eight bytes per column, and `bytes_written` the size of the
+ * segment files written for them, including ones later rolled back or rewritten by `compact`.
+ * `open_scans` is the number of scans returned by queries that haven't been dropped yet, and
+ * `peak_open_scans` the most there have been at once; see `Database::set_max_open_scans`.
+ *
+ * `value_columns` reports how sparse each value column is; see `ValueColumnStats`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatabaseStats {
+    pub num_segments: usize,
+    pub size: u64,
+    pub max_size: Option<u64>,
+    pub logical_bytes: u64,
+    pub bytes_written: u64,
+    pub segments_created: u64,
+    pub segments_rewritten: u64,
+    pub segments_deleted: u64,
+    pub open_scans: usize,
+    pub peak_open_scans: usize,
+    pub value_columns: Vec<ValueColumnStats>
+}
+
+impl DatabaseStats {
+    /**
+     * The fraction of the maximum size in use, if there is a maximum.
+     */
+    pub fn utilisation(&self) -> Option<f64> {
+        self.max_size.map(|max_size| self.size as f64 / max_size as f64)
+    }
+
+    /**
+     * Bytes written to segment files for each byte of rows added, if any have been added.
+     * Compression brings this below 1; rewrites and rolled back transactions raise it.
+     */
+    pub fn write_amplification(&self) -> Option<f64> {
+        if self.logical_bytes == 0 {
+            return None;
+        }
+        Some(self.bytes_written as f64 / self.logical_bytes as f64)
+    }
+}
+
+/**
+ * How many of the cells of one value column's committed blocks hold no value, from
+ * `Database::stats`, counted from the occupancy recorded in segment info, so no block is
+ * loaded.  Segments saved before format version 2, or in version 8, which don't record it,
+ * are left out.  A column that is mostly missing may be better split into a table of its own,
+ * or made a dimension.  Columns differ when some rows are partial versions, written by
+ * `Transaction::update`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValueColumnStats {
+    pub name: String,
+    pub cells: u64,
+    pub missing: u64
+}
+
+impl ValueColumnStats {
+    /**
+     * The fraction of the column's cells without a value, or 0 if there are no cells.
+     */
+    pub fn missing_fraction(&self) -> f64 {
+        if self.cells == 0 {
+            return 0.0;
+        }
+        self.missing as f64 / self.cells as f64
+    }
+}
+
+/**
+ * Roughly how many bytes of memory a database is holding, from `Database::memory_usage` or
+ * `Transaction::memory_usage`.  `metadata` covers the schema, checkpoints and the list of
+ * committed segments.  `pinned_blocks` is the number of cached blocks the cache can't evict
+ * because something else, such as a query's iterator, still holds them.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub cached_segments: usize,
+    pub cached_blocks: usize,
+    pub unsaved_blocks: usize,
+    pub metadata: usize,
+    pub pinned_blocks: usize
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.cached_segments + self.cached_blocks + self.unsaved_blocks + self.metadata
+    }
+}
+
+/**
+ * The occupancy of one committed block, from `Database::block_stats`.  `chunk` is the origin
+ * of the chunk the block lies in; several blocks in the same chunk, from different segments,
+ * make queries on it read more than they need to and are candidates for compaction.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockStats {
+    pub block_id: BlockId,
+    pub chunk: Vec<Datum>,
+    pub num_rows: usize,
+    pub num_cells: usize
+}
+
+impl BlockStats {
+    /**
+     * The fraction of the block's cells that hold a row.
+     */
+    pub fn fill_ratio(&self) -> f64 {
+        if self.num_cells == 0 {
+            return 0.0;
+        }
+        self.num_rows as f64 / self.num_cells as f64
+    }
 }
 
 struct ScanResult {
     next_transaction_id: TransactionId,
-    committed_segments: HashSet<SegmentId>
+    committed_segments: HashSet<SegmentId>,
+    used_size: u64,
+    num_reverted: usize
 }
 
 impl Database {
+    #[cfg(feature = "fs")]
     pub fn create(schema: Schema, path: &Path) -> Result<Database, Error> {
-        std::fs::create_dir(path)?;
-        schema.save(path)?;
+        let backend = FileBackend::create(path)?;
+        let mut database = Database::create_with_backend(schema, Box::new(backend))?;
+        database.path = path.to_path_buf();
         info!("Created database in {:?}", path);
+        Ok(database)
+    }
+
+    /**
+     * Create a new database stored in the given backend, which should be empty.
+     */
+    pub fn create_with_backend(schema: Schema, backend: Box<dyn Backend>) -> Result<Database, Error> {
+        schema.validate()?;
+        schema.save(backend.as_ref())?;
         debug!("Dimensions: {:?}", schema.dimensions.iter().map(|d| (&d.name, d.chunk_size)).collect::<Vec<_>>());
         debug!("Values: {:?}", schema.values.iter().map(|v| &v.name).collect::<Vec<_>>());
 
         Ok(Database {
-            path: path.to_path_buf(),
+            path: PathBuf::new(),
+            backend,
+            labels: Labels::new(schema.dimensions.len()),
             schema,
+            renames: Vec::new(),
             next_transaction_id: 1,
             committed_segments: HashSet::new(),
             cached_segments: RefCell::new(Cache::new(SEGMENT_CACHE_SIZE)),
-            cached_blocks: RefCell::new(Cache::new(BLOCK_CACHE_SIZE))
+            cached_blocks: RefCell::new(Cache::new(BLOCK_CACHE_SIZE)),
+            checkpoints: Checkpoints::default(),
+            max_size: None,
+            used_size: 0,
+            max_block_size: None,
+            max_segment_size: Some(DEFAULT_MAX_SEGMENT_SIZE),
+            auto_flush: None,
+            late_data: None,
+            late_segments: HashSet::new(),
+            compression: Compression::default(),
+            dictionaries: Dictionaries::default(),
+            dictionary: None,
+            layout: Layout::default(),
+            counters: WriteCounters::default(),
+            next_query_id: Cell::new(1),
+            slow_query_threshold: None,
+            open_scans: Cell::new(0),
+            peak_open_scans: Cell::new(0),
+            max_open_scans: None,
+            segment_occupancy: RefCell::new(HashMap::new()),
+            verify_block_bounds: cfg!(debug_assertions),
+            fetch_retry: RetryPolicy::default(),
+            frozen: false,
+            format_version: CURRENT_FORMAT_VERSION,
+            health: Health::Healthy
         })
     }
 
+    #[cfg(feature = "fs")]
     pub fn open(path: &Path) -> Result<Database, Error> {
-        let schema = Schema::load(path)?;
-        let scan = scan_files(path)?;
+        let mut database = Database::open_with_backend(Box::new(FileBackend::new(path)))?;
+        database.path = path.to_path_buf();
         info!("Opened database in {:?}", path);
+        Ok(database)
+    }
+
+    /**
+     * Open an existing database stored in the given backend.
+     *
+     * Fails with `UnsupportedVersion` if the database was written by a newer version of the
+     * library, in a format this one can't read.
+     */
+    pub fn open_with_backend(backend: Box<dyn Backend>) -> Result<Database, Error> {
+        let (schema, format_version) = Schema::load_versioned(backend.as_ref())?;
+        let renames = schema::load_renames(backend.as_ref())?;
+        let labels = Labels::load(backend.as_ref(), schema.dimensions.len())?;
+        let scan = scan_files(backend.as_ref())?;
+        let checkpoints = Checkpoints::load(backend.as_ref(), &scan.committed_segments)?;
+        let late_segments = load_late_segments(backend.as_ref(), &scan.committed_segments)?;
+        let dictionaries = Dictionaries::default();
+        let dictionary = match Dictionary::latest_id(backend.as_ref())? {
+            Some(id) => Some(dictionaries.get(backend.as_ref(), id)?),
+            None => None
+        };
+        if scan.num_reverted > 0 {
+            journal::record(backend.as_ref(), "revert",
+                format!("Deleted {} segments of transactions that didn't commit", scan.num_reverted));
+        }
         debug!("Next transaction is {:?}, number of committed segments is {:?}",
             scan.next_transaction_id, scan.committed_segments.len());
         Ok(Database {
-            path: path.to_path_buf(),
+            path: PathBuf::new(),
+            backend,
             schema,
+            renames,
+            labels,
             next_transaction_id: scan.next_transaction_id,
             committed_segments: scan.committed_segments,
             cached_segments: RefCell::new(Cache::new(SEGMENT_CACHE_SIZE)),
-            cached_blocks: RefCell::new(Cache::new(BLOCK_CACHE_SIZE))
+            cached_blocks: RefCell::new(Cache::new(BLOCK_CACHE_SIZE)),
+            checkpoints,
+            max_size: None,
+            used_size: scan.used_size,
+            max_block_size: None,
+            max_segment_size: Some(DEFAULT_MAX_SEGMENT_SIZE),
+            auto_flush: None,
+            late_data: None,
+            late_segments,
+            compression: Compression::default(),
+            dictionaries,
+            dictionary,
+            layout: Layout::default(),
+            counters: WriteCounters::default(),
+            next_query_id: Cell::new(1),
+            slow_query_threshold: None,
+            open_scans: Cell::new(0),
+            peak_open_scans: Cell::new(0),
+            max_open_scans: None,
+            segment_occupancy: RefCell::new(HashMap::new()),
+            verify_block_bounds: cfg!(debug_assertions),
+            fetch_retry: RetryPolicy::default(),
+            frozen: false,
+            format_version,
+            health: Health::Healthy
         })
     }
 
-    pub fn new_transaction(&mut self) -> Result<Transaction, Error> {
+    /**
+     * Open the database in a directory, or create it, as the options say.  See `OpenOptions`.
+     */
+    #[cfg(feature = "fs")]
+    pub fn open_with(path: &Path, options: OpenOptions) -> Result<Database, Error> {
+        let backend = if path.exists() {
+            if options.mode == OpenMode::ErrorIfExists {
+                error!("Database in {:?} already exists", path);
+                return Err(IoError);
+            }
+            FileBackend::new(path)
+        } else if options.mode == OpenMode::OpenExisting {
+            FileBackend::new(path)
+        } else if options.schema.is_none() {
+            error!("Database in {:?} doesn't exist and no schema was given to create it", path);
+            return Err(SchemaError);
+        } else {
+            FileBackend::create(path)?
+        };
+
+        let mut database = Database::open_with_backend_options(Box::new(backend), options)?;
+        database.path = path.to_path_buf();
+        info!("Opened database in {:?}", path);
+        Ok(database)
+    }
+
+    /**
+     * Open the database stored in the given backend, or create it, as the options say.  A
+     * backend holds a database if it has a schema file.
+     */
+    pub fn open_with_backend_options(backend: Box<dyn Backend>, options: OpenOptions) -> Result<Database, Error> {
+        let exists = backend.exists(SCHEMA_FILENAME);
+        match (exists, options.mode, options.schema) {
+            (true, OpenMode::ErrorIfExists, _) => {
+                error!("Database already exists");
+                Err(IoError)
+            },
+            (true, _, Some(expected)) => {
+                let renames = schema::load_renames(backend.as_ref())?;
+                let diff = Schema::load(backend.as_ref())?.diff(&expected).with_renames(&renames);
+                if !diff.is_empty() {
+                    error!("Database schema doesn't match the expected schema: {:?}", diff.differences);
+                    return Err(SchemaError);
+                }
+                Database::open_with_backend(backend)
+            },
+            (_, OpenMode::OpenExisting, _) | (true, _, None) => Database::open_with_backend(backend),
+            (false, _, Some(schema)) => Database::create_with_backend(schema, backend),
+            (false, _, None) => {
+                error!("Database doesn't exist and no schema was given to create it");
+                Err(SchemaError)
+            }
+        }
+    }
+
+    /**
+     * Compare the database's schema with the one the application expects, matching columns by
+     * name, so that a mismatch can be reported clearly at startup instead of turning up as wrong
+     * columns in query results.  `OpenOptions::schema` makes opening fail on any difference.
+     */
+    pub fn check_schema(&self, expected: &Schema) -> SchemaDiff {
+        self.schema.diff(expected).with_renames(&self.renames)
+    }
+
+    /**
+     * Rename a dimension or value.  The data is unchanged, but the column is known by its new
+     * name from then on.  The old name is remembered, so that looking it up with `dimension_no`
+     * or `value_no`, or expecting it in `check_schema`, reports the new name.
+     */
+    pub fn alter_schema(&mut self, change: SchemaChange) -> Result<(), Error> {
+        self.check_writable()?;
+        let schema = self.schema.altered(&change)?;
+        schema.save(self.backend.as_ref())?;
+        self.schema = schema;
+
+        self.renames.push(change.clone());
+        schema::save_renames(self.backend.as_ref(), &self.renames)?;
+        info!("Altered schema: {:?}", change);
+        journal::record(self.backend.as_ref(), "alter-schema", format!("{:?}", change));
+        Ok(())
+    }
+
+    /**
+     * The position of a dimension, by name.  A name the dimension had before being renamed fails
+     * with `SchemaError`, and the error logged gives the current name.
+     */
+    pub fn dimension_no(&self, name: &str) -> Result<usize, Error> {
+        self.schema.dimension_no(name).ok_or_else(|| self.unknown_column("dimension", name, true))
+    }
+
+    /**
+     * The position of a value, by name.  See `dimension_no`.
+     */
+    pub fn value_no(&self, name: &str) -> Result<usize, Error> {
+        self.schema.value_no(name).ok_or_else(|| self.unknown_column("value", name, false))
+    }
+
+    fn unknown_column(&self, kind: &str, name: &str, is_dimension: bool) -> Error {
+        match schema::renamed_to(&self.renames, name, is_dimension) {
+            Some(new_name) => error!("No {} named {:?}; it has been renamed to {:?}", kind, name, new_name),
+            None => error!("No {} named {:?}", kind, name)
+        }
+        SchemaError
+    }
+
+    /**
+     * The id standing for a string in a label dimension, if it has been used.
+     */
+    pub fn label_id(&self, dim_no: usize, name: &str) -> Option<Datum> {
+        self.labels.id(dim_no, name)
+    }
+
+    /**
+     * The string an id stands for in a label dimension.
+     */
+    pub fn label(&self, dim_no: usize, id: Datum) -> Option<&str> {
+        self.labels.label(dim_no, id)
+    }
+
+    /**
+     * A row with the ids of its label dimensions turned back into strings, and its values
+     * converted to their types.  Other dimensions, and ids with no string, are left as they are.
+     */
+    pub fn resolve_row(&self, row: &QueryRow) -> Vec<Field> {
+        let num_dims = self.schema.dimensions.len();
+        row.values_array.iter().enumerate().map(|(col_no, &datum)| {
+            if col_no >= num_dims {
+                return Field::Value(TypedValue::from_datum(self.schema.values[col_no - num_dims].value_type, datum));
+            }
+            match self.labels.label(col_no, datum) {
+                Some(label) if self.schema.dimensions[col_no].labels => Field::Label(label.to_string()),
+                _ => Field::Datum(datum)
+            }
+        }).collect()
+    }
+
+    /**
+     * The current name of a dimension or value that has been renamed from `name`, if any.
+     */
+    pub(crate) fn renamed_to(&self, name: &str, is_dimension: bool) -> Option<String> {
+        schema::renamed_to(&self.renames, name, is_dimension)
+    }
+
+    /**
+     * The checkpoints recorded by committed transactions.
+     */
+    pub fn checkpoints(&self) -> &Checkpoints {
+        &self.checkpoints
+    }
+
+    /**
+     * Limit the total size of the segment files and pool objects, or remove the limit with
+     * `None`.  Writes that would exceed the limit fail with `QuotaExceeded` before anything is
+     * written: a failed `Transaction::flush` keeps its rows, so it can be retried after space
+     * has been freed, and a failed `commit` rolls the transaction back.  The limit is not saved
+     * with the database.
+     */
+    pub fn set_max_size(&mut self, max_size: Option<u64>) {
+        self.max_size = max_size;
+    }
+
+    /**
+     * Make the chunk size advisory: when a transaction is flushed, blocks that would save to
+     * more than `max_block_size` bytes are split into smaller ones, each with its own bounds,
+     * so dense regions of a chunk are stored apart from sparse ones.  Blocks never span more
+     * than one chunk, so the chunk size should suit the sparsest data.  The limit is not saved
+     * with the database.
+     */
+    pub fn set_max_block_size(&mut self, max_block_size: Option<usize>) {
+        self.max_block_size = max_block_size;
+    }
+
+    /**
+     * Set the target size of segment files, or remove it with `None`.  When a flush would
+     * write a larger file, its blocks are split over several segments, each within the
+     * target, so files stay manageable for backup tools and object store uploads.  A single
+     * block larger than the target still gets a segment of its own.  The default is 256 MB, and
+     * the setting is not saved with the database.
+     */
+    pub fn set_max_segment_size(&mut self, max_segment_size: Option<u64>) {
+        self.max_segment_size = max_segment_size;
+    }
+
+    /**
+     * Make transactions flush automatically as rows are added, aiming for segments of about
+     * `target_size` bytes, or turn it off with `None`.  How many rows that takes depends on
+     * the schema and how well the data compresses, so each transaction starts from the
+     * database's write amplification so far, and tunes its threshold by the compression ratio
+     * its own flushes achieve; see `Transaction::flush_stats`.  The setting is not saved with
+     * the database.
+     */
+    pub fn set_auto_flush(&mut self, target_size: Option<u64>) {
+        self.auto_flush = target_size;
+    }
+
+    /**
+     * Record queries that take at least `threshold` in the slow-query log in the database's
+     * directory, with their tracing id, wall time, blocks read, rows returned and criteria, or
+     * stop recording them with `None`.  A query's time runs from when its scan is made until its
+     * last row is read or it is dropped.  The setting is not saved with the database.
+     */
+    pub fn set_slow_query_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_query_threshold = threshold;
+    }
+
+    /**
+     * Limit how many scans can be open at once, or remove the limit with `None`.  Each open scan
+     * pins the blocks it is reading in the cache, so an application that leaks scans, or starts
+     * too many at once, uses more and more memory.  Queries that would exceed the limit fail
+     * with `QuotaExceeded` until a scan is dropped.  The infallible `query` methods of
+     * transactions and snapshots are exempt, and only count towards it.  The setting is not
+     * saved with the database.
+     */
+    pub fn set_max_open_scans(&mut self, max_open_scans: Option<usize>) {
+        self.max_open_scans = max_open_scans;
+    }
+
+    /**
+     * Check every block loaded for a query against the bounds recorded for it in its segment's
+     * info.  Scans skip blocks by those bounds, so if they are wrong, through corruption or a
+     * bug in whatever wrote the segment, rows go missing from results without any error.  A
+     * block that doesn't match is logged and not read.  The check is on by default in debug
+     * builds, and the setting is not saved with the database.
+     */
+    pub fn set_verify_block_bounds(&mut self, verify: bool) {
+        self.verify_block_bounds = verify;
+    }
+
+    /**
+     * Choose how reads of segments and blocks for queries are retried when the storage fails
+     * with `IoError`, by default three attempts with a short backoff.  A query whose read
+     * still fails stops there, with the error in `Scan::check`, unless it allows missing rows.
+     * The setting is not saved with the database.
+     */
+    pub fn set_fetch_retry(&mut self, retry: RetryPolicy) {
+        self.fetch_retry = retry;
+    }
+
+    /**
+     * The queries recorded in the slow-query log, oldest first.
+     */
+    pub fn slow_queries(&self) -> Result<Vec<QueryTrace>, Error> {
+        trace::load(self.backend.as_ref())
+    }
+
+    /**
+     * Choose how segments written from now on are compressed.  Each block records its own
+     * compression, so segments written with different settings can be read side by side, by
+     * any build with their codecs.  Flushes fail with `DataError` if the codec isn't available
+     * in this build; see `Compression::is_available`.  The setting is not saved with the
+     * database.
+     */
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /**
+     * Choose how blocks written from now on are stored; see `Layout`.  Segments already saved
+     * keep their layout, and both can be read side by side.  The setting is not saved with the
+     * database.
+     */
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /**
+     * A new segment to save blocks in, with the database's compression and layout, and the
+     * schema's cell order.
+     */
+    pub(crate) fn new_segment(&self, seg_id: SegmentId) -> Segment {
+        let mut segment = Segment::new(seg_id);
+        segment.compression = self.compression;
+        segment.dictionary = self.dictionary.clone();
+        segment.layout = self.layout;
+        segment.cell_order = self.schema.cell_order;
+        segment
+    }
+
+    /**
+     * Turn on late data mode, or turn it off with `None`.  With `Some((dim_no, lag))`, when a
+     * transaction is flushed, blocks lying entirely more than `lag` below the largest committed
+     * value of dimension `dim_no`, usually a time dimension, are saved in a segment of their own
+     * rather than with the transaction's recent blocks.  Backfilled or clock-skewed rows then
+     * don't spread the segments holding recent data over old chunks, which keeps those segments
+     * from overlapping and recent queries fast.  The separate segments are flagged as late, and
+     * are listed by `late_segments` as priority candidates for compaction.  The setting is not
+     * saved with the database.
+     */
+    pub fn set_late_data(&mut self, late_data: Option<(usize, Datum)>) {
+        self.late_data = late_data;
+    }
+
+    /**
+     * The committed segments holding late data, in order.  See `set_late_data`.
+     */
+    pub fn late_segments(&self) -> Vec<SegmentId> {
+        let mut seg_ids: Vec<SegmentId> = self.late_segments.iter().copied().collect();
+        seg_ids.sort();
+        seg_ids
+    }
+
+    /**
+     * In late data mode, the dimension and the value below which blocks are late: the largest
+     * value of that dimension in any committed block, less the lag.
+     */
+    pub(crate) fn late_data_cutoff(&self) -> Option<(usize, Datum)> {
+        let (dim_no, lag) = self.late_data?;
+        let source = self.get_scan_source();
+        let max_value = self.committed_segments.iter()
+            .filter_map(|&seg_id| source.get_segment(seg_id).ok())
+            .flat_map(|segment| segment.block_info.iter().map(|info| info.max_bounds[dim_no]).collect::<Vec<_>>())
+            .max()?;
+        Some((dim_no, max_value.checked_sub(lag)?))
+    }
+
+    /**
+     * Flag newly committed segments as holding late data.  The flags only guide compaction, so
+     * if saving them fails the segments stay committed, unflagged.
+     */
+    pub(crate) fn add_late_segments(&mut self, seg_ids: &[SegmentId]) {
+        self.late_segments.extend(seg_ids);
+        let result = (|| {
+            let temp_filename = format!("{LATE_SEGMENTS_FILENAME}.tmp");
+            let json = serde_json::to_string(&self.late_segments())?;
+            self.backend.write(&temp_filename, json.as_bytes())?;
+            self.backend.rename(&temp_filename, LATE_SEGMENTS_FILENAME)
+        })();
+        if let Err(err) = result {
+            error!("Failed to save late segments: {:?}", err);
+        }
+    }
+
+    pub fn stats(&self) -> DatabaseStats {
+        DatabaseStats {
+            num_segments: self.committed_segments.len(),
+            size: self.used_size,
+            max_size: self.max_size,
+            logical_bytes: self.counters.logical_bytes,
+            bytes_written: self.counters.bytes_written,
+            segments_created: self.counters.segments_created,
+            segments_rewritten: self.counters.segments_rewritten,
+            segments_deleted: self.counters.segments_deleted,
+            open_scans: self.open_scans.get(),
+            peak_open_scans: self.peak_open_scans.get(),
+            value_columns: self.value_column_stats()
+        }
+    }
+
+    /**
+     * The occupancy of each value column over the committed blocks, from the segment info.
+     * Each segment's info is only read the first time, or after the segment is rewritten.
+     * Segments that can't be read are left out, with a warning.
+     */
+    fn value_column_stats(&self) -> Vec<ValueColumnStats> {
+        let num_values = self.schema.values.len();
+        let mut occupancy = self.segment_occupancy.borrow_mut();
+        occupancy.retain(|seg_id, _| self.committed_segments.contains(seg_id));
+        let source = self.get_scan_source();
+        let mut cells = 0;
+        let mut present = vec![0; num_values];
+        for &seg_id in &self.committed_segments {
+            let segment_occupancy = match occupancy.entry(seg_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match source.get_segment(seg_id) {
+                    Ok(segment) => entry.insert(segment.column_occupancy(num_values)),
+                    Err(err) => {
+                        warn!("Leaving segment {:?} out of the column statistics: {:?}", seg_id, err);
+                        continue;
+                    }
+                }
+            };
+            if let Some((segment_cells, segment_present)) = &*segment_occupancy {
+                cells += segment_cells;
+                for (total, count) in present.iter_mut().zip(segment_present) {
+                    *total += count;
+                }
+            }
+        }
+        self.schema.values.iter().zip(present)
+            .map(|(value, present)| ValueColumnStats { name: value.name.clone(), cells, missing: cells.saturating_sub(present) })
+            .collect()
+    }
+
+    /**
+     * Bytes written to segment files for each byte of rows added, as in `DatabaseStats`,
+     * without gathering the rest of the statistics.
+     */
+    pub(crate) fn write_amplification(&self) -> Option<f64> {
+        if self.counters.logical_bytes == 0 {
+            return None;
+        }
+        Some(self.counters.bytes_written as f64 / self.counters.logical_bytes as f64)
+    }
+
+    /**
+     * Roughly how much memory the database is using.  A transaction's unsaved blocks are
+     * reported by `Transaction::memory_usage`.
+     */
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let (cached_segments, _) = self.cached_segments.borrow().measure(Segment::memory_size);
+        let (cached_blocks, pinned_blocks) = self.cached_blocks.borrow().measure(Block::memory_size);
+        let schema_size: usize = self.schema.dimensions.iter().map(|dim| size_of::<Dimension>() + dim.name.capacity()).sum::<usize>()
+            + self.schema.values.iter().map(|value| size_of::<Value>() + value.name.capacity()).sum::<usize>();
+        let checkpoints_size: usize = self.checkpoints.iter().map(|(source, position)| source.len() + position.len()).sum();
+        let segments_size = (self.committed_segments.capacity() + self.late_segments.capacity()) * size_of::<SegmentId>();
+        MemoryUsage {
+            cached_segments,
+            cached_blocks,
+            unsaved_blocks: 0,
+            metadata: size_of::<Database>() + schema_size + checkpoints_size + segments_size + self.dictionaries.memory_size(),
+            pinned_blocks
+        }
+    }
+
+    /**
+     * The largest number of segments holding blocks in any one chunk.  A query on that chunk
+     * has to merge blocks from all of them, so a high value means compaction is overdue.
+     */
+    pub fn overlap_depth(&self) -> Result<usize, Error> {
+        let mut chunk_segments: HashMap<Vec<Datum>, HashSet<SegmentId>> = HashMap::new();
+        for stats in self.block_stats()? {
+            chunk_segments.entry(stats.chunk).or_default().insert((stats.block_id.0, stats.block_id.1));
+        }
+        Ok(chunk_segments.values().map(|segments| segments.len()).max().unwrap_or(0))
+    }
+
+    /**
+     * The occupancy of every committed block, ordered by block id.  Segments saved in format
+     * version 2 or later record it in their segment info; blocks in older segments are loaded
+     * to count their rows.
+     */
+    pub fn block_stats(&self) -> Result<Vec<BlockStats>, Error> {
+        let mut seg_ids: Vec<SegmentId> = self.committed_segments.iter().copied().collect();
+        seg_ids.sort();
+
+        let mut stats = Vec::new();
+        for seg_id in seg_ids {
+            let segment = Segment::load(self.backend.as_ref(), seg_id)?;
+            segment.check_dimensions(self.schema.dimensions.len())?;
+            for (block_num, info) in segment.block_info.iter().enumerate() {
+                let (num_rows, num_cells) = match info.counts {
+                    Some(counts) => counts,
+                    None => {
+                        let block = segment.load_one_block(self.backend.as_ref(), &self.dictionaries, block_num as BlockNum)?;
+                        (block.num_rows(), block.num_cells())
+                    }
+                };
+                stats.push(BlockStats {
+                    block_id: (seg_id.0, seg_id.1, block_num as BlockNum),
+                    chunk: self.schema.get_chunk_origin(&info.min_bounds),
+                    num_rows,
+                    num_cells
+                });
+            }
+        }
+        Ok(stats)
+    }
+
+    /**
+     * The content hash of every committed block.  Segments saved in format version 7 or later
+     * record the hashes in their segment info; blocks in older segments are loaded and hashed
+     * as they would be saved now.
+     */
+    pub fn block_manifest(&self) -> Result<BlockManifest, Error> {
+        let mut blocks = Vec::new();
+        for &seg_id in &self.committed_segments {
+            let segment = Segment::load(self.backend.as_ref(), seg_id)?;
+            for (block_num, info) in segment.block_info.iter().enumerate() {
+                let hash = match info.hash {
+                    Some(hash) => hash,
+                    None => {
+                        let block = segment.load_one_block(self.backend.as_ref(), &self.dictionaries, block_num as BlockNum)?;
+                        let mut payload = Vec::new();
+                        block.with_order(self.schema.cell_order).save(&mut payload, CURRENT_FORMAT_VERSION)?;
+                        pool::hash_payload(&payload)
+                    }
+                };
+                blocks.push(((seg_id.0, seg_id.1, block_num as BlockNum), hash));
+            }
+        }
+        Ok(BlockManifest::new(blocks))
+    }
+
+    /**
+     * Compare the committed blocks with a manifest of a reference copy of the database, such as
+     * one made by `backup_to`, to find where they have diverged.  Copies compacted separately
+     * have different segments, so every block of each differs.
+     */
+    pub fn verify_against(&self, reference: &BlockManifest) -> Result<BlockDivergence, Error> {
+        let divergence = self.block_manifest()?.compare(reference);
+        if !divergence.is_empty() {
+            warn!("Database differs from reference: {:?} blocks missing, {:?} extra, {:?} different",
+                divergence.missing.len(), divergence.extra.len(), divergence.different.len());
+        }
+        Ok(divergence)
+    }
+
+    /**
+     * The statistics on each dimension and value gathered by the last `compact`, or `None` if
+     * it has never been run.  See `DataStats`.
+     */
+    pub fn data_stats(&self) -> Result<Option<DataStats>, Error> {
+        column_stats::load(self.backend.as_ref())
+    }
+
+    /**
+     * Read all of the committed data to gather statistics on it, and save them for
+     * `data_stats`.
+     */
+    fn gather_data_stats(&self) -> Result<(), Error> {
+        let snapshot = self.query_snapshot();
+        let mut collector = StatsCollector::new(&self.schema);
+        let mut scan = snapshot.query_with(&Criteria::new())?.cache_hint(CacheHint::OneShot);
+        for row in scan.by_ref() {
+            collector.add(&row);
+        }
+        scan.check()?;
+        let stats = collector.finish(&self.schema, snapshot.horizon());
+        column_stats::save(self.backend.as_ref(), &stats)?;
+        info!("Gathered statistics on {:?} rows", stats.num_rows);
+        Ok(())
+    }
+
+    /**
+     * The maintenance actions recorded in the database's journal, oldest first: compactions,
+     * ingested segments, and transactions reverted when the database was opened after a crash.
+     */
+    pub fn history(&self) -> Result<Vec<JournalEntry>, Error> {
+        journal::load(self.backend.as_ref())
+    }
+
+    pub fn health(&self) -> Health {
+        self.health
+    }
+
+    /**
+     * Stop any further writes after an error that couldn't be cleaned up.
+     */
+    pub(crate) fn set_read_only(&mut self) {
+        if self.health != Health::ReadOnly {
+            error!("Database is now read-only after an unrecoverable write error");
+            self.health = Health::ReadOnly;
+        }
+    }
+
+    /**
+     * Check that the database can be written to.  Before the first write to a database last
+     * written by an older version of the library, the current format version is recorded, so
+     * that older versions that check it refuse to open the database rather than misread it.
+     */
+    pub(crate) fn check_writable(&mut self) -> Result<(), Error> {
+        if self.health == Health::ReadOnly {
+            error!("Can't write to a read-only database");
+            return Err(ReadOnly);
+        }
+        if self.format_version < CURRENT_FORMAT_VERSION {
+            self.schema.save(self.backend.as_ref())?;
+            info!("Upgraded database from format version {:?} to {:?}", self.format_version, CURRENT_FORMAT_VERSION);
+            self.format_version = CURRENT_FORMAT_VERSION;
+        }
+        Ok(())
+    }
+
+    /**
+     * Remove what's left of a file whose write failed, becoming read-only if it can't be.
+     */
+    pub(crate) fn remove_partial_file(&mut self, name: &str) {
+        if self.backend.exists(name) && self.backend.remove(name).is_err() {
+            error!("Couldn't remove partly written file {:?}", name);
+            self.set_read_only();
+        }
+    }
+
+    /**
+     * Check that writing `additional` more bytes, on top of `pending` bytes already written
+     * but not yet committed, would not take the database over its maximum size.
+     */
+    pub(crate) fn check_quota(&self, pending: u64, additional: u64) -> Result<(), Error> {
+        if let Some(max_size) = self.max_size {
+            if self.used_size + pending + additional > max_size {
+                error!("Writing {:?} bytes would exceed the maximum size {:?}, with {:?} used and {:?} pending",
+                    additional, max_size, self.used_size, pending);
+                return Err(QuotaExceeded);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Copy the database to another backend, leaving it holding the same committed data, and
+     * return what was copied.  The destination can be empty, or hold an earlier backup of this
+     * database, in which case only what has changed since is copied.  With the `Pool` layout
+     * that is little more than the new blocks, since segment files only refer to blocks, and
+     * blocks already in the destination's pool are not copied again; see `Layout`.
+     */
+    pub fn backup_to(&self, dest: &dyn Backend) -> Result<BackupStats, Error> {
+        let stats = backup::backup(self.backend.as_ref(), &self.committed_segments, dest)?;
+        info!("Backed up database, copied {:?} files of {:?} bytes and skipped {:?}",
+            stats.files_copied, stats.bytes_copied, stats.files_skipped);
+        Ok(stats)
+    }
+
+    /**
+     * Copy the database to another backend with `backup_to`, and open the copy.
+     */
+    pub fn clone_to(&self, dest: Box<dyn Backend>) -> Result<Database, Error> {
+        self.backup_to(dest.as_ref())?;
+        Database::open_with_backend(dest)
+    }
+
+    /**
+     * Export the committed rows to another backend as CSV, in one file for each partition of
+     * the first dimension's values, from 0 in steps of `partition_size`, with a manifest
+     * listing them.  For a time dimension with a resolution of seconds, a partition size of
+     * 86400 gives a file for each day since the epoch.  Partitions without any rows are left
+     * out.
+     *
+     * If the export is interrupted, calling this again with the same destination finishes it,
+     * skipping the partitions the manifest lists as done, and exporting the data committed
+     * when it started; compacting in between may change what the rest of the export holds.
+     * Fails with `DataError` if the destination holds an export with a different partition
+     * size, or from another database.
+     */
+    pub fn export_partitions(&self, dest: &dyn Backend, partition_size: Datum) -> Result<ExportManifest, Error> {
+        export::export(self, dest, partition_size)
+    }
+
+    /**
+     * Write the schema and all committed rows to a dump stream, returning the number of rows
+     * written.  See the `dump` module for the layout.
+     */
+    pub fn dump<W: Write>(&mut self, dest: &mut W) -> Result<u64, Error> {
+        let txn = self.new_transaction()?;
+        let total_rows = write_dump(&txn, dest)?;
+        txn.rollback();
+        Ok(total_rows)
+    }
+
+    /**
+     * Create a new database at `path` from a dump stream produced by `dump`.
+     */
+    #[cfg(feature = "fs")]
+    pub fn restore<R: Read>(src: &mut R, path: &Path) -> Result<Database, Error> {
+        let (schema, labels) = read_dump_schema(src)?;
+        Database::create(schema, path)?.restore_rows(labels, src)
+    }
+
+    /**
+     * Create a new database in the given backend from a dump stream produced by `dump`.
+     */
+    pub fn restore_with_backend<R: Read>(src: &mut R, backend: Box<dyn Backend>) -> Result<Database, Error> {
+        let (schema, labels) = read_dump_schema(src)?;
+        Database::create_with_backend(schema, backend)?.restore_rows(labels, src)
+    }
+
+    fn restore_rows<R: Read>(mut self, mut labels: Labels, src: &mut R) -> Result<Database, Error> {
+        labels.set_dirty();
+        self.labels = labels;
+        let mut txn = self.new_transaction()?;
+        read_dump_rows(src, &mut txn)?;
+        txn.commit()?;
+        Ok(self)
+    }
+
+    pub fn new_transaction(&mut self) -> Result<Transaction<'_>, Error> {
         let horizon = self.next_transaction_id;
         info!("Created transaction with horizon < {:?}", horizon);
         Ok(Transaction::new(self, horizon))
     }
 
-    pub(crate) fn get_next_transaction_id(&mut self) -> TransactionId {
-        let txn_id = self.next_transaction_id;
-        self.next_transaction_id += 1;
-        info!("Allocated transaction id {:?}", txn_id);
-        txn_id
+    /**
+     * Take a read-only snapshot of the committed data, for readers that don't need a
+     * transaction.  See `Snapshot`.
+     */
+    pub fn query_snapshot(&self) -> Snapshot<'_> {
+        info!("Created snapshot with horizon < {:?}", self.next_transaction_id);
+        Snapshot::new(self)
     }
 
-    pub(crate) fn add_committed_segment(&mut self, seg_id: SegmentId) {
-        self.committed_segments.insert(seg_id);
+    /**
+     * Query the rows matching the criteria that were written by transactions committed between
+     * two horizons, from `horizon_a` up to but not including `horizon_b`, such as those of two
+     * snapshots taken some time apart.  A downstream system can be kept in sync by applying the
+     * diff from the horizon it last saw to the current one.  Where a point was written more than
+     * once in that time, only its latest value is returned; the `txn_id` of each row says which
+     * transaction wrote it.  Compaction keeps the transaction ids of the rows it rewrites, but
+     * a row that was written again after `horizon_b` with exactly the same block can be moved to
+     * that later transaction.  Merging segments or vacuuming before a horizon can change diffs
+     * from before it, so a reader keeping a downstream system in sync should be counted among
+     * those passed to `merge_segments` and `vacuum`.
+     *
+     * Fails with `OutOfBounds` if the horizons are out of order or `horizon_b` is in the future,
+     * and otherwise as `Snapshot::query_with` does.
+     */
+    pub fn diff(&self, horizon_a: TransactionId, horizon_b: TransactionId, criteria: &Criteria) -> Result<Scan<'_>, Error> {
+        if horizon_a > horizon_b || horizon_b > self.next_transaction_id {
+            error!("Can't diff from horizon {:?} to {:?}, with next transaction {:?}",
+                horizon_a, horizon_b, self.next_transaction_id);
+            return Err(OutOfBounds);
+        }
+        let num_dims = self.schema.dimensions.len();
+        if criteria.num_dims() > num_dims {
+            return Err(SchemaError);
+        }
+
+        let mut scan = Scan::new(self.open_scan_source()?, num_dims, 0);
+        scan.set_criteria(criteria.clone());
+        for seg_id in self.get_visible_committed_segments(horizon_b) {
+            if seg_id.0 >= horizon_a {
+                debug!("Add committed segment {:?} to diff", seg_id);
+                scan.add_segment_id(seg_id);
+            }
+        }
+        info!("Created diff from horizon {:?} to {:?}", horizon_a, horizon_b);
+        Ok(scan)
     }
 
-    pub(crate) fn get_visible_committed_segments(&self, horizon: TransactionId) -> Vec<SegmentId> {
-        let mut segments = Vec::new();
-        segments.extend(self.committed_segments.iter().filter(|&seg| seg.0 < horizon));
-        segments
+    /**
+     * Shut down the database, reporting any error, which dropping it can't.  The blocks in the
+     * main part of the block cache are recorded so that `preload_hot_blocks` can load them
+     * again after the next `open`, unless the database is read-only (see `health`), and the
+     * backend is synced so that every committed change is durable.
+     */
+    pub fn close(self) -> Result<(), Error> {
+        self.save_hot_blocks()?;
+        self.backend.sync()?;
+        info!("Closed database in {:?}", self.path);
+        Ok(())
     }
 
-    pub(crate) fn get_scan_source<'db>(&'db self) -> Box<dyn ScanSource + 'db> {
-        Box::new(
-            DatabaseScanSource {
-                database: self,
-            }
-        )
+    /**
+     * Bring the database's files up to date and keep them unchanged until the returned guard
+     * is dropped, so that the directory can be copied, or snapshotted with LVM or ZFS, and
+     * opened again from the copy as it stands.  The hot blocks are saved, as on `close`, and
+     * the directory is synced.  No transaction can be open, and none can be started while the
+     * guard is held.
+     */
+    pub fn freeze(&mut self) -> Result<Frozen<'_>, Error> {
+        self.save_hot_blocks()?;
+        self.backend.sync()?;
+        Ok(Frozen::new(self))
     }
-}
 
-fn scan_files(database_path: &Path) -> Result<ScanResult, Error> {
-    let mut max_seen_txn_id = 0;
-    let mut known_segments = HashSet::new();
-    for entry in std::fs::read_dir(database_path)? {
-        let entry = entry.unwrap();
-        if let Some((txn_id, seg_num, committed)) = decode_segment_path(&entry.path()) {
-            let seg_id = (txn_id, seg_num);
-            if txn_id > max_seen_txn_id {
-                max_seen_txn_id = txn_id;
+    fn save_hot_blocks(&self) -> Result<(), Error> {
+        if self.health != Health::Healthy {
+            return Ok(());
+        }
+        let hot_blocks = self.cached_blocks.borrow().hot_keys();
+        let temp_filename = format!("{HOT_BLOCKS_FILENAME}.tmp");
+        if let Err(err) = self.backend.write(&temp_filename, serde_json::to_string(&hot_blocks)?.as_bytes())
+            .and_then(|_| self.backend.rename(&temp_filename, HOT_BLOCKS_FILENAME)) {
+            error!("Failed to save hot blocks: {:?}", err);
+            if self.backend.exists(&temp_filename) {
+                let _ = self.backend.remove(&temp_filename);
             }
+            return Err(err);
+        }
+        debug!("Saved {:?} hot blocks", hot_blocks.len());
+        Ok(())
+    }
 
-            if !committed {
-                info!("Deleting uncommitted segment {:?}", seg_id);
-                std::fs::remove_file(&entry.path())?;
+    /**
+     * Load the blocks that were hot when the database was last closed, so a restarted service
+     * doesn't start with a cold cache.  Blocks that no longer exist are skipped.  Returns the
+     * number of blocks loaded.
+     */
+    pub fn preload_hot_blocks(&self) -> Result<usize, Error> {
+        if !self.backend.exists(HOT_BLOCKS_FILENAME) {
+            return Ok(0);
+        }
+        let json = self.backend.read(HOT_BLOCKS_FILENAME)?;
+        let hot_blocks: Vec<BlockId> = serde_json::from_slice(&json)?;
+
+        let source = self.get_scan_source();
+        let mut num_loaded = 0;
+        for block_id in hot_blocks.into_iter().take(BLOCK_CACHE_SIZE) {
+            if !self.committed_segments.contains(&(block_id.0, block_id.1)) {
+                debug!("Skipping hot block {:?} from a segment that no longer exists", block_id);
                 continue;
             }
+            if source.get_block(block_id).is_ok() {
+                /* Count this as a second use, so the block skips probation */
+                self.cached_blocks.borrow_mut().get(&block_id);
+                num_loaded += 1;
+            }
+        }
 
-            known_segments.insert(seg_id);
-        };
+        info!("Preloaded {:?} hot blocks", num_loaded);
+        Ok(num_loaded)
     }
 
-    //TODO any transaction with no segment 0 didn't commit fully, so ignore those segments
+    /**
+     * Create a transaction seeing the same committed data as the one a cursor came from, for
+     * fetching the next page of a query.
+     */
+    pub fn resume_transaction(&mut self, cursor: &Cursor) -> Result<Transaction<'_>, Error> {
+        let horizon = cursor.horizon();
+        if horizon > self.next_transaction_id {
+            error!("Cursor horizon {:?} is from the future", horizon);
+            return Err(DataError);
+        }
+        info!("Resumed transaction with horizon < {:?}", horizon);
+        Ok(Transaction::new(self, horizon))
+    }
 
-    Ok(ScanResult {
-        next_transaction_id: max_seen_txn_id + 1,
-        committed_segments: known_segments
-    })
-}
+    /**
+     * Rewrite any committed segments that were saved in an older format version, so they are
+     * stored in the current one, and remove blocks deleted by tombstones (see
+     * `remove_deleted_blocks`).  Returns the number of segments rewritten or removed.  If the
+     * database compresses with a dictionary, a new one is trained first, so it keeps up with
+     * the data.  Finally, the statistics returned by `data_stats` are gathered again, which
+     * reads all of the data.  Segments are not merged, nor duplicate blocks removed, since
+     * those depend on the horizon of the oldest reader; see `merge_segments` and
+     * `remove_duplicate_blocks`.
+     */
+    pub fn compact(&mut self) -> Result<usize, Error> {
+        self.check_writable()?;
+        if self.dictionary.is_some() {
+            self.train_dictionary()?;
+        }
+        let mut seg_ids: Vec<SegmentId> = self.committed_segments.iter().copied().collect();
+        seg_ids.sort();
 
-struct DatabaseScanSource<'db> {
-    database: &'db Database
-}
+        let mut num_upgraded = 0;
+        for seg_id in seg_ids {
+            let mut segment = Segment::load(self.backend.as_ref(), seg_id)?;
+            if segment.version >= CURRENT_FORMAT_VERSION {
+                continue;
+            }
 
-impl<'db> ScanSource for DatabaseScanSource<'db> {
-    fn get_segment(&self, seg_id: SegmentId) -> Option<Rc<Segment>> {
-        info!("Request for segment {:?}", seg_id);
+            let old_size = self.backend.size(&segment.name)?;
+            let pooled_size = segment.upgrade(self.backend.as_ref(), &self.dictionaries, self.new_segment(seg_id))?;
+            let new_size = self.backend.size(&segment.name)?;
+            self.used_size = self.used_size - old_size + new_size + pooled_size;
+            self.counters.bytes_written += new_size + pooled_size;
+            self.counters.segments_rewritten += 1;
 
-        /* Try get it from the cache and return it */
-        let mut borrowed = self.database.cached_segments.borrow_mut();
-        if let Some(rc) = borrowed.get(&seg_id) {
-            return Some(rc);
+            /* The block positions have changed, so the cached copy is no longer usable. */
+            self.cached_segments.borrow_mut().evict(&seg_id);
+            self.segment_occupancy.borrow_mut().remove(&seg_id);
+            num_upgraded += 1;
         }
 
-        /* Otherwise, load it from disk, put it into the cache, and return it */
-        let segment = match Segment::load(
-            self.database.path.as_path(),
-            seg_id
-        ) {
-            Ok(segment) => segment,
-            Err(err) => {
-                error!("Error during fetch of segment {seg_id:?}: {err:?}");
-                return None;
-            }
+        info!("Compacted database, upgraded {:?} segments", num_upgraded);
+        if num_upgraded > 0 {
+            journal::record(self.backend.as_ref(), "compact",
+                format!("Rewrote {} segments in format version {}", num_upgraded, CURRENT_FORMAT_VERSION));
+        }
 
-        };
+        let num_deleted = self.remove_deleted_blocks()?;
+        self.remove_unused_pool_objects()?;
+        self.gather_data_stats()?;
+        Ok(num_upgraded + num_deleted)
+    }
 
-        let rc = Rc::new(segment);
-        borrowed.add(seg_id, rc.clone());
+    /**
+     * Merge one run of small committed segments, at most `max_segments` of them, into a single
+     * segment, for workloads that commit many small transactions and are left with many tiny
+     * segments for every query to merge.  Only transactions before `horizon` are merged, which
+     * should be the oldest horizon of any reader still in use, as for `vacuum`.  Returns the
+     * number of segments removed, which is 0 once there is nothing left to merge, so it can be
+     * called a run at a time between other work.
+     *
+     * A segment is small if its file is under half the maximum segment size, and a run is of
+     * consecutive whole transactions before the horizon whose segments are all small, holding
+     * at least two segments and at most the maximum segment size between them.  Segments with
+     * tombstones end a run.  The run's blocks are merged oldest first, so only the newest
+     * version of each point survives, with any values a partial version is missing taken from
+     * the versions before it.  The result is saved over the first segment of the run's last
+     * transaction; its other segments are then removed, oldest first, so if merging is
+     * interrupted, the ones left only hold rows that the merged segment or a later one
+     * replaces.
+     *
+     * The merged rows take the id of the run's last transaction, which is before the horizon,
+     * so queries at or after it return the same rows as before.  As with `vacuum`, the older
+     * versions are gone for good, so scans with a merge strategy other than `NewestWins`, and
+     * diffs from before the horizon, may return less than they did.
+     *
+     * Fails with `OutOfBounds` if the horizon is in the future.
+     */
+    pub fn merge_segments(&mut self, horizon: TransactionId, max_segments: usize) -> Result<usize, Error> {
+        self.check_writable()?;
+        if horizon > self.next_transaction_id {
+            error!("Can't merge segments up to horizon {:?}, with next transaction {:?}", horizon, self.next_transaction_id);
+            return Err(OutOfBounds);
+        }
+        let run = self.find_merge_run(horizon, max_segments)?;
+        if run.is_empty() {
+            return Ok(0);
+        }
+        let first_id = run[0].id;
+        let target_id = (run[run.len() - 1].id.0, 0);
 
-        Some(rc)
-    }
+        let num_dims = self.schema.dimensions.len();
+        let mut chunks: HashMap<Vec<Datum>, Block> = HashMap::new();
+        /* Oldest first, so each version replaces the one before, but for any values it is missing */
+        for segment in &run {
+            for block in segment.load_all_blocks(self.backend.as_ref(), &self.dictionaries)? {
+                for (row, missing) in Block::iter(&Rc::new(block)).partial() {
+                    chunks.entry(self.schema.get_chunk_origin(&row))
+                        .or_insert_with(|| Block::new(num_dims))
+                        .add_partial_row(&row, &missing);
+                }
+            }
+        }
+        let mut blocks: Vec<Rc<Block>> = chunks.into_values().map(Rc::new).collect();
+        if let Some(max_size) = self.max_block_size {
+            blocks = blocks.iter().flat_map(|rc| Block::split(rc, max_size)).collect();
+        }
+        blocks.sort_by_cached_key(|block| self.schema.block_order_key(block));
+        let block_refs: Vec<&Block> = blocks.iter().map(|rc| rc.as_ref()).collect();
 
-    fn get_block(&self, block_id: BlockId) -> Option<Rc<Block>> {
-        info!("Request for block {:?}", block_id);
+        let mut target = Segment::load(self.backend.as_ref(), target_id)?;
+        let num_target_blocks = target.block_info.len();
+        let old_size = self.backend.size(&target.name)?;
+        let mut new_segment = self.new_segment(target_id);
+        new_segment.ordered = true;
+        let pooled_size = target.rewrite(self.backend.as_ref(), &block_refs, new_segment)?;
+        let new_size = self.backend.size(&target.name)?;
+        self.used_size = self.used_size - old_size + new_size + pooled_size;
+        self.counters.bytes_written += new_size + pooled_size;
+        self.counters.segments_rewritten += 1;
+        self.evict_segment(target_id, num_target_blocks);
 
-        /* Try get it from the cache and return it */
-        let mut borrowed = self.database.cached_blocks.borrow_mut();
-        if let Some(rc) = borrowed.get(&block_id) {
-            return Some(rc);
+        for segment in run.iter().filter(|segment| segment.id != target_id) {
+            self.remove_empty_segment(segment)?;
+            self.evict_segment(segment.id, segment.block_info.len());
         }
 
-        /* Otherwise, load it from disk, put it into the cache, and return it */
-        let seg_id = (block_id.0, block_id.1);
-        let block_num = block_id.2;
+        let num_removed = run.len() - 1;
+        info!("Merged {:?} segments from {:?} to {:?} into {:?}", run.len(), first_id, target_id, target_id);
+        journal::record(self.backend.as_ref(), "merge",
+            format!("Merged {} segments from {:?} into {:?}", run.len(), first_id, target_id));
+        Ok(num_removed)
+    }
 
-        /* Get the segment first (which will be loaded if not already cached) */
-        let segment = self.get_segment(seg_id)?;
+    /**
+     * Find the first run of segments for `merge_segments` to merge, in order, or none.
+     */
+    fn find_merge_run(&self, horizon: TransactionId, max_segments: usize) -> Result<Vec<Segment>, Error> {
+        let max_size = self.max_segment_size.unwrap_or(DEFAULT_MAX_SEGMENT_SIZE);
+        let mut seg_ids: Vec<SegmentId> = self.committed_segments.iter().filter(|seg_id| seg_id.0 < horizon).copied().collect();
+        seg_ids.sort();
 
-        /* Get the block from the segment */
-        let block = match segment.load_one_block(block_num) {
-            Ok(block) => block,
-            Err(err) => {
-                error!("Error during fetch of block {block_id:?}: {err:?}");
-                return None;
+        /* Each transaction's segments, and whether they can all be merged, with their size */
+        let mut transactions: Vec<(Vec<Segment>, bool, u64)> = Vec::new();
+        for seg_id in seg_ids {
+            let segment = Segment::load(self.backend.as_ref(), seg_id)?;
+            let size = self.backend.size(&segment.name)?;
+            let mergeable = size < max_size / 2 && segment.tombstones.is_empty();
+            match transactions.last_mut() {
+                Some((segments, all_mergeable, total_size)) if segments[0].id.0 == seg_id.0 => {
+                    segments.push(segment);
+                    *all_mergeable &= mergeable;
+                    *total_size += size;
+                },
+                _ => transactions.push((vec![segment], mergeable, size))
             }
-        };
+        }
 
-        let rc = Rc::new(block);
-        borrowed.add(block_id, rc.clone());
+        let mut run: Vec<Segment> = Vec::new();
+        let mut run_size = 0;
+        for (segments, mergeable, size) in transactions {
+            let fits = mergeable && run.len() + segments.len() <= max_segments && run_size + size <= max_size;
+            if !fits && run.len() >= 2 {
+                break;
+            }
+            if !fits {
+                run.clear();
+                run_size = 0;
+                if !mergeable || segments.len() > max_segments || size > max_size {
+                    continue;
+                }
+            }
+            run.extend(segments);
+            run_size += size;
+        }
+        if run.len() < 2 {
+            run.clear();
+        }
+        Ok(run)
+    }
+
+    /**
+     * Remove pool objects that no committed segment refers to any more, such as those of
+     * rewritten segments or of transactions that were rolled back, along with any left
+     * partly written.  Returns the number of files removed.
+     */
+    fn remove_unused_pool_objects(&mut self) -> Result<usize, Error> {
+        let names = pool::list_files(self.backend.as_ref())?;
+        if names.is_empty() {
+            return Ok(0);
+        }
+
+        let mut referenced = HashSet::new();
+        for &seg_id in &self.committed_segments {
+            let segment = Segment::load(self.backend.as_ref(), seg_id)?;
+            referenced.extend(segment.pooled_hashes(self.backend.as_ref())?);
+        }
+
+        let mut num_removed = 0;
+        for name in names {
+            if decode_pool_name(&name).is_some_and(|hash| referenced.contains(&hash)) {
+                continue;
+            }
+            if pool::is_object(&name) {
+                self.used_size -= self.backend.size(&name)?;
+            }
+            self.backend.remove(&name)?;
+            num_removed += 1;
+        }
+
+        if num_removed > 0 {
+            info!("Removed {:?} unused pool objects", num_removed);
+            journal::record(self.backend.as_ref(), "collect-pool",
+                format!("Removed {} unused pool objects", num_removed));
+        }
+        Ok(num_removed)
+    }
+
+    /**
+     * Remove blocks whose rows have all been deleted by a tombstone in a later segment; see
+     * `Transaction::delete_where`.  Which blocks those are is known from the segment info, so
+     * they are never loaded, and the other blocks of their segments are copied as they are.
+     * Segments left without any blocks are removed, as in `remove_duplicate_blocks`.  Returns
+     * the number of segments rewritten or removed.
+     */
+    fn remove_deleted_blocks(&mut self) -> Result<usize, Error> {
+        let mut seg_ids: Vec<SegmentId> = self.committed_segments.iter().copied().collect();
+        seg_ids.sort();
+        let mut segments = Vec::new();
+        let mut tombstones = Tombstones::default();
+        for seg_id in seg_ids {
+            let segment = Segment::load(self.backend.as_ref(), seg_id)?;
+            tombstones.add_segment(&segment);
+            segments.push(segment);
+        }
+
+        let mut num_blocks = 0;
+        let mut num_segments = 0;
+        for mut segment in segments {
+            let removed: HashSet<BlockNum> = segment.block_info.iter().enumerate()
+                .filter(|(_, info)| tombstones.covers(&info.min_bounds, &info.max_bounds, segment.id))
+                .map(|(block_num, _)| block_num as BlockNum)
+                .collect();
+            if removed.is_empty() {
+                continue;
+            }
+
+            let num_segment_blocks = segment.block_info.len();
+            if removed.len() == num_segment_blocks && segment.id.1 != 0 && segment.tombstones.is_empty() {
+                self.remove_empty_segment(&segment)?;
+            } else {
+                let old_size = self.backend.size(&segment.name)?;
+                segment.remove_blocks(self.backend.as_ref(), &removed)?;
+                let new_size = self.backend.size(&segment.name)?;
+                self.used_size = self.used_size - old_size + new_size;
+                self.counters.bytes_written += new_size;
+                self.counters.segments_rewritten += 1;
+            }
+            self.evict_segment(segment.id, num_segment_blocks);
+            num_blocks += removed.len();
+            num_segments += 1;
+        }
+
+        if num_blocks > 0 {
+            info!("Removed {:?} deleted blocks from {:?} segments", num_blocks, num_segments);
+            journal::record(self.backend.as_ref(), "remove-deleted",
+                format!("Removed {} deleted blocks from {} segments", num_blocks, num_segments));
+        }
+        Ok(num_segments)
+    }
+
+    /**
+     * Delete a committed segment that compaction has left without any blocks.
+     */
+    fn remove_empty_segment(&mut self, segment: &Segment) -> Result<(), Error> {
+        let old_size = self.backend.size(&segment.name)?;
+        segment.delete(self.backend.as_ref())?;
+        self.committed_segments.remove(&segment.id);
+        self.late_segments.remove(&segment.id);
+        self.used_size -= old_size;
+        self.counters.segments_deleted += 1;
+        Ok(())
+    }
+
+    /**
+     * Forget any cached copy of a segment and its blocks, after its blocks have been
+     * renumbered.
+     */
+    fn evict_segment(&self, seg_id: SegmentId, num_blocks: usize) {
+        self.cached_segments.borrow_mut().evict(&seg_id);
+        self.segment_occupancy.borrow_mut().remove(&seg_id);
+        for block_num in 0..num_blocks {
+            self.cached_blocks.borrow_mut().evict(&(seg_id.0, seg_id.1, block_num as BlockNum));
+        }
+    }
+
+    /**
+     * Find blocks with exactly the same content as a block in a later segment, as left by
+     * importing the same data more than once, and rewrite their segments without them.  Only
+     * transactions before `horizon` are checked, which should be the oldest horizon of any
+     * reader still in use, as for `vacuum`: for those readers the later copy replaces all of
+     * the earlier one's rows anyway, so this doesn't change what they see.  Blocks are
+     * compared by the content hash in the segment info, and only those whose hashes match are
+     * loaded, to check that they really are the same; blocks of segments saved before format
+     * version 7, which don't record hashes, are loaded to hash them.  Segments left without any
+     * blocks are removed, except for the first segment of a transaction, which marks the
+     * transaction as committed, and segments with tombstones, which are kept empty.  Returns
+     * the number of segments rewritten or removed.
+     *
+     * Fails with `OutOfBounds` if the horizon is in the future.
+     */
+    pub fn remove_duplicate_blocks(&mut self, horizon: TransactionId) -> Result<usize, Error> {
+        self.check_writable()?;
+        if horizon > self.next_transaction_id {
+            error!("Can't remove duplicate blocks up to horizon {:?}, with next transaction {:?}", horizon, self.next_transaction_id);
+            return Err(OutOfBounds);
+        }
+        let mut seg_ids: Vec<SegmentId> = self.committed_segments.iter().filter(|seg_id| seg_id.0 < horizon).copied().collect();
+        seg_ids.sort();
+
+        /* Find the latest copy of each distinct block, by chunk and content hash */
+        let mut latest: HashMap<(Vec<Datum>, PoolHash), BlockId> = HashMap::new();
+        let mut duplicates: HashMap<SegmentId, HashSet<BlockNum>> = HashMap::new();
+        for &seg_id in &seg_ids {
+            let segment = Segment::load(self.backend.as_ref(), seg_id)?;
+            for (block_num, info) in segment.block_info.iter().enumerate() {
+                let block_id = (seg_id.0, seg_id.1, block_num as BlockNum);
+                let hash = match info.hash {
+                    Some(hash) => hash,
+                    None => pool::hash_payload(&block_payload(&self.load_block(block_id)?)?)
+                };
+                let key = (self.schema.get_chunk_origin(&info.min_bounds), hash);
+                if let Some(earlier) = latest.insert(key, block_id) {
+                    /* Hashes can collide, so check the content really is the same */
+                    if block_payload(&self.load_block(earlier)?)? == block_payload(&self.load_block(block_id)?)? {
+                        duplicates.entry((earlier.0, earlier.1)).or_default().insert(earlier.2);
+                    }
+                }
+            }
+        }
+
+        let mut num_blocks = 0;
+        let mut num_segments = 0;
+        for seg_id in seg_ids {
+            let Some(removed) = duplicates.get(&seg_id) else { continue };
+            let mut segment = Segment::load(self.backend.as_ref(), seg_id)?;
+            let blocks = segment.load_all_blocks(self.backend.as_ref(), &self.dictionaries)?;
+            let kept: Vec<&Block> = blocks.iter().enumerate()
+                .filter(|(block_num, _)| !removed.contains(&(*block_num as BlockNum)))
+                .map(|(_, block)| block)
+                .collect();
+
+            if kept.is_empty() && seg_id.1 != 0 && segment.tombstones.is_empty() {
+                self.remove_empty_segment(&segment)?;
+            } else {
+                let old_size = self.backend.size(&segment.name)?;
+                /* The kept blocks are still in order, if they were before */
+                let mut new_segment = self.new_segment(seg_id);
+                new_segment.ordered = segment.ordered;
+                let pooled_size = segment.rewrite(self.backend.as_ref(), &kept, new_segment)?;
+                let new_size = self.backend.size(&segment.name)?;
+                self.used_size = self.used_size - old_size + new_size + pooled_size;
+                self.counters.bytes_written += new_size + pooled_size;
+                self.counters.segments_rewritten += 1;
+            }
+
+            /* The blocks have been renumbered, so no cached copy is usable. */
+            self.evict_segment(seg_id, blocks.len());
+            num_blocks += removed.len();
+            num_segments += 1;
+        }
+
+        if num_blocks > 0 {
+            self.remove_unused_pool_objects()?;
+            info!("Removed {:?} duplicate blocks from {:?} segments", num_blocks, num_segments);
+            journal::record(self.backend.as_ref(), "deduplicate",
+                format!("Removed {} duplicate blocks from {} segments", num_blocks, num_segments));
+        }
+        Ok(num_segments)
+    }
+
+    /**
+     * Remove the row versions no reader can see any more: those replaced by a newer version,
+     * or deleted by a tombstone, in a transaction before `horizon`, which should be the oldest
+     * horizon of any reader still in use, such as a saved cursor's.  A partial version written
+     * by `Transaction::update` replaces nothing, since it takes its missing values from the
+     * version before it.  Queries at or after the horizon return the same rows as before, with
+     * the same transaction ids.  Only the blocks of chunks written by more than one segment, or
+     * overlapping a later tombstone, are checked.  Segments holding such versions are rewritten
+     * without them, and those left without any blocks are removed, as in
+     * `remove_duplicate_blocks`.  Returns the number of row versions removed.
+     *
+     * The older versions are gone for good, so scans with a merge strategy other than
+     * `NewestWins`, and diffs ending before the horizon, may return less than they did.
+     *
+     * Fails with `OutOfBounds` if the horizon is in the future.
+     */
+    pub fn vacuum(&mut self, horizon: TransactionId) -> Result<usize, Error> {
+        self.check_writable()?;
+        if horizon > self.next_transaction_id {
+            error!("Can't vacuum up to horizon {:?}, with next transaction {:?}", horizon, self.next_transaction_id);
+            return Err(OutOfBounds);
+        }
+        let mut seg_ids: Vec<SegmentId> = self.committed_segments.iter().filter(|seg_id| seg_id.0 < horizon).copied().collect();
+        seg_ids.sort();
+
+        /* The segments with blocks in each chunk, newest last */
+        let mut segments = Vec::new();
+        let mut tombstones = Tombstones::default();
+        let mut chunk_segments: HashMap<Vec<Datum>, Vec<SegmentId>> = HashMap::new();
+        for seg_id in seg_ids {
+            let segment = Segment::load(self.backend.as_ref(), seg_id)?;
+            tombstones.add_segment(&segment);
+            for info in &segment.block_info {
+                let seg_ids = chunk_segments.entry(self.schema.get_chunk_origin(&info.min_bounds)).or_default();
+                if seg_ids.last() != Some(&seg_id) {
+                    seg_ids.push(seg_id);
+                }
+            }
+            segments.push(segment);
+        }
+
+        /* Newest first, so each block is checked against the points of later segments */
+        let num_dims = self.schema.dimensions.len();
+        let mut newer_points: HashMap<Vec<Datum>, HashSet<Vec<Datum>>> = HashMap::new();
+        let mut num_rows = 0;
+        let mut num_segments = 0;
+        for mut segment in segments.into_iter().rev() {
+            let chunks: Vec<Vec<Datum>> = segment.block_info.iter().map(|info| self.schema.get_chunk_origin(&info.min_bounds)).collect();
+            let shared: Vec<bool> = chunks.iter().map(|chunk| chunk_segments[chunk].len() > 1).collect();
+            let checked: Vec<bool> = segment.block_info.iter().zip(&chunks)
+                .map(|(info, chunk)| chunk_segments[chunk].last() != Some(&segment.id)
+                    || tombstones.overlaps(&info.min_bounds, &info.max_bounds, segment.id))
+                .collect();
+            if !shared.iter().chain(&checked).any(|&flag| flag) {
+                continue;
+            }
+
+            let blocks = segment.load_all_blocks(self.backend.as_ref(), &self.dictionaries)?;
+            let num_segment_blocks = blocks.len();
+            let mut kept = Vec::new();
+            let mut segment_points = Vec::new();
+            let mut num_segment_rows = 0;
+            for (block_num, block) in blocks.into_iter().enumerate() {
+                let rc = Rc::new(block);
+                if shared[block_num] {
+                    /* A partial version takes its missing values from the one before, so it replaces nothing */
+                    segment_points.extend(Block::iter(&rc).partial()
+                        .filter(|(_, missing)| missing.is_empty())
+                        .map(|(row, _)| (block_num, row[..num_dims].to_vec())));
+                }
+                if !checked[block_num] {
+                    kept.push(rc);
+                    continue;
+                }
+
+                let newer = newer_points.get(&chunks[block_num]);
+                let mut new_block = Block::new(num_dims);
+                let mut num_block_rows = 0;
+                for (row, missing) in Block::iter(&rc).partial() {
+                    if newer.is_some_and(|points| points.contains(&row[..num_dims])) || tombstones.deletes(&row, segment.id) {
+                        num_block_rows += 1;
+                    } else {
+                        new_block.add_partial_row(&row, &missing);
+                    }
+                }
+                if num_block_rows == 0 {
+                    kept.push(rc);
+                } else if new_block.num_rows() > 0 {
+                    kept.push(Rc::new(new_block));
+                }
+                num_segment_rows += num_block_rows;
+            }
+            for (block_num, point) in segment_points {
+                newer_points.entry(chunks[block_num].clone()).or_default().insert(point);
+            }
+            if num_segment_rows == 0 {
+                continue;
+            }
+
+            if kept.is_empty() && segment.id.1 != 0 && segment.tombstones.is_empty() {
+                self.remove_empty_segment(&segment)?;
+            } else {
+                let old_size = self.backend.size(&segment.name)?;
+                /* Removing rows doesn't reorder the blocks */
+                let mut new_segment = self.new_segment(segment.id);
+                new_segment.ordered = segment.ordered;
+                let block_refs: Vec<&Block> = kept.iter().map(|rc| rc.as_ref()).collect();
+                let pooled_size = segment.rewrite(self.backend.as_ref(), &block_refs, new_segment)?;
+                let new_size = self.backend.size(&segment.name)?;
+                self.used_size = self.used_size - old_size + new_size + pooled_size;
+                self.counters.bytes_written += new_size + pooled_size;
+                self.counters.segments_rewritten += 1;
+            }
+            self.evict_segment(segment.id, num_segment_blocks);
+            num_rows += num_segment_rows;
+            num_segments += 1;
+        }
+
+        if num_rows > 0 {
+            self.remove_unused_pool_objects()?;
+            info!("Vacuumed {:?} row versions from {:?} segments before horizon {:?}", num_rows, num_segments, horizon);
+            journal::record(self.backend.as_ref(), "vacuum",
+                format!("Removed {} row versions from {} segments before transaction {}", num_rows, num_segments, horizon));
+        }
+        Ok(num_rows)
+    }
+
+    /**
+     * Load a committed block directly, without using the cache.
+     */
+    fn load_block(&self, block_id: BlockId) -> Result<Block, Error> {
+        let segment = Segment::load(self.backend.as_ref(), (block_id.0, block_id.1))?;
+        segment.load_one_block(self.backend.as_ref(), &self.dictionaries, block_id.2)
+    }
+
+    /**
+     * Train a zstd dictionary on a sample of the most recently committed blocks, and compress
+     * blocks with it from now on.  Small blocks have little data of their own to find patterns
+     * in, so they compress far better with a dictionary.  The dictionary is saved in the
+     * database, and used again when it is reopened; each block records the dictionary it was
+     * compressed with, so older dictionaries are kept for reading.  Only applies when the
+     * compression is zstd.  Returns the id of the new dictionary.
+     */
+    pub fn train_dictionary(&mut self) -> Result<DictionaryId, Error> {
+        self.check_writable()?;
+        let mut seg_ids: Vec<SegmentId> = self.committed_segments.iter().copied().collect();
+        seg_ids.sort();
+
+        let mut samples = Vec::new();
+        for seg_id in seg_ids.into_iter().rev() {
+            if samples.len() >= MAX_DICTIONARY_SAMPLES {
+                break;
+            }
+            let segment = Segment::load(self.backend.as_ref(), seg_id)?;
+            for block in segment.load_all_blocks(self.backend.as_ref(), &self.dictionaries)? {
+                let mut payload = Vec::new();
+                block.save(&mut payload, CURRENT_FORMAT_VERSION)?;
+                samples.push(payload);
+            }
+        }
+
+        let id = Dictionary::latest_id(self.backend.as_ref())?.map_or(1, |id| id + 1);
+        let dictionary = Rc::new(Dictionary::train(self.backend.as_ref(), id, &samples)?);
+        self.dictionaries.add(dictionary.clone());
+        self.dictionary = Some(dictionary);
+
+        info!("Trained dictionary {:?} from {:?} blocks", id, samples.len());
+        journal::record(self.backend.as_ref(), "train-dictionary",
+            format!("Trained dictionary {} from {} blocks", id, samples.len()));
+        Ok(id)
+    }
+
+    /**
+     * Adopt a segment file built elsewhere, such as by a `SegmentEncoder` on an edge device, as
+     * the only segment of a new transaction.  Every block is read back and checked against the
+     * schema before the segment is made visible, so a bad file is rejected without changing
+     * what the database contains.  Returns the id of the new segment.
+     */
+    pub fn ingest_segment(&mut self, data: &[u8]) -> Result<SegmentId, Error> {
+        self.check_writable()?;
+        self.check_quota(0, data.len() as u64)?;
+        let seg_id = (self.get_next_transaction_id(), 0);
+        let temp_name = get_segment_name(seg_id, false);
+        if let Err(err) = self.backend.write(&temp_name, data) {
+            self.remove_partial_file(&temp_name);
+            return Err(err);
+        }
+
+        let mut segment = match self.check_segment(seg_id) {
+            Ok(segment) => segment,
+            Err(err) => {
+                error!("Rejected ingested segment {seg_id:?}: {err:?}");
+                self.remove_partial_file(&temp_name);
+                return Err(err);
+            }
+        };
+        segment.make_visible(self.backend.as_ref())?;
+        self.add_committed_segment(seg_id, data.len() as u64);
+
+        let num_rows: usize = segment.block_info.iter().filter_map(|info| info.counts).map(|(num_rows, _)| num_rows).sum();
+        let row_size = (self.schema.dimensions.len() + self.schema.values.len()) * size_of::<Datum>();
+        self.counters.logical_bytes += (num_rows * row_size) as u64;
+        self.counters.bytes_written += data.len() as u64;
+        self.counters.segments_created += 1;
+
+        info!("Ingested segment {:?} with {:?} blocks", seg_id, segment.block_info.len());
+        journal::record(self.backend.as_ref(), "ingest",
+            format!("Adopted segment {:?} with {} blocks", seg_id, segment.block_info.len()));
+        Ok(seg_id)
+    }
+
+    /**
+     * Adopt a segment file from the filesystem; see `ingest_segment`.
+     */
+    #[cfg(feature = "fs")]
+    pub fn ingest_segment_file(&mut self, path: &Path) -> Result<SegmentId, Error> {
+        let data = std::fs::read(path)?;
+        self.ingest_segment(&data)
+    }
+
+    /**
+     * Begin an import of a large dataset by several loaders writing segments in parallel, each
+     * under one of the returned leases.  The import has a transaction of its own, and its
+     * leases are saved in a manifest in the database, so that opening the database meanwhile
+     * keeps the segments the loaders have written.
+     *
+     * Fails with `DataError` if the leases would need more segments than a transaction can
+     * have.
+     */
+    pub fn begin_import(&mut self, num_loaders: usize, segments_per_loader: SegmentNum) -> Result<Vec<ImportLease>, Error> {
+        self.check_writable()?;
+        let txn_id = self.get_next_transaction_id();
+        let leases = import::begin(self.backend.as_ref(), txn_id, num_loaders, segments_per_loader)?;
+        info!("Began import {:?} for {:?} loaders", txn_id, num_loaders);
+        Ok(leases)
+    }
+
+    /**
+     * Commit the segments written under the leases of an import, as one transaction.  Every
+     * segment is checked as by `ingest_segment` first, and if any is rejected, or they would
+     * take the database over its maximum size, nothing is committed and the import can be
+     * retried or aborted.  Segments from later leases replace rows from earlier ones.
+     *
+     * The segments are made visible in the same way a transaction's are, so if the commit is
+     * interrupted, the whole import is discarded when the database is opened again.  Returns
+     * the ids of the committed segments.
+     */
+    pub fn commit_import(&mut self, txn_id: TransactionId) -> Result<Vec<SegmentId>, Error> {
+        self.check_writable()?;
+        let leases = import::load(self.backend.as_ref(), txn_id)?;
+        let written = import::written_segments(self.backend.as_ref(), &leases)?;
+
+        let mut segments = Vec::with_capacity(written.len());
+        let mut total_size = 0;
+        for (seg_id, name) in &written {
+            let size = self.backend.size(name)?;
+            let segment = self.check_segment(*seg_id).inspect_err(|err| {
+                error!("Rejected segment {seg_id:?} of import: {err:?}");
+            })?;
+            segments.push((segment, size));
+            total_size += size;
+        }
+        self.check_quota(0, total_size)?;
+
+        /* The first segment becomes segment 0, which commits the import when it is made visible */
+        let mut committed = Vec::with_capacity(segments.len());
+        for (index, (segment, size)) in segments.iter().enumerate().rev() {
+            let seg_id = if index == 0 { (txn_id, 0) } else { segment.id };
+            if let Err(err) = self.backend.rename(&segment.name, &get_segment_name(seg_id, true)) {
+                error!("Failed to make segment {:?} of import visible: {:?}", segment.name, err);
+                if !committed.is_empty() {
+                    self.set_read_only();
+                }
+                return Err(err);
+            }
+            committed.push((seg_id, *size));
+        }
+        if let Err(err) = import::remove_manifest(self.backend.as_ref(), txn_id) {
+            error!("Failed to remove the manifest of import {:?}: {:?}", txn_id, err);
+        }
+
+        let num_rows: usize = segments.iter()
+            .flat_map(|(segment, _)| segment.block_info.iter().filter_map(|info| info.counts))
+            .map(|(num_rows, _)| num_rows)
+            .sum();
+        let row_size = (self.schema.dimensions.len() + self.schema.values.len()) * size_of::<Datum>();
+        self.counters.logical_bytes += (num_rows * row_size) as u64;
+        self.counters.bytes_written += total_size;
+        self.counters.segments_created += committed.len() as u64;
+        for &(seg_id, size) in &committed {
+            self.add_committed_segment(seg_id, size);
+        }
+
+        let seg_ids: Vec<SegmentId> = committed.into_iter().rev().map(|(seg_id, _)| seg_id).collect();
+        info!("Committed import {:?} with {:?} segments", txn_id, seg_ids.len());
+        journal::record(self.backend.as_ref(), "import",
+            format!("Committed {} segments from {} loaders as transaction {}", seg_ids.len(), leases.len(), txn_id));
+        Ok(seg_ids)
+    }
+
+    /**
+     * Abandon an import, removing the segments its loaders have written.
+     */
+    pub fn abort_import(&mut self, txn_id: TransactionId) -> Result<(), Error> {
+        import::discard(self.backend.as_ref(), txn_id)?;
+        info!("Aborted import {:?}", txn_id);
+        Ok(())
+    }
+
+    /**
+     * Load a segment and all its blocks, checking that each block matches the schema, lies in
+     * a single chunk, and agrees with the bounds recorded for it in the segment info, and that
+     * the blocks are in scan order if the segment info says so.
+     */
+    fn check_segment(&self, seg_id: SegmentId) -> Result<Segment, Error> {
+        let segment = Segment::load(self.backend.as_ref(), seg_id)?;
+        segment.check_dimensions(self.schema.dimensions.len())?;
+        if segment.block_info.is_empty() {
+            error!("Segment {:?} has no blocks", seg_id);
+            return Err(DataError);
+        }
+
+        let blocks = segment.load_all_blocks(self.backend.as_ref(), &self.dictionaries)?;
+        if segment.ordered && !blocks.windows(2).all(|pair| self.schema.block_order_key(&pair[0]) <= self.schema.block_order_key(&pair[1])) {
+            error!("Segment {:?} claims its blocks are in scan order, but they aren't", seg_id);
+            return Err(DataError);
+        }
+        for (info, block) in segment.block_info.iter().zip(&blocks) {
+            if block.dimension_values.len() != self.schema.dimensions.len() {
+                error!("Block has {:?} dimensions, expected {:?}",
+                    block.dimension_values.len(), self.schema.dimensions.len());
+                return Err(SchemaError);
+            }
+
+            info.check_block(block)?;
+            let min_bounds = block.get_min_bounds();
+            let max_bounds = block.get_max_bounds();
+
+            self.schema.check_bounds(&min_bounds)?;
+            self.schema.check_bounds(&max_bounds)?;
+            if self.schema.get_chunk_key(&min_bounds).key_values != self.schema.get_chunk_key(&max_bounds).key_values {
+                error!("Block {:?}..{:?} spans more than one chunk", min_bounds, max_bounds);
+                return Err(DataError);
+            }
+        }
+
+        Ok(segment)
+    }
+
+    pub(crate) fn get_next_transaction_id(&mut self) -> TransactionId {
+        let txn_id = self.next_transaction_id;
+        self.next_transaction_id += 1;
+        info!("Allocated transaction id {:?}", txn_id);
+        txn_id
+    }
+
+    pub(crate) fn add_committed_segment(&mut self, seg_id: SegmentId, size: u64) {
+        self.committed_segments.insert(seg_id);
+        self.used_size += size;
+    }
+
+    pub(crate) fn add_pooled_size(&mut self, size: u64) {
+        self.used_size += size;
+    }
+
+    pub(crate) fn get_visible_committed_segments(&self, horizon: TransactionId) -> Vec<SegmentId> {
+        let mut segments = Vec::new();
+        segments.extend(self.committed_segments.iter().filter(|&seg| seg.0 < horizon));
+        segments
+    }
+
+    pub(crate) fn get_scan_source<'db>(&'db self) -> Box<dyn ScanSource + 'db> {
+        Box::new(
+            DatabaseScanSource {
+                database: self,
+                counted: false
+            }
+        )
+    }
+
+    /**
+     * A source for a scan returned to the application, which counts as open until it is dropped.
+     * Fails with `QuotaExceeded` if the limit on open scans has been reached.
+     */
+    pub(crate) fn open_scan_source<'db>(&'db self) -> Result<Box<dyn ScanSource + 'db>, Error> {
+        let open_scans = self.open_scans.get();
+        if self.max_open_scans.is_some_and(|max_open_scans| open_scans >= max_open_scans) {
+            error!("Can't open another scan, {open_scans} are already open");
+            return Err(QuotaExceeded);
+        }
+        Ok(self.counted_scan_source())
+    }
+
+    /**
+     * A source for a scan that counts as open until it is dropped, as `open_scan_source`
+     * returns, but regardless of the limit on open scans.
+     */
+    pub(crate) fn counted_scan_source<'db>(&'db self) -> Box<dyn ScanSource + 'db> {
+        let open_scans = self.open_scans.get();
+        self.open_scans.set(open_scans + 1);
+        self.peak_open_scans.set(self.peak_open_scans.get().max(open_scans + 1));
+        Box::new(
+            DatabaseScanSource {
+                database: self,
+                counted: true
+            }
+        )
+    }
+}
+
+/**
+ * Load the flags on segments holding late data, dropping any for segments that no longer exist.
+ */
+fn load_late_segments(backend: &dyn Backend, committed_segments: &HashSet<SegmentId>) -> Result<HashSet<SegmentId>, Error> {
+    if !backend.exists(LATE_SEGMENTS_FILENAME) {
+        return Ok(HashSet::new());
+    }
+    let json = backend.read(LATE_SEGMENTS_FILENAME)?;
+    let seg_ids: Vec<SegmentId> = serde_json::from_slice(&json)?;
+    Ok(seg_ids.into_iter().filter(|seg_id| committed_segments.contains(seg_id)).collect())
+}
+
+fn scan_files(backend: &dyn Backend) -> Result<ScanResult, Error> {
+    let imports: HashSet<TransactionId> = import::list(backend)?.into_iter().collect();
+    let mut max_seen_txn_id = imports.iter().copied().max().unwrap_or(0);
+    let mut num_reverted = 0;
+    let mut visible_segments = Vec::new();
+    for name in backend.list()? {
+        if let Some((txn_id, seg_num, committed)) = decode_segment_path(Path::new(&name)) {
+            let seg_id = (txn_id, seg_num);
+            if txn_id > max_seen_txn_id {
+                max_seen_txn_id = txn_id;
+            }
+
+            if !committed && imports.contains(&txn_id) {
+                debug!("Keeping segment {:?} written for an import", seg_id);
+                continue;
+            }
+            if !committed {
+                info!("Deleting uncommitted segment {:?}", seg_id);
+                backend.remove(&name)?;
+                num_reverted += 1;
+                continue;
+            }
+
+            visible_segments.push((seg_id, name));
+        };
+    }
+
+    /* Segment 0 is made visible last, so any transaction without it didn't commit fully. */
+    let committed_txns: HashSet<TransactionId> = visible_segments.iter()
+        .filter(|((_, seg_num), _)| *seg_num == 0)
+        .map(|((txn_id, _), _)| *txn_id)
+        .collect();
+
+    let mut known_segments = HashSet::new();
+    let mut used_size = 0;
+    let mut interrupted_imports = HashSet::new();
+    for (seg_id, name) in visible_segments {
+        if !committed_txns.contains(&seg_id.0) {
+            info!("Deleting segment {:?} from partly committed transaction", seg_id);
+            backend.remove(&name)?;
+            num_reverted += 1;
+            if imports.contains(&seg_id.0) {
+                interrupted_imports.insert(seg_id.0);
+            }
+            continue;
+        }
+
+        known_segments.insert(seg_id);
+        used_size += backend.size(&name)?;
+    }
+    for name in pool::list_files(backend)? {
+        if pool::is_object(&name) {
+            used_size += backend.size(&name)?;
+        }
+    }
+
+    /* An import whose commit was interrupted is discarded entirely, so it is all or nothing */
+    for &txn_id in &imports {
+        if interrupted_imports.contains(&txn_id) {
+            info!("Discarding import {:?} whose commit was interrupted", txn_id);
+            import::discard(backend, txn_id)?;
+        } else if committed_txns.contains(&txn_id) {
+            import::remove_manifest(backend, txn_id)?;
+        }
+    }
+
+    Ok(ScanResult {
+        next_transaction_id: max_seen_txn_id + 1,
+        committed_segments: known_segments,
+        used_size,
+        num_reverted
+    })
+}
+
+/**
+ * The uncompressed form of a block, for comparing blocks' content.
+ */
+fn block_payload(block: &Block) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    block.save(&mut payload, CURRENT_FORMAT_VERSION)?;
+    Ok(payload)
+}
+
+struct DatabaseScanSource<'db> {
+    database: &'db Database,
+    counted: bool
+}
+
+impl<'db> Drop for DatabaseScanSource<'db> {
+    fn drop(&mut self) {
+        if self.counted {
+            self.database.open_scans.set(self.database.open_scans.get() - 1);
+        }
+    }
+}
+
+impl<'db> DatabaseScanSource<'db> {
+    /**
+     * Fetch a block from the cache, or else load it, with only the value columns numbered in
+     * `values` if given.  A block loaded without all of its value columns isn't cached.
+     */
+    fn fetch_block(&self, block_id: BlockId, hint: CacheHint, values: Option<&[usize]>) -> Result<Rc<Block>, Error> {
+        info!("Request for block {:?}", block_id);
+
+        /* Try get it from the cache and return it */
+        let mut borrowed = self.database.cached_blocks.borrow_mut();
+        if let Some(rc) = borrowed.get_with_hint(&block_id, hint) {
+            return Ok(rc);
+        }
+
+        /* Otherwise, load it from disk, put it into the cache if it's whole, and return it */
+        let seg_id = (block_id.0, block_id.1);
+        let block_num = block_id.2;
+
+        /* Get the segment first (which will be loaded if not already cached) */
+        let segment = self.get_segment(seg_id)?;
+
+        /* Get the block from the segment */
+        let num_dims = self.database.schema.dimensions.len();
+        let load = || segment.load_one_block_values(self.database.backend.as_ref(), &self.database.dictionaries, block_num, values);
+        let block = match self.database.fetch_retry.run(block_id, load) {
+            Ok(block) if block.dimension_values.len() != num_dims => {
+                error!("Block {block_id:?} has {:?} dimensions, expected {num_dims:?}", block.dimension_values.len());
+                return Err(DataError);
+            },
+            Ok(block) if self.database.verify_block_bounds
+                && segment.block_info.get(block_num as usize).is_some_and(|info| info.check_block(&block).is_err()) => {
+                error!("Block {block_id:?} doesn't match its segment info");
+                return Err(DataError);
+            },
+            Ok(block) => block,
+            Err(err) => {
+                error!("Error during fetch of block {block_id:?}: {err:?}");
+                return Err(err);
+            }
+        };
+
+        let rc = Rc::new(block);
+        if rc.has_all_values() {
+            borrowed.add_with_hint(block_id, rc.clone(), hint);
+        }
+
+        Ok(rc)
+    }
+}
+
+impl<'db> ScanSource for DatabaseScanSource<'db> {
+    fn get_segment(&self, seg_id: SegmentId) -> Result<Rc<Segment>, Error> {
+        info!("Request for segment {:?}", seg_id);
+
+        /* Try get it from the cache and return it */
+        let mut borrowed = self.database.cached_segments.borrow_mut();
+        if let Some(rc) = borrowed.get(&seg_id) {
+            return Ok(rc);
+        }
+
+        /* Otherwise, load it from disk, put it into the cache, and return it */
+        let num_dims = self.database.schema.dimensions.len();
+        let segment = match self.database.fetch_retry.run(seg_id, || Segment::load(
+            self.database.backend.as_ref(),
+            seg_id
+        )).and_then(|segment| segment.check_dimensions(num_dims).map(|_| segment)) {
+            Ok(segment) => segment,
+            Err(err) => {
+                error!("Error during fetch of segment {seg_id:?}: {err:?}");
+                return Err(err);
+            }
+
+        };
+
+        let rc = Rc::new(segment);
+        borrowed.add(seg_id, rc.clone());
+
+        Ok(rc)
+    }
+
+    fn get_block_with_hint(&self, block_id: BlockId, hint: CacheHint) -> Result<Rc<Block>, Error> {
+        self.fetch_block(block_id, hint, None)
+    }
+
+    fn get_block_values(&self, block_id: BlockId, hint: CacheHint, values: &[usize]) -> Result<Rc<Block>, Error> {
+        self.fetch_block(block_id, hint, Some(values))
+    }
+
+    fn chunk_sizes(&self) -> Option<Vec<usize>> {
+        Some(self.database.schema.dimensions.iter().map(|dim| dim.chunk_size).collect())
+    }
+
+    fn num_values(&self) -> Option<usize> {
+        Some(self.database.schema.values.len())
+    }
+
+    fn next_query_id(&self) -> u64 {
+        let id = self.database.next_query_id.get();
+        self.database.next_query_id.set(id + 1);
+        id
+    }
+
+    fn query_finished(&self, trace: QueryTrace) {
+        if self.database.frozen {
+            debug!("Not recording query {:?} while the database is frozen", trace.id);
+            return;
+        }
+        trace::record_if_slow(self.database.backend.as_ref(), self.database.slow_query_threshold, trace);
+    }
+}
+
+#[cfg(test)]
+mod database_tests {
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use crate::{Backend, BlockManifest, CacheHint, CellOrder, Compression, Criteria, Database, Datum, Error, Field, Health, ImportLease, KeepAllVersions, Layout, MemoryBackend, OpenMode, OpenOptions, RetryPolicy, Schema, SchemaChange, SchemaDifference, SegmentEncoder, SumValues, TypedValue, ValueColumnStats, ValueType};
+    use crate::{import, SegmentNum};
+    use crate::backend::FaultyBackend;
+    use crate::cache::Cache;
+    use crate::format::CURRENT_FORMAT_VERSION;
+    use crate::segment::Segment;
+    use crate::storage::{get_segment_name, HOT_BLOCKS_FILENAME, LABELS_FILENAME, SCHEMA_FILENAME};
+    use crate::test_util::schema;
+    #[cfg(feature = "fs")]
+    use crate::test_util::create_database;
+    use super::BLOCK_CACHE_SIZE;
+
+    #[test]
+    fn ingest_segment() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[1, 1, 11]).unwrap();
+        txn.commit().unwrap();
+
+        let mut encoder = SegmentEncoder::new(&database.schema);
+        for i in 0..30 {
+            encoder.add_row(&[i, 5, i * 3]).unwrap();
+        }
+        let data = encoder.encode().unwrap();
+        assert_eq!(database.ingest_segment(&data).unwrap(), (2, 0));
+
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 31);
+        txn.rollback();
+
+        /* A segment for a different schema is rejected and leaves no trace */
+        let mut other_schema = schema();
+        other_schema.dimensions.pop();
+        let mut encoder = SegmentEncoder::new(&other_schema);
+        encoder.add_row(&[3, 33]).unwrap();
+        assert!(database.ingest_segment(&encoder.encode().unwrap()).is_err());
+        assert!(database.ingest_segment(&data[..8]).is_err());
+        /* The schema, the two segments, and the journal of the successful ingest */
+        assert_eq!(database.backend.list().unwrap().len(), 4);
+
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 31);
+    }
+
+    #[test]
+    fn block_stats() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(&[vec![1, 1, 11], vec![2, 3, 23], vec![15, 1, 151]]).unwrap();
+        txn.commit().unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[5, 5, 55]).unwrap();
+        txn.commit().unwrap();
+
+        let stats = database.block_stats().unwrap();
+        let mut summary: Vec<_> = stats.iter().map(|s| (s.block_id.0, s.chunk.clone(), s.num_rows, s.num_cells)).collect();
+        summary.sort();
+        assert_eq!(summary, vec![(1, vec![0, 0], 2, 4), (1, vec![10, 0], 1, 1), (2, vec![0, 0], 1, 1)]);
+        assert_eq!(stats.iter().find(|s| s.num_cells == 4).unwrap().fill_ratio(), 0.5);
+    }
+
+    #[test]
+    fn flush_order() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        for i in (0..100).rev() {
+            txn.add_row(&[i * 7 % 100, i, i]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let seg_id = *database.committed_segments.iter().next().unwrap();
+        let segment = Segment::load(backend.as_ref(), seg_id).unwrap();
+        assert!(segment.ordered);
+        let starts: Vec<_> = segment.block_info.iter().map(|info| database.schema.get_chunk_origin(&info.min_bounds)).collect();
+        assert_eq!(starts.len(), 70);
+        assert!(starts.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn auto_flush() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        database.set_auto_flush(Some(1000));
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..5000 {
+            txn.add_row(&[i % 100, i / 100, i]).unwrap();
+        }
+
+        /* The threshold moves away from the target once the compression ratio is known */
+        let stats = txn.flush_stats();
+        assert!(stats.auto_flushes > 1);
+        assert_eq!(stats.flushes, stats.auto_flushes);
+        assert_ne!(stats.threshold, Some(1000));
+        let mean_segment_size = stats.bytes_written / stats.flushes as u64;
+        assert!((800..1200).contains(&mean_segment_size));
+        assert!(stats.mean_block_size().unwrap() < mean_segment_size as f64);
+        txn.commit().unwrap();
+
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 5000);
+    }
+
+    #[test]
+    fn max_segment_size() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        database.set_max_segment_size(Some(300));
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..100 {
+            txn.add_row(&[i, i * 7 % 100, i]).unwrap();
+        }
+        txn.flush().unwrap();
+        assert!(txn.uncommitted_segments.len() > 1);
+        txn.commit().unwrap();
+
+        /* Each file is within the target, unless it holds a single larger block */
+        for seg_id in database.committed_segments.clone() {
+            let segment = Segment::load(backend.as_ref(), seg_id).unwrap();
+            assert!(backend.size(&segment.name).unwrap() <= 300 || segment.block_info.len() == 1);
+        }
+        assert_eq!(database.block_stats().unwrap().iter().map(|s| s.num_rows).sum::<usize>(), 100);
+
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 100);
+    }
+
+    #[test]
+    fn max_block_size() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        database.set_max_block_size(Some(200));
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..10 {
+            txn.add_row(&[i, i, i * 2]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let stats = database.block_stats().unwrap();
+        assert!(stats.len() > 1);
+        assert!(stats.iter().all(|s| s.chunk == vec![0, 0]));
+        assert_eq!(stats.iter().map(|s| s.num_rows).sum::<usize>(), 10);
+
+        let txn = database.new_transaction().unwrap();
+        let rows: Vec<_> = txn.query().map(|r| r[2]).collect();
+        assert_eq!(rows, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn write_counters() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        assert_eq!(database.stats().write_amplification(), None);
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(&[[1, 1, 11], [2, 2, 22]]).unwrap();
+        txn.flush().unwrap();
+        txn.add_row(&[3, 3, 33]).unwrap();
+        txn.commit().unwrap();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[4, 4, 44]).unwrap();
+        txn.flush().unwrap();
+        txn.rollback();
+
+        let stats = database.stats();
+        assert_eq!(stats.logical_bytes, 4 * 3 * 8);
+        assert_eq!(stats.segments_created, 3);
+        assert_eq!(stats.segments_deleted, 1);
+        assert!(stats.bytes_written > stats.size);
+        assert!(stats.write_amplification().unwrap() > 0.0);
+        assert_eq!(database.overlap_depth().unwrap(), 2);
+
+        /* The first block has rows on its diagonal only */
+        assert_eq!(stats.value_columns, vec![ValueColumnStats { name: String::from("value"), cells: 5, missing: 2 }]);
+        assert_eq!(stats.value_columns[0].missing_fraction(), 0.4);
+    }
+
+    #[test]
+    fn late_data() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        database.set_late_data(Some((0, 50)));
+        add_rows(&mut database, 100..110).unwrap();
+        assert!(database.late_segments().is_empty());
+
+        /* Rows from chunk 0 are more than 50 behind, but those from chunk 60 are not */
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[3, 0, 1]).unwrap();
+        txn.add_row(&[65, 0, 2]).unwrap();
+        txn.add_row(&[110, 0, 3]).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(database.late_segments(), vec![(2, 1)]);
+        let segment = Segment::load(backend.as_ref(), (2, 1)).unwrap();
+        assert_eq!(segment.block_info.len(), 1);
+        assert_eq!(segment.block_info[0].max_bounds, vec![3, 0]);
+
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 13);
+        txn.rollback();
+
+        /* The flags are kept when the database is opened again */
+        drop(database);
+        let database = Database::open_with_backend(Box::new(backend)).unwrap();
+        assert_eq!(database.late_segments(), vec![(2, 1)]);
+    }
+
+    #[test]
+    fn memory_usage() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        let empty = database.memory_usage();
+        assert_eq!((empty.cached_segments, empty.cached_blocks, empty.unsaved_blocks), (0, 0, 0));
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[1, 2, 3]).unwrap();
+        txn.add_row(&[1, 3, 4]).unwrap();
+        assert!(txn.memory_usage().unsaved_blocks > 0);
+        txn.commit().unwrap();
+
+        let txn = database.new_transaction().unwrap();
+        /* The scan holds the block until it has returned both rows */
+        let mut scan = txn.query();
+        assert!(scan.next().is_some());
+        let usage = txn.memory_usage();
+        assert!(usage.cached_segments > 0 && usage.cached_blocks > 0);
+        assert_eq!(usage.pinned_blocks, 1);
+        drop(scan);
+        assert_eq!(txn.memory_usage().pinned_blocks, 0);
+        assert!(usage.total() > empty.total());
+    }
+
+    #[test]
+    fn history() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        assert!(database.history().unwrap().is_empty());
+
+        let mut encoder = SegmentEncoder::new(&database.schema);
+        encoder.add_row(&[1, 2, 3]).unwrap();
+        database.ingest_segment(&encoder.encode().unwrap()).unwrap();
+        drop(database);
+
+        backend.write("00000002.00000000.tmp", b"unfinished").unwrap();
+        let database = Database::open_with_backend(Box::new(backend)).unwrap();
+        let history = database.history().unwrap();
+        let actions: Vec<_> = history.iter().map(|e| e.action.as_str()).collect();
+        assert_eq!(actions, vec!["ingest", "revert"]);
+        assert!(history[1].detail.contains("1 segments"));
+    }
+
+    #[test]
+    fn quota() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..50 {
+            txn.add_row(&[i, i % 3, i * 2]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let stats = database.stats();
+        assert_eq!(stats.num_segments, 1);
+        assert!(stats.size > 0);
+        assert_eq!(stats.utilisation(), None);
+
+        database.set_max_size(Some(stats.size + 10));
+        assert!(database.stats().utilisation().unwrap() > 0.9);
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[100, 0, 1]).unwrap();
+        assert!(matches!(txn.flush(), Err(Error::QuotaExceeded)));
+        let rows: Vec<_> = txn.query().map(|r| r[0]).collect();
+        assert_eq!(rows.len(), 51);
+        assert!(matches!(txn.commit(), Err(Error::QuotaExceeded)));
+        assert_eq!(database.stats().size, stats.size);
+
+        database.set_max_size(None);
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[100, 0, 1]).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(database.stats().num_segments, 2);
+        assert!(database.stats().size > stats.size);
+    }
+
+    fn add_rows(database: &mut Database, xs: std::ops::Range<usize>) -> Result<(), Error> {
+        let mut txn = database.new_transaction().unwrap();
+        for i in xs {
+            txn.add_row(&[i, i % 3, i * 2]).unwrap();
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn temp_files(backend: &FaultyBackend) -> usize {
+        backend.list().unwrap().iter().filter(|name| name.ends_with(".tmp")).count()
+    }
+
+    #[test]
+    fn write_failure() {
+        let backend = Rc::new(FaultyBackend::default());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        add_rows(&mut database, 0..10).unwrap();
+
+        backend.fail_writes.set(true);
+        assert!(add_rows(&mut database, 10..20).is_err());
+        assert_eq!(temp_files(&backend), 0);
+
+        /* A failed flush keeps the rows, and the transaction can still be rolled back */
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[50, 0, 0]).unwrap();
+        assert!(txn.flush().is_err());
+        assert_eq!(txn.query().count(), 11);
+        txn.rollback();
+        assert_eq!(temp_files(&backend), 0);
+
+        backend.fail_writes.set(false);
+        assert_eq!(database.health(), Health::Healthy);
+        add_rows(&mut database, 20..30).unwrap();
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 20);
+    }
+
+    #[test]
+    fn fetch_retry() {
+        let backend = Rc::new(FaultyBackend::default());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        add_rows(&mut database, 0..10).unwrap();
+        drop(database);
+
+        /* A reopened database has nothing cached, so the query reads the segment and its block */
+        let mut database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        database.set_fetch_retry(RetryPolicy { attempts: 3, initial_delay: Duration::ZERO, max_delay: Duration::ZERO });
+        backend.failed_opens.set(2);
+        let mut scan = database.query_snapshot().query();
+        assert_eq!(scan.by_ref().count(), 10);
+        assert!(scan.missing_rows().is_empty());
+        drop(scan);
+
+        let mut database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        database.set_fetch_retry(RetryPolicy::none());
+        backend.failed_opens.set(1);
+        let missing = database.query_snapshot().query().collect_complete().unwrap_err();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].error, Error::IoError);
+        assert_eq!(backend.failed_opens.get(), 0);
+
+        /* A dump that can't read everything fails rather than writing part of the data */
+        let mut database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        backend.failed_opens.set(3);
+        assert!(matches!(database.dump(&mut Vec::new()), Err(Error::IoError)));
+    }
+
+    #[test]
+    fn partial_commit() {
+        let backend = Rc::new(FaultyBackend::default());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+
+        /* The first rename fails, so nothing is visible yet and the database is unharmed */
+        backend.renames_before_failure.set(Some(0));
+        assert!(add_rows(&mut database, 0..10).is_err());
+        assert_eq!(temp_files(&backend), 0);
+        assert_eq!(database.health(), Health::Healthy);
+
+        /* Only some of the segments are renamed */
+        backend.renames_before_failure.set(Some(1));
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[1, 1, 1]).unwrap();
+        txn.flush().unwrap();
+        txn.add_row(&[2, 2, 2]).unwrap();
+        assert!(txn.commit().is_err());
+        assert_eq!(temp_files(&backend), 0);
+        assert_eq!(database.health(), Health::ReadOnly);
+
+        backend.renames_before_failure.set(None);
+        assert!(matches!(add_rows(&mut database, 0..10), Err(Error::ReadOnly)));
+        assert!(matches!(database.compact(), Err(Error::ReadOnly)));
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 0);
+    }
+
+    #[test]
+    fn freeze() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        add_rows(&mut database, 0..10).unwrap();
+        database.set_slow_query_threshold(Some(Duration::ZERO));
+
+        let files = |backend: &MemoryBackend| {
+            let mut names = backend.list().unwrap();
+            names.sort();
+            names.into_iter().map(|name| (backend.read(&name).unwrap(), name)).collect::<Vec<_>>()
+        };
+        let frozen = database.freeze().unwrap();
+        let before = files(&backend);
+        assert!(before.iter().any(|(_, name)| name == HOT_BLOCKS_FILENAME));
+        assert_eq!(frozen.database().query_snapshot().query().count(), 10);
+        assert_eq!(files(&backend), before);
+        drop(frozen);
+
+        database.query_snapshot().query().count();
+        assert_eq!(database.slow_queries().unwrap().len(), 1);
+        add_rows(&mut database, 10..20).unwrap();
+    }
+
+    #[test]
+    fn close_failure() {
+        let backend = Rc::new(FaultyBackend::default());
+        let database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        backend.fail_writes.set(true);
+        assert!(matches!(database.close(), Err(Error::IoError)));
+        assert_eq!(temp_files(&backend), 0);
+    }
+
+    #[test]
+    fn remove_failure() {
+        let backend = Rc::new(FaultyBackend::default());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[1, 1, 1]).unwrap();
+        txn.flush().unwrap();
+        backend.fail_removes.set(true);
+        txn.rollback();
+        assert_eq!(database.health(), Health::ReadOnly);
+    }
+
+    #[test]
+    fn memory_backend() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..50 {
+            txn.add_row(&[i, i % 3, i * 2]).unwrap();
+        }
+        txn.commit().unwrap();
+        drop(database);
+
+        let mut database = Database::open_with_backend(Box::new(backend)).unwrap();
+        let txn = database.new_transaction().unwrap();
+        let rows: Vec<_> = txn.query().map(|r| r.values_array).collect();
+        assert_eq!(rows.len(), 50);
+        assert_eq!(rows[49], vec![49, 1, 98]);
+    }
+
+    #[test]
+    fn cache_hint() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        add_rows(&mut database, 0..50).unwrap();
+
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().cache_hint(CacheHint::OneShot).count(), 50);
+        assert_eq!(txn.database.cached_blocks.borrow().measure(|_| 1), (0, 0));
+
+        assert_eq!(txn.query().cache_hint(CacheHint::Hot).count(), 50);
+        assert_eq!(txn.database.cached_blocks.borrow().hot_keys().len(), 5);
+    }
+
+    #[test]
+    fn duplicate_blocks() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        add_rows(&mut database, 0..30).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[15, 0, 99]).unwrap();
+        txn.commit().unwrap();
+
+        /* Importing the same rows again duplicates every block of the first transaction */
+        add_rows(&mut database, 0..30).unwrap();
+        let size = database.stats().size;
+        assert_eq!(database.compact().unwrap(), 0);
+        assert_eq!(database.block_stats().unwrap().len(), 7);
+
+        /* Not while a reader from before the second copy could still see the first */
+        let horizon = database.next_transaction_id;
+        assert_eq!(database.remove_duplicate_blocks(horizon - 1).unwrap(), 0);
+        assert_eq!(database.remove_duplicate_blocks(horizon).unwrap(), 1);
+        assert!(database.stats().size < size);
+        assert_eq!(database.block_stats().unwrap().len(), 4);
+        assert!(matches!(database.remove_duplicate_blocks(horizon + 1), Err(Error::OutOfBounds)));
+
+        let txn = database.new_transaction().unwrap();
+        let rows: Vec<_> = txn.query().map(|r| r.values_array).collect();
+        assert_eq!(rows.len(), 30);
+        assert_eq!(rows[15], vec![15, 0, 30]);
+        txn.rollback();
+        drop(database);
+
+        /* The emptied first segment still marks its transaction as committed */
+        let mut database = Database::open_with_backend(Box::new(backend)).unwrap();
+        assert_eq!(database.remove_duplicate_blocks(horizon).unwrap(), 0);
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 30);
+    }
+
+    #[test]
+    fn deleted_blocks() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        add_rows(&mut database, 0..30).unwrap();
+        add_rows(&mut database, 35..36).unwrap();
+
+        /* The first chunk is deleted entirely, and the second only in part */
+        let mut txn = database.new_transaction().unwrap();
+        txn.delete_where(&Criteria::new().range(0, 0..=14)).unwrap();
+        txn.commit().unwrap();
+        let size = database.stats().size;
+        assert_eq!(database.compact().unwrap(), 1);
+        assert!(database.stats().size < size);
+        assert_eq!(database.block_stats().unwrap().len(), 3);
+
+        let txn = database.new_transaction().unwrap();
+        let rows: Vec<_> = txn.query().map(|r| r.values_array).collect();
+        assert_eq!(rows.len(), 16);
+        assert_eq!(rows[0], vec![15, 0, 30]);
+        txn.rollback();
+        drop(database);
+
+        /* The tombstone still hides the rest of the second chunk */
+        let mut database = Database::open_with_backend(Box::new(backend)).unwrap();
+        assert_eq!(database.compact().unwrap(), 0);
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 16);
+    }
+
+    #[test]
+    fn merge_segments() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        for i in 0..5 {
+            let mut txn = database.new_transaction().unwrap();
+            for x in i * 5..i * 5 + 10 {
+                txn.add_row(&[x, 0, i]).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+        let mut txn = database.new_transaction().unwrap();
+        txn.delete_where(&Criteria::new().range(0, 0..=2)).unwrap();
+        txn.commit().unwrap();
+        add_rows(&mut database, 40..45).unwrap();
+        add_rows(&mut database, 42..50).unwrap();
+        let query = |database: &mut Database| {
+            let txn = database.new_transaction().unwrap();
+            txn.query().map(|row| row.values_array).collect::<Vec<_>>()
+        };
+        let rows = query(&mut database);
+        assert_eq!(rows.len(), 37);
+        assert_eq!(database.stats().num_segments, 8);
+
+        /* Compacting doesn't merge anything */
+        assert_eq!(database.compact().unwrap(), 0);
+        assert_eq!(database.stats().num_segments, 8);
+
+        /* The first three transactions are merged into the last one's segment */
+        assert_eq!(database.merge_segments(9, 3).unwrap(), 2);
+        assert_eq!(database.stats().num_segments, 6);
+        assert_eq!(query(&mut database), rows);
+        drop(database);
+
+        let mut database = Database::open_with_backend(Box::new(backend)).unwrap();
+        assert_eq!(query(&mut database), rows);
+
+        /* Only transactions before the horizon are merged */
+        assert_eq!(database.merge_segments(8, usize::MAX).unwrap(), 2);
+        assert_eq!(database.merge_segments(8, usize::MAX).unwrap(), 0);
+        assert_eq!(database.stats().num_segments, 4);
+
+        /* The tombstone's segment separates the rest into two runs */
+        assert_eq!(database.merge_segments(9, usize::MAX).unwrap(), 1);
+        assert_eq!(database.merge_segments(9, usize::MAX).unwrap(), 0);
+        assert_eq!(database.stats().num_segments, 3);
+        assert_eq!(query(&mut database), rows);
+        assert_eq!(database.block_stats().unwrap().len(), 4);
+        assert!(matches!(database.merge_segments(10, 1), Err(Error::OutOfBounds)));
+    }
+
+    #[test]
+    fn vacuum() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        let add = |database: &mut Database, xs: std::ops::Range<usize>, value: usize| {
+            let mut txn = database.new_transaction().unwrap();
+            for x in xs {
+                txn.add_row(&[x, 0, value]).unwrap();
+            }
+            txn.commit().unwrap();
+        };
+        add(&mut database, 0..20, 1);
+        add(&mut database, 5..15, 2);
+        let mut txn = database.new_transaction().unwrap();
+        txn.delete_where(&Criteria::new().range(0, 0..=2)).unwrap();
+        txn.commit().unwrap();
+        add(&mut database, 15..20, 4);
+        let query = |database: &mut Database| {
+            let txn = database.new_transaction().unwrap();
+            txn.query().map(|row| (row.txn_id, row.values_array)).collect::<Vec<_>>()
+        };
+        let rows = query(&mut database);
+        assert_eq!(rows.len(), 17);
+
+        /* The last transaction is after the horizon, so the rows it replaces are kept */
+        let size = database.stats().size;
+        assert_eq!(database.vacuum(4).unwrap(), 13);
+        assert!(database.stats().size < size);
+        assert_eq!(query(&mut database), rows);
+        assert_eq!(rows[0], (1, vec![3, 0, 1]));
+
+        assert_eq!(database.vacuum(5).unwrap(), 5);
+        assert_eq!(database.vacuum(5).unwrap(), 0);
+        assert_eq!(query(&mut database), rows);
+        assert!(matches!(database.vacuum(6), Err(Error::OutOfBounds)));
+    }
+
+    fn pool_objects(backend: &MemoryBackend) -> usize {
+        backend.list().unwrap().iter().filter(|name| name.starts_with("pool-")).count()
+    }
+
+    #[test]
+    fn pool_layout() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        database.set_layout(Layout::Pool);
+        add_rows(&mut database, 0..30).unwrap();
+        let num_objects = pool_objects(&backend);
+        assert_eq!(num_objects, 3);
+
+        /* Importing the same rows again adds nothing to the pool */
+        add_rows(&mut database, 0..30).unwrap();
+        assert_eq!(pool_objects(&backend), num_objects);
+
+        /* A second backup only copies what changed since the first */
+        let copy = Rc::new(MemoryBackend::new());
+        let stats = database.backup_to(copy.as_ref()).unwrap();
+        assert_eq!(stats.files_skipped, 0);
+        assert_eq!(pool_objects(&copy), num_objects);
+        add_rows(&mut database, 30..40).unwrap();
+        let stats = database.backup_to(copy.as_ref()).unwrap();
+        assert_eq!(stats.files_copied, 2);
+        assert_eq!(stats.files_skipped, num_objects + 3);
+        let mut clone = Database::open_with_backend(Box::new(copy)).unwrap();
+        assert_eq!(clone.new_transaction().unwrap().query().count(), 40);
+
+        /* Blocks of a rolled back transaction stay in the pool until compaction */
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[100, 0, 0]).unwrap();
+        txn.flush().unwrap();
+        txn.rollback();
+        assert_eq!(pool_objects(&backend), num_objects + 2);
+        database.compact().unwrap();
+        assert_eq!(pool_objects(&backend), num_objects + 1);
+
+        let mut clone = database.clone_to(Box::new(MemoryBackend::new())).unwrap();
+        let rows: Vec<_> = clone.new_transaction().unwrap().query().map(|r| r.values_array).collect();
+        assert_eq!(rows.len(), 40);
+        assert_eq!(rows[35], vec![35, 2, 70]);
+    }
+
+    #[test]
+    fn mixed_compression() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        let all = [Compression::Zstd, Compression::None, Compression::Lz4, Compression::Snappy];
+        for (i, compression) in all.into_iter().filter(|c| c.is_available()).enumerate() {
+            database.set_compression(compression);
+            add_rows(&mut database, i * 10..i * 10 + 10).unwrap();
+        }
+        let num_rows = all.iter().filter(|c| c.is_available()).count() * 10;
+        drop(database);
+
+        let mut database = Database::open_with_backend(Box::new(backend)).unwrap();
+        database.set_compression(Compression::None);
+        database.compact().unwrap();
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), num_rows);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn dictionary() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        for i in 0..20 {
+            add_rows(&mut database, i * 30..i * 30 + 30).unwrap();
+        }
+        let before = database.stats().bytes_written;
+        add_rows(&mut database, 600..630).unwrap();
+        let without_dictionary = database.stats().bytes_written - before;
+
+        assert_eq!(database.train_dictionary().unwrap(), 1);
+        let before = database.stats().bytes_written;
+        add_rows(&mut database, 630..660).unwrap();
+        assert!(database.stats().bytes_written - before < without_dictionary);
+        drop(database);
+
+        /* The dictionary is used again after reopening, and retrained by compaction */
+        let mut database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        assert_eq!(database.dictionary.as_ref().map(|d| d.id), Some(1));
+        database.compact().unwrap();
+        assert_eq!(database.dictionary.as_ref().map(|d| d.id), Some(2));
+        add_rows(&mut database, 660..690).unwrap();
+        drop(database);
+
+        let mut database = Database::open_with_backend(Box::new(backend)).unwrap();
+        let txn = database.new_transaction().unwrap();
+        let rows: Vec<_> = txn.query().map(|r| r.values_array).collect();
+        assert_eq!(rows.len(), 690);
+        assert_eq!(rows[689], vec![689, 2, 1378]);
+    }
+
+    #[test]
+    #[cfg(feature = "fs")]
+    fn warm_start() {
+        let mut database = create_database("database-warm-start");
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..100 {
+            txn.add_row(&[i, i, i]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        /* Query twice so the blocks leave probation */
+        for _ in 0..2 {
+            let txn = database.new_transaction().unwrap();
+            assert_eq!(txn.query().count(), 100);
+            txn.rollback();
+        }
+        let path = database.path.clone();
+        database.close().unwrap();
+
+        let database = Database::open(path.as_path()).unwrap();
+        assert_eq!(database.preload_hot_blocks().unwrap(), 10);
+        assert_eq!(database.cached_blocks.borrow().hot_keys().len(), 10);
+    }
+
+    #[test]
+    fn open_options() {
+        let backend = Rc::new(MemoryBackend::new());
+        let open = |options| Database::open_with_backend_options(Box::new(backend.clone()), options);
+
+        assert!(matches!(open(OpenOptions::new()), Err(Error::IoError)));
+        assert!(matches!(open(OpenOptions::new().mode(OpenMode::CreateIfMissing)), Err(Error::SchemaError)));
+
+        let options = OpenOptions::new().mode(OpenMode::ErrorIfExists).schema(schema());
+        let mut database = open(options).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[1, 2, 3]).unwrap();
+        txn.commit().unwrap();
+        drop(database);
+
+        let options = OpenOptions::new().mode(OpenMode::ErrorIfExists).schema(schema());
+        assert!(matches!(open(options), Err(Error::IoError)));
+
+        let options = OpenOptions::new().mode(OpenMode::CreateIfMissing).schema(schema());
+        assert_eq!(open(options).unwrap().stats().num_segments, 1);
+        assert_eq!(open(OpenOptions::new()).unwrap().stats().num_segments, 1);
+
+        let mut other_schema = schema();
+        other_schema.dimensions[1].chunk_size = 20;
+        let database = open(OpenOptions::new()).unwrap();
+        assert!(database.check_schema(&schema()).is_empty());
+        assert_eq!(database.check_schema(&other_schema).differences, vec![
+            SchemaDifference::ChunkSizeChanged { name: String::from("y"), expected: 20, actual: 10 }
+        ]);
+        drop(database);
+        let options = OpenOptions::new().mode(OpenMode::CreateIfMissing).schema(other_schema);
+        assert!(matches!(open(options), Err(Error::SchemaError)));
+    }
+
+    #[test]
+    fn verify_block_bounds() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[3, 4, 5]).unwrap();
+        txn.commit().unwrap();
+        drop(database);
+
+        /* A segment whose info claims a wider block than it holds */
+        let corrupt = |database: &Database| {
+            let mut segment = Segment::load(database.backend.as_ref(), (1, 0)).unwrap();
+            segment.block_info[0].min_bounds = vec![0, 0];
+            database.cached_segments.borrow_mut().add((1, 0), Rc::new(segment));
+        };
+
+        let mut database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        corrupt(&database);
+        database.set_verify_block_bounds(true);
+        assert_eq!(database.query_snapshot().query().count(), 0);
+
+        let mut database = Database::open_with_backend(Box::new(backend)).unwrap();
+        corrupt(&database);
+        database.set_verify_block_bounds(false);
+        assert_eq!(database.query_snapshot().query().count(), 1);
+    }
+
+    #[test]
+    fn format_version() {
+        let backend = Rc::new(MemoryBackend::new());
+        let database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        drop(database);
+        let recorded = |backend: &MemoryBackend| {
+            let json: serde_json::Value = serde_json::from_slice(&backend.read(SCHEMA_FILENAME).unwrap()).unwrap();
+            json["format_version"].as_u64()
+        };
+        assert_eq!(recorded(&backend), Some(CURRENT_FORMAT_VERSION as u64));
+
+        /* A schema file from before the version was recorded is upgraded on the first write */
+        backend.write(SCHEMA_FILENAME, serde_json::to_string(&schema()).unwrap().as_bytes()).unwrap();
+        let mut database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        assert_eq!(recorded(&backend), None);
+        add_rows(&mut database, 0..10).unwrap();
+        assert_eq!(recorded(&backend), Some(CURRENT_FORMAT_VERSION as u64));
+        drop(database);
+
+        let json = String::from_utf8(backend.read(SCHEMA_FILENAME).unwrap()).unwrap();
+        let newer = json.replace(&format!("\"format_version\":{CURRENT_FORMAT_VERSION}"), "\"format_version\":99");
+        backend.write(SCHEMA_FILENAME, newer.as_bytes()).unwrap();
+        assert!(matches!(Database::open_with_backend(Box::new(backend.clone())),
+            Err(Error::UnsupportedVersion { found: 99, supported: CURRENT_FORMAT_VERSION })));
+
+        let mut dump = Vec::new();
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        database.dump(&mut dump).unwrap();
+        /* The version follows the ten byte magic */
+        dump[11] = 3;
+        assert!(matches!(Database::restore_with_backend(&mut dump.as_slice(), Box::new(MemoryBackend::new())),
+            Err(Error::UnsupportedVersion { found: 3, supported: 2 })));
+    }
+
+    #[test]
+    fn verify_against() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        add_rows(&mut database, 0..30).unwrap();
+        let replica_backend = Rc::new(MemoryBackend::new());
+        database.backup_to(replica_backend.as_ref()).unwrap();
+        let replica = Database::open_with_backend(Box::new(replica_backend)).unwrap();
+
+        let manifest = database.block_manifest().unwrap();
+        assert_eq!(manifest.len(), database.block_stats().unwrap().len());
+        assert!(replica.verify_against(&manifest).unwrap().is_empty());
+
+        add_rows(&mut database, 100..105).unwrap();
+        let divergence = replica.verify_against(&database.block_manifest().unwrap()).unwrap();
+        assert_eq!(divergence.missing.len(), 1);
+        assert!(divergence.extra.is_empty() && divergence.different.is_empty());
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(serde_json::from_str::<BlockManifest>(&json).unwrap(), manifest);
+    }
+
+    #[test]
+    fn value_columns() {
+        let schema = Schema::builder().dimension("t", 10).value("min").value("max").value("count").build().unwrap();
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema, Box::new(backend.clone())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        for t in 0..25 {
+            txn.add_row(&[t, t, t * 2, 1]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        let rows: Vec<_> = database.query_snapshot().query().map(|row| row.values_array).collect();
+        assert_eq!(rows.len(), 25);
+        assert_eq!(rows[7], vec![7, 7, 14, 1]);
+
+        /* An update is a partial version, taking the row's other values from the one before */
+        let mut database = database;
+        let mut txn = database.new_transaction().unwrap();
+        txn.update(&[7], 2, 5).unwrap();
+        txn.update(&[40], 1, 9).unwrap();
+        let rows: Vec<_> = txn.query_with(&Criteria::new().values(0, &[7, 40])).unwrap().map(|row| row.values_array).collect();
+        assert_eq!(rows, vec![vec![7, 7, 14, 5], vec![40, 0, 9, 0]]);
+        txn.commit().unwrap();
+        let missing: Vec<_> = database.stats().value_columns.iter().map(|column| (column.cells, column.missing)).collect();
+        assert_eq!(missing, vec![(27, 2), (27, 1), (27, 1)]);
+        let mut txn = database.new_transaction().unwrap();
+        txn.update(&[7], 0, 3).unwrap();
+        let txn_id = txn.commit().unwrap().txn_id.unwrap();
+
+        /* Merging and vacuuming keep the versions the partial ones need */
+        let mut database = Database::open_with_backend(Box::new(backend)).unwrap();
+        let expected = vec![vec![7, 3, 14, 5], vec![40, 0, 9, 0]];
+        let criteria = Criteria::new().values(0, &[7, 40]);
+        let rows: Vec<_> = database.query_snapshot().query_with(&criteria).unwrap().map(|row| row.values_array).collect();
+        assert_eq!(rows, expected);
+        assert_eq!(database.vacuum(txn_id + 1).unwrap(), 0);
+        assert_eq!(database.merge_segments(txn_id, usize::MAX).unwrap(), 1);
+        let rows: Vec<_> = database.query_snapshot().query_with(&criteria).unwrap().map(|row| row.values_array).collect();
+        assert_eq!(rows, expected);
+        assert_eq!(database.merge_segments(txn_id + 1, usize::MAX).unwrap(), 1);
+        let rows: Vec<_> = database.query_snapshot().query_with(&criteria).unwrap().map(|row| row.values_array).collect();
+        assert_eq!(rows, expected);
+
+        /* Selected values are resolved from the versions before them in the same way */
+        let snapshot = database.query_snapshot();
+        let rows: Vec<_> = snapshot.query_with(&criteria).unwrap().select_values(&[2, 0]).unwrap().map(|row| row.values_array).collect();
+        assert_eq!(rows, vec![vec![7, 5, 3], vec![40, 0, 0]]);
+        let rows: Vec<_> = snapshot.query_with(&criteria).unwrap().select_values(&[]).unwrap().map(|row| row.values_array).collect();
+        assert_eq!(rows, vec![vec![7], vec![40]]);
+        assert!(matches!(snapshot.query().select_values(&[3]), Err(Error::SchemaError)));
+        assert!(matches!(snapshot.query().select_values(&[1, 1]), Err(Error::SchemaError)));
+        let mut scan = snapshot.query_with(&criteria).unwrap();
+        assert!(scan.next().is_some());
+        assert!(matches!(scan.select_values(&[0]), Err(Error::SchemaError)));
+
+        /* Blocks that aren't cached are loaded with only the selected values, and not cached */
+        *database.cached_blocks.borrow_mut() = Cache::new(BLOCK_CACHE_SIZE);
+        let rows: Vec<_> = snapshot.query_with(&criteria).unwrap().select_values(&[2, 0]).unwrap().map(|row| row.values_array).collect();
+        assert_eq!(rows, vec![vec![7, 5, 3], vec![40, 0, 0]]);
+        assert_eq!(database.cached_blocks.borrow().measure(|_| 1), (0, 0));
+        let rows: Vec<_> = snapshot.query_with(&criteria).unwrap().map(|row| row.values_array).collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn partial_versions_summed() {
+        let schema = Schema::builder().dimension("x", 10).dimension("y", 10).value("a").value("b").build().unwrap();
+        let mut database = Database::create_with_backend(schema, Box::new(MemoryBackend::new())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[0, 1, 2, 20]).unwrap();
+        txn.commit().unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.update(&[0, 1], 0, 99).unwrap();
+        txn.commit().unwrap();
+
+        /* The update adds to the value it sets, and nothing to the one it leaves */
+        let txn = database.new_transaction().unwrap();
+        let rows: Vec<_> = txn.query().merge_strategy(SumValues::new(2)).map(|row| row.values_array).collect();
+        assert_eq!(rows, vec![vec![0, 1, 101, 20]]);
+        let rows: Vec<_> = txn.query().map(|row| row.values_array).collect();
+        assert_eq!(rows, vec![vec![0, 1, 99, 20]]);
+        let rows: Vec<_> = txn.query().merge_strategy(KeepAllVersions).map(|row| row.values_array).collect();
+        assert_eq!(rows, vec![vec![0, 1, 2, 20], vec![0, 1, 99, 20]]);
+    }
+
+    #[test]
+    fn tiled_cells() {
+        let schema = Schema::builder().dimension("x", 20).dimension("y", 20).value("value").cell_order(CellOrder::Tiled(4)).build().unwrap();
+        let mut database = Database::create_with_backend(schema, Box::new(MemoryBackend::new())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        let points: Vec<_> = (0..20).flat_map(|x| (0..20).map(move |y| [x, y, x * 100 + y])).filter(|row| row[0] * row[1] % 3 != 0).collect();
+        txn.add_rows(&points).unwrap();
+        txn.commit().unwrap();
+
+        let &seg_id = database.committed_segments.iter().next().unwrap();
+        assert_eq!(database.load_block((seg_id.0, seg_id.1, 0)).unwrap().order, CellOrder::Tiled(4));
+        *database.cached_blocks.borrow_mut() = Cache::new(BLOCK_CACHE_SIZE);
+        let criteria = Criteria::new().range(0, 5..=9).range(1, 10..=13);
+        let rows: Vec<_> = database.query_snapshot().query_with(&criteria).unwrap().map(|row| row.values_array).collect();
+        let expected: Vec<_> = points.iter().filter(|row| criteria.matches_point(&row[..2])).map(|row| row.to_vec()).collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn labels_and_typed_values() {
+        let schema = Schema::builder()
+            .dimension("sensor", 4).labels()
+            .dimension("time", 100)
+            .value("temperature").value_type(ValueType::F64)
+            .build().unwrap();
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema.clone(), Box::new(backend.clone())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row_named(&["north".into(), Field::Datum(5), 21.5.into()]).unwrap();
+        txn.add_row_named(&["south".into(), Field::Datum(5), (-0.25).into()]).unwrap();
+        txn.add_row_named(&["north".into(), Field::Datum(6), 22.0.into()]).unwrap();
+        assert!(matches!(txn.add_row_named(&["north".into(), Field::Datum(7)]), Err(Error::ArityMismatch)));
+        assert!(matches!(txn.add_row_named(&["north".into(), "noon".into(), 1.0.into()]), Err(Error::SchemaError)));
+        assert!(matches!(txn.add_row_named(&["north".into(), Field::Datum(7), 1i64.into()]), Err(Error::SchemaError)));
+        txn.commit().unwrap();
+        assert!(backend.exists(LABELS_FILENAME));
+
+        let database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        assert_eq!(database.label_id(0, "south"), Some(1));
+        assert_eq!(database.label(0, 0), Some("north"));
+        let rows: Vec<_> = database.query_snapshot().query().map(|row| database.resolve_row(&row)).collect();
+        assert_eq!(rows[1], vec![Field::Label(String::from("north")), Field::Datum(6), Field::Value(TypedValue::F64(22.0))]);
+        assert_eq!(rows[2][0], Field::Label(String::from("south")));
+        let row = database.query_snapshot().query().last().unwrap();
+        assert_eq!(row.typed_value(&database.schema, 0), TypedValue::F64(-0.25));
+
+        let mut dump = Vec::new();
+        let mut database = database;
+        database.dump(&mut dump).unwrap();
+        let restored = Database::restore_with_backend(&mut dump.as_slice(), Box::new(MemoryBackend::new())).unwrap();
+        assert_eq!(restored.label_id(0, "south"), Some(1));
+        assert_eq!(restored.query_snapshot().query().count(), 3);
+    }
+
+    #[test]
+    fn alter_schema() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        let rename = |from: &str, to: &str| SchemaChange::RenameDimension { from: from.to_string(), to: to.to_string() };
+        database.alter_schema(rename("x", "time")).unwrap();
+        database.alter_schema(rename("time", "t")).unwrap();
+        assert!(matches!(database.alter_schema(rename("x", "u")), Err(Error::SchemaError)));
+        assert!(matches!(database.alter_schema(rename("t", "y")), Err(Error::SchemaError)));
+        database.alter_schema(SchemaChange::RenameValue { from: String::from("value"), to: String::from("reading") }).unwrap();
+        drop(database);
+
+        let database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        assert_eq!(database.dimension_no("t"), Ok(0));
+        assert_eq!(database.dimension_no("x"), Err(Error::SchemaError));
+        assert_eq!(database.value_no("reading"), Ok(0));
+        assert_eq!(database.value_no("t"), Err(Error::SchemaError));
+        assert_eq!(database.check_schema(&schema()).differences, vec![
+            SchemaDifference::Renamed { name: String::from("x"), new_name: String::from("t") },
+            SchemaDifference::Renamed { name: String::from("value"), new_name: String::from("reading") }
+        ]);
+        drop(database);
+
+        let options = OpenOptions::new().schema(schema());
+        assert!(matches!(Database::open_with_backend_options(Box::new(backend), options), Err(Error::SchemaError)));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn open_with_path() {
+        let path = std::env::temp_dir().join("open_with_path");
+        if path.exists() {
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+
+        assert!(matches!(Database::open_with(&path, OpenOptions::new().mode(OpenMode::CreateIfMissing)),
+            Err(Error::SchemaError)));
+        assert!(!path.exists());
+
+        let options = OpenOptions::new().mode(OpenMode::CreateIfMissing).schema(schema());
+        let database = Database::open_with(&path, options).unwrap();
+        assert_eq!(database.path, path);
+        drop(database);
+
+        let options = OpenOptions::new().mode(OpenMode::CreateIfMissing).schema(schema());
+        assert!(Database::open_with(&path, options).is_ok());
+        let options = OpenOptions::new().mode(OpenMode::ErrorIfExists).schema(schema());
+        assert!(matches!(Database::open_with(&path, options), Err(Error::IoError)));
+    }
+
+    #[test]
+    fn slow_query_log() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..50 {
+            txn.add_row(&[i, i % 3, i * 2]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 50);
+        drop(txn);
+        assert!(database.slow_queries().unwrap().is_empty());
+
+        database.set_slow_query_threshold(Some(Duration::ZERO));
+        let txn = database.new_transaction().unwrap();
+        let first_id = txn.query().query_id();
+        let mut scan = txn.query_with(&Criteria::new().range(0, 0..=9)).unwrap();
+        let second_id = scan.query_id();
+        assert!(second_id > first_id);
+        assert!(scan.next().is_some());
+        drop(scan);
+        drop(txn);
+
+        let traces = database.slow_queries().unwrap();
+        assert_eq!(traces.len(), 2);
+        assert_eq!((traces[0].id, traces[0].rows_returned), (first_id, 0));
+        assert_eq!((traces[1].id, traces[1].rows_returned), (second_id, 1));
+        assert!(traces[1].blocks_read > 0);
+        assert!(traces[1].criteria.contains("0..=9"), "{:?}", traces[1].criteria);
+
+        database.set_slow_query_threshold(Some(Duration::from_secs(3600)));
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.query().count(), 50);
+        drop(txn);
+        assert_eq!(database.slow_queries().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn max_open_scans() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        database.set_max_open_scans(Some(2));
+        let snapshot = database.query_snapshot();
+        let first = snapshot.query();
+        let second = snapshot.query_with(&Criteria::new()).unwrap();
+        assert_eq!(database.stats().open_scans, 2);
+        assert!(matches!(snapshot.query_with(&Criteria::new()), Err(Error::QuotaExceeded)));
+
+        drop(first);
+        let third = snapshot.query_with(&Criteria::new()).unwrap();
+        drop((second, third));
+        let stats = database.stats();
+        assert_eq!((stats.open_scans, stats.peak_open_scans), (0, 2));
+
+        database.set_max_open_scans(Some(0));
+        let txn = database.new_transaction().unwrap();
+        assert!(matches!(txn.query_with(&Criteria::new()), Err(Error::QuotaExceeded)));
+        assert_eq!(txn.query().count(), 0);
+    }
+
+    #[test]
+    fn data_stats() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        assert_eq!(database.data_stats().unwrap(), None);
+        let mut txn = database.new_transaction().unwrap();
+        for x in 0..100 {
+            txn.add_row(&[x, x % 5, 7]).unwrap();
+        }
+        txn.commit().unwrap();
+        database.compact().unwrap();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[200, 0, 0]).unwrap();
+        txn.commit().unwrap();
+        let stats = database.data_stats().unwrap().unwrap();
+        assert_eq!((stats.horizon, stats.num_rows), (2, 100));
+        let summary: Vec<_> = stats.dimensions.iter().chain(&stats.values)
+            .map(|column| (column.name.as_str(), column.distinct_values, column.min(), column.max()))
+            .collect();
+        assert_eq!(summary, vec![("x", 100, Some(0), Some(99)), ("y", 5, Some(0), Some(4)), ("value", 1, Some(7), Some(7))]);
+        assert_eq!(stats.dimensions[0].quantiles, vec![0, 9, 19, 29, 39, 49, 59, 69, 79, 89, 99]);
+    }
+
+    #[test]
+    fn export_partitions() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(&[[1, 1, 10], [5, 2, 50], [25, 1, 250], [26, 0, 260], [48, 0, 480]]).unwrap();
+        txn.commit().unwrap();
+
+        /* Interrupted after writing the first partition, but before recording it */
+        let dest = FaultyBackend::default();
+        dest.renames_before_failure.set(Some(2));
+        assert!(database.export_partitions(&dest, 20).is_err());
+        dest.renames_before_failure.set(None);
+
+        /* Rows committed since the export began are left out when it is resumed */
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[3, 3, 30]).unwrap();
+        txn.commit().unwrap();
+        assert!(matches!(database.export_partitions(&dest, 10), Err(Error::DataError)));
+        let manifest = database.export_partitions(&dest, 20).unwrap();
+        assert!(manifest.complete);
+        assert_eq!(manifest.horizon, 2);
+        let partitions: Vec<_> = manifest.partitions.iter()
+            .map(|partition| (partition.start, partition.file.as_str(), partition.num_rows))
+            .collect();
+        assert_eq!(partitions, vec![
+            (0, "part-00000000000000000000.csv", 2),
+            (20, "part-00000000000000000020.csv", 2),
+            (40, "part-00000000000000000040.csv", 1)
+        ]);
+        assert_eq!(dest.read("part-00000000000000000000.csv").unwrap(), b"x,y,value\n1,1,10\n5,2,50\n");
+        assert_eq!(dest.read("part-00000000000000000020.csv").unwrap(), b"x,y,value\n25,1,250\n26,0,260\n");
+        assert_eq!(database.export_partitions(&dest, 20).unwrap(), manifest);
+    }
+
+    #[test]
+    fn diff() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(&[[1, 1, 10], [2, 2, 20]]).unwrap();
+        txn.commit().unwrap();
+        let first = database.query_snapshot().horizon();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(&[[2, 2, 21], [30, 3, 30]]).unwrap();
+        txn.commit().unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(&[[2, 2, 22], [4, 4, 40]]).unwrap();
+        txn.commit().unwrap();
+        let last = database.query_snapshot().horizon();
+
+        let rows: Vec<_> = database.diff(first, last, &Criteria::new()).unwrap()
+            .map(|r| (r.values_array, r.txn_id)).collect();
+        assert_eq!(rows, vec![(vec![2, 2, 22], 3), (vec![4, 4, 40], 3), (vec![30, 3, 30], 2)]);
+        let rows: Vec<_> = database.diff(first, first + 1, &Criteria::new().range(0, 0..=9)).unwrap()
+            .map(|r| r.values_array).collect();
+        assert_eq!(rows, vec![vec![2, 2, 21]]);
+        assert_eq!(database.diff(last, last, &Criteria::new()).unwrap().count(), 0);
+        assert_eq!(database.diff(0, first, &Criteria::new()).unwrap().count(), 2);
+
+        assert!(matches!(database.diff(last, first, &Criteria::new()), Err(Error::OutOfBounds)));
+        assert!(matches!(database.diff(first, last + 1, &Criteria::new()), Err(Error::OutOfBounds)));
+        assert!(matches!(database.diff(first, last, &Criteria::new().range(2, 0..=0)), Err(Error::SchemaError)));
+    }
+
+    #[test]
+    fn parallel_import() {
+        let backend = Rc::new(MemoryBackend::new());
+        let mut database = Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap();
+        let leases = database.begin_import(2, 10).unwrap();
+        assert_eq!(leases.iter().map(|lease| lease.segment_id(0).unwrap()).collect::<Vec<_>>(), vec![(1, 1), (1, 11)]);
+        assert_eq!(ImportLease::from_token(&leases[1].to_token()).unwrap(), leases[1]);
+        assert!(matches!(leases[0].write_segment(backend.as_ref(), 10, &[]), Err(Error::OutOfBounds)));
+
+        /* Each loader writes through its own backend, as another process would */
+        for (loader_no, lease) in leases.iter().enumerate() {
+            let loader_backend = backend.clone();
+            for n in 0..2 {
+                let mut encoder = SegmentEncoder::new(&database.schema);
+                for i in 0..10 {
+                    encoder.add_row(&[i, loader_no * 10 + n, 100 * loader_no + i]).unwrap();
+                }
+                encoder.add_row(&[50, 50, 100 * loader_no + n]).unwrap();
+                lease.write_segment(loader_backend.as_ref(), n as SegmentNum, &encoder.encode().unwrap()).unwrap();
+            }
+        }
+
+        /* Opening the database meanwhile keeps the import's segments, and doesn't reuse its id */
+        drop(database);
+        let mut database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        assert_eq!(database.stats().num_segments, 0);
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[60, 60, 1]).unwrap();
+        txn.commit().unwrap();
+
+        assert_eq!(database.commit_import(1).unwrap(), vec![(1, 0), (1, 2), (1, 11), (1, 12)]);
+        let txn = database.new_transaction().unwrap();
+        let rows: Vec<_> = txn.query().map(|row| row.values_array).collect();
+        assert_eq!(rows.len(), 42);
+        assert!(rows.contains(&vec![50, 50, 101]));
+        txn.rollback();
+        assert!(import::list(backend.as_ref()).unwrap().is_empty());
+        assert!(matches!(database.commit_import(1), Err(Error::IoError)));
+
+        /* An aborted import leaves nothing behind */
+        let lease = database.begin_import(1, 1).unwrap().remove(0);
+        lease_segment(&database, &lease, 0, backend.as_ref());
+        let num_files = backend.list().unwrap().len();
+        database.abort_import(lease.txn_id).unwrap();
+        assert_eq!(backend.list().unwrap().len(), num_files - 2);
+
+        /* An import interrupted while being committed is discarded when the database is opened */
+        let leases = database.begin_import(1, 2).unwrap();
+        for n in 0..2 {
+            lease_segment(&database, &leases[0], n, backend.as_ref());
+        }
+        let seg_id = leases[0].segment_id(1).unwrap();
+        backend.rename(&get_segment_name(seg_id, false), &get_segment_name(seg_id, true)).unwrap();
+        drop(database);
+        let database = Database::open_with_backend(Box::new(backend.clone())).unwrap();
+        assert_eq!(database.stats().num_segments, 5);
+        assert!(import::list(backend.as_ref()).unwrap().is_empty());
+        assert!(!backend.list().unwrap().iter().any(|name| name.starts_with(&format!("{:08x}.", leases[0].txn_id))));
+    }
 
-        Some(rc)
+    fn lease_segment(database: &Database, lease: &ImportLease, n: SegmentNum, backend: &dyn Backend) {
+        let mut encoder = SegmentEncoder::new(&database.schema);
+        encoder.add_row(&[80, n as Datum, 8]).unwrap();
+        lease.write_segment(backend, n, &encoder.encode().unwrap()).unwrap();
     }
 }
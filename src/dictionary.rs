@@ -0,0 +1,100 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::rc::Rc;
+
+use log::{debug, error};
+
+use crate::{DictionaryId, Error};
+use crate::backend::Backend;
+use crate::Error::DataError;
+use crate::storage::{decode_dictionary_name, get_dictionary_name};
+
+/* Small blocks gain most from a dictionary, and a small one is quick to load */
+#[cfg(feature = "zstd")]
+const MAX_DICTIONARY_SIZE: usize = 16 * 1024;
+
+/**
+ * A zstd dictionary trained on sample blocks of a database, so that small blocks, which have
+ * little data of their own to find patterns in, compress much better.  Each dictionary is saved
+ * in its own file and never changes or is removed, since any segment written while it was
+ * current may refer to it.
+ */
+pub(crate) struct Dictionary {
+    pub id: DictionaryId,
+    pub data: Vec<u8>
+}
+
+impl Dictionary {
+    /**
+     * Train a dictionary with the given id from sample block payloads, and save it.
+     */
+    pub(crate) fn train(backend: &dyn Backend, id: DictionaryId, samples: &[Vec<u8>]) -> Result<Dictionary, Error> {
+        let data = train_data(samples)?;
+        backend.write(&get_dictionary_name(id), &data)?;
+        debug!("Trained dictionary {:?} of {:?} bytes from {:?} samples", id, data.len(), samples.len());
+        Ok(Dictionary { id, data })
+    }
+
+    pub(crate) fn load(backend: &dyn Backend, id: DictionaryId) -> Result<Dictionary, Error> {
+        let data = backend.read(&get_dictionary_name(id))?;
+        Ok(Dictionary { id, data })
+    }
+
+    /**
+     * The id of the most recently trained dictionary in the backend, if there is one.
+     */
+    pub(crate) fn latest_id(backend: &dyn Backend) -> Result<Option<DictionaryId>, Error> {
+        Ok(backend.list()?.iter().filter_map(|name| decode_dictionary_name(name)).max())
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn train_data(samples: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    zstd::dict::from_samples(samples, MAX_DICTIONARY_SIZE).map_err(|err| {
+        error!("Failed to train dictionary from {:?} samples: {err}", samples.len());
+        DataError
+    })
+}
+
+#[cfg(not(feature = "zstd"))]
+fn train_data(_samples: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    error!("Training a dictionary needs the zstd feature");
+    Err(DataError)
+}
+
+/**
+ * The dictionaries loaded so far, shared by all the segments of a database.
+ */
+#[derive(Default)]
+pub(crate) struct Dictionaries {
+    loaded: RefCell<HashMap<DictionaryId, Rc<Dictionary>>>
+}
+
+impl Dictionaries {
+    /**
+     * Get a dictionary, loading it from the backend the first time it is needed.
+     */
+    pub(crate) fn get(&self, backend: &dyn Backend, id: DictionaryId) -> Result<Rc<Dictionary>, Error> {
+        if let Some(dictionary) = self.loaded.borrow().get(&id) {
+            return Ok(dictionary.clone());
+        }
+
+        let dictionary = Rc::new(Dictionary::load(backend, id)?);
+        self.add(dictionary.clone());
+        Ok(dictionary)
+    }
+
+    pub(crate) fn add(&self, dictionary: Rc<Dictionary>) {
+        self.loaded.borrow_mut().insert(dictionary.id, dictionary);
+    }
+
+    /**
+     * Roughly how many bytes the loaded dictionaries occupy in memory.
+     */
+    pub(crate) fn memory_size(&self) -> usize {
+        self.loaded.borrow().values()
+            .map(|dictionary| size_of::<Dictionary>() + dictionary.data.capacity())
+            .sum()
+    }
+}
@@ -0,0 +1,267 @@
+use std::io::{Read, Write};
+
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use flate2::Crc;
+use log::{debug, error, info};
+
+use crate::{Datum, Error};
+use crate::Error::DataError;
+use crate::labels::Labels;
+use crate::schema::Schema;
+use crate::storage::read_bytes;
+use crate::transaction::Transaction;
+
+/**
+ * A dump is a single stream containing a database's schema and all of its visible rows, in a
+ * layout that does not depend on the segment format.  It is intended for migrating data between
+ * versions of MatDB whose on-disk formats are incompatible.
+ *
+ * The layout is:
+ *
+ *   - `MATDB-DUMP` magic and a `u16` dump version
+ *   - `u32` schema length, the schema as JSON, and a `u32` CRC of the schema
+ *   - From version 2, the strings of the label dimensions in the same way: `u32` length, JSON,
+ *     and a `u32` CRC
+ *   - Any number of row frames: `u32` row count, `u16` row width, the row values as `u64`s,
+ *     and a `u32` CRC of the frame contents
+ *   - An end frame: a `u32` row count of zero, followed by the `u64` total number of rows
+ *
+ * All integers are big-endian.
+ */
+const DUMP_MAGIC: &[u8] = "MATDB-DUMP".as_bytes();
+const DUMP_VERSION: u16 = 2;
+/* Dumps before labels, which can still be restored */
+const DUMP_VERSION_WITHOUT_LABELS: u16 = 1;
+const ROWS_PER_FRAME: usize = 10000;
+/* Far more than any frame written by `write_frame`, so a corrupt row count is caught before it is read */
+const MAX_FRAME_BYTES: usize = 64 << 20;
+const ROWS_PER_FLUSH: usize = 1000000;
+
+pub(crate) fn write_dump<W: Write>(txn: &Transaction, dest: &mut W) -> Result<u64, Error> {
+    dest.write_all(DUMP_MAGIC)?;
+    dest.write_u16::<BE>(DUMP_VERSION)?;
+
+    write_section(dest, &serde_json::to_string(&txn.database.schema)?)?;
+    write_section(dest, &txn.database.labels.to_json()?)?;
+
+    let mut total_rows = 0;
+    let mut frame: Vec<Vec<Datum>> = Vec::with_capacity(ROWS_PER_FRAME);
+    let mut scan = txn.query();
+    for row in scan.by_ref() {
+        if !frame.is_empty() && frame[0].len() != row.values_array.len() {
+            write_frame(dest, &frame)?;
+            frame.clear();
+        }
+        frame.push(row.values_array);
+        total_rows += 1;
+        if frame.len() >= ROWS_PER_FRAME {
+            write_frame(dest, &frame)?;
+            frame.clear();
+        }
+    }
+    scan.check()?;
+    if !frame.is_empty() {
+        write_frame(dest, &frame)?;
+    }
+
+    dest.write_u32::<BE>(0)?;
+    dest.write_u64::<BE>(total_rows)?;
+
+    info!("Dumped {:?} rows", total_rows);
+    Ok(total_rows)
+}
+
+fn write_section<W: Write>(dest: &mut W, json: &str) -> Result<(), Error> {
+    dest.write_u32::<BE>(json.len() as u32)?;
+    dest.write_all(json.as_bytes())?;
+    dest.write_u32::<BE>(checksum(json.as_bytes()))?;
+    Ok(())
+}
+
+fn write_frame<W: Write>(dest: &mut W, rows: &[Vec<Datum>]) -> Result<(), Error> {
+    let width = rows[0].len();
+    let mut bytes: Vec<u8> = Vec::with_capacity(rows.len() * width * 8);
+    for row in rows {
+        for &val in row {
+            bytes.extend((val as u64).to_be_bytes());
+        }
+    }
+
+    dest.write_u32::<BE>(rows.len() as u32)?;
+    dest.write_u16::<BE>(width as u16)?;
+    dest.write_all(&bytes)?;
+    dest.write_u32::<BE>(checksum(&bytes))?;
+    debug!("Wrote dump frame of {:?} rows", rows.len());
+    Ok(())
+}
+
+/**
+ * Read the schema at the start of a dump, and the labels that go with it.
+ */
+pub(crate) fn read_dump_schema<R: Read>(src: &mut R) -> Result<(Schema, Labels), Error> {
+    let mut magic = [0; DUMP_MAGIC.len()];
+    src.read_exact(&mut magic)?;
+    if magic != DUMP_MAGIC {
+        error!("Not a dump file");
+        return Err(DataError);
+    }
+
+    let version = src.read_u16::<BE>()?;
+    if version > DUMP_VERSION {
+        error!("Dump version {version:?} is newer than supported");
+        return Err(Error::UnsupportedVersion { found: version, supported: DUMP_VERSION });
+    }
+    if version < DUMP_VERSION_WITHOUT_LABELS {
+        error!("Unsupported dump version {version:?}");
+        return Err(DataError);
+    }
+
+    let schema: Schema = serde_json::from_slice(&read_section(src, "schema")?)?;
+    let num_dims = schema.dimensions.len();
+    let labels = if version == DUMP_VERSION_WITHOUT_LABELS {
+        Labels::new(num_dims)
+    } else {
+        Labels::from_names(num_dims, serde_json::from_slice(&read_section(src, "labels")?)?)?
+    };
+    Ok((schema, labels))
+}
+
+fn read_section<R: Read>(src: &mut R, what: &str) -> Result<Vec<u8>, Error> {
+    let len = src.read_u32::<BE>()? as usize;
+    let json = read_bytes(src, len)?;
+    let expected = src.read_u32::<BE>()?;
+    if checksum(&json) != expected {
+        error!("Checksum mismatch in dump {what}");
+        return Err(DataError);
+    }
+    Ok(json)
+}
+
+pub(crate) fn read_dump_rows<R: Read>(src: &mut R, txn: &mut Transaction) -> Result<u64, Error> {
+    let mut total_rows = 0;
+    let mut rows_since_flush = 0;
+
+    loop {
+        let num_rows = src.read_u32::<BE>()? as usize;
+        if num_rows == 0 {
+            break;
+        }
+        let width = src.read_u16::<BE>()? as usize;
+        let schema = &txn.database.schema;
+        let num_columns = schema.dimensions.len() + schema.values.len();
+        if width != num_columns {
+            error!("Dump frame after row {total_rows:?} has rows of width {width:?}, but the schema has {num_columns:?} columns");
+            return Err(DataError);
+        }
+
+        let frame_size = num_rows.checked_mul(width * 8).filter(|&size| size <= MAX_FRAME_BYTES);
+        let Some(frame_size) = frame_size else {
+            error!("Dump frame after row {total_rows:?} has too many rows: {num_rows:?}");
+            return Err(DataError);
+        };
+        let bytes = read_bytes(src, frame_size)?;
+        let expected = src.read_u32::<BE>()?;
+        if checksum(&bytes) != expected {
+            error!("Checksum mismatch in dump frame after row {total_rows:?}");
+            return Err(DataError);
+        }
+
+        let mut row: Vec<Datum> = Vec::with_capacity(width);
+        for chunk in bytes.chunks_exact(width * 8) {
+            row.clear();
+            for val_bytes in chunk.chunks_exact(8) {
+                let val = u64::from_be_bytes(val_bytes.try_into().unwrap());
+                row.push(val as Datum);
+            }
+            txn.add_row(&row)?;
+        }
+
+        total_rows += num_rows as u64;
+        rows_since_flush += num_rows;
+        if rows_since_flush >= ROWS_PER_FLUSH {
+            txn.flush()?;
+            rows_since_flush = 0;
+        }
+    }
+
+    let expected_rows = src.read_u64::<BE>()?;
+    if expected_rows != total_rows {
+        error!("Dump contained {total_rows:?} rows but expected {expected_rows:?}");
+        return Err(DataError);
+    }
+
+    info!("Restored {:?} rows", total_rows);
+    Ok(total_rows)
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod dump_tests {
+    use crate::{Database, Error};
+    use crate::test_util::{create_database, temp_path};
+
+    #[test]
+    fn round_trip() {
+        let mut database = create_database("dump-round-trip");
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..30 {
+            txn.add_row(&[i, i % 7, i * 100]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let mut dump = Vec::new();
+        assert_eq!(database.dump(&mut dump).unwrap(), 30);
+
+        let restore_path = temp_path("dump-round-trip-restored");
+        let mut restored = Database::restore(&mut dump.as_slice(), restore_path.as_path()).unwrap();
+        assert_eq!(restored.schema.dimensions.len(), 2);
+
+        let txn = restored.new_transaction().unwrap();
+        let rows: Vec<_> = txn.query().map(|r| r.values_array).collect();
+        assert_eq!(rows.len(), 30);
+        assert!(rows.contains(&vec![13, 6, 1300]));
+    }
+
+    #[test]
+    fn corrupted_frame() {
+        let mut database = create_database("dump-corrupted");
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[1, 2, 3]).unwrap();
+        txn.commit().unwrap();
+
+        let mut dump = Vec::new();
+        database.dump(&mut dump).unwrap();
+        let len = dump.len();
+        dump[len - 20] ^= 0xFF;
+
+        let restore_path = temp_path("dump-corrupted-restored");
+        assert!(Database::restore(&mut dump.as_slice(), restore_path.as_path()).is_err());
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut database = create_database("dump-oversized");
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[1, 2, 3]).unwrap();
+        txn.commit().unwrap();
+
+        /* The frame holds a row count, a width, 24 bytes of values and a CRC, then the end frame */
+        let mut dump = Vec::new();
+        database.dump(&mut dump).unwrap();
+        let frame = dump.len() - 12 - 28 - 6;
+        let mut huge = dump.clone();
+        huge[frame..frame + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let restore_path = temp_path("dump-oversized-restored");
+        assert!(matches!(Database::restore(&mut huge.as_slice(), restore_path.as_path()), Err(Error::DataError)));
+
+        let mut wide = dump;
+        wide[frame + 4..frame + 6].copy_from_slice(&u16::MAX.to_be_bytes());
+        let restore_path = temp_path("dump-wide-restored");
+        assert!(matches!(Database::restore(&mut wide.as_slice(), restore_path.as_path()), Err(Error::DataError)));
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+
+use crate::{Datum, Error};
+use crate::block::Block;
+use crate::schema::Schema;
+use crate::segment::Segment;
+
+/**
+ * Builds a complete segment file in memory from rows, without a database or a filesystem.
+ *
+ * This is intended for devices that collect data locally and ship it to a central MatDB,
+ * which can adopt the result as a segment of its own.  The rows are chunked and encoded by the
+ * same code the database uses, so the output is exactly what a transaction would have written.
+ */
+pub struct SegmentEncoder<'s> {
+    schema: &'s Schema,
+    blocks: BTreeMap<Vec<Datum>, Block>
+}
+
+impl<'s> SegmentEncoder<'s> {
+    pub fn new(schema: &'s Schema) -> SegmentEncoder<'s> {
+        SegmentEncoder { schema, blocks: BTreeMap::new() }
+    }
+
+    /**
+     * Add a row, replacing any earlier row at the same point.
+     *
+     * Fails with `ArityMismatch` if the row doesn't match the schema, or `OutOfBounds` if a
+     * dimension value lies outside the bounds declared in it.
+     */
+    pub fn add_row(&mut self, values: &[Datum]) -> Result<(), Error> {
+        self.schema.check_row(values)?;
+        let key = self.schema.get_chunk_key(values).key_values;
+        self.blocks.entry(key)
+            .or_insert_with(|| Block::new(self.schema.dimensions.len()))
+            .add_row(values);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /**
+     * Encode all the rows added so far as the contents of a segment file.
+     */
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let blocks: Vec<&Block> = self.blocks.values().collect();
+        let mut segment = Segment::new((0, 0));
+        segment.ordered = true;
+        segment.cell_order = self.schema.cell_order;
+        segment.encode(&blocks)
+    }
+}
+
+#[cfg(test)]
+mod encoder_tests {
+    use std::rc::Rc;
+
+    use crate::{CellOrder, Dimension, Schema, Value, ValueType};
+    use crate::backend::{Backend, MemoryBackend};
+    use crate::block::Block;
+    use crate::dictionary::Dictionaries;
+    use crate::segment::Segment;
+    use super::SegmentEncoder;
+
+    #[test]
+    fn encode() {
+        let schema = Schema {
+            dimensions: vec![
+                Dimension { name: String::from("x"), chunk_size: 10, bounds: Some((0, 99)), time: None, labels: false },
+            ],
+            values: vec![
+                Value { name: String::from("value"), value_type: ValueType::U64 }
+            ],
+            cell_order: CellOrder::RowMajor
+        };
+        let mut encoder = SegmentEncoder::new(&schema);
+        assert!(encoder.is_empty());
+        for x in [25, 3, 14, 7] {
+            encoder.add_row(&[x, x * 10]).unwrap();
+        }
+        assert!(encoder.add_row(&[100, 0]).is_err());
+
+        let backend = MemoryBackend::new();
+        backend.write("00000001.00000000", &encoder.encode().unwrap()).unwrap();
+        let segment = Segment::load(&backend, (1, 0)).unwrap();
+        assert_eq!(segment.block_info.len(), 3);
+
+        let rows: Vec<_> = (0..3)
+            .flat_map(|n| Block::iter(&Rc::new(segment.load_one_block(&backend, &Dictionaries::default(), n).unwrap())).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(rows, vec![vec![3, 30], vec![7, 70], vec![14, 140], vec![25, 250]]);
+    }
+}
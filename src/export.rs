@@ -0,0 +1,149 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{Datum, Error, TransactionId};
+use crate::backend::Backend;
+use crate::cache::CacheHint;
+use crate::database::Database;
+use crate::Error::DataError;
+use crate::query::Criteria;
+
+pub const EXPORT_MANIFEST_FILENAME: &str = "manifest.json";
+
+/**
+ * One file written by `Database::export_partitions`, holding the rows whose first dimension is
+ * from `start` up to but not including `start` plus the partition size.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExportPartition {
+    pub start: Datum,
+    pub file: String,
+    pub num_rows: u64
+}
+
+/**
+ * The manifest of an export by `Database::export_partitions`, saved alongside the files.  The
+ * export is of the data committed before `horizon`.  Partitions are listed as they are
+ * finished, and `complete` is set once all of them are.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExportManifest {
+    pub horizon: TransactionId,
+    pub partition_size: Datum,
+    pub partitions: Vec<ExportPartition>,
+    pub complete: bool
+}
+
+pub(crate) fn export(database: &Database, dest: &dyn Backend, partition_size: Datum) -> Result<ExportManifest, Error> {
+    if partition_size == 0 {
+        error!("Can't export in partitions of size 0");
+        return Err(DataError);
+    }
+
+    let mut manifest = match load_manifest(dest)? {
+        Some(manifest) if manifest.partition_size != partition_size || manifest.horizon > database.next_transaction_id => {
+            error!("Destination holds another export, with partition size {:?} and horizon {:?}",
+                manifest.partition_size, manifest.horizon);
+            return Err(DataError);
+        },
+        Some(manifest) => manifest,
+        None => {
+            let manifest = ExportManifest {
+                horizon: database.next_transaction_id,
+                partition_size,
+                partitions: Vec::new(),
+                complete: false
+            };
+            save_manifest(dest, &manifest)?;
+            manifest
+        }
+    };
+    if manifest.complete {
+        return Ok(manifest);
+    }
+
+    /* Every partition overlapping a chunk with a block in it may have rows */
+    let chunk_size = database.schema.dimensions[0].chunk_size;
+    let done: BTreeSet<Datum> = manifest.partitions.iter().map(|partition| partition.start).collect();
+    let mut starts = BTreeSet::new();
+    for stats in database.block_stats()? {
+        if stats.block_id.0 >= manifest.horizon {
+            continue;
+        }
+        let chunk_start = stats.chunk[0];
+        let chunk_end = chunk_start.saturating_add(chunk_size - 1);
+        for partition_no in chunk_start / partition_size..=chunk_end / partition_size {
+            starts.insert(partition_no * partition_size);
+        }
+    }
+
+    for start in starts.difference(&done) {
+        let end = start.saturating_add(partition_size - 1);
+        let file = format!("part-{start:020}.csv");
+        let Some(num_rows) = write_partition(database, dest, manifest.horizon, *start..=end, &file)? else {
+            continue;
+        };
+        manifest.partitions.push(ExportPartition { start: *start, file, num_rows });
+        save_manifest(dest, &manifest)?;
+    }
+
+    manifest.partitions.sort_by_key(|partition| partition.start);
+    manifest.complete = true;
+    save_manifest(dest, &manifest)?;
+    info!("Exported {:?} partitions", manifest.partitions.len());
+    Ok(manifest)
+}
+
+/**
+ * Write the rows in a range of the first dimension as CSV, with a header of the column names.
+ * Returns the number of rows, or `None` if there were none and no file was written.
+ */
+fn write_partition(
+    database: &Database,
+    dest: &dyn Backend,
+    horizon: TransactionId,
+    range: std::ops::RangeInclusive<Datum>,
+    file: &str
+) -> Result<Option<u64>, Error> {
+    let schema = &database.schema;
+    let mut data = Vec::new();
+    let names: Vec<&str> = schema.dimensions.iter().map(|dim| dim.name.as_str())
+        .chain(schema.values.iter().map(|value| value.name.as_str()))
+        .collect();
+    writeln!(data, "{}", names.join(","))?;
+
+    let mut num_rows = 0;
+    let mut scan = database.diff(0, horizon, &Criteria::new().range(0, range))?.cache_hint(CacheHint::OneShot);
+    for row in scan.by_ref() {
+        let fields: Vec<String> = row.values_array.iter().map(|value| value.to_string()).collect();
+        writeln!(data, "{}", fields.join(","))?;
+        num_rows += 1;
+    }
+    scan.check()?;
+    if num_rows == 0 {
+        return Ok(None);
+    }
+
+    let temp_filename = format!("{file}.tmp");
+    dest.write(&temp_filename, &data)?;
+    dest.rename(&temp_filename, file)?;
+    Ok(Some(num_rows))
+}
+
+fn load_manifest(dest: &dyn Backend) -> Result<Option<ExportManifest>, Error> {
+    if !dest.exists(EXPORT_MANIFEST_FILENAME) {
+        return Ok(None);
+    }
+    let json = dest.read(EXPORT_MANIFEST_FILENAME)?;
+    Ok(Some(serde_json::from_slice(&json)?))
+}
+
+fn save_manifest(dest: &dyn Backend, manifest: &ExportManifest) -> Result<(), Error> {
+    let temp_filename = format!("{EXPORT_MANIFEST_FILENAME}.tmp");
+    dest.write(&temp_filename, serde_json::to_string(manifest)?.as_bytes())?;
+    dest.rename(&temp_filename, EXPORT_MANIFEST_FILENAME)?;
+    Ok(())
+}
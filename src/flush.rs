@@ -0,0 +1,115 @@
+use log::debug;
+
+/* Assumed until something has been written, so the first automatic flush comes early, not late */
+const INITIAL_COMPRESSION_RATIO: f64 = 1.0;
+
+/**
+ * How a transaction's flushes have gone, from `Transaction::flush_stats`.
+ *
+ * `bytes_flushed` is the size of the rows flushed, at eight bytes per column, and
+ * `bytes_written` the size of the segment files written for them.  The compression ratio is
+ * the second divided by the first, and sets the `threshold` of unsaved rows, in the same
+ * units, at which the transaction next flushes automatically; it is `None` without automatic
+ * flushing.  See `Database::set_auto_flush`.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlushStats {
+    pub flushes: usize,
+    pub auto_flushes: usize,
+    pub bytes_flushed: u64,
+    pub bytes_written: u64,
+    pub blocks_written: usize,
+    pub compression_ratio: f64,
+    pub threshold: Option<u64>
+}
+
+impl FlushStats {
+    /**
+     * The average size of the blocks written, in bytes of segment file.
+     */
+    pub fn mean_block_size(&self) -> Option<f64> {
+        (self.blocks_written > 0).then(|| self.bytes_written as f64 / self.blocks_written as f64)
+    }
+}
+
+/**
+ * Decides when a transaction flushes automatically, so that its segments come out close to a
+ * target size.  How many rows fit in a segment depends on the schema and how well the data
+ * compresses, so the compression ratio achieved by each flush is used to tune the threshold
+ * for the next.
+ */
+pub(crate) struct FlushSizer {
+    target: Option<u64>,
+    unsaved_bytes: u64,
+    stats: FlushStats
+}
+
+impl FlushSizer {
+    /**
+     * A sizer aiming for segments of `target` bytes, starting from the compression ratio the
+     * database has achieved so far, if known.
+     */
+    pub(crate) fn new(target: Option<u64>, compression_ratio: Option<f64>) -> FlushSizer {
+        let mut sizer = FlushSizer {
+            target,
+            unsaved_bytes: 0,
+            stats: FlushStats {
+                flushes: 0,
+                auto_flushes: 0,
+                bytes_flushed: 0,
+                bytes_written: 0,
+                blocks_written: 0,
+                compression_ratio: compression_ratio.unwrap_or(INITIAL_COMPRESSION_RATIO),
+                threshold: None
+            }
+        };
+        sizer.update_threshold();
+        sizer
+    }
+
+    pub(crate) fn stats(&self) -> FlushStats {
+        self.stats.clone()
+    }
+
+    /**
+     * Count the bytes of a row added to the transaction.
+     */
+    pub(crate) fn add(&mut self, bytes: u64) {
+        self.unsaved_bytes += bytes;
+    }
+
+    /**
+     * Whether enough rows are unsaved to fill a segment of the target size.
+     */
+    pub(crate) fn is_due(&self) -> bool {
+        self.stats.threshold.is_some_and(|threshold| self.unsaved_bytes >= threshold)
+    }
+
+    pub(crate) fn record_auto_flush(&mut self) {
+        self.stats.auto_flushes += 1;
+    }
+
+    /**
+     * Learn from a flush that saved all the unsaved rows.
+     */
+    pub(crate) fn record_flush(&mut self, bytes_written: u64, blocks_written: usize) {
+        if self.unsaved_bytes == 0 {
+            return;
+        }
+
+        self.stats.flushes += 1;
+        self.stats.bytes_flushed += self.unsaved_bytes;
+        self.stats.bytes_written += bytes_written;
+        self.stats.blocks_written += blocks_written;
+        self.stats.compression_ratio = self.stats.bytes_written as f64 / self.stats.bytes_flushed as f64;
+        self.unsaved_bytes = 0;
+        self.update_threshold();
+        debug!("Flushed at compression ratio {:.3}, next automatic flush at {:?} bytes",
+            self.stats.compression_ratio, self.stats.threshold);
+    }
+
+    fn update_threshold(&mut self) {
+        let ratio = self.stats.compression_ratio.max(f64::EPSILON);
+        self.stats.threshold = self.target.map(|target| ((target as f64 / ratio) as u64).max(1));
+    }
+}
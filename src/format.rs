@@ -0,0 +1,265 @@
+use std::io::{BufRead, BufReader, Read, Seek, Write};
+use std::rc::Rc;
+
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use log::error;
+
+use crate::{DictionaryId, Error};
+use crate::Error::DataError;
+use crate::compression::{compress_with_dictionary, Compression, decompress_with_dictionary, ZSTD_DICTIONARY_ID};
+use crate::dictionary::Dictionary;
+use crate::pool::{hash_payload, PoolHash};
+use crate::storage::{read_bytes, read_tag, Tag, TAG_LENGTH, write_tag};
+
+/**
+ * Version of the segment file layout.
+ *
+ * Version 0 is the original, unversioned layout: a sequence of tagged zstd streams with no
+ * header, in which the end of each stream can only be found by decompressing it.
+ *
+ * Version 1 adds a header tag and version number at the start of the file, prefixes each
+ * compressed section with its length, and stores missing values in blocks as a bitmap.
+ *
+ * Version 2 records the number of rows and cells in each block in the segment info, so block
+ * occupancy can be reported without loading the blocks.
+ *
+ * Version 3 starts each section with the id of its compression, so builds without zstd can
+ * write segments; see `Compression`.  Block sections compressed with a zstd dictionary also
+ * record the dictionary's id, and block sections saved in the pool record only the hash of their
+ * payload.
+ *
+ * Version 4 records in the segment info whether the blocks are in scan order, sorted by chunk
+ * and then by first row, as a flush saves them.
+ *
+ * Version 5 records in the segment info the criteria of any rows deleted by the segment's
+ * transaction; see `Transaction::delete_where`.
+ *
+ * Version 6 saves every value column of a block, each after the last, where earlier versions
+ * saved only the first.
+ *
+ * Version 7 records in the segment info the SHA-256 hash of each block's uncompressed payload,
+ * for comparing databases with `Database::verify_against`.
+ *
+ * Version 8 records which rows of a block have each value, so that a row can be saved without
+ * some of them as a partial version; see `Transaction::update`.  The bitmap at the start of
+ * the values marks the cells holding a row, rather than those with a first value.
+ *
+ * Version 9 records in the segment info how many rows of each block have each value, for the
+ * column statistics of `Database::stats`.
+ *
+ * Version 10 gives the number of value columns in a block before the first of them, and
+ * prefixes each with its length in bytes, so a scan selecting some values can skip the others
+ * without decoding them; see `Scan::select_values`.
+ *
+ * Version 11 records the order of a block's cells after its dimensions, so they can be saved
+ * in tiles rather than rows; see `CellOrder`.
+ */
+pub(crate) type FormatVersion = u16;
+
+pub(crate) const CURRENT_FORMAT_VERSION: FormatVersion = 11;
+
+/* Recorded for block sections saved in the pool, followed by the hash of their payload */
+const POOLED_SECTION_ID: u8 = 5;
+
+#[cfg(feature = "zstd")]
+const COMPRESSION_LEVEL: i32 = 1;
+
+pub(crate) fn write_header<W: Write>(file: &mut W, version: FormatVersion) -> Result<(), Error> {
+    if version == 0 {
+        return Ok(());
+    }
+    write_tag(file, Tag::Header)?;
+    file.write_u16::<BE>(version)?;
+    Ok(())
+}
+
+/**
+ * Read the format version from the start of a segment file.  Files without a header are
+ * version 0, and are left positioned at their first tag.
+ */
+pub(crate) fn read_header<R: Read + Seek>(src: &mut BufReader<R>) -> Result<FormatVersion, Error> {
+    if read_tag(src)? != Tag::Header {
+        src.seek_relative(-(TAG_LENGTH as i64))?;
+        return Ok(0);
+    }
+
+    let version = src.read_u16::<BE>()?;
+    if version > CURRENT_FORMAT_VERSION {
+        error!("Segment format version {version:?} is newer than supported");
+        return Err(Error::UnsupportedVersion { found: version, supported: CURRENT_FORMAT_VERSION });
+    }
+    Ok(version)
+}
+
+/**
+ * Compress and write a section of a segment file.  Versions before 3 are always compressed
+ * with zstd, whatever `compression` is.  From version 3, zstd sections are compressed with the
+ * dictionary if there is one, and record its id.
+ */
+pub(crate) fn write_section<W: Write>(
+    file: &mut W,
+    version: FormatVersion,
+    compression: Compression,
+    dictionary: Option<&Dictionary>,
+    payload: &[u8]
+) -> Result<(), Error> {
+    if version == 0 {
+        write_version_0_section(file, payload)?;
+        return Ok(());
+    }
+
+    let compression = if version >= 3 { compression } else { Compression::Zstd };
+    let compressed = match dictionary {
+        Some(dictionary) if version >= 3 && compression == Compression::Zstd => {
+            file.write_u8(ZSTD_DICTIONARY_ID)?;
+            file.write_u32::<BE>(dictionary.id)?;
+            compress_with_dictionary(payload, &dictionary.data)?
+        },
+        _ => {
+            if version >= 3 {
+                file.write_u8(compression.id())?;
+            }
+            compression.compress(payload)?
+        }
+    };
+    file.write_u32::<BE>(compressed.len() as u32)?;
+    file.write_all(&compressed)?;
+    Ok(())
+}
+
+#[cfg(feature = "zstd")]
+fn write_version_0_section<W: Write>(file: &mut W, payload: &[u8]) -> Result<(), Error> {
+    let mut encoder = zstd::stream::write::Encoder::new(file, COMPRESSION_LEVEL)?;
+    encoder.write_all(payload)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn write_version_0_section<W: Write>(_file: &mut W, _payload: &[u8]) -> Result<(), Error> {
+    error!("Format version 0 needs the zstd feature");
+    Err(DataError)
+}
+
+/**
+ * What `read_section` needs for sections that refer to data outside the segment file.
+ */
+pub(crate) trait SectionResolver {
+    fn get_dictionary(&self, id: DictionaryId) -> Result<Rc<Dictionary>, Error>;
+
+    /**
+     * Read the pool object holding the block section with the given hash.
+     */
+    fn get_pooled_section(&self, hash: &PoolHash) -> Result<Vec<u8>, Error>;
+}
+
+/**
+ * For `read_section`, when reading a section that should never refer to anything outside the
+ * segment file, such as the segment info.
+ */
+pub(crate) struct NoResolver;
+
+impl SectionResolver for NoResolver {
+    fn get_dictionary(&self, id: DictionaryId) -> Result<Rc<Dictionary>, Error> {
+        error!("Unexpected use of dictionary {:?}", id);
+        Err(DataError)
+    }
+
+    fn get_pooled_section(&self, _hash: &PoolHash) -> Result<Vec<u8>, Error> {
+        error!("Unexpected pooled section");
+        Err(DataError)
+    }
+}
+
+/**
+ * Write a reference to a block section saved in the pool, in place of the section itself.
+ */
+pub(crate) fn write_pooled_section<W: Write>(file: &mut W, hash: &PoolHash) -> Result<(), Error> {
+    file.write_u8(POOLED_SECTION_ID)?;
+    file.write_all(hash)?;
+    Ok(())
+}
+
+/**
+ * Read the hash a section refers to, if it was saved in the pool, without reading the rest of
+ * the section.
+ */
+pub(crate) fn read_pooled_hash<R: BufRead>(src: &mut R, version: FormatVersion) -> Result<Option<PoolHash>, Error> {
+    if version < 3 || src.read_u8()? != POOLED_SECTION_ID {
+        return Ok(None);
+    }
+    let mut hash: PoolHash = Default::default();
+    src.read_exact(&mut hash)?;
+    Ok(Some(hash))
+}
+
+/**
+ * Read and decompress a section of a segment file, written by `write_section`, or by
+ * `write_pooled_section`, in which case the section is read from the pool and checked against
+ * its hash.
+ */
+pub(crate) fn read_section<R: BufRead>(
+    src: &mut R,
+    version: FormatVersion,
+    resolver: &dyn SectionResolver
+) -> Result<Vec<u8>, Error> {
+    if version == 0 {
+        return read_version_0_section(src);
+    }
+
+    let compression_id = if version >= 3 { src.read_u8()? } else { Compression::Zstd.id() };
+    if compression_id == POOLED_SECTION_ID {
+        let mut hash: PoolHash = Default::default();
+        src.read_exact(&mut hash)?;
+        let section = resolver.get_pooled_section(&hash)?;
+        let mut pooled_src = section.as_slice();
+        let pooled_id = pooled_src.read_u8()?;
+        if pooled_id == POOLED_SECTION_ID {
+            error!("Pooled section refers to another pooled section");
+            return Err(DataError);
+        }
+        let payload = read_section_body(&mut pooled_src, pooled_id, resolver)?;
+        if hash_payload(&payload) != hash {
+            error!("Pooled section does not match its hash");
+            return Err(DataError);
+        }
+        return Ok(payload);
+    }
+
+    read_section_body(src, compression_id, resolver)
+}
+
+fn read_section_body<R: BufRead>(
+    src: &mut R,
+    compression_id: u8,
+    resolver: &dyn SectionResolver
+) -> Result<Vec<u8>, Error> {
+    if compression_id == ZSTD_DICTIONARY_ID {
+        let dictionary = resolver.get_dictionary(src.read_u32::<BE>()?)?;
+        let length = src.read_u32::<BE>()? as usize;
+        let compressed = read_bytes(src, length)?;
+        return decompress_with_dictionary(&compressed, &dictionary.data);
+    }
+
+    let compression = Compression::from_id(compression_id)?;
+    let length = src.read_u32::<BE>()? as usize;
+    let compressed = read_bytes(src, length)?;
+    compression.decompress(&compressed)
+}
+
+/**
+ * Version 0 sections have no length, so decompress a single frame and stop there.
+ */
+#[cfg(feature = "zstd")]
+fn read_version_0_section<R: BufRead>(src: &mut R) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    let mut decoder = zstd::stream::read::Decoder::with_buffer(src)?.single_frame();
+    decoder.read_to_end(&mut payload)?;
+    Ok(payload)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn read_version_0_section<R: BufRead>(_src: &mut R) -> Result<Vec<u8>, Error> {
+    error!("Format version 0 needs the zstd feature");
+    Err(DataError)
+}
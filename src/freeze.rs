@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use log::info;
+
+use crate::database::Database;
+
+/**
+ * A database whose files won't change until the guard is dropped, from `Database::freeze`, so
+ * they can be copied or snapshotted by the filesystem in a consistent state.  The guard holds
+ * the database mutably, so nothing can be committed, compacted or flushed meanwhile, but the
+ * database can still be queried through it.
+ */
+pub struct Frozen<'db> {
+    database: &'db mut Database
+}
+
+impl<'db> Frozen<'db> {
+    pub(crate) fn new(database: &'db mut Database) -> Frozen<'db> {
+        database.frozen = true;
+        info!("Froze database in {:?}", database.path);
+        Frozen { database }
+    }
+
+    /**
+     * The database, for queries.  Slow queries aren't logged while it is frozen.
+     */
+    pub fn database(&self) -> &Database {
+        self.database
+    }
+
+    /**
+     * The directory to copy or snapshot.
+     */
+    pub fn path(&self) -> &Path {
+        &self.database.path
+    }
+}
+
+impl<'db> Drop for Frozen<'db> {
+    fn drop(&mut self) {
+        self.database.frozen = false;
+        info!("Thawed database in {:?}", self.database.path);
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, SegmentId, SegmentNum, TransactionId};
+use crate::backend::Backend;
+use crate::Error::{DataError, OutOfBounds};
+use crate::storage::{decode_import_name, decode_segment_path, get_import_name, get_segment_name};
+
+/**
+ * A range of segment numbers in the transaction of an import, granted to one loader by
+ * `Database::begin_import`.
+ *
+ * Leases are disjoint, so loaders can write their segments in parallel, even from other
+ * processes with their own `FileBackend` on the database directory, without coordinating with
+ * each other or the database.  The segments are uncommitted until `Database::commit_import`
+ * makes all of them visible at once.  A lease can be handed to a loader as a token.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ImportLease {
+    pub txn_id: TransactionId,
+    pub first_segment: SegmentNum,
+    pub num_segments: SegmentNum
+}
+
+impl ImportLease {
+    /**
+     * The id of the `n`th segment of the lease, if the lease has that many.
+     */
+    pub fn segment_id(&self, n: SegmentNum) -> Option<SegmentId> {
+        (n < self.num_segments).then(|| (self.txn_id, self.first_segment + n))
+    }
+
+    /**
+     * Write the `n`th segment of the lease, such as one encoded by a `SegmentEncoder`, to the
+     * database's backend, replacing any earlier attempt at it.  It is checked when the import
+     * is committed.  Fails with `OutOfBounds` if the lease doesn't have an `n`th segment.
+     */
+    pub fn write_segment(&self, backend: &dyn Backend, n: SegmentNum, data: &[u8]) -> Result<SegmentId, Error> {
+        let Some(seg_id) = self.segment_id(n) else {
+            error!("Lease {:?} has no segment {:?}", self, n);
+            return Err(OutOfBounds);
+        };
+        backend.write(&get_segment_name(seg_id, false), data)?;
+        debug!("Wrote segment {:?} for import", seg_id);
+        Ok(seg_id)
+    }
+
+    pub fn to_token(&self) -> String {
+        let mut token = format!("{:x}", self.txn_id);
+        write!(token, ".{:x}.{:x}", self.first_segment, self.num_segments).unwrap();
+        token
+    }
+
+    /**
+     * Parse a token made by `to_token`, failing with `DataError` if it is malformed.
+     */
+    pub fn from_token(token: &str) -> Result<ImportLease, Error> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let [txn_id, first_segment, num_segments] = parts[..] else {
+            return Err(DataError);
+        };
+        Ok(ImportLease {
+            txn_id: TransactionId::from_str_radix(txn_id, 16).map_err(|_| DataError)?,
+            first_segment: SegmentNum::from_str_radix(first_segment, 16).map_err(|_| DataError)?,
+            num_segments: SegmentNum::from_str_radix(num_segments, 16).map_err(|_| DataError)?
+        })
+    }
+}
+
+/**
+ * Grant `num_loaders` leases of `segments_per_loader` segments each in a new transaction, and
+ * save them in the import's manifest.  Segment 0, which marks the transaction as committed, is
+ * kept back for `commit`.
+ */
+pub(crate) fn begin(backend: &dyn Backend, txn_id: TransactionId, num_loaders: usize, segments_per_loader: SegmentNum) -> Result<Vec<ImportLease>, Error> {
+    let total = (num_loaders as u64) * (segments_per_loader as u64);
+    if total == 0 || total > SegmentNum::MAX as u64 {
+        error!("Can't lease {:?} segments to each of {:?} loaders", segments_per_loader, num_loaders);
+        return Err(DataError);
+    }
+
+    let leases: Vec<ImportLease> = (0..num_loaders)
+        .map(|loader_no| ImportLease {
+            txn_id,
+            first_segment: 1 + loader_no as SegmentNum * segments_per_loader,
+            num_segments: segments_per_loader
+        })
+        .collect();
+    backend.write(&get_import_name(txn_id), serde_json::to_string(&leases)?.as_bytes())?;
+    Ok(leases)
+}
+
+/**
+ * The transactions of imports that have begun but not been committed or aborted.
+ */
+pub(crate) fn list(backend: &dyn Backend) -> Result<Vec<TransactionId>, Error> {
+    Ok(backend.list()?.iter().filter_map(|name| decode_import_name(name)).collect())
+}
+
+pub(crate) fn load(backend: &dyn Backend, txn_id: TransactionId) -> Result<Vec<ImportLease>, Error> {
+    let json = backend.read(&get_import_name(txn_id))?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/**
+ * The segments the loaders have written so far, in order, with their file names.
+ */
+pub(crate) fn written_segments(backend: &dyn Backend, leases: &[ImportLease]) -> Result<Vec<(SegmentId, String)>, Error> {
+    let mut segments: Vec<(SegmentId, String)> = backend.list()?.into_iter()
+        .filter_map(|name| {
+            let (txn_id, seg_num, committed) = decode_segment_path(Path::new(&name))?;
+            let leased = leases.iter().any(|lease| lease.txn_id == txn_id
+                && (lease.first_segment..lease.first_segment + lease.num_segments).contains(&seg_num));
+            (!committed && leased).then_some(((txn_id, seg_num), name))
+        })
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+/**
+ * Remove an import's uncommitted segments and its manifest.
+ */
+pub(crate) fn discard(backend: &dyn Backend, txn_id: TransactionId) -> Result<(), Error> {
+    for name in backend.list()? {
+        if let Some((seg_txn_id, _, false)) = decode_segment_path(Path::new(&name)) {
+            if seg_txn_id == txn_id {
+                backend.remove(&name)?;
+            }
+        }
+    }
+    remove_manifest(backend, txn_id)
+}
+
+pub(crate) fn remove_manifest(backend: &dyn Backend, txn_id: TransactionId) -> Result<(), Error> {
+    let name = get_import_name(txn_id);
+    if backend.exists(&name) {
+        backend.remove(&name)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+use crate::backend::Backend;
+use crate::clock;
+use crate::storage::JOURNAL_FILENAME;
+
+/**
+ * One maintenance action recorded in a database's journal, from `Database::history`.  `time` is
+ * in seconds since 1970, and `detail` is a human-readable description of what was done.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub time: u64,
+    pub action: String,
+    pub detail: String
+}
+
+/**
+ * The journal of maintenance actions, oldest first.  A database without one has no history.
+ */
+pub(crate) fn load(backend: &dyn Backend) -> Result<Vec<JournalEntry>, Error> {
+    if !backend.exists(JOURNAL_FILENAME) {
+        return Ok(Vec::new());
+    }
+    let json = backend.read(JOURNAL_FILENAME)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/**
+ * Add an entry to the journal.  Backends can only replace whole files, so the journal is
+ * rewritten and renamed into place, which never loses earlier entries.  The action it records
+ * has already happened, so a failure is logged rather than returned.
+ */
+pub(crate) fn record(backend: &dyn Backend, action: &str, detail: String) {
+    let time = clock::unix_seconds();
+    let entry = JournalEntry { time, action: action.to_string(), detail };
+    if let Err(err) = append(backend, entry) {
+        error!("Failed to record {:?} in the journal: {:?}", action, err);
+    }
+}
+
+fn append(backend: &dyn Backend, entry: JournalEntry) -> Result<(), Error> {
+    let mut entries = load(backend)?;
+    entries.push(entry);
+    let temp_filename = format!("{JOURNAL_FILENAME}.tmp");
+    backend.write(&temp_filename, serde_json::to_string_pretty(&entries)?.as_bytes())?;
+    backend.rename(&temp_filename, JOURNAL_FILENAME)?;
+    Ok(())
+}
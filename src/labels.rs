@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use log::{debug, error};
+
+use crate::{Datum, Error};
+use crate::backend::Backend;
+use crate::Error::DataError;
+use crate::storage::LABELS_FILENAME;
+
+/**
+ * The strings of a database's label dimensions, and the ids standing for them in rows.  Ids are
+ * assigned in order from 0 as new strings are added, separately for each dimension, and never
+ * change or are reused, so the file only grows.  It is saved before the segments of the
+ * transaction that first used a label are made visible, so no committed row refers to an id
+ * that isn't on disk.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Labels {
+    /* The strings of each dimension, indexed by id; empty for dimensions without labels */
+    names: Vec<Vec<String>>,
+    ids: Vec<HashMap<String, Datum>>,
+    dirty: bool
+}
+
+impl Labels {
+    pub(crate) fn new(num_dims: usize) -> Labels {
+        Labels { names: vec![Vec::new(); num_dims], ids: vec![HashMap::new(); num_dims], dirty: false }
+    }
+
+    /**
+     * Build the labels from their strings for each dimension, as saved.
+     */
+    pub(crate) fn from_names(num_dims: usize, names: Vec<Vec<String>>) -> Result<Labels, Error> {
+        if names.len() != num_dims {
+            error!("Labels are for {:?} dimensions but the schema has {:?}", names.len(), num_dims);
+            return Err(DataError);
+        }
+        let ids = names.iter()
+            .map(|dim_names| dim_names.iter().enumerate().map(|(id, name)| (name.clone(), id)).collect())
+            .collect();
+        Ok(Labels { names, ids, dirty: false })
+    }
+
+    pub(crate) fn load(backend: &dyn Backend, num_dims: usize) -> Result<Labels, Error> {
+        if !backend.exists(LABELS_FILENAME) {
+            return Ok(Labels::new(num_dims));
+        }
+        let json = backend.read(LABELS_FILENAME)?;
+        Labels::from_names(num_dims, serde_json::from_slice(&json)?)
+    }
+
+    /**
+     * Save the labels if any have been added since they were loaded or last saved, replacing the
+     * file in a single rename.
+     */
+    pub(crate) fn save(&mut self, backend: &dyn Backend) -> Result<(), Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let temp_filename = format!("{LABELS_FILENAME}.tmp");
+        backend.write(&temp_filename, self.to_json()?.as_bytes())?;
+        backend.rename(&temp_filename, LABELS_FILENAME)?;
+        self.dirty = false;
+        debug!("Saved labels");
+        Ok(())
+    }
+
+    pub(crate) fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(&self.names)?)
+    }
+
+    /**
+     * Mark the labels as needing to be saved, such as after they have been restored from a dump.
+     */
+    pub(crate) fn set_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    pub(crate) fn id(&self, dim_no: usize, name: &str) -> Option<Datum> {
+        self.ids.get(dim_no)?.get(name).copied()
+    }
+
+    /**
+     * The id of a string in a dimension, assigning the next one if it is new.
+     */
+    pub(crate) fn get_or_add(&mut self, dim_no: usize, name: &str) -> Datum {
+        if let Some(id) = self.id(dim_no, name) {
+            return id;
+        }
+        let names = &mut self.names[dim_no];
+        let id = names.len();
+        names.push(name.to_string());
+        self.ids[dim_no].insert(name.to_string(), id);
+        self.dirty = true;
+        debug!("Added label {:?} as {:?} in dimension {:?}", name, id, dim_no);
+        id
+    }
+
+    pub(crate) fn label(&self, dim_no: usize, id: Datum) -> Option<&str> {
+        self.names.get(dim_no)?.get(id).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod labels_tests {
+    use crate::MemoryBackend;
+    use super::Labels;
+
+    #[test]
+    fn save_and_load() {
+        let backend = MemoryBackend::new();
+        let mut labels = Labels::load(&backend, 2).unwrap();
+        assert_eq!(labels.get_or_add(1, "north"), 0);
+        assert_eq!(labels.get_or_add(1, "south"), 1);
+        assert_eq!(labels.get_or_add(1, "north"), 0);
+        assert_eq!(labels.id(0, "north"), None);
+        labels.save(&backend).unwrap();
+
+        let loaded = Labels::load(&backend, 2).unwrap();
+        assert_eq!(loaded, labels);
+        assert_eq!(loaded.label(1, 1), Some("south"));
+        assert_eq!(loaded.label(1, 2), None);
+        assert!(Labels::load(&backend, 3).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+
+use crate::BlockId;
+use crate::pool::PoolHash;
+
+/**
+ * The content hash of every committed block of a database, by block id, from
+ * `Database::block_manifest`.  It can be saved as JSON and sent to a copy of the database, such
+ * as a backup or replica, for `Database::verify_against` to find where the two differ without
+ * comparing their data.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockManifest {
+    /* Sorted by block id */
+    blocks: Vec<(BlockId, PoolHash)>
+}
+
+impl BlockManifest {
+    pub(crate) fn new(mut blocks: Vec<(BlockId, PoolHash)>) -> BlockManifest {
+        blocks.sort();
+        BlockManifest { blocks }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /**
+     * The SHA-256 hash of a block's uncompressed payload.
+     */
+    pub fn hash(&self, block_id: BlockId) -> Option<&[u8; 32]> {
+        let idx = self.blocks.binary_search_by_key(&block_id, |(id, _)| *id).ok()?;
+        Some(&self.blocks[idx].1)
+    }
+
+    /**
+     * How this manifest differs from a reference one.
+     */
+    pub fn compare(&self, reference: &BlockManifest) -> BlockDivergence {
+        let mut divergence = BlockDivergence::default();
+        let mut local = self.blocks.iter().peekable();
+        let mut expected = reference.blocks.iter().peekable();
+        loop {
+            match (local.peek(), expected.peek()) {
+                (Some((local_id, local_hash)), Some((expected_id, expected_hash))) if local_id == expected_id => {
+                    if local_hash != expected_hash {
+                        divergence.different.push(*local_id);
+                    }
+                    local.next();
+                    expected.next();
+                },
+                (Some((local_id, _)), Some((expected_id, _))) if local_id < expected_id => {
+                    divergence.extra.push(*local_id);
+                    local.next();
+                },
+                (_, Some((expected_id, _))) => {
+                    divergence.missing.push(*expected_id);
+                    expected.next();
+                },
+                (Some((local_id, _)), None) => {
+                    divergence.extra.push(*local_id);
+                    local.next();
+                },
+                (None, None) => break
+            }
+        }
+        divergence
+    }
+}
+
+/**
+ * The blocks in which a database differs from a reference manifest, from
+ * `Database::verify_against`: those only the reference has, those only the database has, and
+ * those both have with different contents.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockDivergence {
+    pub missing: Vec<BlockId>,
+    pub extra: Vec<BlockId>,
+    pub different: Vec<BlockId>
+}
+
+impl BlockDivergence {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.different.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod manifest_tests {
+    use super::{BlockDivergence, BlockManifest};
+
+    #[test]
+    fn compare() {
+        let local = BlockManifest::new(vec![((2, 0, 0), [2; 32]), ((1, 0, 0), [1; 32]), ((1, 0, 1), [5; 32])]);
+        let reference = BlockManifest::new(vec![((1, 0, 0), [1; 32]), ((1, 0, 1), [6; 32]), ((3, 0, 0), [3; 32])]);
+        assert_eq!(local.hash((2, 0, 0)), Some(&[2; 32]));
+        assert!(local.compare(&local).is_empty());
+        assert_eq!(local.compare(&reference), BlockDivergence {
+            missing: vec![(3, 0, 0)],
+            extra: vec![(2, 0, 0)],
+            different: vec![(1, 0, 1)]
+        });
+    }
+}
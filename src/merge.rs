@@ -0,0 +1,96 @@
+use crate::query::QueryRow;
+
+/**
+ * How a scan resolves a row that several blocks have a version of, for `Scan::merge_strategy`.
+ * The default, `NewestWins`, returns only the version from the latest transaction, which is
+ * what makes newer rows replace older ones.  Other strategies let the same storage be used in
+ * other ways, such as `SumValues` for counters that are incremented by adding rows, or
+ * `KeepAllVersions` for an event log.
+ */
+pub trait MergeStrategy {
+    /**
+     * Resolve the versions of the row at one point, which are given oldest first, each with
+     * the id of the transaction that wrote it.  A partial version from `Transaction::update`
+     * has already taken its missing values from the version before it, if
+     * `fills_partial_versions` says so, or else is 0 in them.  The rows left in `versions` are
+     * returned by the scan, in order; if none are left, the point is skipped.
+     */
+    fn merge(&mut self, versions: &mut Vec<QueryRow>);
+
+    /**
+     * Whether a partial version takes its missing values from the version before it, as suits
+     * strategies returning versions as they stand.  Strategies that combine the values of all
+     * versions, like `SumValues`, would count those values twice, so they see 0 instead.
+     */
+    fn fills_partial_versions(&self) -> bool {
+        true
+    }
+
+    /**
+     * Whether only the newest version of a row is ever returned.  A scan can then skip older
+     * blocks without reading them, when a newer one fills their whole chunk.
+     */
+    fn newest_only(&self) -> bool {
+        false
+    }
+}
+
+/**
+ * Return only the newest version of each row.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NewestWins;
+
+impl MergeStrategy for NewestWins {
+    fn merge(&mut self, versions: &mut Vec<QueryRow>) {
+        let num_older = versions.len().saturating_sub(1);
+        versions.drain(..num_older);
+    }
+
+    fn newest_only(&self) -> bool {
+        true
+    }
+}
+
+/**
+ * Return one row for each point, with each value the sum of that value over all versions of
+ * the row, and the transaction id of the newest.  A partial version adds nothing to the values
+ * it is missing.  Sums saturate rather than overflow.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct SumValues {
+    num_dims: usize
+}
+
+impl SumValues {
+    pub fn new(num_dims: usize) -> SumValues {
+        SumValues { num_dims }
+    }
+}
+
+impl MergeStrategy for SumValues {
+    fn merge(&mut self, versions: &mut Vec<QueryRow>) {
+        let Some(mut total) = versions.pop() else { return };
+        for version in versions.drain(..) {
+            for (sum, value) in total.values_array.iter_mut().zip(&version.values_array).skip(self.num_dims) {
+                *sum = sum.saturating_add(*value);
+            }
+        }
+        versions.push(total);
+    }
+
+    fn fills_partial_versions(&self) -> bool {
+        false
+    }
+}
+
+/**
+ * Return every version of each row, oldest first, so a row's history can be read from its
+ * transaction ids.  Points then repeat in the scan's results, so this doesn't suit cursors.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeepAllVersions;
+
+impl MergeStrategy for KeepAllVersions {
+    fn merge(&mut self, _versions: &mut Vec<QueryRow>) {}
+}
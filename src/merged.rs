@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+
+use log::error;
+
+use crate::{compare_points, Error};
+use crate::database::Database;
+use crate::Error::SchemaError;
+use crate::query::{Criteria, QueryRow};
+use crate::scan::Scan;
+
+/**
+ * An iterator merging the rows of several scans, each in order by point, into one stream in
+ * order by point, such as queries of databases holding a month each.  A point found in more
+ * than one scan is returned from each of them, in the order the scans were given, or only from
+ * the last with `newest_wins`, so the scans should be listed oldest first.
+ */
+pub struct MergedScan<I> {
+    inputs: Vec<I>,
+    num_dims: usize,
+    newest_wins: bool,
+    /* The next row from each input, once the merge has started */
+    heads: Option<Vec<Option<QueryRow>>>,
+    /* Rows at the current point that haven't been returned yet */
+    pending: VecDeque<QueryRow>
+}
+
+impl<I: Iterator<Item=QueryRow>> MergedScan<I> {
+    /**
+     * Merge scans of data with `num_dims` dimensions.
+     */
+    pub fn new(num_dims: usize, inputs: Vec<I>) -> MergedScan<I> {
+        MergedScan { inputs, num_dims, newest_wins: false, heads: None, pending: VecDeque::new() }
+    }
+
+    /**
+     * Return only one row for each point, from the last scan that has one.
+     */
+    pub fn newest_wins(mut self) -> Self {
+        self.newest_wins = true;
+        self
+    }
+}
+
+impl<'db> MergedScan<Scan<'db>> {
+    /**
+     * Query the committed rows matching the criteria in each database, merged.  The databases
+     * are listed oldest first.
+     *
+     * Fails with `SchemaError` if the databases' schemas differ, and otherwise as
+     * `Snapshot::query_with` does.
+     */
+    pub fn query(databases: &[&'db Database], criteria: &Criteria) -> Result<MergedScan<Scan<'db>>, Error> {
+        let Some(first) = databases.first() else {
+            return Ok(MergedScan::new(0, Vec::new()));
+        };
+        if let Some(position) = databases.iter().position(|database| database.schema != first.schema) {
+            error!("Database {:?} of the merge has a different schema to the first", position);
+            return Err(SchemaError);
+        }
+
+        let scans = databases.iter()
+            .map(|database| database.query_snapshot().query_with(criteria))
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(MergedScan::new(first.schema.dimensions.len(), scans))
+    }
+
+    /**
+     * Fail with the first error of any of the scans, as `Scan::check` does.
+     */
+    pub fn check(&self) -> Result<(), Error> {
+        self.inputs.iter().try_for_each(|scan| scan.check())
+    }
+}
+
+impl<I: Iterator<Item=QueryRow>> Iterator for MergedScan<I> {
+    type Item = QueryRow;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(row) = self.pending.pop_front() {
+            return Some(row);
+        }
+
+        let inputs = &mut self.inputs;
+        let heads = self.heads.get_or_insert_with(|| inputs.iter_mut().map(|input| input.next()).collect());
+        let num_dims = self.num_dims;
+        let current = heads.iter().flatten()
+            .map(|row| &row.values_array)
+            .min_by(|a, b| compare_points(num_dims, a, b))?
+            .clone();
+
+        for (head, input) in heads.iter_mut().zip(inputs.iter_mut()) {
+            if head.as_ref().is_some_and(|row| compare_points(num_dims, &row.values_array, &current).is_eq()) {
+                self.pending.push_back(head.take().unwrap());
+                *head = input.next();
+            }
+        }
+        if self.newest_wins {
+            let num_older = self.pending.len() - 1;
+            self.pending.drain(..num_older);
+        }
+        self.pending.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod merged_tests {
+    use crate::{Criteria, Database, Error, MemoryBackend, Schema};
+    use super::*;
+
+    fn rows(txn_id: u32, points: &[[usize; 3]]) -> impl Iterator<Item=QueryRow> + '_ {
+        points.iter().map(move |r| QueryRow { txn_id, segment: None, values_array: r.to_vec() })
+    }
+
+    #[test]
+    fn merge() {
+        let inputs = || vec![rows(1, &[[1, 1, 10], [3, 0, 30]]), rows(2, &[[0, 5, 1], [1, 1, 11]]), rows(3, &[])];
+        let merged: Vec<_> = MergedScan::new(2, inputs()).map(|r| (r.txn_id, r.values_array)).collect();
+        assert_eq!(merged, vec![(2, vec![0, 5, 1]), (1, vec![1, 1, 10]), (2, vec![1, 1, 11]), (1, vec![3, 0, 30])]);
+
+        let merged: Vec<_> = MergedScan::new(2, inputs()).newest_wins().map(|r| r.values_array).collect();
+        assert_eq!(merged, vec![vec![0, 5, 1], vec![1, 1, 11], vec![3, 0, 30]]);
+    }
+
+    fn database(value_name: &str, rows: &[[usize; 2]]) -> Database {
+        let schema = Schema::builder().dimension("t", 10).value(value_name).build().unwrap();
+        let mut database = Database::create_with_backend(schema, Box::new(MemoryBackend::new())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        for row in rows {
+            txn.add_row(row).unwrap();
+        }
+        txn.commit().unwrap();
+        database
+    }
+
+    #[test]
+    fn databases() {
+        let january = database("v", &[[1, 10], [5, 50]]);
+        let february = database("v", &[[3, 30], [5, 51], [40, 400]]);
+        let criteria = Criteria::new().range(0, 0..=9);
+        let merged = MergedScan::query(&[&january, &february], &criteria).unwrap().newest_wins();
+        assert!(merged.check().is_ok());
+        assert_eq!(merged.map(|r| r.values_array).collect::<Vec<_>>(), vec![vec![1, 10], vec![3, 30], vec![5, 51]]);
+
+        let other = database("w", &[]);
+        assert!(matches!(MergedScan::query(&[&january, &other], &criteria), Err(Error::SchemaError)));
+    }
+}
@@ -0,0 +1,46 @@
+use crate::schema::Schema;
+
+/**
+ * What `Database::open_with` does depending on whether the database already exists.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OpenMode {
+    /** Open an existing database, failing with `IoError` if there isn't one. */
+    #[default]
+    OpenExisting,
+    /** Open the database if it exists, or create it with the options' schema if not. */
+    CreateIfMissing,
+    /** Create the database with the options' schema, failing with `IoError` if it exists. */
+    ErrorIfExists
+}
+
+/**
+ * How to open a database with `Database::open_with`, replacing the usual check for whether the
+ * database exists before choosing between `create` and `open`, as in
+ * `OpenOptions::new().mode(OpenMode::CreateIfMissing).schema(schema)`.
+ *
+ * A schema is needed to create a database.  When an existing database is opened with one, its
+ * own schema must match it exactly, or opening fails with `SchemaError`, so an application
+ * finds out at startup if the database isn't the one it expects.
+ */
+#[derive(Debug, Default)]
+pub struct OpenOptions {
+    pub(crate) mode: OpenMode,
+    pub(crate) schema: Option<Schema>
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    pub fn mode(mut self, mode: OpenMode) -> OpenOptions {
+        self.mode = mode;
+        self
+    }
+
+    pub fn schema(mut self, schema: Schema) -> OpenOptions {
+        self.schema = Some(schema);
+        self
+    }
+}
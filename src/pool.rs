@@ -0,0 +1,77 @@
+use log::debug;
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+use crate::backend::Backend;
+use crate::storage::{decode_pool_name, get_pool_name, POOL_PREFIX};
+
+/**
+ * The SHA-256 hash of a block's uncompressed payload, which names its object in the block pool.
+ */
+pub(crate) type PoolHash = [u8; 32];
+
+/**
+ * How blocks are stored, chosen with `Database::set_layout`.
+ *
+ * With the `Segments` layout, each segment file holds its blocks.  With the `Pool` layout, each
+ * block is saved once in a content-addressed pool, named by the hash of its content, and the
+ * segment file only refers to it.  Identical blocks in different segments then share one pool
+ * object, and a copy of the database made with `Database::backup_to` only needs the objects
+ * the destination doesn't already have, so repeated backups and clones are cheap.
+ *
+ * Databases can be read whatever the layout, and segments saved with both can be mixed.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    #[default]
+    Segments,
+    Pool
+}
+
+/**
+ * A block section to save in the pool, as encoded by `Segment::encode`.
+ */
+pub(crate) struct PoolObject {
+    pub hash: PoolHash,
+    pub data: Vec<u8>
+}
+
+pub(crate) fn hash_payload(payload: &[u8]) -> PoolHash {
+    Sha256::digest(payload).into()
+}
+
+/**
+ * Save the objects the pool doesn't already have, returning the number of bytes written.  Each
+ * is written under a temporary name and renamed into place, so an interrupted write never
+ * leaves a damaged object that later writes would skip.
+ */
+pub(crate) fn write_objects(backend: &dyn Backend, objects: &[PoolObject]) -> Result<u64, Error> {
+    let mut bytes_written = 0;
+    for object in objects {
+        let name = get_pool_name(&object.hash);
+        if backend.exists(&name) {
+            continue;
+        }
+
+        let temp_name = format!("{name}.tmp");
+        backend.write(&temp_name, &object.data)?;
+        backend.rename(&temp_name, &name)?;
+        debug!("Wrote pool object {:?}", name);
+        bytes_written += object.data.len() as u64;
+    }
+    Ok(bytes_written)
+}
+
+/**
+ * The names of all files in the pool, including objects whose writing was interrupted.
+ */
+pub(crate) fn list_files(backend: &dyn Backend) -> Result<Vec<String>, Error> {
+    Ok(backend.list()?.into_iter().filter(|name| name.starts_with(POOL_PREFIX)).collect())
+}
+
+/**
+ * Whether a pool file is a complete object, rather than an interrupted write.
+ */
+pub(crate) fn is_object(name: &str) -> bool {
+    decode_pool_name(name).is_some()
+}
@@ -0,0 +1,115 @@
+use log::debug;
+
+use crate::{BlockId, BlockNum, Datum, Error};
+use crate::Error::SchemaError;
+use crate::query::Criteria;
+use crate::scan::Scan;
+use crate::tombstone::Tombstones;
+use crate::transaction::Transaction;
+
+/**
+ * A query whose pruning has been worked out in advance, for running many times with slightly
+ * different parameters.  A dashboard might prepare a query for some sensors and execute it
+ * every few seconds with the latest time range.
+ *
+ * The set of visible segments is fixed by the transaction's horizon, so the blocks overlapping
+ * the prepared criteria are found once, from the segment metadata, when the query is prepared.
+ * Each execution then only checks those blocks' bounds against its parameters.
+ */
+pub struct PreparedQuery<'db> {
+    txn: &'db Transaction<'db>,
+    criteria: Criteria,
+    blocks: Vec<PreparedBlock>,
+    tombstones: Tombstones
+}
+
+struct PreparedBlock {
+    block_id: BlockId,
+    min_bounds: Vec<Datum>,
+    max_bounds: Vec<Datum>
+}
+
+impl<'db> PreparedQuery<'db> {
+    pub(crate) fn new(txn: &'db Transaction<'db>, criteria: &Criteria) -> Result<PreparedQuery<'db>, Error> {
+        if criteria.num_dims() > txn.database.schema.dimensions.len() {
+            return Err(SchemaError);
+        }
+
+        txn.record_read(criteria);
+        let mut blocks = Vec::new();
+        for segment in txn.visible_segments()? {
+            for (block_num, block_info) in segment.block_info.iter().enumerate() {
+                if !criteria.overlaps_bounds(&block_info.min_bounds, &block_info.max_bounds) {
+                    continue;
+                }
+                blocks.push(PreparedBlock {
+                    block_id: (segment.id.0, segment.id.1, block_num as BlockNum),
+                    min_bounds: block_info.min_bounds.clone(),
+                    max_bounds: block_info.max_bounds.clone()
+                });
+            }
+        }
+        debug!("Prepared query over {:?} blocks", blocks.len());
+
+        Ok(PreparedQuery { txn, criteria: criteria.clone(), blocks, tombstones: txn.tombstones()? })
+    }
+
+    /**
+     * Run the query, returning only rows matching both the prepared criteria and the parameters.
+     *
+     * Fails with `SchemaError` if the parameters refer to more dimensions than the schema has, and
+     * `QuotaExceeded` if the database's limit on open scans has been reached.
+     */
+    pub fn execute(&self, parameters: &Criteria) -> Result<Scan<'db>, Error> {
+        let num_dims = self.txn.database.schema.dimensions.len();
+        if parameters.num_dims() > num_dims {
+            return Err(SchemaError);
+        }
+
+        let criteria = self.criteria.and(parameters);
+        let mut scan = Scan::new(self.txn.database.open_scan_source()?, num_dims, self.txn.id.unwrap_or(0));
+        for block in &self.blocks {
+            if criteria.overlaps_bounds(&block.min_bounds, &block.max_bounds) {
+                scan.add_block_id(block.block_id, block.min_bounds.clone());
+            }
+        }
+        scan.set_criteria(criteria);
+        scan.add_tombstones(&self.tombstones);
+
+        for block in self.txn.unsaved_blocks.values() {
+            scan.add_block(block.clone());
+        }
+        Ok(scan)
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod prepared_tests {
+    use crate::Criteria;
+    use crate::test_util::create_database;
+
+    #[test]
+    fn sliding_window() {
+        let mut database = create_database("prepared-sliding-window");
+        let mut txn = database.new_transaction().unwrap();
+        for t in 0..100 {
+            txn.add_row(&[t, t % 3, t]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[100, 1, 100]).unwrap();
+
+        let prepared = txn.prepare(&Criteria::new().range(1, 1..=1)).unwrap();
+        for start in [0, 25, 90] {
+            let parameters = Criteria::new().range(0, start..=start + 10);
+            let times: Vec<_> = prepared.execute(&parameters).unwrap().map(|r| r[0]).collect();
+            let expected: Vec<_> = txn.query_with(&Criteria::new().range(0, start..=start + 10).range(1, 1..=1))
+                .unwrap().map(|r| r[0]).collect();
+            assert_eq!(times, expected);
+            assert!(!times.is_empty());
+        }
+
+        assert!(prepared.execute(&Criteria::new().range(2, 0..=0)).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use log::error;
+
+use crate::{Datum, Error};
+use crate::Error::SchemaError;
+use crate::query::{Criteria, QueryRow};
+
+/**
+ * An iterator returning the rows of a query with their dimensions in a different order, from
+ * `Scan::project`.  The values follow the dimensions as usual.
+ */
+pub struct Projection<I> {
+    inner: I,
+    num_dims: usize,
+    dims: Vec<usize>
+}
+
+impl<I: Iterator<Item=QueryRow>> Projection<I> {
+    /**
+     * Check that `dims` names each dimension at most once, and leaves out only dimensions
+     * fixed to a single value by the criteria, so that no two rows become indistinguishable.
+     */
+    pub(crate) fn new(inner: I, num_dims: usize, criteria: &Criteria, dims: &[usize]) -> Result<Projection<I>, Error> {
+        let mut seen = vec![false; num_dims];
+        for &dim_no in dims {
+            if dim_no >= num_dims || seen[dim_no] {
+                error!("Invalid or repeated dimension {:?} in projection", dim_no);
+                return Err(SchemaError);
+            }
+            seen[dim_no] = true;
+        }
+        if let Some(dim_no) = (0..num_dims).find(|&dim_no| !seen[dim_no] && criteria.fixed_value(dim_no).is_none()) {
+            error!("Can't leave out dimension {:?}, which isn't fixed by the criteria", dim_no);
+            return Err(SchemaError);
+        }
+
+        Ok(Projection { inner, num_dims, dims: dims.to_vec() })
+    }
+}
+
+/**
+ * Check that `values` names each value at most once, and only values among the first
+ * `num_values`, if that is known, for `Scan::select_values`.
+ */
+pub(crate) fn check_values(values: &[usize], num_values: Option<usize>) -> Result<(), Error> {
+    for (pos, &value_no) in values.iter().enumerate() {
+        if num_values.is_some_and(|num_values| value_no >= num_values) || values[..pos].contains(&value_no) {
+            error!("Invalid or repeated value {:?} in selection", value_no);
+            return Err(SchemaError);
+        }
+    }
+    Ok(())
+}
+
+impl<I: Iterator<Item=QueryRow>> Iterator for Projection<I> {
+    type Item = QueryRow;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.inner.next()?;
+        let mut values_array: Vec<Datum> = self.dims.iter().map(|&dim_no| row.values_array[dim_no]).collect();
+        values_array.extend_from_slice(&row.values_array[self.num_dims..]);
+        Some(QueryRow { txn_id: row.txn_id, segment: row.segment, values_array })
+    }
+}
+
+#[cfg(test)]
+mod project_tests {
+    use super::*;
+
+    fn rows() -> impl Iterator<Item=QueryRow> {
+        [[1, 5, 7, 100], [2, 5, 8, 200]].into_iter().map(|r| QueryRow { txn_id: 1, segment: None, values_array: r.to_vec() })
+    }
+
+    #[test]
+    fn project() {
+        let projection = Projection::new(rows(), 3, &Criteria::new(), &[2, 0, 1]).unwrap();
+        let result: Vec<_> = projection.map(|r| r.values_array).collect();
+        assert_eq!(result, vec![vec![7, 1, 5, 100], vec![8, 2, 5, 200]]);
+
+        let criteria = Criteria::new().range(1, 5..=5);
+        let projection = Projection::new(rows(), 3, &criteria, &[2, 0]).unwrap();
+        let result: Vec<_> = projection.map(|r| r.values_array).collect();
+        assert_eq!(result, vec![vec![7, 1, 100], vec![8, 2, 200]]);
+
+        assert!(Projection::new(rows(), 3, &Criteria::new(), &[2, 0]).is_err());
+        assert!(Projection::new(rows(), 3, &Criteria::new().range(1, 5..=6), &[2, 0]).is_err());
+        assert!(Projection::new(rows(), 3, &Criteria::new(), &[2, 0, 1, 1]).is_err());
+        assert!(Projection::new(rows(), 3, &Criteria::new(), &[3, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn check_values() {
+        assert!(super::check_values(&[2, 0], Some(3)).is_ok());
+        assert!(super::check_values(&[], Some(3)).is_ok());
+        assert!(super::check_values(&[2, 3], Some(3)).is_err());
+        assert!(super::check_values(&[1, 1], Some(3)).is_err());
+        assert!(super::check_values(&[5], None).is_ok());
+    }
+}
@@ -1,14 +1,264 @@
 use std::fmt::{Debug, Formatter};
-use std::ops::Index;
+use std::io::{Read, Write};
+use std::ops::{Index, IndexMut, RangeInclusive};
 
-use crate::{Datum, TransactionId};
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
 
+use crate::{Datum, Error, SegmentId, TransactionId};
+use crate::schema::Schema;
+use crate::typed::TypedValue;
+
+/**
+ * Restrictions on which rows a query returns.  Each dimension can be limited to one or more
+ * inclusive ranges of values, and a row matches if each of its dimension values lies in any of
+ * the ranges for that dimension; dimensions without ranges are unrestricted.
+ *
+ * Besides filtering rows, the criteria are used to skip blocks whose bounds lie entirely outside
+ * them, without loading them.  This is done per range, so a block falling between two ranges is
+ * skipped too.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Criteria {
+    ranges: Vec<Option<Vec<RangeInclusive<Datum>>>>
+}
+
+impl Criteria {
+    pub fn new() -> Criteria {
+        Criteria::default()
+    }
+
+    /**
+     * Restrict a dimension to a range of values, replacing any previous ranges for it.
+     */
+    pub fn range(self, dim_no: usize, range: RangeInclusive<Datum>) -> Criteria {
+        self.ranges(dim_no, [range])
+    }
+
+    /**
+     * Restrict a dimension to any of several ranges of values, replacing any previous ranges for
+     * it.  The ranges may overlap and be given in any order.
+     */
+    pub fn ranges<I: IntoIterator<Item=RangeInclusive<Datum>>>(mut self, dim_no: usize, ranges: I) -> Criteria {
+        if self.ranges.len() <= dim_no {
+            self.ranges.resize(dim_no + 1, None);
+        }
+        self.ranges[dim_no] = Some(normalise_ranges(ranges));
+        self
+    }
+
+    /**
+     * Restrict a dimension to a list of individual values, such as a set of sensor ids.
+     */
+    pub fn values(self, dim_no: usize, values: &[Datum]) -> Criteria {
+        self.ranges(dim_no, values.iter().map(|&v| v..=v))
+    }
+
+    /**
+     * The ranges a dimension is restricted to, sorted and with overlapping or adjacent ranges
+     * merged.
+     */
+    pub fn get_ranges(&self, dim_no: usize) -> Option<&[RangeInclusive<Datum>]> {
+        self.ranges.get(dim_no)?.as_deref()
+    }
+
+    /**
+     * Combine two sets of criteria so that only rows matching both will match.
+     */
+    pub fn and(&self, other: &Criteria) -> Criteria {
+        let num_dims = self.ranges.len().max(other.ranges.len());
+        let ranges = (0..num_dims).map(|dim_no| {
+            match (self.get_ranges(dim_no), other.get_ranges(dim_no)) {
+                (Some(a), Some(b)) => Some(intersect_ranges(a, b)),
+                (Some(a), None) => Some(a.to_vec()),
+                (None, Some(b)) => Some(b.to_vec()),
+                (None, None) => None
+            }
+        }).collect();
+        Criteria { ranges }
+    }
+
+    /**
+     * The only value allowed for a dimension, if it is restricted to exactly one.
+     */
+    pub(crate) fn fixed_value(&self, dim_no: usize) -> Option<Datum> {
+        match self.get_ranges(dim_no)? {
+            [range] if range.start() == range.end() => Some(*range.start()),
+            _ => None
+        }
+    }
+
+    /**
+     * The lowest point the criteria match, or `None` if they can't match any.
+     */
+    pub(crate) fn start_point(&self, num_dims: usize) -> Option<Vec<Datum>> {
+        (0..num_dims).map(|dim_no| match self.get_ranges(dim_no) {
+            Some(ranges) => ranges.first().map(|range| *range.start()),
+            None => Some(0)
+        }).collect()
+    }
+
+    /**
+     * The number of dimensions the criteria refer to.
+     */
+    pub(crate) fn num_dims(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /**
+     * Whether a value is allowed for a dimension.
+     */
+    pub(crate) fn matches_value(&self, dim_no: usize, value: Datum) -> bool {
+        self.get_ranges(dim_no).is_none_or(|ranges| find_range(ranges, value).is_some_and(|r| r.contains(&value)))
+    }
+
+    pub(crate) fn matches_point(&self, point: &[Datum]) -> bool {
+        point.iter().take(self.ranges.len()).enumerate()
+            .all(|(dim_no, &val)| self.matches_value(dim_no, val))
+    }
+
+    /**
+     * The same criteria with no restriction on one dimension.
+     */
+    pub(crate) fn without(&self, dim_no: usize) -> Criteria {
+        let mut criteria = self.clone();
+        if let Some(ranges) = criteria.ranges.get_mut(dim_no) {
+            *ranges = None;
+        }
+        criteria
+    }
+
+    /**
+     * Whether every point in the box between `min_bounds` and `max_bounds` matches.
+     */
+    pub(crate) fn covers_bounds(&self, min_bounds: &[Datum], max_bounds: &[Datum]) -> bool {
+        self.ranges.iter().zip(min_bounds.iter().zip(max_bounds))
+            .all(|(ranges, (&min, max))| ranges.as_ref().is_none_or(|ranges| {
+                find_range(ranges, min).is_some_and(|r| r.contains(&min) && r.contains(max))
+            }))
+    }
+
+    /**
+     * Whether any point in the box between `min_bounds` and `max_bounds` could match.
+     */
+    pub(crate) fn overlaps_bounds(&self, min_bounds: &[Datum], max_bounds: &[Datum]) -> bool {
+        self.ranges.iter().zip(min_bounds.iter().zip(max_bounds))
+            .all(|(ranges, (&min, max))| ranges.as_ref().is_none_or(|ranges| {
+                find_range(ranges, min).is_some_and(|r| r.start() <= max)
+            }))
+    }
+
+    /**
+     * Save the criteria, as the number of dimensions and then for each, whether it is
+     * restricted, and if so the number of ranges and their start and end values.
+     */
+    pub(crate) fn save<W: Write>(&self, dest: &mut W) -> Result<(), Error> {
+        dest.write_u16::<BE>(self.ranges.len() as u16)?;
+        for ranges in &self.ranges {
+            let Some(ranges) = ranges else {
+                dest.write_u8(0)?;
+                continue;
+            };
+            dest.write_u8(1)?;
+            dest.write_u32::<BE>(ranges.len() as u32)?;
+            for range in ranges {
+                dest.write_u64::<BE>(*range.start() as u64)?;
+                dest.write_u64::<BE>(*range.end() as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn load<R: Read>(src: &mut R) -> Result<Criteria, Error> {
+        let num_dims = src.read_u16::<BE>()?;
+        let mut criteria = Criteria::new();
+        for _ in 0..num_dims {
+            if src.read_u8()? == 0 {
+                criteria.ranges.push(None);
+                continue;
+            }
+            let num_ranges = src.read_u32::<BE>()?;
+            let mut ranges = Vec::new();
+            for _ in 0..num_ranges {
+                let start = src.read_u64::<BE>()? as Datum;
+                let end = src.read_u64::<BE>()? as Datum;
+                ranges.push(start..=end);
+            }
+            criteria.ranges.push(Some(ranges));
+        }
+        Ok(criteria)
+    }
+}
+
+/**
+ * Sort the ranges and merge any that overlap or are adjacent, dropping empty ones.
+ */
+fn normalise_ranges<I: IntoIterator<Item=RangeInclusive<Datum>>>(ranges: I) -> Vec<RangeInclusive<Datum>> {
+    let mut ranges: Vec<_> = ranges.into_iter().filter(|r| !r.is_empty()).collect();
+    ranges.sort_by_key(|r| *r.start());
+
+    let mut merged: Vec<RangeInclusive<Datum>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        if let Some(last) = merged.last_mut() {
+            if *range.start() <= last.end().saturating_add(1) {
+                if range.end() > last.end() {
+                    *last = *last.start()..=*range.end();
+                }
+                continue;
+            }
+        }
+        merged.push(range);
+    }
+    merged
+}
+
+/**
+ * The ranges of values contained in both lists of sorted, disjoint ranges.
+ */
+fn intersect_ranges(a: &[RangeInclusive<Datum>], b: &[RangeInclusive<Datum>]) -> Vec<RangeInclusive<Datum>> {
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let start = *a[i].start().max(b[j].start());
+        let end = *a[i].end().min(b[j].end());
+        if start <= end {
+            result.push(start..=end);
+        }
+        if a[i].end() < b[j].end() { i += 1; } else { j += 1; }
+    }
+    result
+}
+
+/**
+ * The first of the sorted, disjoint ranges that ends at or after the value.
+ */
+fn find_range(ranges: &[RangeInclusive<Datum>], value: Datum) -> Option<&RangeInclusive<Datum>> {
+    let idx = ranges.partition_point(|r| *r.end() < value);
+    ranges.get(idx)
+}
+
+/**
+ * A row returned by a query.  `txn_id` is the transaction that wrote it, which for rows a
+ * transaction hasn't saved yet is its own id, or 0 if it hasn't been given one.  `segment` is
+ * the segment the row was read from, if the scan was asked for it with `Scan::with_provenance`
+ * and the row has been saved.
+ */
 #[derive(Clone)]
 pub struct QueryRow {
     pub txn_id: TransactionId,
+    pub segment: Option<SegmentId>,
     pub(crate) values_array: Vec<Datum>
 }
 
+impl QueryRow {
+    /**
+     * Value number `value_no` of the row, as the type the schema gives it.
+     */
+    pub fn typed_value(&self, schema: &Schema, value_no: usize) -> TypedValue {
+        let datum = self.values_array[schema.dimensions.len() + value_no];
+        TypedValue::from_datum(schema.values[value_no].value_type, datum)
+    }
+}
+
 impl Index<usize> for QueryRow {
     type Output = Datum;
 
@@ -17,8 +267,75 @@ impl Index<usize> for QueryRow {
     }
 }
 
+impl IndexMut<usize> for QueryRow {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.values_array[index]
+    }
+}
+
 impl Debug for QueryRow {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_list().entries(&self.values_array).finish()
    }
 }
+
+#[cfg(test)]
+mod criteria_tests {
+    use super::*;
+
+    #[test]
+    fn matches_point() {
+        let criteria = Criteria::new().range(1, 10..=20);
+        assert!(criteria.matches_point(&[0, 10, 99]));
+        assert!(criteria.matches_point(&[1000, 20, 99]));
+        assert!(!criteria.matches_point(&[0, 9, 99]));
+        assert!(!criteria.matches_point(&[0, 21, 99]));
+        assert!(Criteria::new().matches_point(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn overlaps_bounds() {
+        let criteria = Criteria::new().range(0, 10..=20).range(1, 5..=5);
+        assert!(criteria.overlaps_bounds(&[0, 0], &[10, 9]));
+        assert!(criteria.overlaps_bounds(&[15, 5], &[30, 5]));
+        assert!(!criteria.overlaps_bounds(&[0, 0], &[9, 9]));
+        assert!(!criteria.overlaps_bounds(&[21, 0], &[30, 9]));
+        assert!(!criteria.overlaps_bounds(&[10, 6], &[20, 9]));
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        let criteria = Criteria::new().ranges(0, [30..=40, 10..=20, 15..=25, 26..=27]).values(1, &[7, 3, 5]);
+        assert_eq!(criteria.get_ranges(0).unwrap(), &[10..=27, 30..=40]);
+        assert_eq!(criteria.get_ranges(1).unwrap(), &[3..=3, 5..=5, 7..=7]);
+
+        assert!(criteria.matches_point(&[10, 3]));
+        assert!(criteria.matches_point(&[35, 7]));
+        assert!(!criteria.matches_point(&[28, 3]));
+        assert!(!criteria.matches_point(&[35, 4]));
+        assert!(!criteria.matches_point(&[41, 5]));
+
+        assert!(criteria.overlaps_bounds(&[0, 0], &[10, 3]));
+        assert!(criteria.overlaps_bounds(&[28, 4], &[30, 6]));
+        assert!(!criteria.overlaps_bounds(&[28, 0], &[29, 9]));
+        assert!(!criteria.overlaps_bounds(&[0, 0], &[50, 2]));
+
+        assert!(criteria.covers_bounds(&[10, 5], &[27, 5]));
+        assert!(!criteria.covers_bounds(&[10, 3], &[27, 5]));
+        assert!(!criteria.covers_bounds(&[20, 5], &[30, 5]));
+    }
+
+    #[test]
+    fn and() {
+        let a = Criteria::new().ranges(0, [0..=10, 20..=30]).range(1, 5..=5);
+        let b = Criteria::new().ranges(0, [5..=25, 28..=40]).range(2, 1..=2);
+        let both = a.and(&b);
+        assert_eq!(both.get_ranges(0).unwrap(), &[5..=10, 20..=25, 28..=30]);
+        assert_eq!(both.get_ranges(1).unwrap(), &[5..=5]);
+        assert_eq!(both.get_ranges(2).unwrap(), &[1..=2]);
+
+        let none = a.and(&Criteria::new().range(1, 6..=9));
+        assert!(none.get_ranges(1).unwrap().is_empty());
+        assert!(!none.matches_point(&[0, 5]));
+    }
+}
@@ -0,0 +1,377 @@
+use std::cell::RefCell;
+use std::io::{ErrorKind, Read, Write};
+use std::rc::Rc;
+
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use log::{debug, error, info};
+
+use crate::{BlockId, Error, SegmentId};
+use crate::block::Block;
+use crate::cache::{Cache, CacheHint};
+use crate::database::Database;
+use crate::Error::{DataError, SchemaError};
+use crate::format::CURRENT_FORMAT_VERSION;
+use crate::query::Criteria;
+use crate::scan::{Scan, ScanSource};
+use crate::schema::{CellOrder, Schema};
+use crate::segment::Segment;
+use crate::storage::read_bytes;
+
+/*
+ * The protocol is a sequence of requests from the client, each answered by one response from
+ * the server.  A request is a one byte request type followed by its arguments; a response is a
+ * status byte followed, if the status is OK, by a length-prefixed payload.  All integers are
+ * big-endian.
+ *
+ *     SCHEMA                         -> the schema as JSON
+ *     SEGMENTS                       -> u32 count, then (u32 txn, u16 segment) for each
+ *     SEGMENT_INFO txn seg           -> u16 format version, then the segment's block info as
+ *                                       stored in a segment file of that version
+ *     BLOCK txn seg block            -> the block, encoded in the current format version
+ */
+const REQUEST_SCHEMA: u8 = b'S';
+const REQUEST_SEGMENTS: u8 = b'L';
+const REQUEST_SEGMENT_INFO: u8 = b'I';
+const REQUEST_BLOCK: u8 = b'B';
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+/* Far more than any segment info or block a server sends, so a corrupt length is caught before it is read */
+const MAX_RESPONSE_BYTES: usize = 256 << 20;
+
+const SEGMENT_CACHE_SIZE: usize = 100;
+const BLOCK_CACHE_SIZE: usize = 100;
+
+impl Database {
+    /**
+     * Answer requests from a `RemoteDatabase` on the given stream, until the client closes it.
+     * The client sees the segments committed when the database was opened; to serve newer
+     * data, open the database again for each connection.
+     */
+    pub fn serve<S: Read + Write>(&self, stream: S) -> Result<(), Error> {
+        serve(self, stream)
+    }
+}
+
+fn serve<S: Read + Write>(database: &Database, mut stream: S) -> Result<(), Error> {
+    let source = database.get_scan_source();
+    loop {
+        let request = match stream.read_u8() {
+            Ok(request) => request,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into())
+        };
+
+        let response = match request {
+            REQUEST_SCHEMA => serde_json::to_vec(&database.schema).map_err(Error::from),
+            REQUEST_SEGMENTS => encode_segments(database),
+            REQUEST_SEGMENT_INFO => {
+                let seg_id = (stream.read_u32::<BE>()?, stream.read_u16::<BE>()?);
+                match committed(database, seg_id).and_then(|_| source.get_segment(seg_id).ok()) {
+                    Some(segment) => encode_segment_info(&segment),
+                    None => Err(DataError)
+                }
+            },
+            REQUEST_BLOCK => {
+                let block_id = (stream.read_u32::<BE>()?, stream.read_u16::<BE>()?, stream.read_u16::<BE>()?);
+                match committed(database, (block_id.0, block_id.1)).and_then(|_| source.get_block(block_id).ok()) {
+                    Some(block) => {
+                        let mut payload = Vec::new();
+                        block.save(&mut payload, CURRENT_FORMAT_VERSION)?;
+                        Ok(payload)
+                    },
+                    None => Err(DataError)
+                }
+            },
+            _ => {
+                error!("Unknown request type {:?}, closing connection", request);
+                return Err(DataError);
+            }
+        };
+
+        write_response(&mut stream, response)?;
+    }
+
+    info!("Client closed connection");
+    Ok(())
+}
+
+/**
+ * Only committed segments are served, even though uncommitted ones could be loaded by name.
+ */
+fn committed(database: &Database, seg_id: SegmentId) -> Option<()> {
+    if database.committed_segments.contains(&seg_id) {
+        Some(())
+    } else {
+        error!("Request for segment {:?}, which is not committed", seg_id);
+        None
+    }
+}
+
+fn encode_segments(database: &Database) -> Result<Vec<u8>, Error> {
+    let mut seg_ids: Vec<SegmentId> = database.committed_segments.iter().copied().collect();
+    seg_ids.sort();
+
+    let mut payload = Vec::new();
+    payload.write_u32::<BE>(seg_ids.len() as u32)?;
+    for (txn_id, seg_num) in seg_ids {
+        payload.write_u32::<BE>(txn_id)?;
+        payload.write_u16::<BE>(seg_num)?;
+    }
+    Ok(payload)
+}
+
+fn encode_segment_info(segment: &Segment) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    payload.write_u16::<BE>(segment.version)?;
+    payload.extend(segment.encode_info()?);
+    Ok(payload)
+}
+
+fn write_response<W: Write>(dest: &mut W, response: Result<Vec<u8>, Error>) -> Result<(), Error> {
+    match response {
+        Ok(payload) => {
+            dest.write_u8(STATUS_OK)?;
+            dest.write_u32::<BE>(payload.len() as u32)?;
+            dest.write_all(&payload)?;
+        },
+        Err(err) => {
+            debug!("Sending error response for {:?}", err);
+            dest.write_u8(STATUS_ERROR)?;
+        }
+    }
+    dest.flush()?;
+    Ok(())
+}
+
+fn read_response<R: Read>(src: &mut R) -> Result<Vec<u8>, Error> {
+    if src.read_u8()? != STATUS_OK {
+        return Err(DataError);
+    }
+    let len = src.read_u32::<BE>()? as usize;
+    if len > MAX_RESPONSE_BYTES {
+        error!("Response of {len:?} bytes is too large");
+        return Err(DataError);
+    }
+    Ok(read_bytes(src, len)?)
+}
+
+/**
+ * A read-only client for a database served by `Database::serve` on another node.  Queries are merged
+ * locally, exactly as they are by a `Transaction`; only the segment info and block payloads
+ * are fetched from the server, and they are cached here so repeated queries don't fetch them
+ * again.  This allows many readers to share the data written by a single node.
+ */
+pub struct RemoteDatabase<S> {
+    stream: RefCell<S>,
+    pub schema: Schema,
+    cached_segments: RefCell<Cache<SegmentId, Segment>>,
+    cached_blocks: RefCell<Cache<BlockId, Block>>
+}
+
+impl<S: Read + Write> RemoteDatabase<S> {
+    /**
+     * Start a session on a stream connected to a server, fetching the schema.
+     */
+    pub fn connect(stream: S) -> Result<RemoteDatabase<S>, Error> {
+        let remote = RemoteDatabase {
+            stream: RefCell::new(stream),
+            schema: Schema { dimensions: Vec::new(), values: Vec::new(), cell_order: CellOrder::RowMajor },
+            cached_segments: RefCell::new(Cache::new(SEGMENT_CACHE_SIZE)),
+            cached_blocks: RefCell::new(Cache::new(BLOCK_CACHE_SIZE))
+        };
+        let payload = remote.request(REQUEST_SCHEMA, |_| Ok(()))?;
+        let schema: Schema = serde_json::from_slice(&payload)?;
+        schema.validate()?;
+        info!("Connected to remote database");
+        Ok(RemoteDatabase { schema, ..remote })
+    }
+
+    /**
+     * Query all committed rows.
+     */
+    pub fn query(&self) -> Result<Scan<'_>, Error> {
+        self.query_with(&Criteria::new())
+    }
+
+    /**
+     * Query the committed rows matching the criteria.  Fails with `SchemaError` if the criteria
+     * name more dimensions than the schema has.
+     */
+    pub fn query_with(&self, criteria: &Criteria) -> Result<Scan<'_>, Error> {
+        let num_dims = self.schema.dimensions.len();
+        if criteria.num_dims() > num_dims {
+            return Err(SchemaError);
+        }
+
+        let payload = self.request(REQUEST_SEGMENTS, |_| Ok(()))?;
+        let mut src = payload.as_slice();
+        let num_segments = src.read_u32::<BE>()?;
+
+        let source = Box::new(RemoteScanSource { remote: self });
+        let mut scan = Scan::new(source, num_dims, 0);
+        scan.set_criteria(criteria.clone());
+        for _ in 0..num_segments {
+            let seg_id = (src.read_u32::<BE>()?, src.read_u16::<BE>()?);
+            debug!("Add remote segment {:?}", seg_id);
+            scan.add_segment_id(seg_id);
+        }
+        Ok(scan)
+    }
+
+    fn request<F>(&self, request: u8, write_args: F) -> Result<Vec<u8>, Error>
+        where F: FnOnce(&mut S) -> std::io::Result<()>
+    {
+        let mut stream = self.stream.borrow_mut();
+        stream.write_u8(request)?;
+        write_args(&mut stream)?;
+        stream.flush()?;
+        read_response(&mut *stream)
+    }
+
+    fn fetch_segment(&self, seg_id: SegmentId) -> Result<Segment, Error> {
+        let payload = self.request(REQUEST_SEGMENT_INFO, |s| {
+            s.write_u32::<BE>(seg_id.0)?;
+            s.write_u16::<BE>(seg_id.1)
+        })?;
+        let mut src = payload.as_slice();
+        let mut segment = Segment::new(seg_id);
+        segment.version = src.read_u16::<BE>()?;
+        if segment.version > CURRENT_FORMAT_VERSION {
+            error!("Segment {:?} has unsupported format version {:?}", seg_id, segment.version);
+            return Err(Error::UnsupportedVersion { found: segment.version, supported: CURRENT_FORMAT_VERSION });
+        }
+        segment.decode_info(src)?;
+        segment.check_dimensions(self.schema.dimensions.len())?;
+        Ok(segment)
+    }
+
+    fn fetch_block(&self, block_id: BlockId) -> Result<Block, Error> {
+        let payload = self.request(REQUEST_BLOCK, |s| {
+            s.write_u32::<BE>(block_id.0)?;
+            s.write_u16::<BE>(block_id.1)?;
+            s.write_u16::<BE>(block_id.2)
+        })?;
+        let mut block = Block::new(0);
+        block.load(&mut payload.as_slice(), CURRENT_FORMAT_VERSION)?;
+        if block.dimension_values.len() != self.schema.dimensions.len() || 1 + block.extra_values.len() != self.schema.values.len() {
+            error!("Block {:?} doesn't match the schema", block_id);
+            return Err(SchemaError);
+        }
+        Ok(block)
+    }
+}
+
+struct RemoteScanSource<'r, S> {
+    remote: &'r RemoteDatabase<S>
+}
+
+impl<'r, S: Read + Write> ScanSource for RemoteScanSource<'r, S> {
+    fn get_segment(&self, seg_id: SegmentId) -> Result<Rc<Segment>, Error> {
+        if let Some(rc) = self.remote.cached_segments.borrow_mut().get(&seg_id) {
+            return Ok(rc);
+        }
+
+        let segment = match self.remote.fetch_segment(seg_id) {
+            Ok(segment) => segment,
+            Err(err) => {
+                error!("Error during remote fetch of segment {seg_id:?}: {err:?}");
+                return Err(err);
+            }
+        };
+
+        let rc = Rc::new(segment);
+        self.remote.cached_segments.borrow_mut().add(seg_id, rc.clone());
+        Ok(rc)
+    }
+
+    fn get_block_with_hint(&self, block_id: BlockId, hint: CacheHint) -> Result<Rc<Block>, Error> {
+        if let Some(rc) = self.remote.cached_blocks.borrow_mut().get_with_hint(&block_id, hint) {
+            return Ok(rc);
+        }
+
+        let block = match self.remote.fetch_block(block_id) {
+            Ok(block) => block,
+            Err(err) => {
+                error!("Error during remote fetch of block {block_id:?}: {err:?}");
+                return Err(err);
+            }
+        };
+
+        let rc = Rc::new(block);
+        self.remote.cached_blocks.borrow_mut().add_with_hint(block_id, rc.clone(), hint);
+        Ok(rc)
+    }
+
+    fn num_values(&self) -> Option<usize> {
+        Some(self.remote.schema.values.len())
+    }
+}
+
+#[cfg(test)]
+mod remote_tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use crate::{CellOrder, Database, Dimension, Error, MemoryBackend, Schema, Value, ValueType};
+    use crate::query::Criteria;
+    use super::{read_response, Cache, RefCell, RemoteDatabase, BLOCK_CACHE_SIZE};
+
+    #[test]
+    fn remote_query() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let schema = Schema {
+                dimensions: vec![
+                    Dimension { name: String::from("x"), chunk_size: 10, bounds: None, time: None, labels: false },
+                    Dimension { name: String::from("y"), chunk_size: 10, bounds: None, time: None, labels: false },
+                ],
+                values: vec![
+                    Value { name: String::from("value"), value_type: ValueType::U64 }
+                ],
+                cell_order: CellOrder::RowMajor
+            };
+            let mut database = Database::create_with_backend(schema, Box::new(MemoryBackend::new())).unwrap();
+            let mut txn = database.new_transaction().unwrap();
+            for i in 0..50 {
+                txn.add_row(&[i, i % 3, i * 2]).unwrap();
+            }
+            txn.commit().unwrap();
+
+            let (stream, _) = listener.accept().unwrap();
+            database.serve(stream).unwrap();
+        });
+
+        let remote = RemoteDatabase::connect(TcpStream::connect(addr).unwrap()).unwrap();
+        assert_eq!(remote.schema.dimensions.len(), 2);
+
+        let rows: Vec<_> = remote.query().unwrap().map(|r| r.values_array).collect();
+        assert_eq!(rows.len(), 50);
+        assert_eq!(rows[49], vec![49, 1, 98]);
+
+        let criteria = Criteria::new().range(0, 15..=25).range(1, 0..=0);
+        let rows: Vec<_> = remote.query_with(&criteria).unwrap().map(|r| r[0]).collect();
+        assert_eq!(rows, vec![15, 18, 21, 24]);
+
+        assert!(remote.query_with(&Criteria::new().range(2, 0..=0)).is_err());
+
+        /* Blocks are checked against the schema's values as well as its dimensions */
+        let mut remote = remote;
+        remote.schema.values.push(Value { name: String::from("other"), value_type: ValueType::U64 });
+        remote.cached_blocks = RefCell::new(Cache::new(BLOCK_CACHE_SIZE));
+        assert!(matches!(remote.query().unwrap().try_next(), Err(Error::SchemaError)));
+
+        drop(remote);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn oversized_response() {
+        let mut response = vec![0];
+        response.extend(u32::MAX.to_be_bytes());
+        assert!(matches!(read_response(&mut response.as_slice()), Err(Error::DataError)));
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::Error;
+use crate::clock;
+
+/**
+ * How a failed read of a segment or block for a query is retried, for
+ * `Database::set_fetch_retry`.  Only `IoError`s are retried, as a transient failure of the
+ * storage may clear up; a block that reads but fails to decode will fail again.  The delay
+ * doubles after each attempt, up to `max_delay`.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /** The number of attempts, including the first; 1 never retries. */
+    pub attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration
+}
+
+impl RetryPolicy {
+    /**
+     * Fail on the first error, as the database did before retries were added.
+     */
+    pub fn none() -> RetryPolicy {
+        RetryPolicy { attempts: 1, initial_delay: Duration::ZERO, max_delay: Duration::ZERO }
+    }
+
+    /**
+     * Run a fetch of `what`, retrying it while it fails with `IoError` and attempts remain.
+     * The last error is returned if none succeed.
+     */
+    pub(crate) fn run<T, W: Debug, F: FnMut() -> Result<T, Error>>(&self, what: W, mut fetch: F) -> Result<T, Error> {
+        let mut delay = self.initial_delay;
+        let mut attempt = 1;
+        loop {
+            match fetch() {
+                Err(Error::IoError) if attempt < self.attempts => {
+                    warn!("Fetch of {what:?} failed on attempt {attempt}, retrying in {delay:?}");
+                    clock::sleep(delay);
+                    delay = (delay * 2).min(self.max_delay);
+                    attempt += 1;
+                },
+                result => return result
+            }
+        }
+    }
+}
+
+/**
+ * Three attempts, waiting 10 ms and then 20 ms.
+ */
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy { attempts: 3, initial_delay: Duration::from_millis(10), max_delay: Duration::from_secs(1) }
+    }
+}
+
+#[cfg(test)]
+mod retry_tests {
+    use std::time::Duration;
+
+    use crate::Error;
+    use super::RetryPolicy;
+
+    #[test]
+    fn retries() {
+        let policy = RetryPolicy { attempts: 3, initial_delay: Duration::ZERO, max_delay: Duration::ZERO };
+        let mut calls = 0;
+        assert_eq!(policy.run("a", || { calls += 1; if calls < 3 { Err(Error::IoError) } else { Ok(calls) } }), Ok(3));
+
+        calls = 0;
+        assert_eq!(policy.run("b", || { calls += 1; Err::<(), _>(Error::IoError) }), Err(Error::IoError));
+        assert_eq!(calls, 3);
+
+        calls = 0;
+        assert_eq!(policy.run("c", || { calls += 1; Err::<(), _>(Error::DataError) }), Err(Error::DataError));
+        assert_eq!(calls, 1);
+
+        calls = 0;
+        assert_eq!(RetryPolicy::none().run("d", || { calls += 1; Err::<(), _>(Error::IoError) }), Err(Error::IoError));
+        assert_eq!(calls, 1);
+    }
+}
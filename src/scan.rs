@@ -1,26 +1,97 @@
 use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
 use std::collections::binary_heap::BinaryHeap;
 use std::rc::Rc;
 use log::{debug, error, info};
 
 use crate::block::{Block, BlockIter};
-use crate::{BlockId, BlockNum, compare_points, Datum, SegmentId, TransactionId};
-use crate::query::QueryRow;
+use crate::cache::CacheHint;
+use crate::{BlockId, BlockNum, compare_points, Datum, Error, SegmentId, SegmentNum, TransactionId};
+use crate::merge::{MergeStrategy, NewestWins};
+use crate::project::{self, Projection};
+use crate::query::{Criteria, QueryRow};
+use crate::aggregate::{self, Aggregate, AggregateQuery};
 use crate::segment::Segment;
+#[cfg(feature = "fs")]
+use crate::spool::Spool;
+use crate::tombstone::Tombstones;
+use crate::trace::{QueryTimer, QueryTrace};
+use crate::window::Window;
 
 /**
  * Something that can provide segments and blocks to a scan.
  */
 pub(crate) trait ScanSource {
-    fn get_segment(&self, seg_id: SegmentId) -> Option<Rc<Segment>>;
-    fn get_block(&self, block_id: BlockId) -> Option<Rc<Block>>;
+    fn get_segment(&self, seg_id: SegmentId) -> Result<Rc<Segment>, Error>;
+    fn get_block_with_hint(&self, block_id: BlockId, hint: CacheHint) -> Result<Rc<Block>, Error>;
+
+    fn get_block(&self, block_id: BlockId) -> Result<Rc<Block>, Error> {
+        self.get_block_with_hint(block_id, CacheHint::Normal)
+    }
+
+    /**
+     * Fetch a block for a scan that returns only the value columns numbered in `values`.  A
+     * source may then decode only those columns, when the block isn't already cached; see
+     * `Block::load_values`.
+     */
+    fn get_block_values(&self, block_id: BlockId, hint: CacheHint, _values: &[usize]) -> Result<Rc<Block>, Error> {
+        self.get_block_with_hint(block_id, hint)
+    }
+
+    /**
+     * The bounds of a block, from the info of its segment, which is usually cached, so a scan
+     * can tell whether it needs the block before fetching it.
+     */
+    fn get_block_bounds(&self, block_id: BlockId) -> Option<(Vec<Datum>, Vec<Datum>)> {
+        let segment = self.get_segment((block_id.0, block_id.1)).ok()?;
+        let block_info = segment.block_info.get(block_id.2 as usize)?;
+        Some((block_info.min_bounds.clone(), block_info.max_bounds.clone()))
+    }
+
+    /**
+     * The chunk size of each dimension, which lets a scan recognise blocks that fill a whole
+     * chunk.  Without them, no blocks are skipped as superseded.
+     */
+    fn chunk_sizes(&self) -> Option<Vec<usize>> {
+        None
+    }
+
+    /**
+     * The number of value columns in the schema, which lets a scan check the values selected
+     * from it.  Without it, selecting a value that doesn't exist returns 0 for it.
+     */
+    fn num_values(&self) -> Option<usize> {
+        None
+    }
+
+    /**
+     * A tracing id for a new scan.
+     */
+    fn next_query_id(&self) -> u64 {
+        0
+    }
+
+    /**
+     * Called once when a scan finishes, with what it did.
+     */
+    fn query_finished(&self, _trace: QueryTrace) {}
 }
 
+/**
+ * Which copy of a row is newer: rows from later transactions replace earlier ones, and within a
+ * transaction, rows from later segments replace those flushed earlier.  Unsaved blocks are
+ * newer than anything saved.
+ */
+pub(crate) type Version = (TransactionId, SegmentNum);
+
+pub(crate) const UNSAVED_VERSION: Version = (TransactionId::MAX, SegmentNum::MAX);
+
 pub(crate) enum Type {
     SegmentId(SegmentId),
     Segment(Rc<Segment>),
     BlockId(BlockId),
-    Block(Rc<Block>)
+    Block(Rc<Block>, Version),
+    Tombstone(Criteria, Version)
 }
 
 pub(crate) struct QueuedItem {
@@ -28,11 +99,35 @@ pub(crate) struct QueuedItem {
     item_type: Type
 }
 
+/**
+ * Rows a scan couldn't read, because fetching a segment or block failed even after any
+ * retries.  The missing rows lie within `bounds`, the minimum and maximum of each dimension,
+ * or anywhere in the segment if its info couldn't be read either.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingRows {
+    pub segment: SegmentId,
+    pub block: Option<BlockNum>,
+    pub bounds: Option<(Vec<Datum>, Vec<Datum>)>,
+    pub error: Error
+}
+
 #[derive(Clone)]
 pub(crate) struct LiveItem {
     iter: BlockIter,
     current: Option<Vec<Datum>>,
-    txn_id: TransactionId
+    /* The values missing from the current row, if it is a partial version */
+    missing: Vec<usize>,
+    version: Version
+}
+
+impl LiveItem {
+    fn advance(&mut self) {
+        (self.current, self.missing) = match self.iter.next_partial() {
+            Some((row, missing)) => (Some(row), missing),
+            None => (None, Vec::new())
+        };
+    }
 }
 
 /**
@@ -44,8 +139,9 @@ pub(crate) struct LiveItem {
  * set of live block iterators.
  *
  * When a row is fetched from the iterator, the first row from each live iterator is checked.  The
- * best one is returned from the iterator.  Other rows are either kept for next time, or discarded.
- * Discarded rows are those with the same key as the best row, but from an older transaction.
+ * rows with the lowest key are the versions of that row, and the merge strategy decides which of
+ * them to return; by default it is the one from the newest transaction.  Other rows are kept for
+ * next time.
  *
  * When the current row id reaches the next start point, any blocks containing that point are
  * dequeued and their iterator is added to the live set.
@@ -57,26 +153,271 @@ pub struct Scan<'txn> {
     num_dims: usize,
     this_txn_id: TransactionId,
     queue: BinaryHeap<QueuedItem>,
-    live: Vec<LiveItem>
+    live: Vec<LiveItem>,
+    criteria: Criteria,
+    cache_hint: CacheHint,
+    seek_point: Option<Vec<Datum>>,
+    chunk_sizes: Option<Vec<usize>>,
+    /* The newest version of each chunk that has a block filling it entirely */
+    full_chunks: HashMap<Vec<Datum>, Version>,
+    /* Tombstones that have been dequeued, which hide older rows matching them */
+    tombstones: Tombstones,
+    merge: Box<dyn MergeStrategy + 'txn>,
+    provenance: bool,
+    /* Rows the merge strategy returned for a point that haven't been returned yet */
+    pending: VecDeque<QueryRow>,
+    missing: Vec<MissingRows>,
+    /* Whether the scan stops at the first segment or block it can't read */
+    strict: bool,
+    /* The value columns to return, in order, if not all of them */
+    selected_values: Option<Vec<usize>>,
+    timer: QueryTimer
 }
 
 impl<'txn> Scan<'txn> {
     pub(crate) fn new(source: Box<dyn ScanSource + 'txn>, num_dims: usize, txn_id: TransactionId) -> Scan<'txn> {
+        let timer = QueryTimer::start(source.next_query_id());
+        let chunk_sizes = source.chunk_sizes();
         Scan {
             source,
             num_dims,
             this_txn_id: txn_id,
             queue: Default::default(),
-            live: Default::default()
+            live: Default::default(),
+            criteria: Criteria::new(),
+            cache_hint: CacheHint::Normal,
+            seek_point: None,
+            chunk_sizes,
+            full_chunks: HashMap::new(),
+            tombstones: Tombstones::default(),
+            merge: Box::new(NewestWins),
+            provenance: false,
+            pending: VecDeque::new(),
+            missing: Vec::new(),
+            strict: true,
+            selected_values: None,
+            timer
         }
     }
 
-    pub(crate) fn add_segment_id(&mut self, seg_id: SegmentId) {
-        let start_point = Some(vec![0, 0]);  //TODO should know the segment coords
-        if start_point.is_none() {
-            return;
+    /**
+     * The scan's tracing id, which appears in log messages about it and in the slow-query log.
+     */
+    pub fn query_id(&self) -> u64 {
+        self.timer.id
+    }
+
+    /**
+     * Declare how the blocks read by the scan should be cached, such as `CacheHint::OneShot`
+     * for an export that shouldn't displace the blocks other queries use.
+     */
+    pub fn cache_hint(mut self, hint: CacheHint) -> Self {
+        self.cache_hint = hint;
+        self
+    }
+
+    /**
+     * Choose how rows that several transactions have written at the same point are resolved,
+     * before reading any rows.  See `MergeStrategy`.
+     */
+    pub fn merge_strategy<M: MergeStrategy + 'txn>(mut self, strategy: M) -> Self {
+        self.merge = Box::new(strategy);
+        self
+    }
+
+    /**
+     * Fill in the segment each row was read from, in `QueryRow::segment`, to trace where a
+     * value came from.
+     */
+    pub fn with_provenance(mut self) -> Self {
+        self.provenance = true;
+        self
+    }
+
+    /**
+     * Go on past segments and blocks that can't be read, returning the rest of the rows and
+     * listing what was left out in `missing_rows`.  By default a scan stops at the first one,
+     * so that partial results aren't mistaken for complete ones.
+     */
+    pub fn allow_missing(mut self) -> Self {
+        self.strict = false;
+        self
+    }
+
+    /**
+     * The rows the scan has had to leave out so far, because a segment or block it needed
+     * couldn't be read.  Check this once the scan is finished to tell whether its results are
+     * complete.
+     */
+    pub fn missing_rows(&self) -> &[MissingRows] {
+        &self.missing
+    }
+
+    /**
+     * Fail with the error of the first segment or block the scan couldn't read, if any.
+     */
+    pub fn check(&self) -> Result<(), Error> {
+        match self.missing.first() {
+            Some(missing) => Err(missing.error),
+            None => Ok(())
+        }
+    }
+
+    /**
+     * The next row, like `next`, but failing if the scan stopped because a segment or block
+     * couldn't be read, rather than ending as if there were no more rows.
+     */
+    pub fn try_next(&mut self) -> Result<Option<QueryRow>, Error> {
+        let row = self.next();
+        if row.is_none() {
+            self.check()?;
+        }
+        Ok(row)
+    }
+
+    /**
+     * Read all the rows, failing with what is missing if any segment or block couldn't be read.
+     */
+    pub fn collect_complete(mut self) -> Result<Vec<QueryRow>, Vec<MissingRows>> {
+        let rows: Vec<QueryRow> = self.by_ref().collect();
+        if self.missing.is_empty() {
+            Ok(rows)
+        } else {
+            Err(std::mem::take(&mut self.missing))
         }
-        let start_point = start_point.unwrap();
+    }
+
+    /**
+     * Apply a moving window of `size` rows along the first dimension, separately for each
+     * combination of the other dimensions.  Choose an operation on the result, for example
+     * `scan.window(10).mean()`.
+     */
+    pub fn window(self, size: usize) -> Window<Self> {
+        let num_dims = self.num_dims;
+        Window::new(self, num_dims, size)
+    }
+
+    /**
+     * Return the rows with their dimensions in the order given by `dims`, followed by the values
+     * as usual.  For example, `scan.project(&[1, 0])` swaps the two dimensions of each row.
+     * Dimensions fixed to a single value by the criteria can be left out.
+     *
+     * Fails with `SchemaError` if a dimension is repeated or doesn't exist, or if one that's
+     * left out could have more than one value.
+     */
+    pub fn project(self, dims: &[usize]) -> Result<Projection<Self>, crate::Error> {
+        let num_dims = self.num_dims;
+        let criteria = self.criteria.clone();
+        Projection::new(self, num_dims, &criteria, dims)
+    }
+
+    /**
+     * Return only the values numbered in `values`, in that order, after the dimensions, before
+     * reading any rows.  The other value columns are never copied out of the blocks or merged,
+     * which saves work on wide schemas.  Value numbers given to later steps, such as
+     * `aggregate` or `top_k`, are positions in `values`.  A partial version still takes the
+     * selected values it is missing from the version before it.  Use `project` as well to
+     * reorder or leave out dimensions.  Blocks saved in format version 10 or later are loaded
+     * without decoding the other value columns, unless they are already cached.
+     *
+     * Fails with `SchemaError` if a value is repeated or doesn't exist, or if the scan has
+     * already started reading rows, which would then have a different number of values.
+     */
+    pub fn select_values(mut self, values: &[usize]) -> Result<Self, crate::Error> {
+        if self.timer.rows_returned > 0 || !self.live.is_empty() || !self.pending.is_empty() {
+            error!("Can't select values after a scan has started reading rows");
+            return Err(crate::Error::SchemaError);
+        }
+        project::check_values(values, self.source.num_values())?;
+        self.selected_values = Some(values.to_vec());
+        Ok(self)
+    }
+
+    /**
+     * Run the scan to completion, writing the rows to a temporary file to be read back later.
+     * This suits large results consumed slowly, such as over a network, since the blocks used
+     * by the scan are released as soon as it finishes.
+     */
+    #[cfg(feature = "fs")]
+    pub fn spool(mut self) -> Result<Spool, crate::Error> {
+        let spool = Spool::create(self.by_ref())?;
+        self.check()?;
+        Ok(spool)
+    }
+
+    /**
+     * Group the rows by their coordinate in `group_dim` and return the `k` groups with the
+     * largest aggregate of value `value_no`, as `(group, aggregate)` pairs, largest first.
+     * For example, the ten sensors with the highest readings in a time range.
+     *
+     * Fails if a segment or block couldn't be read, as `check` does, rather than returning the
+     * groups from the rows read before it.
+     */
+    pub fn top_k(mut self, group_dim: usize, value_no: usize, aggregate: Aggregate, k: usize) -> Result<Vec<(Datum, Datum)>, Error> {
+        let num_dims = self.num_dims;
+        let groups = aggregate::top_k(self.by_ref(), num_dims, group_dim, value_no, aggregate, k);
+        self.check()?;
+        Ok(groups)
+    }
+
+    /**
+     * Aggregate value `value_no` over all the rows, returning the count, sum, minimum, maximum
+     * and mean together when the query is run, rather than the rows themselves.  For example,
+     * `txn.query().aggregate(0).run()?.mean`.
+     */
+    pub fn aggregate(self, value_no: usize) -> AggregateQuery<'txn> {
+        let num_dims = self.num_dims;
+        AggregateQuery::new(self, num_dims, value_no)
+    }
+
+    /**
+     * Only return rows matching the criteria, and skip any blocks that can't contain them.
+     */
+    pub(crate) fn set_criteria(&mut self, criteria: Criteria) {
+        self.criteria = criteria;
+    }
+
+    /**
+     * Skip the rows before `point`.  Each live block's iterator jumps straight to it, and blocks
+     * that become live later start there, so the skipped rows are never merged.
+     */
+    pub(crate) fn seek_to(&mut self, point: &[Datum]) {
+        let num_dims = self.num_dims;
+        for item in &mut self.live {
+            if item.current.as_ref().is_some_and(|current| compare_points(num_dims, current, point).is_lt()) {
+                item.iter.seek_to(point);
+                item.advance();
+            }
+        }
+        self.live.retain(|x| x.current.is_some());
+        self.pending.retain(|row| compare_points(num_dims, &row.values_array, point).is_ge());
+        self.seek_point = Some(point.to_vec());
+    }
+
+    /**
+     * Hide the rows deleted by tombstones that aren't in any segment added to the scan, such
+     * as the transaction's unsaved deletes.
+     */
+    pub(crate) fn add_tombstones(&mut self, tombstones: &Tombstones) {
+        for (version, criteria) in tombstones.iter() {
+            self.add_tombstone(criteria.clone(), *version);
+        }
+    }
+
+    /**
+     * Queue a tombstone to start at the lowest point it matches.  No row before that point
+     * can match it, and blocks it covers start no earlier, so it is always dequeued in time.
+     */
+    fn add_tombstone(&mut self, criteria: Criteria, version: Version) {
+        let Some(start_point) = criteria.start_point(self.num_dims) else { return };
+        self.queue.push(QueuedItem {
+            start_point,
+            item_type: Type::Tombstone(criteria, version)
+        });
+    }
+
+    pub(crate) fn add_segment_id(&mut self, seg_id: SegmentId) {
+        let start_point = vec![0; self.num_dims];  //TODO should know the segment coords
         self.queue.push(QueuedItem {
             start_point,
             item_type: Type::SegmentId(seg_id)
@@ -84,11 +425,7 @@ impl<'txn> Scan<'txn> {
     }
 
     pub(crate) fn add_segment(&mut self, segment: Rc<Segment>) {
-        let start_point = Some(vec![0, 0]);  //TODO should know the segment coords
-        if start_point.is_none() {
-            return;
-        }
-        let start_point = start_point.unwrap();
+        let start_point = vec![0; self.num_dims];  //TODO should know the segment coords
         self.queue.push(QueuedItem {
             start_point,
             item_type: Type::Segment(segment)
@@ -102,70 +439,178 @@ impl<'txn> Scan<'txn> {
         })
     }
 
+    /**
+     * Add a block that hasn't been saved yet, whose rows replace any saved ones.
+     */
     pub(crate) fn add_block(&mut self, block: Rc<Block>) {
-        let start_point = block.get_start_point();
-        if start_point.is_none() {
+        self.add_block_version(block, UNSAVED_VERSION);
+    }
+
+    fn add_block_version(&mut self, block: Rc<Block>, version: Version) {
+        let Some(start_point) = block.get_start_point() else {
             info!("Not enqueuing empty block");
             return;
+        };
+        if !self.criteria.overlaps_bounds(&block.get_min_bounds(), &block.get_max_bounds()) {
+            debug!("Not enqueuing block outside criteria");
+            return;
+        }
+        if block.is_complete() {
+            self.note_full_chunk(&start_point, block.num_rows(), version);
         }
-        let start_point = start_point.unwrap();
         debug!("Enqueued block starting at {:?}", start_point);
         self.queue.push(QueuedItem {
             start_point,
-            item_type: Type::Block(block)
+            item_type: Type::Block(block, version)
         });
     }
 
+    /**
+     * The first point of the chunk containing the given point, if the chunk sizes are known.
+     */
+    fn chunk_origin(&self, point: &[Datum]) -> Option<Vec<Datum>> {
+        let chunk_sizes = self.chunk_sizes.as_ref()?;
+        Some(chunk_sizes.iter().zip(point).map(|(&size, &value)| value - value % size).collect())
+    }
+
+    /**
+     * Remember a block that has a row at every point of its chunk, so that blocks of older
+     * versions of the chunk can be skipped without fetching them: every one of their rows is
+     * superseded.  Blocks are kept within one chunk, so a block has as many rows as its chunk
+     * has points only if it fills it.
+     */
+    fn note_full_chunk(&mut self, point: &[Datum], num_rows: usize, version: Version) {
+        let Some(chunk_sizes) = &self.chunk_sizes else { return };
+        let num_points = chunk_sizes.iter().try_fold(1usize, |product, &size| product.checked_mul(size));
+        if num_points != Some(num_rows) {
+            return;
+        }
+        let Some(origin) = self.chunk_origin(point) else { return };
+        let newest = self.full_chunks.entry(origin).or_insert(version);
+        *newest = (*newest).max(version);
+    }
+
+    /**
+     * Whether a newer version fills the whole chunk containing the given point.
+     */
+    fn is_superseded(&self, point: &[Datum], version: Version) -> bool {
+        self.merge.newest_only() && self.chunk_origin(point)
+            .and_then(|origin| self.full_chunks.get(&origin))
+            .is_some_and(|&newest| newest > version)
+    }
+
     fn pop_queue_item(&mut self) {
-        let queue_item = self.queue.pop().expect("at least one queued item");
+        let Some(queue_item) = self.queue.pop() else { return };
         match queue_item.item_type {
             Type::SegmentId(seg_id) => {
-                let opt_rc = self.source.get_segment(seg_id);
-                if let Some(rc) = opt_rc {
-                    self.add_segment(rc);
-                } else {
-                    error!("Couldn't get segment {:?} from source", seg_id);
+                match self.source.get_segment(seg_id) {
+                    Ok(rc) => self.add_segment(rc),
+                    Err(error) => {
+                        error!("Couldn't get segment {:?} from source: {:?}", seg_id, error);
+                        self.add_missing(MissingRows { segment: seg_id, block: None, bounds: None, error });
+                    }
                 }
             }
             Type::Segment(rc) => {
                 //TODO add every block in the segment, not just the cached ones
                 let segment = &*rc;
+                for criteria in &segment.tombstones {
+                    self.add_tombstone(criteria.clone(), segment.id);
+                }
                 for (block_num, block_info) in segment.block_info.iter().enumerate() {
+                    if !self.criteria.overlaps_bounds(&block_info.min_bounds, &block_info.max_bounds) {
+                        continue;
+                    }
+                    if let Some((num_rows, _)) = block_info.counts {
+                        /* Formats before 8 can't hold partial rows; from 9 they can be counted */
+                        let complete = segment.version < 8 || block_info.column_counts.as_ref()
+                            .is_some_and(|counts| counts.iter().all(|&count| count == num_rows));
+                        if complete {
+                            self.note_full_chunk(&block_info.min_bounds, num_rows, segment.id);
+                        }
+                    }
                     let block_id = (segment.id.0, segment.id.1, block_num as BlockNum);
                     let start_point = block_info.min_bounds.clone();
                     self.add_block_id(block_id, start_point);
                 }
             }
             Type::BlockId(block_id) => {
-                let opt_rc = self.source.get_block(block_id);
-                if let Some(rc) = opt_rc {
-                    self.add_block(rc);
-                } else {
-                    error!("Couldn't get block {:?} from source", block_id);
+                /* The criteria may have changed since the block was queued */
+                let bounds = self.source.get_block_bounds(block_id);
+                if let Some((min_bounds, max_bounds)) = &bounds {
+                    if !self.criteria.overlaps_bounds(min_bounds, max_bounds) {
+                        debug!("Not fetching block {:?} outside criteria", block_id);
+                        return;
+                    }
+                    if self.is_superseded(min_bounds, (block_id.0, block_id.1)) {
+                        debug!("Not fetching block {:?} superseded by a newer full chunk", block_id);
+                        return;
+                    }
+                    if self.tombstones.covers(min_bounds, max_bounds, (block_id.0, block_id.1)) {
+                        debug!("Not fetching block {:?} deleted by a newer tombstone", block_id);
+                        return;
+                    }
+                }
+                let fetched = match &self.selected_values {
+                    Some(values) => self.source.get_block_values(block_id, self.cache_hint, values),
+                    None => self.source.get_block_with_hint(block_id, self.cache_hint)
+                };
+                match fetched {
+                    Ok(rc) => {
+                        self.timer.blocks_read += 1;
+                        self.add_block_version(rc, (block_id.0, block_id.1));
+                    },
+                    Err(error) => {
+                        error!("Couldn't get block {:?} from source: {:?}", block_id, error);
+                        self.add_missing(MissingRows { segment: (block_id.0, block_id.1), block: Some(block_id.2), bounds, error });
+                    }
                 }
             }
-            Type::Block(rc) => {
+            Type::Block(rc, version) => {
                 let mut iter = Block::iter(&rc);
+                if let Some(values) = &self.selected_values {
+                    iter.select_values(values);
+                }
+                if !self.criteria.covers_bounds(&rc.get_min_bounds(), &rc.get_max_bounds()) {
+                    iter.restrict(&self.criteria);
+                }
+                if let Some(point) = &self.seek_point {
+                    iter.seek_to(point);
+                }
 
                 /* Get the first row in this block; if there isn't one, skip the block entirely.
                    Otherwise, set it as the next start point if necessary.
                  */
-                let current = iter.next();
-                if current.is_none() {
+                let mut item = LiveItem { iter, current: None, missing: Vec::new(), version };
+                item.advance();
+                if item.current.is_none() {
                     return;
                 }
 
-                info!("Begin block starting at {:?}", current);
-                self.live.push(LiveItem {
-                    iter,
-                    current,
-                    txn_id: TransactionId::MAX
-                });
+                info!("Begin block starting at {:?}", item.current);
+                self.live.push(item);
+            }
+            Type::Tombstone(criteria, version) => {
+                debug!("Begin tombstone {:?} from version {:?}", criteria, version);
+                self.tombstones.add(version, &criteria);
             }
         }
 
     }
 
+    /**
+     * Record rows that couldn't be read.  A strict scan abandons everything it has queued or
+     * not yet returned, and so returns no more rows.
+     */
+    fn add_missing(&mut self, missing: MissingRows) {
+        self.missing.push(missing);
+        if self.strict {
+            self.queue.clear();
+            self.live.clear();
+            self.pending.clear();
+        }
+    }
+
     fn check_queue(&mut self, current: &[Datum]) {
         debug!("Checking for queue for stuff to become live");
         while let Some(next_queue_item) = self.queue.peek() {
@@ -178,13 +623,27 @@ impl<'txn> Scan<'txn> {
             self.pop_queue_item();
         }
     }
-}
 
-impl<'txn> Iterator for Scan<'txn> {
-    type Item = QueryRow;
+    fn finish(&mut self) {
+        if let Some(trace) = self.timer.finish(&self.criteria) {
+            self.source.query_finished(trace);
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    fn make_row(&self, version: Version, values_array: Vec<Datum>) -> QueryRow {
+        if version == UNSAVED_VERSION {
+            QueryRow { txn_id: self.this_txn_id, segment: None, values_array }
+        } else {
+            QueryRow { txn_id: version.0, segment: self.provenance.then_some(version), values_array }
+        }
+    }
+
+    fn next_row(&mut self) -> Option<QueryRow> {
         loop {
+            if let Some(row) = self.pending.pop_front() {
+                return Some(row);
+            }
+
             let mut current = self.queue.peek().map(|x| x.start_point.clone());
             let mut need_to_deqeue = true;
             debug!("Current is {:?}", current);
@@ -209,32 +668,70 @@ impl<'txn> Iterator for Scan<'txn> {
                 continue;
             }
 
-            /* Now check everything that's live for the best thing to return. */
-            let mut best_txn_id = 0;
-            let mut best_row: Option<Vec<Datum>> = None;
+            /* Now collect every version of the row at the current point from the live set. */
+            let mut versions: Vec<(Version, Vec<Datum>, Vec<usize>)> = Vec::new();
             debug!("Current is {:?}", current_point);
-            debug!("Looking for best row in {:?} live iterators", self.live.len());
+            debug!("Looking for versions in {:?} live iterators", self.live.len());
             for item in self.live.iter_mut() {
                 let item_point = item.current.as_ref().unwrap();
-                debug!("Iterator current is {:?} from txn {:?}", item_point, item.txn_id);
+                debug!("Iterator current is {:?} from version {:?}", item_point, item.version);
                 if compare_points(self.num_dims, item_point, current_point).is_eq() {
-                    if item.txn_id > best_txn_id {
-                        best_txn_id = item.txn_id;
-                        best_row = Some(item.current.as_ref().unwrap().clone());
-                        item.current = item.iter.next();
-                    } else {
-                        debug!("Ignoring row {:?} from txn {:?}", item_point, item.txn_id);
-                        item.current = item.iter.next();
-                    }
+                    versions.push((item.version, item.current.take().unwrap(), std::mem::take(&mut item.missing)));
+                    item.advance();
                 }
             }
-            debug!("Best row found was {:?}", best_row);
 
             /* Clean up the live set. */
             self.live.retain(|x| x.current.is_some());
 
-            return best_row.map(|x| QueryRow { txn_id: best_txn_id, values_array: x });
+            /* Versions older than a tombstone matching the point were deleted. */
+            versions.retain(|(version, row, _)| !self.tombstones.deletes(row, *version));
+
+            /* Blocks can overlap the criteria without every row in them matching. */
+            if versions.first().is_none_or(|(_, row, _)| !self.criteria.matches_point(row)) {
+                continue;
+            }
+
+            /* A partial version takes its missing values from the version before it, or is 0 in them */
+            versions.sort_by_key(|(version, _, _)| *version);
+            let fill = self.merge.fills_partial_versions();
+            let mut rows: Vec<QueryRow> = Vec::with_capacity(versions.len());
+            for (version, mut values_array, missing) in versions {
+                if let Some(previous) = rows.last().filter(|_| fill) {
+                    for value_no in missing {
+                        let idx = self.num_dims + value_no;
+                        values_array[idx] = previous.values_array.get(idx).copied().unwrap_or(0);
+                    }
+                }
+                rows.push(self.make_row(version, values_array));
+            }
+            self.merge.merge(&mut rows);
+            debug!("Merged rows are {:?}", rows);
+            self.pending.extend(rows);
+        }
+    }
+}
+
+impl<'txn> Iterator for Scan<'txn> {
+    type Item = QueryRow;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.next_row();
+        if row.is_some() {
+            self.timer.rows_returned += 1;
+        } else {
+            self.finish();
         }
+        row
+    }
+}
+
+/**
+ * A scan that is dropped before its last row is read still counts as a finished query.
+ */
+impl<'txn> Drop for Scan<'txn> {
+    fn drop(&mut self) {
+        self.finish();
     }
 }
 
@@ -252,39 +749,54 @@ impl PartialOrd<Self> for QueuedItem {
     }
 }
 
+/**
+ * Items are dequeued in order of their start points, and tombstones before blocks starting at
+ * the same point, so the blocks they cover aren't fetched.
+ */
 impl Ord for QueuedItem {
     fn cmp(&self, other: &Self) -> Ordering {
+        let is_tombstone = |item: &QueuedItem| matches!(item.item_type, Type::Tombstone(..));
         compare_points(self.start_point.len(), &self.start_point, &other.start_point).reverse()
+            .then_with(|| is_tombstone(self).cmp(&is_tombstone(other)))
     }
 }
 
 #[cfg(test)]
 mod scan_tests {
+    use std::cell::Cell;
     use std::collections::HashMap;
+    use crate::merge::{KeepAllVersions, SumValues};
     use super::*;
 
     struct MemSource {
-        segments: HashMap<(TransactionId, SegmentId), Rc<Segment>>
+        segments: HashMap<SegmentId, Rc<Segment>>,
+        blocks: HashMap<BlockId, Rc<Block>>,
+        fetches: Rc<Cell<usize>>,
+        chunk_sizes: Option<Vec<usize>>
     }
 
     impl MemSource {
-        fn new<'t>() -> Box<dyn ScanSource + 't> {
-            Box::new(MemSource { segments: HashMap::new() })
+        fn new_boxed<'t>() -> Box<dyn ScanSource + 't> {
+            Box::new(MemSource { segments: HashMap::new(), blocks: HashMap::new(), fetches: Default::default(), chunk_sizes: None })
         }
     }
 
     impl ScanSource for MemSource {
-        fn get_segment(&self, seg_id: SegmentId) -> Option<Rc<Segment>> {
-            todo!()
+        fn get_segment(&self, seg_id: SegmentId) -> Result<Rc<Segment>, Error> {
+            self.segments.get(&seg_id).cloned().ok_or(Error::IoError)
+        }
+        fn get_block_with_hint(&self, block_id: BlockId, _hint: CacheHint) -> Result<Rc<Block>, Error> {
+            self.fetches.set(self.fetches.get() + 1);
+            self.blocks.get(&block_id).cloned().ok_or(Error::IoError)
         }
-        fn get_block(&self, block_id: BlockId) -> Option<Rc<Block>> {
-            todo!()
+        fn chunk_sizes(&self) -> Option<Vec<usize>> {
+            self.chunk_sizes.clone()
         }
     }
 
     #[test]
     fn empty_scan() {
-        let source = MemSource::new();
+        let source = MemSource::new_boxed();
         let mut scan = Scan::new(source, 2, 5);
 
         assert!(&scan.next().is_none());
@@ -294,7 +806,7 @@ mod scan_tests {
     fn one_empty_local_block() {
         let b = Rc::new(Block::new(2));
 
-        let source = MemSource::new();
+        let source = MemSource::new_boxed();
         let mut scan = Scan::new(source, 2, 5);
         scan.add_block(b);
 
@@ -308,7 +820,7 @@ mod scan_tests {
         b.add_row(&[9, 0, 101]);
         let b = Rc::new(b);
 
-        let source = MemSource::new();
+        let source = MemSource::new_boxed();
         let mut scan = Scan::new(source, 2, 5);
         scan.add_block(b);
 
@@ -318,7 +830,7 @@ mod scan_tests {
         assert_eq!(r[0], 7);
         assert_eq!(r[1], 4);
         assert_eq!(r[2], 99);
-        assert_eq!(r.txn_id, TransactionId::MAX);
+        assert_eq!(r.txn_id, 5);
 
         let r = scan.next();
         assert!(r.is_some());
@@ -326,7 +838,7 @@ mod scan_tests {
         assert_eq!(r[0], 9);
         assert_eq!(r[1], 0);
         assert_eq!(r[2], 101);
-        assert_eq!(r.txn_id, TransactionId::MAX);
+        assert_eq!(r.txn_id, 5);
 
         assert!(&scan.next().is_none());
     }
@@ -340,7 +852,7 @@ mod scan_tests {
         b2.add_row(&[9, 0, 101]);
         let b2 = Rc::new(b2);
 
-        let source = MemSource::new();
+        let source = MemSource::new_boxed();
         let mut scan = Scan::new(source, 2, 5);
         scan.add_block(b);
         scan.add_block(b2);
@@ -361,4 +873,225 @@ mod scan_tests {
 
         assert!(&scan.next().is_none());
     }
+
+    #[test]
+    fn skip_blocks_outside_criteria() {
+        let mut b = Block::new(2);
+        b.add_row(&[7, 4, 99]);
+        let mut b2 = Block::new(2);
+        b2.add_row(&[19, 0, 101]);
+        let mut segment = Segment::new((1, 0));
+        segment.encode(&[&b, &b2]).unwrap();
+
+        let fetches = Rc::new(Cell::new(0));
+        let source = Box::new(MemSource {
+            segments: HashMap::from([((1, 0), Rc::new(segment))]),
+            blocks: HashMap::from([((1, 0, 0), Rc::new(b)), ((1, 0, 1), Rc::new(b2))]),
+            fetches: fetches.clone(),
+            chunk_sizes: None
+        });
+        let mut scan = Scan::new(source, 2, 5);
+        scan.add_block_id((1, 0, 0), vec![7, 4]);
+        scan.add_block_id((1, 0, 1), vec![19, 0]);
+        scan.set_criteria(Criteria::new().range(0, 10..=19));
+
+        let rows: Vec<_> = scan.map(|r| r.values_array).collect();
+        assert_eq!(rows, vec![vec![19, 0, 101]]);
+        assert_eq!(fetches.get(), 1);
+    }
+
+    #[test]
+    fn seek_to() {
+        let mut b = Block::new(2);
+        let mut b2 = Block::new(2);
+        for i in 0..10 {
+            b.add_row(&[i, 0, i]);
+            b2.add_row(&[i, 1, i + 100]);
+        }
+
+        let source = MemSource::new_boxed();
+        let mut scan = Scan::new(source, 2, 5);
+        scan.add_block(Rc::new(b));
+        scan.add_block(Rc::new(b2));
+        assert_eq!(scan.next().unwrap().values_array, vec![0, 0, 0]);
+
+        scan.seek_to(&[4, 0]);
+        let rows: Vec<_> = scan.take(3).map(|row| row.values_array).collect();
+        assert_eq!(rows, vec![vec![4, 0, 4], vec![4, 1, 104], vec![5, 0, 5]]);
+
+        /* Seeking past a partial version doesn't leave its missing values on the next row */
+        let mut old = Block::new(2);
+        for i in 0..10 {
+            old.add_row(&[i, 0, i, i * 10]);
+        }
+        let mut new = Block::new(2);
+        new.add_row(&[0, 0, 1, 2]);
+        new.add_partial_row(&[1, 0, 11, 0], &[1]);
+        new.add_row(&[5, 0, 55, 555]);
+        let mut scan = Scan::new(MemSource::new_boxed(), 2, 5);
+        scan.add_block_version(Rc::new(old), (1, 0));
+        scan.add_block_version(Rc::new(new), (2, 0));
+        assert_eq!(scan.next().unwrap().values_array, vec![0, 0, 1, 2]);
+        scan.seek_to(&[5, 0]);
+        assert_eq!(scan.next().unwrap().values_array, vec![5, 0, 55, 555]);
+    }
+
+    #[test]
+    fn merge_strategies() {
+        let scan_with = |strategy: Box<dyn MergeStrategy>| {
+            let mut b1 = Block::new(2);
+            b1.add_row(&[1, 1, 10]);
+            b1.add_row(&[2, 2, 20]);
+            let mut b2 = Block::new(2);
+            b2.add_row(&[1, 1, 5]);
+            let mut b3 = Block::new(2);
+            b3.add_row(&[1, 1, 1]);
+            let mut scan = Scan::new(MemSource::new_boxed(), 2, 5);
+            scan.merge = strategy;
+            scan.add_block_version(Rc::new(b2), (2, 0));
+            scan.add_block_version(Rc::new(b1), (1, 0));
+            scan.add_block_version(Rc::new(b3), (2, 1));
+            scan.map(|r| (r.txn_id, r.values_array)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(scan_with(Box::new(NewestWins)), vec![(2, vec![1, 1, 1]), (1, vec![2, 2, 20])]);
+        assert_eq!(scan_with(Box::new(SumValues::new(2))), vec![(2, vec![1, 1, 16]), (1, vec![2, 2, 20])]);
+        assert_eq!(scan_with(Box::new(KeepAllVersions)), vec![
+            (1, vec![1, 1, 10]), (2, vec![1, 1, 5]), (2, vec![1, 1, 1]), (1, vec![2, 2, 20])
+        ]);
+    }
+
+    #[test]
+    fn skip_superseded_blocks() {
+        let mut old = Block::new(2);
+        old.add_row(&[3, 4, 1]);
+        let mut old2 = Block::new(2);
+        old2.add_row(&[13, 4, 1]);
+        old2.add_row(&[15, 5, 1]);
+        let mut old_segment = Segment::new((1, 0));
+        old_segment.encode(&[&old, &old2]).unwrap();
+
+        let mut full = Block::new(2);
+        for x in 0..10 {
+            for y in 0..10 {
+                full.add_row(&[x + 10, y, 2]);
+            }
+        }
+        let mut new_segment = Segment::new((2, 0));
+        new_segment.encode(&[&full]).unwrap();
+
+        let fetches = Rc::new(Cell::new(0));
+        let segments = HashMap::from([((1, 0), Rc::new(old_segment)), ((2, 0), Rc::new(new_segment))]);
+        let blocks = HashMap::from([((1, 0, 0), Rc::new(old)), ((1, 0, 1), Rc::new(old2)), ((2, 0, 0), Rc::new(full))]);
+        let source = || Box::new(MemSource {
+            segments: segments.clone(),
+            blocks: blocks.clone(),
+            fetches: fetches.clone(),
+            chunk_sizes: Some(vec![10, 10])
+        });
+        let mut scan = Scan::new(source(), 2, 5);
+        scan.add_segment_id((1, 0));
+        scan.add_segment_id((2, 0));
+
+        let rows: Vec<_> = scan.map(|r| r.values_array).collect();
+        assert_eq!(rows.len(), 101);
+        assert_eq!(rows[0], vec![3, 4, 1]);
+        assert!(rows[1..].iter().all(|row| row[2] == 2));
+        assert_eq!(fetches.get(), 2);
+
+        /* Keeping every version means reading the older blocks after all */
+        fetches.set(0);
+        let mut scan = Scan::new(source(), 2, 5).merge_strategy(KeepAllVersions);
+        scan.add_segment_id((1, 0));
+        scan.add_segment_id((2, 0));
+        assert_eq!(scan.count(), 103);
+        assert_eq!(fetches.get(), 3);
+    }
+
+    #[test]
+    fn tombstones() {
+        let mut old = Block::new(2);
+        old.add_row(&[3, 4, 1]);
+        old.add_row(&[5, 5, 1]);
+        let mut old2 = Block::new(2);
+        old2.add_row(&[13, 4, 1]);
+        old2.add_row(&[15, 5, 1]);
+        let mut old_segment = Segment::new((1, 0));
+        old_segment.encode(&[&old, &old2]).unwrap();
+
+        /* A later segment deletes the first block and writes one of its rows again */
+        let mut again = Block::new(2);
+        again.add_row(&[5, 5, 2]);
+        let mut new_segment = Segment::new((2, 0));
+        new_segment.tombstones = vec![Criteria::new().range(0, 0..=9)];
+        new_segment.encode(&[&again]).unwrap();
+
+        let fetches = Rc::new(Cell::new(0));
+        let source = Box::new(MemSource {
+            segments: HashMap::from([((1, 0), Rc::new(old_segment)), ((2, 0), Rc::new(new_segment))]),
+            blocks: HashMap::from([((1, 0, 0), Rc::new(old)), ((1, 0, 1), Rc::new(old2)), ((2, 0, 0), Rc::new(again))]),
+            fetches: fetches.clone(),
+            chunk_sizes: None
+        });
+        let mut scan = Scan::new(source, 2, 5);
+        scan.add_segment_id((1, 0));
+        scan.add_segment_id((2, 0));
+
+        /* The unsaved tombstone only deletes part of the second block */
+        let mut unsaved = Tombstones::default();
+        unsaved.add(UNSAVED_VERSION, &Criteria::new().range(0, 13..=13));
+        scan.add_tombstones(&unsaved);
+
+        let rows: Vec<_> = scan.map(|r| r.values_array).collect();
+        assert_eq!(rows, vec![vec![5, 5, 2], vec![15, 5, 1]]);
+        assert_eq!(fetches.get(), 2);
+    }
+
+    #[test]
+    fn missing_rows() {
+        let mut b1 = Block::new(2);
+        b1.add_row(&[3, 4, 1]);
+        let mut b2 = Block::new(2);
+        b2.add_row(&[13, 4, 1]);
+        b2.add_row(&[15, 5, 1]);
+        let mut segment = Segment::new((1, 0));
+        segment.encode(&[&b1, &b2]).unwrap();
+
+        /* The second block can't be read, and the second segment can't be found at all */
+        let segments = HashMap::from([((1, 0), Rc::new(segment))]);
+        let blocks = HashMap::from([((1, 0, 0), Rc::new(b1))]);
+        let source = || Box::new(MemSource {
+            segments: segments.clone(),
+            blocks: blocks.clone(),
+            fetches: Default::default(),
+            chunk_sizes: None
+        });
+        let mut scan = Scan::new(source(), 2, 5).allow_missing();
+        scan.add_segment_id((1, 0));
+        scan.add_segment_id((2, 0));
+
+        let rows: Vec<_> = scan.by_ref().map(|r| r.values_array).collect();
+        assert_eq!(rows, vec![vec![3, 4, 1]]);
+        assert_eq!(scan.missing_rows(), &[
+            MissingRows { segment: (2, 0), block: None, bounds: None, error: Error::IoError },
+            MissingRows { segment: (1, 0), block: Some(1), bounds: Some((vec![13, 4], vec![15, 5])), error: Error::IoError }
+        ]);
+
+        /* By default, the scan stops at the first */
+        let mut scan = Scan::new(source(), 2, 5);
+        scan.add_segment_id((1, 0));
+        scan.add_segment_id((2, 0));
+        assert!(matches!(scan.try_next(), Err(Error::IoError)));
+        assert_eq!(scan.missing_rows().len(), 1);
+
+        let mut scan = Scan::new(source(), 2, 5);
+        scan.add_segment_id((1, 0));
+        assert_eq!(scan.try_next().unwrap().unwrap().values_array, vec![3, 4, 1]);
+        assert!(matches!(scan.try_next(), Err(Error::IoError)));
+
+        /* Nor does an aggregate over the rows read so far hide it */
+        let mut scan = Scan::new(source(), 2, 5);
+        scan.add_segment_id((1, 0));
+        assert!(matches!(scan.top_k(1, 0, crate::Aggregate::Sum, 3), Err(Error::IoError)));
+    }
 }
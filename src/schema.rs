@@ -1,30 +1,317 @@
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::collections::HashSet;
 
+use log::{debug, error};
 use serde::{Serialize, Deserialize};
 
 use crate::{BlockKey, Datum, Error};
-use crate::storage::SCHEMA_FILENAME;
+use crate::backend::Backend;
+use crate::block::Block;
+use crate::Error::{ArityMismatch, OutOfBounds, SchemaError};
+use crate::format::{CURRENT_FORMAT_VERSION, FormatVersion};
+use crate::storage::{RENAMES_FILENAME, SCHEMA_FILENAME};
+use crate::time::{TimeResolution, TimeScale};
 
-#[derive(Serialize, Deserialize, Debug)]
+/* The field of the schema file recording the format version of the database */
+const FORMAT_VERSION_FIELD: &str = "format_version";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Dimension {
     pub name: String,
-    pub chunk_size: usize
+    pub chunk_size: usize,
+    /** Optional inclusive range of valid values; rows outside it are rejected by `add_row`. */
+    #[serde(default)]
+    pub bounds: Option<(Datum, Datum)>,
+    /** Optional mapping from values to times, for a time dimension; see `TimeScale`. */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<TimeScale>,
+    /**
+     * Whether the coordinates are ids of strings, assigned and kept by the database; see
+     * `Transaction::add_row_named`.
+     */
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub labels: bool
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Value {
-    pub name: String
+    pub name: String,
+    /** How the value is interpreted by `TypedValue`; it is stored as a `Datum` whatever its type. */
+    #[serde(default, skip_serializing_if = "ValueType::is_u64")]
+    pub value_type: ValueType
+}
+
+/**
+ * The type of a value column.  Values are all stored as `Datum`s, so that blocks, merging and
+ * compaction treat every column alike: signed integers as their two's complement, floats as
+ * their bits, and booleans as 0 or 1.  `TypedValue` converts them.  Aggregates and statistics
+ * work on the stored `Datum`s, so they are only meaningful for `U64` columns.
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    #[default]
+    U64,
+    I64,
+    F64,
+    Bool
+}
+
+impl ValueType {
+    fn is_u64(&self) -> bool {
+        *self == ValueType::U64
+    }
+}
+
+/**
+ * The order of the cells of each block, in memory and in saved segments.  `RowMajor` orders
+ * them by the first dimension, then the second, and so on, so a cell's neighbours in the last
+ * dimension are next to it but those in the others are a whole row away.  `Tiled`, for schemas
+ * with two dimensions, orders them in square tiles of the given side, row-major within each
+ * tile, so a query constrained on both dimensions of a large block reads a few runs of nearby
+ * cells rather than a short run from every row.  Blocks being written are row-major; the
+ * order is applied when they are saved, in segments of format version 11 or later.
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CellOrder {
+    #[default]
+    RowMajor,
+    Tiled(u16)
+}
+
+impl CellOrder {
+    fn is_row_major(&self) -> bool {
+        *self == CellOrder::RowMajor
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/**
+ * The dimensions and values of a database.  The fields can be read directly, but new schemas
+ * are best made with `Schema::builder`, which checks them and keeps working as optional
+ * settings are added to dimensions and values.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Schema {
     pub dimensions: Vec<Dimension>,
     pub values: Vec<Value>,
+    #[serde(default, skip_serializing_if = "CellOrder::is_row_major")]
+    pub cell_order: CellOrder
+}
+
+/**
+ * Builds a `Schema`, from `Schema::builder`.  Settings such as `bounds` apply to the dimension
+ * added most recently, as in `Schema::builder().dimension("time", 3600).bounds(0, 86399)`.
+ */
+#[derive(Default)]
+pub struct SchemaBuilder {
+    dimensions: Vec<Dimension>,
+    values: Vec<Value>,
+    cell_order: CellOrder,
+    misplaced_setting: bool
+}
+
+impl SchemaBuilder {
+    pub fn dimension(mut self, name: &str, chunk_size: usize) -> SchemaBuilder {
+        self.dimensions.push(Dimension { name: name.to_string(), chunk_size, bounds: None, time: None, labels: false });
+        self
+    }
+
+    /**
+     * Restrict the last dimension added to an inclusive range of values.
+     */
+    pub fn bounds(mut self, min: Datum, max: Datum) -> SchemaBuilder {
+        match self.dimensions.last_mut() {
+            Some(dim) => dim.bounds = Some((min, max)),
+            None => self.misplaced_setting = true
+        }
+        self
+    }
+
+    /**
+     * Declare the last dimension added as a time, counting units of `resolution` since `epoch`
+     * in seconds since 1970.
+     */
+    pub fn time(mut self, epoch: i64, resolution: TimeResolution) -> SchemaBuilder {
+        match self.dimensions.last_mut() {
+            Some(dim) => dim.time = Some(TimeScale { epoch, resolution }),
+            None => self.misplaced_setting = true
+        }
+        self
+    }
+
+    /**
+     * Declare the last dimension added as one of strings, such as sensor names, which the
+     * database maps to ids.
+     */
+    pub fn labels(mut self) -> SchemaBuilder {
+        match self.dimensions.last_mut() {
+            Some(dim) => dim.labels = true,
+            None => self.misplaced_setting = true
+        }
+        self
+    }
+
+    pub fn value(mut self, name: &str) -> SchemaBuilder {
+        self.values.push(Value { name: name.to_string(), value_type: ValueType::U64 });
+        self
+    }
+
+    /**
+     * Set the type of the last value added, as in `.value("temperature").value_type(ValueType::F64)`.
+     */
+    pub fn value_type(mut self, value_type: ValueType) -> SchemaBuilder {
+        match self.values.last_mut() {
+            Some(value) => value.value_type = value_type,
+            None => self.misplaced_setting = true
+        }
+        self
+    }
+
+    /**
+     * Set the order of the cells in each block; see `CellOrder`.
+     */
+    pub fn cell_order(mut self, cell_order: CellOrder) -> SchemaBuilder {
+        self.cell_order = cell_order;
+        self
+    }
+
+    /**
+     * Finish the schema, failing with `SchemaError` if it isn't usable; see `Schema::validate`.
+     */
+    pub fn build(self) -> Result<Schema, Error> {
+        if self.misplaced_setting {
+            error!("Schema has a dimension or value setting before any dimension or value");
+            return Err(SchemaError);
+        }
+        let schema = Schema { dimensions: self.dimensions, values: self.values, cell_order: self.cell_order };
+        schema.validate()?;
+        Ok(schema)
+    }
+}
+
+/**
+ * One way a database's schema differs from the schema an application expects.  Columns are
+ * matched by name, and `Moved` gives a column's position among the dimensions or values.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SchemaDifference {
+    /** The application expects a dimension the database doesn't have. */
+    MissingDimension(String),
+    /** The application expects a column by a name it has since been renamed from. */
+    Renamed { name: String, new_name: String },
+    /** The database has a dimension the application doesn't expect. */
+    ExtraDimension(String),
+    DimensionMoved { name: String, expected: usize, actual: usize },
+    ChunkSizeChanged { name: String, expected: usize, actual: usize },
+    BoundsChanged { name: String, expected: Option<(Datum, Datum)>, actual: Option<(Datum, Datum)> },
+    TimeChanged { name: String, expected: Option<TimeScale>, actual: Option<TimeScale> },
+    LabelsChanged { name: String, expected: bool, actual: bool },
+    MissingValue(String),
+    ExtraValue(String),
+    ValueMoved { name: String, expected: usize, actual: usize },
+    ValueTypeChanged { name: String, expected: ValueType, actual: ValueType }
+}
+
+/**
+ * How a database's schema differs from an expected one, from `Database::check_schema`.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SchemaDiff {
+    pub differences: Vec<SchemaDifference>
+}
+
+impl SchemaDiff {
+    /**
+     * Whether the schemas are the same, so the database's rows have the expected columns.
+     */
+    pub fn is_empty(&self) -> bool {
+        self.differences.is_empty()
+    }
+
+    /**
+     * Report each column the application expects by an old name as renamed, rather than as
+     * one column missing and another extra.
+     */
+    pub(crate) fn with_renames(self, renames: &[SchemaChange]) -> SchemaDiff {
+        let mut differences = self.differences;
+        let mut renamed = Vec::new();
+        for difference in &differences {
+            let (name, is_dimension) = match difference {
+                SchemaDifference::MissingDimension(name) => (name, true),
+                SchemaDifference::MissingValue(name) => (name, false),
+                _ => continue
+            };
+            if let Some(new_name) = renamed_to(renames, name, is_dimension) {
+                renamed.push((name.clone(), new_name, is_dimension));
+            }
+        }
+
+        for (name, new_name, is_dimension) in renamed {
+            differences.retain(|difference| !match difference {
+                SchemaDifference::MissingDimension(n) => is_dimension && *n == name,
+                SchemaDifference::MissingValue(n) => !is_dimension && *n == name,
+                SchemaDifference::ExtraDimension(n) => is_dimension && *n == new_name,
+                SchemaDifference::ExtraValue(n) => !is_dimension && *n == new_name,
+                _ => false
+            });
+            differences.push(SchemaDifference::Renamed { name, new_name });
+        }
+        SchemaDiff { differences }
+    }
+}
+
+/**
+ * A change to a database's schema, made by `Database::alter_schema`.  Renaming a column only
+ * changes the name applications know it by, not the data.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SchemaChange {
+    RenameDimension { from: String, to: String },
+    RenameValue { from: String, to: String }
+}
+
+/**
+ * The current name of a dimension or value that has been renamed from `name`, following the
+ * renames in the order they were made.
+ */
+pub(crate) fn renamed_to(renames: &[SchemaChange], name: &str, is_dimension: bool) -> Option<String> {
+    let mut current: Option<&str> = None;
+    for change in renames {
+        let (from, to) = match change {
+            SchemaChange::RenameDimension { from, to } if is_dimension => (from, to),
+            SchemaChange::RenameValue { from, to } if !is_dimension => (from, to),
+            _ => continue
+        };
+        if from == current.unwrap_or(name) {
+            current = Some(to);
+        }
+    }
+    current.map(str::to_string)
+}
+
+/**
+ * The renames made to a database's schema, oldest first.
+ */
+pub(crate) fn load_renames(backend: &dyn Backend) -> Result<Vec<SchemaChange>, Error> {
+    if !backend.exists(RENAMES_FILENAME) {
+        return Ok(Vec::new());
+    }
+    let json = backend.read(RENAMES_FILENAME)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+pub(crate) fn save_renames(backend: &dyn Backend, renames: &[SchemaChange]) -> Result<(), Error> {
+    let temp_filename = format!("{RENAMES_FILENAME}.tmp");
+    backend.write(&temp_filename, serde_json::to_string(renames)?.as_bytes())?;
+    backend.rename(&temp_filename, RENAMES_FILENAME)?;
+    Ok(())
 }
 
 impl Schema {
+    pub fn builder() -> SchemaBuilder {
+        SchemaBuilder::default()
+    }
+
     pub(crate) fn get_chunk_key(&self, values: &[Datum]) -> BlockKey {
         let mut key_values : Vec<Datum> = Vec::new();
 
@@ -37,20 +324,370 @@ impl Schema {
         BlockKey { key_values }
     }
 
-    pub(crate) fn load(database_path: &Path) -> Result<Schema, Error> {
-        let schema_filename = database_path.join(SCHEMA_FILENAME);
-        let mut file = File::open(schema_filename)?;
-        let mut json = String::new();
-        file.read_to_string(&mut json)?;
-        let schema: Schema = serde_json::from_str(json.as_str())?;
-        Ok(schema)
+    /**
+     * The first point of the chunk containing the given point.
+     */
+    pub(crate) fn get_chunk_origin(&self, values: &[Datum]) -> Vec<Datum> {
+        self.dimensions.iter().zip(values)
+            .map(|(dim, &value)| value - value % dim.chunk_size)
+            .collect()
+    }
+
+    /**
+     * The key blocks are ordered by when a flush saves them: by chunk, then by first row, which
+     * is the order a scan reads them in.
+     */
+    pub(crate) fn block_order_key(&self, block: &Block) -> (Vec<Datum>, Option<Vec<Datum>>) {
+        (self.get_chunk_origin(&block.get_min_bounds()), block.get_start_point())
     }
 
-    pub(crate) fn save(&self, database_path: &Path) -> Result<(), Error> {
-        let schema_filename = database_path.join(SCHEMA_FILENAME);
-        let mut file = File::create(schema_filename)?;
-        let json = serde_json::to_string(&self)?;
-        file.write_all(json.as_bytes())?;
+    /**
+     * Check that a row has one entry for each dimension and value, and lies within bounds.
+     */
+    pub(crate) fn check_row(&self, values: &[Datum]) -> Result<(), Error> {
+        if values.len() != self.dimensions.len() + self.values.len() {
+            error!("Row has {:?} entries, expected {:?}", values.len(), self.dimensions.len() + self.values.len());
+            return Err(ArityMismatch);
+        }
+        self.check_bounds(values)
+    }
+
+    /**
+     * Check that the schema is usable: it needs at least one dimension, each with a non-zero
+     * chunk size and a non-empty range if it has bounds, and no two dimensions or values can
+     * have the same name.  A dimension of labels can't also be a time.  Tiled cells need
+     * exactly two dimensions and a tile side of at least 1.
+     */
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.dimensions.is_empty() || self.dimensions.len() > u16::MAX as usize {
+            error!("Schema has {:?} dimensions", self.dimensions.len());
+            return Err(SchemaError);
+        }
+        for dim in &self.dimensions {
+            let empty_bounds = matches!(dim.bounds, Some((min, max)) if min > max);
+            if dim.chunk_size == 0 || empty_bounds {
+                error!("Dimension {:?} has invalid chunk size or bounds", dim.name);
+                return Err(SchemaError);
+            }
+            if dim.labels && dim.time.is_some() {
+                error!("Dimension {:?} can't be both labels and a time", dim.name);
+                return Err(SchemaError);
+            }
+        }
+
+        if let CellOrder::Tiled(side) = self.cell_order {
+            if self.dimensions.len() != 2 || side == 0 {
+                error!("Schema can't have tiles of side {:?} with {:?} dimensions", side, self.dimensions.len());
+                return Err(SchemaError);
+            }
+        }
+
+        let mut names = HashSet::new();
+        for name in self.dimensions.iter().map(|d| &d.name).chain(self.values.iter().map(|v| &v.name)) {
+            if !names.insert(name) {
+                error!("Schema has more than one column named {:?}", name);
+                return Err(SchemaError);
+            }
+        }
         Ok(())
     }
+
+    /**
+     * Check that each dimension value lies within the bounds declared for its dimension, if any.
+     */
+    pub(crate) fn check_bounds(&self, values: &[Datum]) -> Result<(), Error> {
+        for (dim, &dim_value) in self.dimensions.iter().zip(values) {
+            if let Some((min, max)) = dim.bounds {
+                if dim_value < min || dim_value > max {
+                    debug!("Value {dim_value:?} for dimension {:?} is outside {min:?}..={max:?}", dim.name);
+                    return Err(OutOfBounds);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Compare this schema, as the actual one, with an expected one.
+     */
+    pub(crate) fn diff(&self, expected: &Schema) -> SchemaDiff {
+        let mut differences = Vec::new();
+
+        for (expected_no, expected_dim) in expected.dimensions.iter().enumerate() {
+            let Some(actual_no) = self.dimensions.iter().position(|d| d.name == expected_dim.name) else {
+                differences.push(SchemaDifference::MissingDimension(expected_dim.name.clone()));
+                continue;
+            };
+            let actual_dim = &self.dimensions[actual_no];
+            let name = expected_dim.name.clone();
+            if actual_no != expected_no {
+                differences.push(SchemaDifference::DimensionMoved { name: name.clone(), expected: expected_no, actual: actual_no });
+            }
+            if actual_dim.chunk_size != expected_dim.chunk_size {
+                differences.push(SchemaDifference::ChunkSizeChanged {
+                    name: name.clone(), expected: expected_dim.chunk_size, actual: actual_dim.chunk_size
+                });
+            }
+            if actual_dim.bounds != expected_dim.bounds {
+                differences.push(SchemaDifference::BoundsChanged {
+                    name: name.clone(), expected: expected_dim.bounds, actual: actual_dim.bounds
+                });
+            }
+            if actual_dim.time != expected_dim.time {
+                differences.push(SchemaDifference::TimeChanged { name: name.clone(), expected: expected_dim.time, actual: actual_dim.time });
+            }
+            if actual_dim.labels != expected_dim.labels {
+                differences.push(SchemaDifference::LabelsChanged { name, expected: expected_dim.labels, actual: actual_dim.labels });
+            }
+        }
+        for actual_dim in &self.dimensions {
+            if !expected.dimensions.iter().any(|d| d.name == actual_dim.name) {
+                differences.push(SchemaDifference::ExtraDimension(actual_dim.name.clone()));
+            }
+        }
+
+        for (expected_no, expected_value) in expected.values.iter().enumerate() {
+            let Some(actual_no) = self.values.iter().position(|v| v.name == expected_value.name) else {
+                differences.push(SchemaDifference::MissingValue(expected_value.name.clone()));
+                continue;
+            };
+            if actual_no != expected_no {
+                differences.push(SchemaDifference::ValueMoved {
+                    name: expected_value.name.clone(), expected: expected_no, actual: actual_no
+                });
+            }
+            let actual_type = self.values[actual_no].value_type;
+            if actual_type != expected_value.value_type {
+                differences.push(SchemaDifference::ValueTypeChanged {
+                    name: expected_value.name.clone(), expected: expected_value.value_type, actual: actual_type
+                });
+            }
+        }
+        for actual_value in &self.values {
+            if !expected.values.iter().any(|v| v.name == actual_value.name) {
+                differences.push(SchemaDifference::ExtraValue(actual_value.name.clone()));
+            }
+        }
+
+        SchemaDiff { differences }
+    }
+
+    pub(crate) fn load(backend: &dyn Backend) -> Result<Schema, Error> {
+        Ok(Schema::load_versioned(backend)?.0)
+    }
+
+    /**
+     * Load the schema with the format version recorded alongside it, the newest format the
+     * database's files may be in.  Schema files from before the version was recorded are
+     * version 0.  Fails with `UnsupportedVersion` if the version is newer than this library
+     * supports, since it can't read the files correctly.
+     */
+    pub(crate) fn load_versioned(backend: &dyn Backend) -> Result<(Schema, FormatVersion), Error> {
+        let json = backend.read(SCHEMA_FILENAME)?;
+        let mut fields: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&json)?;
+        let version = match fields.remove(FORMAT_VERSION_FIELD) {
+            Some(value) => value.as_u64().and_then(|version| FormatVersion::try_from(version).ok()).ok_or(Error::DataError)?,
+            None => 0
+        };
+        if version > CURRENT_FORMAT_VERSION {
+            error!("Database format version {version:?} is newer than supported");
+            return Err(Error::UnsupportedVersion { found: version, supported: CURRENT_FORMAT_VERSION });
+        }
+        let schema: Schema = serde_json::from_value(serde_json::Value::Object(fields))?;
+        schema.validate()?;
+        Ok((schema, version))
+    }
+
+    /**
+     * Save the schema, recording that the database's files may be in the current format.
+     */
+    pub(crate) fn save(&self, backend: &dyn Backend) -> Result<(), Error> {
+        let serde_json::Value::Object(mut fields) = serde_json::to_value(self)? else {
+            return Err(Error::DataError);
+        };
+        fields.insert(FORMAT_VERSION_FIELD.to_string(), CURRENT_FORMAT_VERSION.into());
+        let json = serde_json::to_string(&fields)?;
+        let temp_filename = format!("{SCHEMA_FILENAME}.tmp");
+        backend.write(&temp_filename, json.as_bytes())?;
+        backend.rename(&temp_filename, SCHEMA_FILENAME)?;
+        Ok(())
+    }
+
+    /**
+     * The position of the dimension with a name, if there is one.
+     */
+    pub fn dimension_no(&self, name: &str) -> Option<usize> {
+        self.dimensions.iter().position(|dim| dim.name == name)
+    }
+
+    /**
+     * The position of the value with a name, if there is one.
+     */
+    pub fn value_no(&self, name: &str) -> Option<usize> {
+        self.values.iter().position(|value| value.name == name)
+    }
+
+    /**
+     * The schema with a change made, failing with `SchemaError` if the column to rename doesn't
+     * exist or the new name is taken.
+     */
+    pub(crate) fn altered(&self, change: &SchemaChange) -> Result<Schema, Error> {
+        let mut schema = self.clone();
+        let (name, to) = match change {
+            SchemaChange::RenameDimension { from, to } => {
+                schema.dimensions.iter_mut().find(|dim| dim.name == *from).map(|dim| &mut dim.name).zip(Some(to))
+            },
+            SchemaChange::RenameValue { from, to } => {
+                schema.values.iter_mut().find(|value| value.name == *from).map(|value| &mut value.name).zip(Some(to))
+            }
+        }.ok_or_else(|| {
+            error!("Schema has no column to make {:?}", change);
+            SchemaError
+        })?;
+        *name = to.clone();
+        schema.validate()?;
+        Ok(schema)
+    }
+}
+
+#[cfg(test)]
+mod schema_tests {
+    use super::*;
+
+    #[test]
+    fn check_bounds() {
+        let schema = Schema {
+            dimensions: vec![
+                Dimension { name: String::from("time"), chunk_size: 10, bounds: Some((100, 200)), time: None, labels: false },
+                Dimension { name: String::from("sensor_id"), chunk_size: 10, bounds: None, time: None, labels: false },
+            ],
+            values: vec![
+                Value { name: String::from("value"), value_type: ValueType::U64 }
+            ],
+            cell_order: CellOrder::RowMajor
+        };
+
+        assert!(schema.check_bounds(&[100, 0, 0]).is_ok());
+        assert!(schema.check_bounds(&[200, 999, 0]).is_ok());
+        assert!(matches!(schema.check_bounds(&[0, 0, 0]), Err(OutOfBounds)));
+        assert!(matches!(schema.check_bounds(&[201, 0, 0]), Err(OutOfBounds)));
+    }
+
+    #[test]
+    fn check_row_and_validate() {
+        let mut schema = Schema {
+            dimensions: vec![
+                Dimension { name: String::from("x"), chunk_size: 10, bounds: None, time: None, labels: false },
+            ],
+            values: vec![
+                Value { name: String::from("value"), value_type: ValueType::U64 }
+            ],
+            cell_order: CellOrder::RowMajor
+        };
+        assert!(schema.check_row(&[1, 2]).is_ok());
+        assert!(matches!(schema.check_row(&[1]), Err(ArityMismatch)));
+        assert!(matches!(schema.check_row(&[1, 2, 3]), Err(ArityMismatch)));
+        assert!(schema.validate().is_ok());
+
+        schema.dimensions[0].bounds = Some((5, 4));
+        assert!(schema.validate().is_err());
+        schema.dimensions[0].bounds = None;
+        schema.dimensions[0].chunk_size = 0;
+        assert!(schema.validate().is_err());
+        schema.dimensions.clear();
+        assert!(schema.validate().is_err());
+    }
+
+    #[test]
+    fn builder() {
+        let schema = Schema::builder()
+            .dimension("time", 100).bounds(10, 20)
+            .dimension("sensor_id", 10)
+            .value("value")
+            .build().unwrap();
+        assert_eq!(schema.dimensions.len(), 2);
+        assert_eq!(schema.dimensions[0].bounds, Some((10, 20)));
+        assert_eq!(schema.dimensions[1].bounds, None);
+        assert_eq!(schema.values[0].name, "value");
+
+        assert!(Schema::builder().value("value").build().is_err());
+        assert!(Schema::builder().bounds(1, 2).dimension("x", 10).build().is_err());
+        assert!(Schema::builder().dimension("x", 0).build().is_err());
+        assert!(Schema::builder().dimension("x", 10).bounds(5, 4).build().is_err());
+        assert!(Schema::builder().dimension("x", 10).value("x").build().is_err());
+
+        let schema = Schema::builder()
+            .dimension("sensor", 10).labels()
+            .value("value").value_type(ValueType::F64)
+            .build().unwrap();
+        assert!(schema.dimensions[0].labels);
+        assert_eq!(schema.values[0].value_type, ValueType::F64);
+        assert_eq!(serde_json::to_string(&schema.values[0]).unwrap(), r#"{"name":"value","value_type":"f64"}"#);
+        assert!(Schema::builder().value_type(ValueType::I64).dimension("x", 10).build().is_err());
+        assert!(Schema::builder().labels().dimension("x", 10).build().is_err());
+        assert!(Schema::builder().dimension("x", 10).labels().time(0, TimeResolution::Seconds).build().is_err());
+
+        let schema = Schema::builder().dimension("x", 10).dimension("y", 10).value("value").cell_order(CellOrder::Tiled(4)).build().unwrap();
+        assert!(serde_json::to_string(&schema).unwrap().ends_with(r#""cell_order":{"tiled":4}}"#));
+        assert_eq!(serde_json::from_str::<Schema>(&serde_json::to_string(&schema).unwrap()).unwrap(), schema);
+        assert!(Schema::builder().dimension("x", 10).value("value").cell_order(CellOrder::Tiled(4)).build().is_err());
+        assert!(Schema::builder().dimension("x", 10).dimension("y", 10).cell_order(CellOrder::Tiled(0)).build().is_err());
+    }
+
+    #[test]
+    fn load_without_bounds() {
+        let json = r#"{"dimensions":[{"name":"x","chunk_size":10}],"values":[{"name":"value"}]}"#;
+        let schema: Schema = serde_json::from_str(json).unwrap();
+        assert!(schema.dimensions[0].bounds.is_none());
+    }
+
+    #[test]
+    fn diff() {
+        let schema = Schema::builder()
+            .dimension("time", 100).bounds(10, 20)
+            .dimension("sensor_id", 10)
+            .value("value")
+            .value("quality")
+            .build().unwrap();
+        assert!(schema.diff(&schema).is_empty());
+
+        let expected = Schema::builder()
+            .dimension("sensor_id", 10)
+            .dimension("time", 50)
+            .dimension("site", 10)
+            .value("quality")
+            .value("value")
+            .build().unwrap();
+        assert_eq!(schema.diff(&expected).differences, vec![
+            SchemaDifference::DimensionMoved { name: String::from("sensor_id"), expected: 0, actual: 1 },
+            SchemaDifference::DimensionMoved { name: String::from("time"), expected: 1, actual: 0 },
+            SchemaDifference::ChunkSizeChanged { name: String::from("time"), expected: 50, actual: 100 },
+            SchemaDifference::BoundsChanged { name: String::from("time"), expected: None, actual: Some((10, 20)) },
+            SchemaDifference::MissingDimension(String::from("site")),
+            SchemaDifference::ValueMoved { name: String::from("quality"), expected: 0, actual: 1 },
+            SchemaDifference::ValueMoved { name: String::from("value"), expected: 1, actual: 0 },
+        ]);
+
+        let expected = Schema::builder()
+            .dimension("time", 100).bounds(10, 20)
+            .value("value")
+            .value("status")
+            .build().unwrap();
+        assert_eq!(schema.diff(&expected).differences, vec![
+            SchemaDifference::ExtraDimension(String::from("sensor_id")),
+            SchemaDifference::MissingValue(String::from("status")),
+            SchemaDifference::ExtraValue(String::from("quality")),
+        ]);
+
+        let expected = Schema::builder()
+            .dimension("time", 100).bounds(10, 20)
+            .dimension("sensor_id", 10).labels()
+            .value("value").value_type(ValueType::F64)
+            .value("quality")
+            .build().unwrap();
+        assert_eq!(schema.diff(&expected).differences, vec![
+            SchemaDifference::LabelsChanged { name: String::from("sensor_id"), expected: true, actual: false },
+            SchemaDifference::ValueTypeChanged { name: String::from("value"), expected: ValueType::F64, actual: ValueType::U64 },
+        ]);
+    }
 }
@@ -1,68 +1,162 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
-use std::path::{Path, PathBuf};
+use std::ops::Range;
+use std::rc::Rc;
 
 use byteorder::{BE, ReadBytesExt, WriteBytesExt};
-use log::debug;
-use zstd::zstd_safe;
+use log::{debug, error};
 
+use crate::backend::Backend;
 use crate::block::Block;
-use crate::storage::{get_segment_path, read_expected_tag, skip_to_next_tag, Tag, TAG_LENGTH, write_tag};
-use crate::{BlockNum, Datum, Error, SegmentId};
+use crate::compression::Compression;
+use crate::dictionary::{Dictionaries, Dictionary};
+use crate::format::{CURRENT_FORMAT_VERSION, FormatVersion, NoResolver, read_header, read_pooled_hash, read_section, SectionResolver, write_header, write_pooled_section, write_section};
+use crate::query::Criteria;
+use crate::pool::{hash_payload, Layout, PoolHash, PoolObject, write_objects};
+use crate::schema::CellOrder;
+use crate::storage::{get_pool_name, get_segment_name, read_expected_tag, Tag, TAG_LENGTH, write_tag};
+use crate::{BlockNum, Datum, DictionaryId, Error, SegmentId};
+
+/* Enough to hold a compressed block, so it can usually be read in one go */
+const READ_BUFFER_SIZE: usize = 128 * 1024;
+
+/**
+ * The number of cells in some blocks, and how many of them have a value in each value column.
+ */
+pub(crate) type ColumnOccupancy = (u64, Vec<u64>);
 
 pub(crate) struct BlockInfo {
     pub min_bounds: Vec<Datum>,
     pub max_bounds: Vec<Datum>,
+    /** The number of rows and cells in the block, if the segment's format records them. */
+    pub counts: Option<(usize, usize)>,
+    /** The SHA-256 hash of the block's uncompressed payload, if the segment's format records it. */
+    pub hash: Option<PoolHash>,
+    /** The number of rows with each value, if the segment's format records it. */
+    pub column_counts: Option<Vec<usize>>,
     block_pos: u64
 }
 
+impl BlockInfo {
+    /**
+     * Check that a block has the bounds, and the counts if recorded, that the info says it has.
+     * The column counts aren't checked for a block loaded without all of its value columns.
+     */
+    pub(crate) fn check_block(&self, block: &Block) -> Result<(), Error> {
+        let min_bounds = block.get_min_bounds();
+        let max_bounds = block.get_max_bounds();
+        if min_bounds != self.min_bounds || max_bounds != self.max_bounds {
+            error!("Block bounds {:?}..{:?} don't match segment info {:?}..{:?}",
+                min_bounds, max_bounds, self.min_bounds, self.max_bounds);
+            return Err(Error::DataError);
+        }
+        if self.counts.is_some_and(|counts| counts != (block.num_rows(), block.num_cells())) {
+            error!("Block counts {:?} don't match segment info", self.counts);
+            return Err(Error::DataError);
+        }
+        if block.has_all_values() && self.column_counts.as_ref().is_some_and(|counts| *counts != block.column_counts()) {
+            error!("Block column counts {:?} don't match segment info", self.column_counts);
+            return Err(Error::DataError);
+        }
+        Ok(())
+    }
+}
+
 pub struct Segment {
     pub id: SegmentId,
-    pub path: PathBuf,
-    pub(crate) block_info: Vec<BlockInfo>
+    pub name: String,
+    pub(crate) version: FormatVersion,
+    pub(crate) compression: Compression,
+    /** The dictionary to compress blocks with, when saving them with zstd. */
+    pub(crate) dictionary: Option<Rc<Dictionary>>,
+    pub(crate) layout: Layout,
+    /** The order to save the cells of blocks in, from format version 11. */
+    pub(crate) cell_order: CellOrder,
+    /** Whether the blocks are in scan order; see `Schema::block_order_key`. */
+    pub(crate) ordered: bool,
+    /** Blocks encoded for the pool, to be saved before the segment file that refers to them. */
+    pub(crate) pool_objects: Vec<PoolObject>,
+    pub(crate) block_info: Vec<BlockInfo>,
+    /** Criteria of rows deleted from older segments; see `Tombstones`. */
+    pub(crate) tombstones: Vec<Criteria>
+}
+
+/**
+ * Finds the dictionaries and pool objects that a segment's blocks refer to.
+ */
+struct BlockResolver<'a> {
+    backend: &'a dyn Backend,
+    dictionaries: &'a Dictionaries
+}
+
+impl<'a> SectionResolver for BlockResolver<'a> {
+    fn get_dictionary(&self, id: DictionaryId) -> Result<Rc<Dictionary>, Error> {
+        self.dictionaries.get(self.backend, id)
+    }
+
+    fn get_pooled_section(&self, hash: &PoolHash) -> Result<Vec<u8>, Error> {
+        self.backend.read(&get_pool_name(hash))
+    }
 }
 
 impl Segment {
     /**
-     * Create a new segment, and save the given blocks to it.
+     * A new, uncommitted segment with no blocks, for encoding blocks into before writing.
      */
-    pub(crate) fn create(
-        database_path: &Path,
-        seg_id: SegmentId,
-        blocks: &[&Block]
-    ) -> Result<Segment, Error> {
-        let path = get_segment_path(database_path, seg_id, false);
-
-        let mut segment = Segment {
+    pub(crate) fn new(seg_id: SegmentId) -> Segment {
+        Segment {
             id: seg_id,
-            path,
-            block_info: Vec::new()
-        };
-
-        segment.save(blocks)?;
+            name: get_segment_name(seg_id, false),
+            version: CURRENT_FORMAT_VERSION,
+            compression: Compression::default(),
+            dictionary: None,
+            layout: Layout::default(),
+            cell_order: CellOrder::RowMajor,
+            ordered: false,
+            pool_objects: Vec::new(),
+            block_info: Vec::new(),
+            tombstones: Vec::new()
+        }
+    }
 
-        Ok(segment)
+    /**
+     * Roughly how many bytes the segment's info occupies in memory.
+     */
+    pub(crate) fn memory_size(&self) -> usize {
+        let info_size: usize = self.block_info.iter()
+            .map(|info| size_of::<BlockInfo>() + (info.min_bounds.capacity() + info.max_bounds.capacity()) * size_of::<Datum>())
+            .sum();
+        size_of::<Segment>() + self.name.capacity() + info_size
     }
 
     pub(crate) fn load(
-        database_path: &Path,
+        backend: &dyn Backend,
         seg_id: SegmentId
     ) -> Result<Segment, Error> {
-        let mut path = get_segment_path(database_path, seg_id, true);
-        if !path.exists() {
-            path = get_segment_path(database_path, seg_id, false);
+        let mut name = get_segment_name(seg_id, true);
+        if !backend.exists(&name) {
+            name = get_segment_name(seg_id, false);
         }
 
+        let file = backend.open(&name)?;
+        let mut src = BufReader::with_capacity(READ_BUFFER_SIZE, file);
+        let version = read_header(&mut src)?;
+
         let mut segment = Segment {
             id: seg_id,
-            path: path.clone(),
-            block_info: Vec::new()
+            name,
+            version,
+            compression: Compression::default(),
+            dictionary: None,
+            layout: Layout::default(),
+            cell_order: CellOrder::RowMajor,
+            ordered: false,
+            pool_objects: Vec::new(),
+            block_info: Vec::new(),
+            tombstones: Vec::new()
         };
 
-        let file = File::open(path)?;
-        let mut src = BufReader::with_capacity(zstd_safe::DCtx::in_size(), file);
-
         /* Seek to the end and read the end tag and the offset of the segment info */
         const END_SIZE: i64 = TAG_LENGTH as i64 + size_of::<u64>() as i64;
         src.seek(SeekFrom::End(-END_SIZE))?;
@@ -77,130 +171,539 @@ impl Segment {
         Ok(segment)
     }
 
-    pub(crate) fn load_one_block(&self, block_num: BlockNum) -> Result<Block, Error> {
-        let file = File::open(&self.path)?;
-        let mut src = BufReader::with_capacity(zstd_safe::DCtx::in_size(), file);
+    pub(crate) fn load_one_block(&self, backend: &dyn Backend, dictionaries: &Dictionaries, block_num: BlockNum) -> Result<Block, Error> {
+        self.load_one_block_values(backend, dictionaries, block_num, None)
+    }
+
+    /**
+     * Load a block, decoding only the value columns numbered in `values` if the segment's
+     * format version allows it; see `Block::load_values`.
+     */
+    pub(crate) fn load_one_block_values(&self, backend: &dyn Backend, dictionaries: &Dictionaries, block_num: BlockNum, values: Option<&[usize]>) -> Result<Block, Error> {
+        let file = backend.open(&self.name)?;
+        let mut src = BufReader::with_capacity(READ_BUFFER_SIZE, file);
 
-        src.seek(SeekFrom::Start(self.block_info[block_num as usize].block_pos))?;
+        let Some(block_info) = self.block_info.get(block_num as usize) else {
+            error!("Segment {:?} has no block {:?}", self.id, block_num);
+            return Err(Error::DataError);
+        };
+        src.seek(SeekFrom::Start(block_info.block_pos))?;
         read_expected_tag(&mut src, Tag::Block)?;
 
-        let block = self.load_block(&mut src)?;
+        let block = self.load_block(&mut src, &BlockResolver { backend, dictionaries }, values)?;
 
         Ok(block)
     }
 
-    fn load_block(&self, src: &mut BufReader<File>) -> Result<Block, Error> {
+    fn load_block<R: BufRead>(&self, src: &mut R, resolver: &dyn SectionResolver, values: Option<&[usize]>) -> Result<Block, Error> {
         let mut block = Block::new(0);
 
-        let mut decoder = zstd::stream::read::Decoder::with_buffer(src)?;
-        block.load(&mut decoder)?;
-        let src = decoder.finish();
-
-        /* ZStd leaves the last byte of a stream in the buffer, meaning we cant just read any other
-           data after it.  This seems to be the "hostage byte" in the decompressor:
-           https://github.com/facebook/zstd/blob/dev/lib/decompress/zstd_decompress.c#L2238
-           To work around it, we scan for something that looks like a tag.  If there is only
-           ever one byte to skip over, we should be able to do this unambiguously.  If not...?
-         */
-        skip_to_next_tag(src)?;
+        let payload = read_section(src, self.version, resolver)?;
+        match values {
+            Some(values) => block.load_values(&mut payload.as_slice(), self.version, values)?,
+            None => block.load(&mut payload.as_slice(), self.version)?
+        }
 
         Ok(block)
     }
 
-    fn load_segment_info<R: BufRead>(&mut self, src: R) -> Result<(), Error> {
-        let mut decoder = zstd::stream::read::Decoder::with_buffer(src)?;
+    fn load_segment_info<R: BufRead>(&mut self, src: &mut R) -> Result<(), Error> {
+        let payload = read_section(src, self.version, &NoResolver)?;
+        self.decode_info(&payload)
+    }
+
+    /**
+     * Replace the block info with that decoded from the payload of a segment info section.
+     */
+    pub(crate) fn decode_info(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let mut src = payload;
 
         self.block_info.clear();
 
-        let num_blocks = decoder.read_u16::<BE>()?;
+        let num_blocks = src.read_u16::<BE>()?;
         self.block_info.reserve_exact(num_blocks as usize);
-        let num_dims = decoder.read_u16::<BE>()?;
+        let num_dims = src.read_u16::<BE>()?;
+        self.ordered = self.version >= 4 && src.read_u8()? != 0;
         for _ in 0..num_blocks {
             let mut min_bounds = Vec::new();
             for _ in 0..num_dims {
-                let val = decoder.read_u64::<BE>()? as Datum;
+                let val = src.read_u64::<BE>()? as Datum;
                 min_bounds.push(val);
             }
             let mut max_bounds = Vec::new();
             for _ in 0..num_dims {
-                let val = decoder.read_u64::<BE>()? as Datum;
+                let val = src.read_u64::<BE>()? as Datum;
                 max_bounds.push(val);
             }
-            let block_pos = decoder.read_u64::<BE>()?;
-            let block_info = BlockInfo { min_bounds, max_bounds, block_pos };
+            let block_pos = src.read_u64::<BE>()?;
+            let mut counts = None;
+            if self.version >= 2 {
+                let num_rows = src.read_u64::<BE>()? as usize;
+                let num_cells = src.read_u64::<BE>()? as usize;
+                counts = Some((num_rows, num_cells));
+            }
+            let mut hash = None;
+            if self.version >= 7 {
+                let mut bytes: PoolHash = Default::default();
+                src.read_exact(&mut bytes)?;
+                hash = Some(bytes);
+            }
+            let mut column_counts = None;
+            if self.version >= 9 {
+                let num_columns = src.read_u16::<BE>()?;
+                let mut counts = Vec::new();
+                for _ in 0..num_columns {
+                    counts.push(src.read_u64::<BE>()? as usize);
+                }
+                column_counts = Some(counts);
+            }
+            let block_info = BlockInfo { min_bounds, max_bounds, counts, hash, column_counts, block_pos };
             self.block_info.push(block_info);
         }
 
-        decoder.finish();
+        self.tombstones.clear();
+        if self.version >= 5 {
+            let num_tombstones = src.read_u16::<BE>()?;
+            for _ in 0..num_tombstones {
+                self.tombstones.push(Criteria::load(&mut src)?);
+            }
+        }
+
         Ok(())
     }
 
-    fn save(&mut self, blocks: &[&Block]) -> Result<(), Error> {
-        let mut file = File::create(&self.path)?;
+    /**
+     * Save the blocks in the segment's file, and in the pool if its layout uses one.  Returns
+     * the number of bytes added to the pool.
+     */
+    fn save(&mut self, backend: &dyn Backend, blocks: &[&Block]) -> Result<u64, Error> {
+        let file = self.encode(blocks)?;
+        let pooled_size = write_objects(backend, &std::mem::take(&mut self.pool_objects))?;
+        backend.write(&self.name, &file)?;
+        debug!("Wrote segment file {:?}", self.name);
+
+        Ok(pooled_size)
+    }
+
+    /**
+     * Encode the blocks as the contents of a segment file, recording their positions in the
+     * segment's block info.  With the `Pool` layout, the blocks themselves are added to
+     * `pool_objects`, which must be saved before the segment file.
+     */
+    pub(crate) fn encode(&mut self, blocks: &[&Block]) -> Result<Vec<u8>, Error> {
+        let mut file: Vec<u8> = Vec::new();
+        write_header(&mut file, self.version)?;
 
         for &block in blocks.iter() {
-            let block_pos = file.stream_position()?;
+            let block_pos = file.len() as u64;
             write_tag(&mut file, Tag::Block)?;
-            self.save_block(&mut file, block)?;
+            let hash = self.save_block(&mut file, block)?;
             let block_info = BlockInfo {
                 min_bounds: block.get_min_bounds(),
                 max_bounds: block.get_max_bounds(),
+                counts: Some((block.num_rows(), block.num_cells())),
+                hash: Some(hash),
+                column_counts: Some(block.column_counts()),
                 block_pos
             };
             self.block_info.push(block_info);
         }
 
-        let segment_info_pos = file.stream_position()?;
+        let segment_info_pos = file.len() as u64;
         write_tag(&mut file, Tag::Segment)?;
         self.save_segment_info(&mut file)?;
 
         write_tag(&mut file, Tag::End)?;
         file.write_u64::<BE>(segment_info_pos)?;
 
-        debug!("Wrote segment file {:?}", self.path);
+        Ok(file)
+    }
 
-        Ok(())
+    /**
+     * Divide the blocks of an encoded segment into consecutive groups that would each encode to
+     * at most `max_size` bytes, going by their sizes in `file` and an equal share each of the
+     * rest of the file, such as the segment info.  A block larger than that is put in a group
+     * of its own.
+     */
+    pub(crate) fn group_blocks(&self, file: &[u8], max_size: u64) -> Result<Vec<Range<usize>>, Error> {
+        const END_SIZE: usize = TAG_LENGTH + size_of::<u64>();
+        let Some(mut end_src) = file.len().checked_sub(END_SIZE).and_then(|pos| file.get(pos + TAG_LENGTH..)) else {
+            error!("Encoded segment {:?} is truncated", self.id);
+            return Err(Error::DataError);
+        };
+        let segment_info_pos = end_src.read_u64::<BE>()?;
+        let blocks_size = segment_info_pos - self.block_info.first().map_or(segment_info_pos, |info| info.block_pos);
+        let share = (file.len() as u64 - blocks_size).div_ceil(self.block_info.len().max(1) as u64);
+
+        let mut groups = Vec::new();
+        let mut start = 0;
+        let mut group_size = 0;
+        for (block_num, block_info) in self.block_info.iter().enumerate() {
+            let end_pos = self.block_info.get(block_num + 1).map_or(segment_info_pos, |next| next.block_pos);
+            let block_size = end_pos - block_info.block_pos + share;
+            if block_num > start && group_size + block_size > max_size {
+                groups.push(start..block_num);
+                start = block_num;
+                group_size = 0;
+            }
+            group_size += block_size;
+        }
+        if start < self.block_info.len() {
+            groups.push(start..self.block_info.len());
+        }
+        Ok(groups)
     }
 
-    fn save_block(&self, file: &mut File, block: &Block) -> Result<(), Error> {
-        let mut encoder = zstd::stream::write::Encoder::new(file, 1)?;
-        block.save(&mut encoder)?;
-        encoder.finish()?;
+    /**
+     * Save a block's section, returning the hash of its payload.
+     */
+    fn save_block<W: Write>(&mut self, file: &mut W, block: &Block) -> Result<PoolHash, Error> {
+        let mut payload = Vec::new();
+        if self.version >= 11 && block.order != self.cell_order {
+            block.with_order(self.cell_order).save(&mut payload, self.version)?;
+        } else {
+            block.save(&mut payload, self.version)?;
+        }
+        let hash = hash_payload(&payload);
+        if self.layout == Layout::Pool && self.version >= 3 {
+            let mut data = Vec::new();
+            write_section(&mut data, self.version, self.compression, self.dictionary.as_deref(), &payload)?;
+            write_pooled_section(file, &hash)?;
+            self.pool_objects.push(PoolObject { hash, data });
+        } else {
+            write_section(file, self.version, self.compression, self.dictionary.as_deref(), &payload)?;
+        }
+
+        Ok(hash)
+    }
+
+    fn save_segment_info<W: Write>(&self, file: &mut W) -> Result<(), Error> {
+        let payload = self.encode_info()?;
+        write_section(file, self.version, self.compression, None, &payload)?;
+
+        Ok(())
+    }
 
+    /**
+     * Check that the block info is for the given number of dimensions, so a segment written
+     * for a different schema is rejected when it is loaded.
+     */
+    pub(crate) fn check_dimensions(&self, num_dims: usize) -> Result<(), Error> {
+        if let Some(bi) = self.block_info.iter().find(|bi| bi.min_bounds.len() != num_dims) {
+            error!("Segment {:?} has {:?} dimensions, expected {:?}", self.id, bi.min_bounds.len(), num_dims);
+            return Err(Error::SchemaError);
+        }
         Ok(())
     }
 
-    fn save_segment_info(&self, file: &mut File) -> Result<(), Error> {
-        let mut encoder = zstd::stream::write::Encoder::new(file, 1)?;
+    /**
+     * Encode the block info as the payload of a segment info section.
+     */
+    pub(crate) fn encode_info(&self) -> Result<Vec<u8>, Error> {
+        let mut payload = Vec::new();
 
-        let num_dims = self.block_info[0].min_bounds.len() as u16;
+        let num_dims = self.block_info.first().map_or(0, |bi| bi.min_bounds.len()) as u16;
 
-        encoder.write_u16::<BE>(self.block_info.len() as u16)?;
-        encoder.write_u16::<BE>(num_dims)?;
+        payload.write_u16::<BE>(self.block_info.len() as u16)?;
+        payload.write_u16::<BE>(num_dims)?;
+        if self.version >= 4 {
+            payload.write_u8(self.ordered as u8)?;
+        }
         for bi in &self.block_info {
             for dim_val in &bi.min_bounds {
-                encoder.write_u64::<BE>(*dim_val as u64)?;
+                payload.write_u64::<BE>(*dim_val as u64)?;
             }
             for dim_val in &bi.max_bounds {
-                encoder.write_u64::<BE>(*dim_val as u64)?;
+                payload.write_u64::<BE>(*dim_val as u64)?;
+            }
+            payload.write_u64::<BE>(bi.block_pos)?;
+            if self.version >= 2 {
+                let (num_rows, num_cells) = bi.counts.unwrap_or_default();
+                payload.write_u64::<BE>(num_rows as u64)?;
+                payload.write_u64::<BE>(num_cells as u64)?;
+            }
+            if self.version >= 7 {
+                payload.write_all(&bi.hash.unwrap_or_default())?;
+            }
+            if self.version >= 9 {
+                let column_counts = bi.column_counts.as_deref().unwrap_or_default();
+                payload.write_u16::<BE>(column_counts.len() as u16)?;
+                for &count in column_counts {
+                    payload.write_u64::<BE>(count as u64)?;
+                }
+            }
+        }
+        if self.version >= 5 {
+            payload.write_u16::<BE>(self.tombstones.len() as u16)?;
+            for criteria in &self.tombstones {
+                criteria.save(&mut payload)?;
             }
-            encoder.write_u64::<BE>(bi.block_pos)?;
         }
 
-        encoder.finish()?;
+        Ok(payload)
+    }
 
-        Ok(())
+    /**
+     * The number of cells in the segment's blocks, and how many have a value in each of
+     * `num_values` value columns, if the segment's format records them.  Formats before 8 hold
+     * no partial rows, so every row has each value the format can save; version 8 records which
+     * rows have each value only in the blocks themselves.
+     */
+    pub(crate) fn column_occupancy(&self, num_values: usize) -> Option<ColumnOccupancy> {
+        let mut cells = 0;
+        let mut present = vec![0; num_values];
+        for info in &self.block_info {
+            let (num_rows, num_cells) = info.counts?;
+            let column_counts = match &info.column_counts {
+                Some(column_counts) => column_counts.clone(),
+                None if self.version < 6 => vec![num_rows],
+                None if self.version < 8 => vec![num_rows; num_values],
+                None => return None
+            };
+            cells += num_cells as u64;
+            for (total, count) in present.iter_mut().zip(column_counts) {
+                *total += count as u64;
+            }
+        }
+        Some((cells, present))
+    }
+
+    /**
+     * Load every block in the segment.
+     */
+    pub(crate) fn load_all_blocks(&self, backend: &dyn Backend, dictionaries: &Dictionaries) -> Result<Vec<Block>, Error> {
+        let mut blocks = Vec::with_capacity(self.block_info.len());
+        for block_num in 0..self.block_info.len() {
+            blocks.push(self.load_one_block(backend, dictionaries, block_num as BlockNum)?);
+        }
+        Ok(blocks)
+    }
+
+    /**
+     * The hashes of the pool objects the segment's blocks refer to.
+     */
+    pub(crate) fn pooled_hashes(&self, backend: &dyn Backend) -> Result<Vec<PoolHash>, Error> {
+        let file = backend.open(&self.name)?;
+        let mut src = BufReader::new(file);
+        let mut hashes = Vec::new();
+        for block_info in &self.block_info {
+            src.seek(SeekFrom::Start(block_info.block_pos))?;
+            read_expected_tag(&mut src, Tag::Block)?;
+            if let Some(hash) = read_pooled_hash(&mut src, self.version)? {
+                hashes.push(hash);
+            }
+        }
+        Ok(hashes)
+    }
+
+    /**
+     * Rewrite the segment in the current format version, replacing the existing file.  The new
+     * file is saved with the compression and layout of `new_segment`, a new segment with the
+     * same id.  Returns the number of bytes added to the pool.
+     */
+    pub(crate) fn upgrade(
+        &mut self,
+        backend: &dyn Backend,
+        dictionaries: &Dictionaries,
+        new_segment: Segment
+    ) -> Result<u64, Error> {
+        let blocks = self.load_all_blocks(backend, dictionaries)?;
+        let block_refs: Vec<&Block> = blocks.iter().collect();
+
+        let old_version = self.version;
+        let pooled_size = self.rewrite(backend, &block_refs, new_segment)?;
+        debug!("Upgraded segment {:?} from version {:?} to {:?}", self.id, old_version, self.version);
+        Ok(pooled_size)
+    }
+
+    /**
+     * Replace the segment's file with one holding the given blocks, saved as `new_segment`, a
+     * new segment with the same id.  The new file is renamed over the old one, so the segment
+     * is never missing.  Returns the number of bytes added to the pool.
+     */
+    pub(crate) fn rewrite(
+        &mut self,
+        backend: &dyn Backend,
+        blocks: &[&Block],
+        mut new_segment: Segment
+    ) -> Result<u64, Error> {
+        new_segment.tombstones = self.tombstones.clone();
+        let pooled_size = new_segment.save(backend, blocks)?;
+        new_segment.make_visible(backend)?;
+
+        *self = new_segment;
+        Ok(pooled_size)
+    }
+
+    /**
+     * Replace the segment's file with one without the given blocks.  The other blocks are
+     * copied as they are, without decoding them, so the segment keeps its format version and
+     * any pooled blocks still refer to the same pool objects.
+     */
+    pub(crate) fn remove_blocks(&mut self, backend: &dyn Backend, removed: &HashSet<BlockNum>) -> Result<(), Error> {
+        let data = backend.read(&self.name)?;
+        const END_SIZE: usize = TAG_LENGTH + size_of::<u64>();
+        let Some(mut end_src) = data.len().checked_sub(END_SIZE).and_then(|pos| data.get(pos + TAG_LENGTH..)) else {
+            error!("Segment {:?} is truncated", self.id);
+            return Err(Error::DataError);
+        };
+        let segment_info_pos = end_src.read_u64::<BE>()?;
+
+        /* Each block's section runs up to the next one, or to the segment info after the last */
+        let mut positions: Vec<u64> = self.block_info.iter().map(|info| info.block_pos).collect();
+        positions.push(segment_info_pos);
+        positions.sort();
+
+        let mut file = Vec::new();
+        write_header(&mut file, self.version)?;
+        let mut block_info = Vec::new();
+        for (block_num, info) in std::mem::take(&mut self.block_info).into_iter().enumerate() {
+            if removed.contains(&(block_num as BlockNum)) {
+                continue;
+            }
+            let end_pos = positions.get(positions.partition_point(|&pos| pos <= info.block_pos)).copied().unwrap_or(0);
+            let Some(section) = data.get(info.block_pos as usize..end_pos as usize) else {
+                error!("Block {:?} of segment {:?} is truncated", block_num, self.id);
+                return Err(Error::DataError);
+            };
+            let block_pos = file.len() as u64;
+            file.extend_from_slice(section);
+            block_info.push(BlockInfo { block_pos, ..info });
+        }
+        self.block_info = block_info;
+
+        let segment_info_pos = file.len() as u64;
+        write_tag(&mut file, Tag::Segment)?;
+        self.save_segment_info(&mut file)?;
+        write_tag(&mut file, Tag::End)?;
+        file.write_u64::<BE>(segment_info_pos)?;
+
+        self.name = get_segment_name(self.id, false);
+        backend.write(&self.name, &file)?;
+        self.make_visible(backend)
     }
 
-    pub(crate) fn make_visible(&mut self, database_path: &Path) -> Result<(), Error> {
-        let new_path = get_segment_path(database_path,self.id, true);
-        std::fs::rename(self.path.as_path(), new_path.as_path())?;
-        self.path = new_path;
+    pub(crate) fn make_visible(&mut self, backend: &dyn Backend) -> Result<(), Error> {
+        let new_name = get_segment_name(self.id, true);
+        backend.rename(&self.name, &new_name)?;
+        self.name = new_name;
         Ok(())
     }
 
-    pub(crate) fn delete(&self) -> Result<(), Error> {
-        std::fs::remove_file(&self.path)?;
+    pub(crate) fn delete(&self, backend: &dyn Backend) -> Result<(), Error> {
+        backend.remove(&self.name)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod segment_tests {
+    use std::rc::Rc;
+    use crate::backend::MemoryBackend;
+    use super::*;
+
+    fn make_block() -> Block {
+        let mut b = Block::new(2);
+        b.add_row(&[7, 4, 99]);
+        b.add_row(&[9, 0, 101]);
+        b.add_row(&[9, 5, 102]);
+        b
+    }
+
+    fn rows(block: Block) -> Vec<Vec<Datum>> {
+        Block::iter(&Rc::new(block)).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let backend = MemoryBackend::new();
+        let block = make_block();
+        Segment::new((1, 0)).save(&backend, &[&block]).unwrap();
+
+        let segment = Segment::load(&backend, (1, 0)).unwrap();
+        assert_eq!(segment.version, CURRENT_FORMAT_VERSION);
+        assert_eq!(segment.block_info.len(), 1);
+        assert_eq!(segment.block_info[0].min_bounds, vec![7, 0]);
+        assert_eq!(segment.block_info[0].max_bounds, vec![9, 5]);
+        assert_eq!(segment.block_info[0].counts, Some((3, 6)));
+        assert_eq!(segment.block_info[0].column_counts, Some(vec![3]));
+        assert_eq!(rows(segment.load_one_block(&backend, &Dictionaries::default(), 0).unwrap()), rows(make_block()));
+    }
+
+    #[test]
+    fn pool_layout() {
+        let backend = MemoryBackend::new();
+        let block = make_block();
+        let mut segment = Segment::new((1, 0));
+        segment.layout = Layout::Pool;
+        segment.save(&backend, &[&block, &block]).unwrap();
+        assert!(segment.pool_objects.is_empty());
+
+        /* Both blocks have the same content, so share one pool object */
+        let segment = Segment::load(&backend, (1, 0)).unwrap();
+        let hashes = segment.pooled_hashes(&backend).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes[0], hashes[1]);
+        assert!(backend.exists(&get_pool_name(&hashes[0])));
+        assert_eq!(rows(segment.load_one_block(&backend, &Dictionaries::default(), 1).unwrap()), rows(make_block()));
+
+        backend.write(&get_pool_name(&hashes[0]), b"corrupt").unwrap();
+        assert!(segment.load_one_block(&backend, &Dictionaries::default(), 0).is_err());
+    }
+
+    #[test]
+    fn remove_blocks() {
+        let backend = MemoryBackend::new();
+        let mut other = Block::new(2);
+        other.add_row(&[20, 1, 5]);
+        let mut segment = Segment::new((1, 0));
+        segment.tombstones = vec![Criteria::new().range(1, 3..=3)];
+        segment.save(&backend, &[&make_block(), &other, &make_block()]).unwrap();
+
+        let mut segment = Segment::load(&backend, (1, 0)).unwrap();
+        segment.remove_blocks(&backend, &HashSet::from([0, 2])).unwrap();
+        let segment = Segment::load(&backend, (1, 0)).unwrap();
+        assert_eq!(segment.block_info.len(), 1);
+        assert_eq!(segment.block_info[0].min_bounds, vec![20, 1]);
+        assert_eq!(segment.tombstones.len(), 1);
+        assert_eq!(rows(segment.load_one_block(&backend, &Dictionaries::default(), 0).unwrap()), vec![vec![20, 1, 5]]);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn read_version_0() {
+        let backend = MemoryBackend::new();
+        let block = make_block();
+        let mut segment = Segment {
+            id: (1, 0),
+            name: get_segment_name((1, 0), true),
+            version: 0,
+            compression: Compression::default(),
+            dictionary: None,
+            layout: Layout::default(),
+            cell_order: CellOrder::RowMajor,
+            ordered: false,
+            pool_objects: Vec::new(),
+            block_info: Vec::new(),
+            tombstones: Vec::new()
+        };
+        segment.save(&backend, &[&block, &block]).unwrap();
+
+        let mut segment = Segment::load(&backend, (1, 0)).unwrap();
+        assert_eq!(segment.version, 0);
+        assert_eq!(segment.block_info.len(), 2);
+        assert_eq!(segment.block_info[0].counts, None);
+        assert_eq!(segment.column_occupancy(1), None);
+        assert_eq!(rows(segment.load_one_block(&backend, &Dictionaries::default(), 1).unwrap()), rows(make_block()));
+
+        segment.upgrade(&backend, &Dictionaries::default(), Segment::new((1, 0))).unwrap();
+        assert_eq!(segment.version, CURRENT_FORMAT_VERSION);
+
+        let segment = Segment::load(&backend, (1, 0)).unwrap();
+        assert_eq!(segment.version, CURRENT_FORMAT_VERSION);
+        assert_eq!(segment.block_info[1].counts, Some((3, 6)));
+        assert_eq!(segment.column_occupancy(1), Some((12, vec![6])));
+        assert_eq!(rows(segment.load_one_block(&backend, &Dictionaries::default(), 0).unwrap()), rows(make_block()));
+        assert_eq!(rows(segment.load_one_block(&backend, &Dictionaries::default(), 1).unwrap()), rows(make_block()));
+    }
+}
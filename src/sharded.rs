@@ -0,0 +1,312 @@
+use log::{error, info};
+
+use crate::{Datum, Error};
+use crate::database::Database;
+use crate::Error::{ArityMismatch, SchemaError};
+use crate::merged::MergedScan;
+use crate::query::Criteria;
+use crate::scan::Scan;
+use crate::transaction::{CommitSummary, Transaction};
+
+/**
+ * How a `ShardedDatabase` chooses the shard for a row, from its coordinate in the sharding
+ * dimension.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShardBy {
+    /**
+     * By a hash of the coordinate, which spreads rows evenly whatever their distribution.  The
+     * hash is fixed, so rows go to the same shard every time the databases are opened.
+     */
+    Hash,
+    /**
+     * By range: shard `i` holds the coordinates below `bounds[i]` and at or above
+     * `bounds[i - 1]`, and the last shard holds the rest, so there is one bound fewer than
+     * shards.  Queries on a range of the dimension only read the shards it overlaps.
+     */
+    Ranges(Vec<Datum>)
+}
+
+/**
+ * Several databases with the same schema acting as one, each holding the rows whose
+ * coordinates in one dimension route to it.  Each database still has a single writer, but the
+ * shards can live on different disks, be compacted separately and stay smaller.
+ *
+ * A transaction on a sharded database is a transaction on each shard, committed one after
+ * another, so it isn't atomic across shards: if one shard fails to commit, the shards before
+ * it have committed and those after it are rolled back.
+ */
+pub struct ShardedDatabase {
+    shards: Vec<Database>,
+    dim_no: usize,
+    shard_by: ShardBy
+}
+
+impl ShardedDatabase {
+    /**
+     * Shard rows across the databases by their coordinate in dimension `dim_no`.  The same
+     * databases must be given in the same order each time, for rows to be found again.
+     *
+     * Fails with `SchemaError` if there are no databases, their schemas differ, the dimension
+     * doesn't exist, or the range bounds don't increase or don't number one fewer than the
+     * databases.
+     */
+    pub fn new(shards: Vec<Database>, dim_no: usize, shard_by: ShardBy) -> Result<ShardedDatabase, Error> {
+        let Some(first) = shards.first() else {
+            error!("A sharded database needs at least one shard");
+            return Err(SchemaError);
+        };
+        if let Some(position) = shards.iter().position(|shard| shard.schema != first.schema) {
+            error!("Shard {:?} has a different schema to the first", position);
+            return Err(SchemaError);
+        }
+        if dim_no >= first.schema.dimensions.len() {
+            error!("Can't shard by dimension {:?}, which doesn't exist", dim_no);
+            return Err(SchemaError);
+        }
+        if let ShardBy::Ranges(bounds) = &shard_by {
+            if bounds.len() + 1 != shards.len() || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+                error!("Invalid range bounds {:?} for {:?} shards", bounds, shards.len());
+                return Err(SchemaError);
+            }
+        }
+
+        info!("Sharding {:?} databases by dimension {:?}", shards.len(), dim_no);
+        Ok(ShardedDatabase { shards, dim_no, shard_by })
+    }
+
+    pub fn shards(&self) -> &[Database] {
+        &self.shards
+    }
+
+    /**
+     * The shards, for settings and maintenance on each, such as `Database::set_auto_flush`.
+     */
+    pub fn shards_mut(&mut self) -> &mut [Database] {
+        &mut self.shards
+    }
+
+    /**
+     * Give back the underlying databases, in order.
+     */
+    pub fn into_shards(self) -> Vec<Database> {
+        self.shards
+    }
+
+    /**
+     * The number of the shard that rows with this coordinate in the sharding dimension go to.
+     */
+    pub fn shard_for(&self, value: Datum) -> usize {
+        shard_for(&self.shard_by, self.shards.len(), value)
+    }
+
+    /**
+     * Start a transaction on every shard.
+     */
+    pub fn new_transaction(&mut self) -> Result<ShardedTransaction<'_>, Error> {
+        let txns = self.shards.iter_mut()
+            .map(|shard| shard.new_transaction())
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(ShardedTransaction { txns, dim_no: self.dim_no, shard_by: self.shard_by.clone() })
+    }
+
+    /**
+     * Query all the committed rows, in order by point.  As with `Snapshot::query`, the scans
+     * count as open but are exempt from each shard's limit on open scans, so this never fails.
+     */
+    pub fn query(&self) -> MergedScan<Scan<'_>> {
+        let scans = self.shards.iter().map(|shard| shard.query_snapshot().query()).collect();
+        MergedScan::new(self.shards[0].schema.dimensions.len(), scans)
+    }
+
+    /**
+     * Query the committed rows matching the criteria, in order by point, from only the shards
+     * that can hold them.
+     *
+     * Fails as `Snapshot::query_with` does on any shard.
+     */
+    pub fn query_with(&self, criteria: &Criteria) -> Result<MergedScan<Scan<'_>>, Error> {
+        let scans = self.shards.iter().enumerate()
+            .filter(|(shard_no, _)| may_hold(&self.shard_by, self.shards.len(), self.dim_no, criteria, *shard_no))
+            .map(|(_, shard)| shard.query_snapshot().query_with(criteria))
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(MergedScan::new(self.shards[0].schema.dimensions.len(), scans))
+    }
+
+    /**
+     * Compact every shard, returning the total number of segments removed.
+     */
+    pub fn compact(&mut self) -> Result<usize, Error> {
+        self.shards.iter_mut().map(|shard| shard.compact()).sum()
+    }
+}
+
+/**
+ * A transaction on each shard of a `ShardedDatabase`, from `ShardedDatabase::new_transaction`.
+ */
+pub struct ShardedTransaction<'db> {
+    txns: Vec<Transaction<'db>>,
+    dim_no: usize,
+    shard_by: ShardBy
+}
+
+impl<'db> ShardedTransaction<'db> {
+    /**
+     * The transactions on each shard, for operations not offered here.  Rows added to them
+     * directly must belong to their shard, or queries on a range of the sharding dimension may
+     * miss them.
+     */
+    pub fn shards_mut(&mut self) -> &mut [Transaction<'db>] {
+        &mut self.txns
+    }
+
+    /**
+     * Add a row to the transaction on its shard.
+     */
+    pub fn add_row(&mut self, values: &[Datum]) -> Result<(), Error> {
+        let Some(&value) = values.get(self.dim_no) else {
+            error!("Row {:?} has no coordinate in the sharding dimension", values);
+            return Err(ArityMismatch);
+        };
+        let shard_no = shard_for(&self.shard_by, self.txns.len(), value);
+        self.txns[shard_no].add_row(values)
+    }
+
+    /**
+     * Delete the rows matching the criteria from every shard that may hold them.
+     */
+    pub fn delete_where(&mut self, criteria: &Criteria) -> Result<(), Error> {
+        let num_shards = self.txns.len();
+        for (shard_no, txn) in self.txns.iter_mut().enumerate() {
+            if may_hold(&self.shard_by, num_shards, self.dim_no, criteria, shard_no) {
+                txn.delete_where(criteria)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.txns.iter_mut().try_for_each(|txn| txn.flush())
+    }
+
+    /**
+     * Commit the transaction on each shard in turn, returning the summary of each.  If one
+     * fails, the rest are rolled back, but those before it stay committed.
+     */
+    pub fn commit(self) -> Result<Vec<CommitSummary>, Error> {
+        self.txns.into_iter().enumerate()
+            .map(|(shard_no, txn)| txn.commit().inspect_err(|err| error!("Commit of shard {shard_no:?} failed with {err:?}")))
+            .collect()
+    }
+
+    pub fn rollback(self) {
+        for txn in self.txns {
+            txn.rollback();
+        }
+    }
+
+    /**
+     * Query the rows matching the criteria, including the transaction's own, in order by
+     * point, from only the shards that can hold them.
+     */
+    pub fn query_with(&'db self, criteria: &Criteria) -> Result<MergedScan<Scan<'db>>, Error> {
+        let num_shards = self.txns.len();
+        let scans = self.txns.iter().enumerate()
+            .filter(|(shard_no, _)| may_hold(&self.shard_by, num_shards, self.dim_no, criteria, *shard_no))
+            .map(|(_, txn)| txn.query_with(criteria))
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(MergedScan::new(self.txns[0].database.schema.dimensions.len(), scans))
+    }
+}
+
+fn shard_for(shard_by: &ShardBy, num_shards: usize, value: Datum) -> usize {
+    match shard_by {
+        ShardBy::Hash => (((value as u64).wrapping_mul(0x9e3779b97f4a7c15) >> 32) % num_shards as u64) as usize,
+        ShardBy::Ranges(bounds) => bounds.partition_point(|&bound| bound <= value)
+    }
+}
+
+/**
+ * Whether a shard can hold rows matching the criteria.  By hash, only criteria listing single
+ * values of the dimension rule any shards out.
+ */
+fn may_hold(shard_by: &ShardBy, num_shards: usize, dim_no: usize, criteria: &Criteria, shard_no: usize) -> bool {
+    let Some(ranges) = criteria.get_ranges(dim_no) else { return true };
+    match shard_by {
+        ShardBy::Hash if ranges.iter().all(|range| range.start() == range.end()) =>
+            ranges.iter().any(|range| shard_for(shard_by, num_shards, *range.start()) == shard_no),
+        ShardBy::Hash => true,
+        ShardBy::Ranges(bounds) => {
+            let low = if shard_no == 0 { 0 } else { bounds[shard_no - 1] };
+            let high = bounds.get(shard_no).map_or(Datum::MAX, |&bound| bound - 1);
+            ranges.iter().any(|range| *range.start() <= high && *range.end() >= low)
+        }
+    }
+}
+
+#[cfg(test)]
+mod sharded_tests {
+    use crate::{Criteria, Database, Error, MemoryBackend, Schema};
+    use super::{ShardBy, ShardedDatabase};
+
+    fn shards(num_shards: usize) -> Vec<Database> {
+        let schema = Schema::builder().dimension("sensor", 4).dimension("time", 100).value("reading").build().unwrap();
+        (0..num_shards).map(|_| Database::create_with_backend(schema.clone(), Box::new(MemoryBackend::new())).unwrap()).collect()
+    }
+
+    #[test]
+    fn ranges() {
+        let mut database = ShardedDatabase::new(shards(3), 1, ShardBy::Ranges(vec![100, 200])).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        for time in (0..300).step_by(10) {
+            txn.add_row(&[time % 7, time, time * 2]).unwrap();
+        }
+        assert!(matches!(txn.add_row(&[1]), Err(Error::ArityMismatch)));
+        assert_eq!(txn.query_with(&Criteria::new()).unwrap().count(), 30);
+        txn.commit().unwrap();
+
+        assert_eq!(database.shard_for(99), 0);
+        assert_eq!(database.shard_for(100), 1);
+        assert_eq!(database.shard_for(1000), 2);
+        assert!(database.shards().iter().all(|shard| shard.query_snapshot().query().count() == 10));
+
+        /* A query is merged in order, and only reads the shards it needs */
+        let criteria = Criteria::new().range(1, 150..=250);
+        assert_eq!(database.query_with(&criteria).unwrap().count(), 11);
+        let rows: Vec<_> = database.query().map(|row| (row[0], row[1])).collect();
+        let mut sorted = rows.clone();
+        sorted.sort();
+        assert_eq!(rows, sorted);
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.delete_where(&Criteria::new().range(1, 0..=149)).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(database.query().count(), 15);
+
+        assert!(ShardedDatabase::new(shards(3), 1, ShardBy::Ranges(vec![100])).is_err());
+        assert!(ShardedDatabase::new(shards(3), 1, ShardBy::Ranges(vec![200, 100])).is_err());
+        assert!(ShardedDatabase::new(shards(2), 2, ShardBy::Hash).is_err());
+    }
+
+    #[test]
+    fn hash() {
+        let mut database = ShardedDatabase::new(shards(4), 0, ShardBy::Hash).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        for sensor in 0..40 {
+            txn.add_row(&[sensor, 5, sensor]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        assert!(database.shards().iter().all(|shard| shard.query_snapshot().query().count() > 0));
+        let rows: Vec<_> = database.query_with(&Criteria::new().values(0, &[3, 17])).unwrap().map(|row| row[2]).collect();
+        assert_eq!(rows, vec![3, 17]);
+        assert_eq!(database.query().map(|row| row[0]).collect::<Vec<_>>(), (0..40).collect::<Vec<_>>());
+
+        /* Only query_with is held to the limit on open scans */
+        database.shards_mut().iter_mut().for_each(|shard| shard.set_max_open_scans(Some(1)));
+        let open = database.shards()[0].query_snapshot().query();
+        assert_eq!(database.query().count(), 40);
+        assert!(matches!(database.query_with(&Criteria::new()), Err(Error::QuotaExceeded)));
+        drop(open);
+    }
+}
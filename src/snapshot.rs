@@ -0,0 +1,95 @@
+use log::debug;
+
+use crate::{Error, TransactionId};
+use crate::database::Database;
+use crate::Error::SchemaError;
+use crate::query::Criteria;
+use crate::scan::{Scan, ScanSource};
+
+/**
+ * A read-only view of the data committed when it was taken, from `Database::query_snapshot`.
+ *
+ * Unlike a `Transaction`, a snapshot only borrows the database immutably, so any number can be
+ * open at once and reading doesn't need a mutable database.  It never allocates a transaction
+ * id.  The database can't be written to while a snapshot is open, so the data visible to it
+ * can't change anyway.
+ */
+pub struct Snapshot<'db> {
+    database: &'db Database,
+    horizon: TransactionId
+}
+
+impl<'db> Snapshot<'db> {
+    pub(crate) fn new(database: &'db Database) -> Snapshot<'db> {
+        Snapshot { database, horizon: database.next_transaction_id }
+    }
+
+    /**
+     * Transactions with ids below the horizon are visible to the snapshot.
+     */
+    pub fn horizon(&self) -> TransactionId {
+        self.horizon
+    }
+
+    /**
+     * Query all the rows.  The scan counts as open, but is exempt from the database's limit on
+     * open scans, so this never fails; `query_with` is subject to it.
+     */
+    pub fn query(&self) -> Scan<'db> {
+        self.scan(self.database.counted_scan_source(), &Criteria::new())
+    }
+
+    /**
+     * Query only the rows matching the criteria.
+     *
+     * Fails with `SchemaError` if the criteria refer to more dimensions than the schema has, and
+     * `QuotaExceeded` if the database's limit on open scans has been reached.
+     */
+    pub fn query_with(&self, criteria: &Criteria) -> Result<Scan<'db>, Error> {
+        if criteria.num_dims() > self.database.schema.dimensions.len() {
+            return Err(SchemaError);
+        }
+        Ok(self.scan(self.database.open_scan_source()?, criteria))
+    }
+
+    fn scan(&self, source: Box<dyn ScanSource + 'db>, criteria: &Criteria) -> Scan<'db> {
+        let num_dims = self.database.schema.dimensions.len();
+        let mut scan = Scan::new(source, num_dims, 0);
+        scan.set_criteria(criteria.clone());
+        for seg_id in self.database.get_visible_committed_segments(self.horizon) {
+            debug!("Add committed segment {:?}", seg_id);
+            scan.add_segment_id(seg_id);
+        }
+        scan
+    }
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use crate::{CellOrder, Criteria, Database, Dimension, MemoryBackend, Schema, Value, ValueType};
+
+    #[test]
+    fn query_snapshot() {
+        let schema = Schema {
+            dimensions: vec![
+                Dimension { name: String::from("x"), chunk_size: 10, bounds: None, time: None, labels: false },
+            ],
+            values: vec![
+                Value { name: String::from("value"), value_type: ValueType::U64 }
+            ],
+            cell_order: CellOrder::RowMajor
+        };
+        let mut database = Database::create_with_backend(schema, Box::new(MemoryBackend::new())).unwrap();
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(&[[1, 10], [12, 120], [25, 250]]).unwrap();
+        txn.commit().unwrap();
+
+        let snapshot = database.query_snapshot();
+        let other = database.query_snapshot();
+        assert_eq!(snapshot.horizon(), 2);
+        assert_eq!(snapshot.query().map(|r| r[1]).collect::<Vec<_>>(), vec![10, 120, 250]);
+        assert_eq!(other.query_with(&Criteria::new().range(0, 10..=20)).unwrap().count(), 1);
+        assert!(snapshot.query_with(&Criteria::new().range(1, 0..=0)).is_err());
+        assert_eq!(database.next_transaction_id, 2);
+    }
+}
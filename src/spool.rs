@@ -0,0 +1,138 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use log::{debug, error, info};
+
+use crate::{Datum, Error, TransactionId};
+use crate::query::QueryRow;
+
+static NEXT_SPOOL_NUM: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * Query results that have been written to a temporary spill file, to be read back at the
+ * consumer's own pace.  Once the results are spooled, the scan that produced them is finished
+ * and none of its blocks are held, so a slow consumer doesn't keep them pinned in the cache.
+ *
+ * Each row is stored as a `u16` width, a `u32` transaction id, and the row values as `u64`s.
+ * The file is deleted when the spool is dropped.
+ */
+pub struct Spool {
+    path: PathBuf,
+    reader: BufReader<File>,
+    remaining: u64
+}
+
+impl Spool {
+    pub(crate) fn create<I: Iterator<Item=QueryRow>>(rows: I) -> Result<Spool, Error> {
+        let spool_num = NEXT_SPOOL_NUM.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("matdb-spool-{}-{}.tmp", std::process::id(), spool_num));
+
+        let file = File::options().read(true).write(true).create_new(true).open(&path)?;
+        let mut spool = Spool { path, reader: BufReader::new(file), remaining: 0 };
+
+        let mut writer = BufWriter::new(spool.reader.get_mut());
+        let mut num_rows = 0;
+        for row in rows {
+            writer.write_u16::<BE>(row.values_array.len() as u16)?;
+            writer.write_u32::<BE>(row.txn_id)?;
+            match row.segment {
+                Some((txn_id, seg_num)) => {
+                    writer.write_u8(1)?;
+                    writer.write_u32::<BE>(txn_id)?;
+                    writer.write_u16::<BE>(seg_num)?;
+                },
+                None => writer.write_u8(0)?
+            }
+            for &val in &row.values_array {
+                writer.write_u64::<BE>(val as u64)?;
+            }
+            num_rows += 1;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        spool.reader.seek(SeekFrom::Start(0))?;
+        spool.remaining = num_rows;
+        info!("Spooled {:?} rows to {:?}", num_rows, spool.path);
+        Ok(spool)
+    }
+
+    /**
+     * The number of rows not yet read.
+     */
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    fn read_row(&mut self) -> Result<QueryRow, Error> {
+        let width = self.reader.read_u16::<BE>()? as usize;
+        let txn_id: TransactionId = self.reader.read_u32::<BE>()?;
+        let segment = match self.reader.read_u8()? {
+            0 => None,
+            _ => Some((self.reader.read_u32::<BE>()?, self.reader.read_u16::<BE>()?))
+        };
+        let mut values_array = Vec::with_capacity(width);
+        for _ in 0..width {
+            values_array.push(self.reader.read_u64::<BE>()? as Datum);
+        }
+        Ok(QueryRow { txn_id, segment, values_array })
+    }
+}
+
+impl Iterator for Spool {
+    type Item = QueryRow;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match self.read_row() {
+            Ok(row) => {
+                self.remaining -= 1;
+                Some(row)
+            }
+            Err(err) => {
+                error!("Error reading spool file {:?}: {err:?}", self.path);
+                self.remaining = 0;
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        debug!("Removing spool file {:?}", self.path);
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            error!("Couldn't remove spool file {:?}: {err:?}", self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod spool_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let rows: Vec<_> = (0..1000).map(|i| QueryRow { txn_id: i as TransactionId, segment: Some((i as TransactionId, 1)), values_array: vec![i, i * 2, usize::MAX - i] }).collect();
+        let mut spool = Spool::create(rows.clone().into_iter()).unwrap();
+        let path = spool.path.clone();
+        assert!(path.exists());
+        assert_eq!(spool.remaining(), 1000);
+
+        let first = spool.next().unwrap();
+        assert_eq!(first.values_array, rows[0].values_array);
+        let rest: Vec<_> = spool.by_ref().collect();
+        assert_eq!(rest.len(), 999);
+        assert_eq!(rest[998].txn_id, 999);
+        assert_eq!(rest[998].segment, Some((999, 1)));
+        assert_eq!(rest[998].values_array, rows[999].values_array);
+
+        drop(spool);
+        assert!(!path.exists());
+    }
+}
@@ -1,71 +1,58 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, Write};
-use std::path::{Path, PathBuf};
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
 
 use log::error;
 
-use crate::{Error, SegmentId, SegmentNum, TransactionId};
+use crate::{DictionaryId, Error, SegmentId, SegmentNum, TransactionId};
 use crate::Error::{DataError};
+use crate::pool::PoolHash;
 
-const TAG_PREFIX: &[u8] = "MD:".as_bytes();
-const TAG_PREFIX_LENGTH: usize = TAG_PREFIX.len();
 pub const TAG_LENGTH: usize = 6;
 
 #[derive(PartialEq, Eq)]
 pub enum Tag {
+    Header,
     Block,
     Segment,
     End
 }
 
 pub const SCHEMA_FILENAME: &str = "schema.json";
-
-pub fn check_for_prefix<F>(reader: &mut BufReader<F>) -> std::io::Result<bool>
-where F: Read + Seek
-{
-    let mut buffer:[u8; TAG_PREFIX_LENGTH] = [0; TAG_PREFIX_LENGTH];
-    reader.read_exact(&mut buffer)?;
-
-    reader.seek_relative(-(TAG_PREFIX_LENGTH as i64))?;
-
-    Ok(buffer.eq(TAG_PREFIX))
-}
-
-pub fn skip_to_next_tag<F>(reader: &mut BufReader<F>) -> std::io::Result<()>
-where F: Read + Seek
-{
-    if check_for_prefix(reader)? {
-        return Ok(());
-    }
-
-    reader.seek_relative(1)?;
-
-    if !check_for_prefix(reader)? {
-        return Err(std::io::Error::new(ErrorKind::InvalidInput, "Couldn't find tag"));
-    }
-
-    Ok(())
-}
-
-pub fn read_tag<R: BufRead>(reader: &mut R) -> Tag
+pub const CHECKPOINTS_FILENAME: &str = "checkpoints.json";
+pub const COLUMN_STATS_FILENAME: &str = "column-stats.json";
+pub const HOT_BLOCKS_FILENAME: &str = "hot-blocks.json";
+pub const JOURNAL_FILENAME: &str = "journal.json";
+pub const LABELS_FILENAME: &str = "labels.json";
+pub const LATE_SEGMENTS_FILENAME: &str = "late-segments.json";
+pub const RENAMES_FILENAME: &str = "renames.json";
+pub const SLOW_QUERY_LOG_FILENAME: &str = "slow-queries.log";
+pub const DICTIONARY_PREFIX: &str = "dictionary-";
+pub const POOL_PREFIX: &str = "pool-";
+pub const IMPORT_PREFIX: &str = "import-";
+
+pub fn read_tag<R: BufRead>(reader: &mut R) -> Result<Tag, Error>
 {
     let mut buffer:[u8; TAG_LENGTH] = [0; TAG_LENGTH];
-    reader.read_exact(&mut buffer).expect("Insuffient data for tag");
+    reader.read_exact(&mut buffer)?;
 
-    if buffer.eq("MD:BLK".as_bytes()) {
-        Tag::Block
+    if buffer.eq("MD:HDR".as_bytes()) {
+        Ok(Tag::Header)
+    } else if buffer.eq("MD:BLK".as_bytes()) {
+        Ok(Tag::Block)
     } else if buffer.eq("MD:SEG".as_bytes()) {
-        Tag::Segment
+        Ok(Tag::Segment)
     } else if buffer.eq("MD:END".as_bytes()) {
-        Tag::End
+        Ok(Tag::End)
     } else {
-        panic!("Unknown tag")
+        error!("Unknown tag {:?}", buffer);
+        Err(DataError)
     }
 }
 
-pub fn write_tag(file: &mut File, tag: Tag) -> std::io::Result<()> {
+pub fn write_tag<W: Write>(file: &mut W, tag: Tag) -> std::io::Result<()> {
     file.write_all(
         match tag {
+            Tag::Header => "MD:HDR".as_bytes(),
             Tag::Block => "MD:BLK".as_bytes(),
             Tag::Segment => "MD:SEG".as_bytes(),
             Tag::End => "MD:END".as_bytes()
@@ -74,26 +61,38 @@ pub fn write_tag(file: &mut File, tag: Tag) -> std::io::Result<()> {
 }
 
 pub fn read_expected_tag<R: BufRead>(src: &mut R, expected: Tag) -> Result<(), Error> {
-    let tag = read_tag(src);
+    let tag = read_tag(src)?;
     if tag != expected {
-        error!("Did not find end tag in segment!");
+        error!("Did not find expected tag in segment!");
         return Err(DataError);
     }
     Ok(())
 }
 
+/**
+ * Read a number of bytes given by a length field.  Memory is only allocated as the bytes
+ * arrive, so a corrupt length fails with an error rather than an enormous allocation.
+ */
+pub fn read_bytes<R: Read>(src: &mut R, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    src.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        error!("Expected {:?} bytes but found only {:?}", len, bytes.len());
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
 
-pub fn get_segment_path(
-    database_path: &Path,
+
+pub fn get_segment_name(
     seg_id: SegmentId,
     visible: bool
-) -> PathBuf {
-    let segment_filename = if visible {
+) -> String {
+    if visible {
         format!("{:08x}.{:08x}", seg_id.0, seg_id.1)
     } else {
         format!("{:08x}.{:08x}.tmp", seg_id.0, seg_id.1)
-    };
-    database_path.join(segment_filename)
+    }
 }
 
 pub fn decode_segment_path(path: &Path) -> Option<(TransactionId, SegmentNum, bool)> {
@@ -110,6 +109,41 @@ pub fn decode_segment_path(path: &Path) -> Option<(TransactionId, SegmentNum, bo
     Some((txn_id, seg_num, committed))
 }
 
+pub fn get_dictionary_name(id: DictionaryId) -> String {
+    format!("{}{:08x}", DICTIONARY_PREFIX, id)
+}
+
+pub fn decode_dictionary_name(name: &str) -> Option<DictionaryId> {
+    let hex = name.strip_prefix(DICTIONARY_PREFIX)?;
+    DictionaryId::from_str_radix(hex, 16).ok()
+}
+
+pub fn get_import_name(txn_id: TransactionId) -> String {
+    format!("{}{:08x}", IMPORT_PREFIX, txn_id)
+}
+
+pub fn decode_import_name(name: &str) -> Option<TransactionId> {
+    let hex = name.strip_prefix(IMPORT_PREFIX)?;
+    TransactionId::from_str_radix(hex, 16).ok()
+}
+
+pub fn get_pool_name(hash: &PoolHash) -> String {
+    let hex: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}{}", POOL_PREFIX, hex)
+}
+
+pub fn decode_pool_name(name: &str) -> Option<PoolHash> {
+    let hex = name.strip_prefix(POOL_PREFIX)?;
+    let mut hash: PoolHash = Default::default();
+    if hex.len() != hash.len() * 2 {
+        return None;
+    }
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
 #[cfg(test)]
 mod storage_tests {
     use super::*;
@@ -127,5 +161,26 @@ mod storage_tests {
         assert!(!committed);
 
         assert!(decode_segment_path(Path::new("bogusfilename")).is_none());
+        assert!(decode_segment_path(Path::new(&get_dictionary_name(1))).is_none());
+        assert!(decode_segment_path(Path::new(&get_pool_name(&[0; 32]))).is_none());
+    }
+
+    #[test]
+    fn dictionary_name() {
+        assert_eq!(decode_dictionary_name(&get_dictionary_name(0x1a)), Some(0x1a));
+        assert_eq!(decode_dictionary_name("dictionary-bogus"), None);
+        assert_eq!(decode_dictionary_name("00000001.00000000"), None);
+    }
+
+    #[test]
+    fn pool_name() {
+        let mut hash = [0; 32];
+        hash[0] = 0xab;
+        hash[31] = 0x01;
+        let name = get_pool_name(&hash);
+        assert_eq!(name.len(), POOL_PREFIX.len() + 64);
+        assert_eq!(decode_pool_name(&name), Some(hash));
+        assert_eq!(decode_pool_name(&format!("{name}.tmp")), None);
+        assert_eq!(decode_pool_name("pool-bogus"), None);
     }
 }
@@ -0,0 +1,31 @@
+use crate::Schema;
+#[cfg(feature = "fs")]
+use crate::Database;
+
+/**
+ * The schema most tests use: dimensions `x` and `y`, each in chunks of 10, and one value.
+ */
+pub(crate) fn schema() -> Schema {
+    Schema::builder().dimension("x", 10).dimension("y", 10).value("value").build().unwrap()
+}
+
+/**
+ * A directory for a test's database in the system's temporary directory, with anything left
+ * there by an earlier run removed.
+ */
+#[cfg(feature = "fs")]
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(name);
+    if path.exists() {
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+    path
+}
+
+/**
+ * A new database with `schema()`, in a fresh directory from `temp_path`.
+ */
+#[cfg(feature = "fs")]
+pub(crate) fn create_database(name: &str) -> Database {
+    Database::create(schema(), temp_path(name).as_path()).unwrap()
+}
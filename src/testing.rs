@@ -0,0 +1,287 @@
+use std::collections::BTreeMap;
+
+use crate::{Datum, Error};
+use crate::database::Database;
+use crate::query::Criteria;
+use crate::schema::Schema;
+
+/**
+ * A reference implementation of a database's rows, with at most one transaction open.
+ * Operations take effect on the open transaction's rows if there is one, and on the committed
+ * rows otherwise, and fail as the real ones would on rows that don't fit the schema.
+ */
+#[derive(Clone, Debug)]
+pub struct ModelDb {
+    schema: Schema,
+    committed: BTreeMap<Vec<Datum>, Vec<Datum>>,
+    pending: Option<BTreeMap<Vec<Datum>, Vec<Datum>>>
+}
+
+impl ModelDb {
+    pub fn new(schema: &Schema) -> ModelDb {
+        ModelDb { schema: schema.clone(), committed: BTreeMap::new(), pending: None }
+    }
+
+    /**
+     * Start a transaction, seeing the committed rows.  Any transaction already open is rolled
+     * back.
+     */
+    pub fn begin(&mut self) {
+        self.pending = Some(self.committed.clone());
+    }
+
+    pub fn commit(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            self.committed = pending;
+        }
+    }
+
+    pub fn rollback(&mut self) {
+        self.pending = None;
+    }
+
+    fn rows_mut(&mut self) -> &mut BTreeMap<Vec<Datum>, Vec<Datum>> {
+        self.pending.as_mut().unwrap_or(&mut self.committed)
+    }
+
+    /**
+     * Insert a row, replacing any existing row at the same point.
+     */
+    pub fn add_row(&mut self, values: &[Datum]) -> Result<(), Error> {
+        self.schema.check_row(values)?;
+        let num_dims = self.schema.dimensions.len();
+        self.rows_mut().insert(values[..num_dims].to_vec(), values[num_dims..].to_vec());
+        Ok(())
+    }
+
+    /**
+     * Set one value of the row at a point, as `Transaction::update` does.
+     */
+    pub fn update(&mut self, coords: &[Datum], value_no: usize, new_value: Datum) -> Result<(), Error> {
+        if coords.len() != self.schema.dimensions.len() {
+            return Err(Error::ArityMismatch);
+        }
+        if value_no >= self.schema.values.len() || self.schema.values.len() != 1 {
+            return Err(Error::SchemaError);
+        }
+        let mut row = coords.to_vec();
+        row.push(new_value);
+        self.add_row(&row)
+    }
+
+    /**
+     * The rows matching the criteria, sorted by point, each with its dimension values followed
+     * by its values.
+     */
+    pub fn query(&self, criteria: &Criteria) -> Result<Vec<Vec<Datum>>, Error> {
+        if criteria.num_dims() > self.schema.dimensions.len() {
+            return Err(Error::SchemaError);
+        }
+        let rows = self.pending.as_ref().unwrap_or(&self.committed);
+        Ok(rows.iter()
+            .filter(|(point, _)| criteria.matches_point(point))
+            .map(|(point, values)| point.iter().chain(values).copied().collect())
+            .collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.as_ref().unwrap_or(&self.committed).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/**
+ * One step of a differential test.  Any operation other than `Compact` starts a transaction if
+ * none is open, and `Commit` and `Rollback` end it.  `Compact` commits the open transaction,
+ * if any, before compacting.
+ */
+#[derive(Clone, Debug)]
+pub enum Operation {
+    AddRow(Vec<Datum>),
+    Update(Vec<Datum>, Datum),
+    Query(Criteria),
+    Flush,
+    Commit,
+    Rollback,
+    Compact
+}
+
+/**
+ * The first operation on which the database and the model disagreed, with what each returned.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub op_no: usize,
+    pub expected: Result<Vec<Vec<Datum>>, Error>,
+    pub actual: Result<Vec<Vec<Datum>>, Error>
+}
+
+/**
+ * Run the operations against the database and a model starting from its committed rows,
+ * checking that every query and every error matches.  The database's own rows are compared at
+ * the end too.  Errors from flushing, committing or compacting are returned as mismatches, as
+ * the model never fails them.
+ */
+pub fn check_differential(database: &mut Database, operations: &[Operation]) -> Result<(), Mismatch> {
+    let mut model = ModelDb::new(&database.schema);
+    for row in database.query_snapshot().query() {
+        model.add_row(&row.values_array).expect("committed row doesn't fit the schema");
+    }
+
+    let mut op_no = 0;
+    while op_no < operations.len() {
+        if let Operation::Compact = operations[op_no] {
+            database.compact().map_err(|err| Mismatch { op_no, expected: Ok(Vec::new()), actual: Err(err) })?;
+            op_no += 1;
+            continue;
+        }
+
+        let mut txn = database.new_transaction().unwrap();
+        model.begin();
+        loop {
+            let Some(operation) = operations.get(op_no) else {
+                txn.rollback();
+                model.rollback();
+                break;
+            };
+            let (expected, actual) = match operation {
+                Operation::AddRow(row) => (model.add_row(row).map(|_| Vec::new()), txn.add_row(row).map(|_| Vec::new())),
+                Operation::Update(coords, value) => (model.update(coords, 0, *value).map(|_| Vec::new()), txn.update(coords, 0, *value).map(|_| Vec::new())),
+                Operation::Query(criteria) => {
+                    let actual = txn.query_with(criteria).map(|scan| {
+                        let mut rows: Vec<Vec<Datum>> = scan.map(|row| row.values_array).collect();
+                        rows.sort();
+                        rows
+                    });
+                    (model.query(criteria), actual)
+                },
+                Operation::Flush => (Ok(Vec::new()), txn.flush().map(|_| Vec::new())),
+                Operation::Commit | Operation::Compact => {
+                    model.commit();
+                    let actual = txn.commit().map(|_| Vec::new());
+                    if actual.is_err() {
+                        return Err(Mismatch { op_no, expected: Ok(Vec::new()), actual });
+                    }
+                    if let Operation::Commit = operation {
+                        op_no += 1;
+                    }
+                    break;
+                },
+                Operation::Rollback => {
+                    model.rollback();
+                    txn.rollback();
+                    op_no += 1;
+                    break;
+                }
+            };
+            if expected != actual {
+                return Err(Mismatch { op_no, expected, actual });
+            }
+            op_no += 1;
+        }
+    }
+
+    let mut actual: Vec<Vec<Datum>> = database.query_snapshot().query().map(|row| row.values_array).collect();
+    actual.sort();
+    let expected = model.query(&Criteria::new());
+    if expected != Ok(actual.clone()) {
+        return Err(Mismatch { op_no, expected, actual: Ok(actual) });
+    }
+    Ok(())
+}
+
+/**
+ * A sequence of `count` random operations for a schema with one value, from a seed.  Points are
+ * drawn from a small space, so rows are often replaced, and a few rows are out of bounds or
+ * have the wrong number of entries.  Transactions are short, and a few are rolled back.
+ */
+pub fn random_operations(schema: &Schema, seed: u64, count: usize) -> Vec<Operation> {
+    let mut state = seed.wrapping_mul(0x9e3779b97f4a7c15) | 1;
+    let mut random = move |n: u64| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % n) as Datum
+    };
+
+    let num_dims = schema.dimensions.len();
+    let extent = |dim_no: usize| schema.dimensions[dim_no].chunk_size as u64 * 4;
+    (0..count).map(|_| match random(100) {
+        0..=59 => {
+            let mut row: Vec<Datum> = (0..num_dims).map(|dim_no| random(extent(dim_no))).collect();
+            row.extend((0..schema.values.len()).map(|_| random(1000)));
+            if random(50) == 0 {
+                row.pop();
+            }
+            Operation::AddRow(row)
+        },
+        60..=69 => {
+            let coords: Vec<Datum> = (0..num_dims).map(|dim_no| random(extent(dim_no))).collect();
+            Operation::Update(coords, random(1000))
+        },
+        70..=84 => {
+            let mut criteria = Criteria::new();
+            for dim_no in 0..num_dims {
+                if random(2) == 0 {
+                    let start = random(extent(dim_no));
+                    criteria = criteria.range(dim_no, start..=start + random(extent(dim_no)));
+                }
+            }
+            Operation::Query(criteria)
+        },
+        85..=89 => Operation::Flush,
+        90..=96 => Operation::Commit,
+        97..=98 => Operation::Rollback,
+        _ => Operation::Compact
+    }).collect()
+}
+
+#[cfg(test)]
+mod testing_tests {
+    use crate::{Criteria, Database, Error, MemoryBackend, Schema};
+    use super::{check_differential, random_operations, ModelDb, Operation};
+
+    fn schema() -> Schema {
+        Schema::builder().dimension("x", 4).dimension("y", 8).value("value").build().unwrap()
+    }
+
+    #[test]
+    fn model() {
+        let mut model = ModelDb::new(&schema());
+        model.begin();
+        model.add_row(&[1, 2, 10]).unwrap();
+        model.update(&[3, 4], 0, 30).unwrap();
+        assert!(matches!(model.add_row(&[1, 2]), Err(Error::ArityMismatch)));
+        assert_eq!(model.query(&Criteria::new().range(0, 2..=5)).unwrap(), vec![vec![3, 4, 30]]);
+        model.commit();
+        model.begin();
+        model.add_row(&[1, 2, 11]).unwrap();
+        model.rollback();
+        assert_eq!(model.query(&Criteria::new()).unwrap(), vec![vec![1, 2, 10], vec![3, 4, 30]]);
+        assert_eq!(model.len(), 2);
+    }
+
+    #[test]
+    fn differential() {
+        for seed in 0..20 {
+            let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+            let operations = random_operations(&database.schema, seed, 300);
+            if let Err(mismatch) = check_differential(&mut database, &operations) {
+                panic!("seed {seed}: mismatch at {:?}: {:?}", operations[mismatch.op_no], mismatch);
+            }
+        }
+    }
+
+    #[test]
+    fn existing_rows() {
+        let mut database = Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap();
+        let operations = vec![Operation::AddRow(vec![1, 1, 1]), Operation::Commit];
+        assert!(check_differential(&mut database, &operations).is_ok());
+        let operations = vec![Operation::Query(Criteria::new()), Operation::AddRow(vec![1, 1, 2]), Operation::Rollback];
+        assert!(check_differential(&mut database, &operations).is_ok());
+        assert_eq!(database.query_snapshot().query().map(|row| row[2]).collect::<Vec<_>>(), vec![1]);
+    }
+}
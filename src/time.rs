@@ -0,0 +1,298 @@
+use std::ops::{Range, RangeInclusive};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "chrono")]
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{Datum, Error};
+use crate::Error::{DataError, OutOfBounds, SchemaError};
+use crate::query::Criteria;
+use crate::schema::Schema;
+
+const SECONDS_PER_DAY: i64 = 86400;
+const NANOS_PER_SECOND: i128 = 1_000_000_000;
+/* 1970-01-01 was a Thursday */
+const EPOCH_WEEKDAY: i64 = 3;
+
+/**
+ * How the values of a time dimension map to times: each value counts units of `resolution`
+ * since `epoch`, which is in seconds since 1970-01-01 UTC.  Declaring it in the schema lets
+ * every application convert times the same way, with `Schema::time_to_datum` and friends.
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeScale {
+    pub epoch: i64,
+    pub resolution: TimeResolution
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeResolution {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Nanoseconds
+}
+
+impl TimeResolution {
+    fn nanos(self) -> i128 {
+        match self {
+            TimeResolution::Seconds => 1_000_000_000,
+            TimeResolution::Milliseconds => 1_000_000,
+            TimeResolution::Microseconds => 1_000,
+            TimeResolution::Nanoseconds => 1
+        }
+    }
+}
+
+impl TimeScale {
+    /**
+     * Convert a time in nanoseconds since 1970, rounding down to the resolution.  Times before
+     * the epoch, or too far after it, fail with `OutOfBounds`.
+     */
+    fn to_datum(self, unix_nanos: i128) -> Result<Datum, Error> {
+        let offset = unix_nanos - self.epoch as i128 * 1_000_000_000;
+        if offset < 0 {
+            return Err(OutOfBounds);
+        }
+        Datum::try_from(offset / self.resolution.nanos()).map_err(|_| OutOfBounds)
+    }
+
+    fn to_unix_nanos(self, value: Datum) -> i128 {
+        self.epoch as i128 * 1_000_000_000 + value as i128 * self.resolution.nanos()
+    }
+
+    /**
+     * The first value whose time is at or after a time in nanoseconds since 1970.
+     */
+    fn first_at_or_after(self, unix_nanos: i128) -> i128 {
+        let offset = unix_nanos - self.epoch as i128 * NANOS_PER_SECOND;
+        offset.div_euclid(self.resolution.nanos()) + (offset.rem_euclid(self.resolution.nanos()) != 0) as i128
+    }
+}
+
+/**
+ * Times of the week, such as weekday business hours, for `Schema::calendar_ranges`.  It
+ * selects the hours from `start` up to but not including `end` on each of the chosen days,
+ * with days numbered from Monday as 0 to Sunday as 6.  Hours are in UTC, unless a fixed offset
+ * from it is given; daylight saving time is not accounted for.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CalendarFilter {
+    days: [bool; 7],
+    hours: Range<u32>,
+    utc_offset: i64
+}
+
+impl CalendarFilter {
+    /**
+     * A filter selecting every hour of every day, to be narrowed down.
+     */
+    pub fn new() -> CalendarFilter {
+        CalendarFilter { days: [true; 7], hours: 0..24, utc_offset: 0 }
+    }
+
+    /**
+     * Select only the days in a range, such as `5..=6` for weekends.
+     */
+    pub fn days(mut self, days: RangeInclusive<u32>) -> CalendarFilter {
+        for (day_no, selected) in self.days.iter_mut().enumerate() {
+            *selected = days.contains(&(day_no as u32));
+        }
+        self
+    }
+
+    /**
+     * Select only Monday to Friday.
+     */
+    pub fn weekdays(self) -> CalendarFilter {
+        self.days(0..=4)
+    }
+
+    /**
+     * Select only the hours in a range, such as `9..17` for business hours.
+     */
+    pub fn hours(mut self, hours: Range<u32>) -> CalendarFilter {
+        self.hours = hours;
+        self
+    }
+
+    /**
+     * Take the days and hours as local times at a fixed offset from UTC, in seconds.
+     */
+    pub fn utc_offset(mut self, seconds: i64) -> CalendarFilter {
+        self.utc_offset = seconds;
+        self
+    }
+}
+
+impl Default for CalendarFilter {
+    fn default() -> Self {
+        CalendarFilter::new()
+    }
+}
+
+impl Schema {
+    /**
+     * The time scale of the first dimension, failing with `SchemaError` if it doesn't have one.
+     */
+    fn time_scale(&self) -> Result<TimeScale, Error> {
+        let Some(scale) = self.dimensions.first().and_then(|dim| dim.time) else {
+            error!("The first dimension has no time scale");
+            return Err(SchemaError);
+        };
+        Ok(scale)
+    }
+
+    /**
+     * The value of the first dimension for a time, using the time scale declared for it.
+     */
+    pub fn time_to_datum(&self, time: SystemTime) -> Result<Datum, Error> {
+        let unix_nanos = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_nanos() as i128,
+            Err(err) => -(err.duration().as_nanos() as i128)
+        };
+        self.time_scale()?.to_datum(unix_nanos)
+    }
+
+    /**
+     * The time for a value of the first dimension, using the time scale declared for it.
+     */
+    pub fn datum_to_time(&self, value: Datum) -> Result<SystemTime, Error> {
+        let unix_nanos = self.time_scale()?.to_unix_nanos(value);
+        let magnitude = Duration::from_nanos(u64::try_from(unix_nanos.unsigned_abs()).map_err(|_| OutOfBounds)?);
+        let time = if unix_nanos >= 0 { UNIX_EPOCH.checked_add(magnitude) } else { UNIX_EPOCH.checked_sub(magnitude) };
+        time.ok_or(OutOfBounds)
+    }
+
+    /**
+     * The ranges of values of the first dimension, within `range`, whose times are selected by
+     * a calendar filter, using the time scale declared for the dimension.  There is up to a
+     * range for each day the filter selects, so for long periods a coarser filter is cheaper.  Fails
+     * with `DataError` if the filter's hours aren't a non-empty range within the day.
+     */
+    pub fn calendar_ranges(&self, filter: &CalendarFilter, range: RangeInclusive<Datum>) -> Result<Vec<RangeInclusive<Datum>>, Error> {
+        let scale = self.time_scale()?;
+        if filter.hours.is_empty() || filter.hours.end > 24 {
+            error!("Calendar filter hours {:?} aren't within a day", filter.hours);
+            return Err(DataError);
+        }
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let local_seconds = |value: Datum| {
+            (scale.to_unix_nanos(value).div_euclid(NANOS_PER_SECOND) as i64).saturating_add(filter.utc_offset)
+        };
+        let first_day = local_seconds(*range.start()).div_euclid(SECONDS_PER_DAY);
+        let last_day = local_seconds(*range.end()).div_euclid(SECONDS_PER_DAY);
+
+        let mut ranges: Vec<RangeInclusive<Datum>> = Vec::new();
+        for day in first_day..=last_day {
+            if !filter.days[(day + EPOCH_WEEKDAY).rem_euclid(7) as usize] {
+                continue;
+            }
+            let day_start = (day * SECONDS_PER_DAY - filter.utc_offset) as i128 * NANOS_PER_SECOND;
+            let start = scale.first_at_or_after(day_start + filter.hours.start as i128 * 3600 * NANOS_PER_SECOND);
+            let end = scale.first_at_or_after(day_start + filter.hours.end as i128 * 3600 * NANOS_PER_SECOND) - 1;
+            let start = start.max(*range.start() as i128);
+            let end = end.min(*range.end() as i128);
+            match ranges.last_mut() {
+                _ if start > end => {},
+                Some(last) if *last.end() as i128 + 1 == start => *last = *last.start()..=end as Datum,
+                _ => ranges.push(start as Datum..=end as Datum)
+            }
+        }
+        Ok(ranges)
+    }
+
+    /**
+     * Criteria restricting the first dimension to the times within `range` selected by a
+     * calendar filter; see `calendar_ranges`.  Combine them with other criteria using
+     * `Criteria::and`.
+     */
+    pub fn calendar_criteria(&self, filter: &CalendarFilter, range: RangeInclusive<Datum>) -> Result<Criteria, Error> {
+        Ok(Criteria::new().ranges(0, self.calendar_ranges(filter, range)?))
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn datetime_to_datum(&self, time: DateTime<Utc>) -> Result<Datum, Error> {
+        let unix_nanos = time.timestamp() as i128 * 1_000_000_000 + time.timestamp_subsec_nanos() as i128;
+        self.time_scale()?.to_datum(unix_nanos)
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn datum_to_datetime(&self, value: Datum) -> Result<DateTime<Utc>, Error> {
+        let unix_nanos = self.time_scale()?.to_unix_nanos(value);
+        let secs = i64::try_from(unix_nanos.div_euclid(1_000_000_000)).map_err(|_| OutOfBounds)?;
+        let nanos = unix_nanos.rem_euclid(1_000_000_000) as u32;
+        DateTime::from_timestamp(secs, nanos).ok_or(OutOfBounds)
+    }
+}
+
+#[cfg(test)]
+mod time_tests {
+    use super::*;
+
+    fn schema() -> Schema {
+        Schema::builder()
+            .dimension("time", 3600).time(1_000_000_000, TimeResolution::Milliseconds)
+            .dimension("sensor_id", 10)
+            .value("value")
+            .build().unwrap()
+    }
+
+    #[test]
+    fn system_time() {
+        let schema = schema();
+        let time = UNIX_EPOCH + Duration::from_millis(1_000_000_000_123);
+        assert_eq!(schema.time_to_datum(time).unwrap(), 123);
+        assert_eq!(schema.datum_to_time(123).unwrap(), time);
+        assert!(matches!(schema.time_to_datum(UNIX_EPOCH), Err(OutOfBounds)));
+    }
+
+    #[test]
+    #[cfg(feature = "chrono")]
+    fn datetime() {
+        let schema = schema();
+        let time = DateTime::from_timestamp(1_000_000_005, 250_000_000).unwrap();
+        assert_eq!(schema.datetime_to_datum(time).unwrap(), 5250);
+        assert_eq!(schema.datum_to_datetime(5250).unwrap(), time);
+    }
+
+    #[test]
+    fn calendar_ranges() {
+        /* Seconds since 2024-01-01, a Monday */
+        let schema = Schema::builder()
+            .dimension("time", 3600).time(1_704_067_200, TimeResolution::Seconds)
+            .value("value")
+            .build().unwrap();
+        const DAY: Datum = 86400;
+        let business_hours = CalendarFilter::new().weekdays().hours(9..17);
+        let ranges = schema.calendar_ranges(&business_hours, 0..=14 * DAY - 1).unwrap();
+        assert_eq!(ranges.len(), 10);
+        assert_eq!(ranges[0], 9 * 3600..=17 * 3600 - 1);
+        assert_eq!(ranges[5], 7 * DAY + 9 * 3600..=7 * DAY + 17 * 3600 - 1);
+
+        let ranges = schema.calendar_ranges(&business_hours, 10 * 3600..=DAY + 10 * 3600).unwrap();
+        assert_eq!(ranges, vec![10 * 3600..=17 * 3600 - 1, DAY + 9 * 3600..=DAY + 10 * 3600]);
+
+        let weekends = CalendarFilter::new().days(5..=6).utc_offset(-3600);
+        assert_eq!(schema.calendar_ranges(&weekends, 0..=7 * DAY - 1).unwrap(), vec![0..=3599, 5 * DAY + 3600..=7 * DAY - 1]);
+
+        let criteria = schema.calendar_criteria(&CalendarFilter::new().hours(0..1), 0..=3 * DAY).unwrap();
+        assert_eq!(criteria.get_ranges(0).unwrap(), [0..=3599, DAY..=DAY + 3599, 2 * DAY..=2 * DAY + 3599, 3 * DAY..=3 * DAY]);
+        assert!(matches!(schema.calendar_ranges(&CalendarFilter::new().hours(9..25), 0..=DAY), Err(DataError)));
+    }
+
+    #[test]
+    fn no_time_scale() {
+        let schema = Schema::builder().dimension("x", 10).value("value").build().unwrap();
+        assert!(matches!(schema.time_to_datum(SystemTime::now()), Err(SchemaError)));
+        assert!(matches!(schema.datum_to_time(0), Err(SchemaError)));
+        #[cfg(feature = "chrono")]
+        assert!(matches!(schema.datum_to_datetime(0), Err(SchemaError)));
+    }
+}
@@ -0,0 +1,51 @@
+use crate::Datum;
+use crate::query::Criteria;
+use crate::scan::Version;
+use crate::segment::Segment;
+
+/**
+ * The criteria of rows deleted by `Transaction::delete_where`, each with the version that
+ * deleted them.  A tombstone hides the matching rows of older versions only, so rows written
+ * again by the same flush or later ones are kept.
+ */
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Tombstones {
+    entries: Vec<(Version, Criteria)>
+}
+
+impl Tombstones {
+    pub(crate) fn add(&mut self, version: Version, criteria: &Criteria) {
+        self.entries.push((version, criteria.clone()));
+    }
+
+    pub(crate) fn add_segment(&mut self, segment: &Segment) {
+        for criteria in &segment.tombstones {
+            self.add(segment.id, criteria);
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &(Version, Criteria)> {
+        self.entries.iter()
+    }
+
+    /**
+     * Whether a newer tombstone deletes the row at a point.
+     */
+    pub(crate) fn deletes(&self, point: &[Datum], version: Version) -> bool {
+        self.entries.iter().any(|(deleted_by, criteria)| *deleted_by > version && criteria.matches_point(point))
+    }
+
+    /**
+     * Whether a newer tombstone deletes every row of a block with the given bounds.
+     */
+    pub(crate) fn covers(&self, min_bounds: &[Datum], max_bounds: &[Datum], version: Version) -> bool {
+        self.entries.iter().any(|(deleted_by, criteria)| *deleted_by > version && criteria.covers_bounds(min_bounds, max_bounds))
+    }
+
+    /**
+     * Whether a newer tombstone may delete any row of a block with the given bounds.
+     */
+    pub(crate) fn overlaps(&self, min_bounds: &[Datum], max_bounds: &[Datum], version: Version) -> bool {
+        self.entries.iter().any(|(deleted_by, criteria)| *deleted_by > version && criteria.overlaps_bounds(min_bounds, max_bounds))
+    }
+}
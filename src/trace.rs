@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+use crate::backend::Backend;
+use crate::clock::{self, Stopwatch};
+use crate::query::Criteria;
+use crate::storage::SLOW_QUERY_LOG_FILENAME;
+
+/**
+ * A query that has finished, as recorded in the slow-query log and returned by
+ * `Database::slow_queries`.  `id` is the query's tracing id, from `Scan::query_id`, which also
+ * appears in log messages about it.  `time` is when it started, in seconds since 1970, and
+ * `duration_us` how long it ran, in microseconds, until its last row was read or it was dropped.
+ * `criteria` describes the criteria it was run with.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct QueryTrace {
+    pub id: u64,
+    pub time: u64,
+    pub duration_us: u64,
+    pub blocks_read: usize,
+    pub rows_returned: usize,
+    pub criteria: String
+}
+
+/**
+ * Measures a scan while it runs.
+ */
+pub(crate) struct QueryTimer {
+    pub id: u64,
+    time: u64,
+    started: Stopwatch,
+    pub blocks_read: usize,
+    pub rows_returned: usize,
+    finished: bool
+}
+
+impl QueryTimer {
+    pub(crate) fn start(id: u64) -> QueryTimer {
+        let time = clock::unix_seconds();
+        debug!("Starting query {id}");
+        QueryTimer { id, time, started: Stopwatch::start(), blocks_read: 0, rows_returned: 0, finished: false }
+    }
+
+    /**
+     * The trace of the query, the first time it finishes.
+     */
+    pub(crate) fn finish(&mut self, criteria: &Criteria) -> Option<QueryTrace> {
+        if self.finished {
+            return None;
+        }
+        self.finished = true;
+
+        let trace = QueryTrace {
+            id: self.id,
+            time: self.time,
+            duration_us: self.started.elapsed().as_micros() as u64,
+            blocks_read: self.blocks_read,
+            rows_returned: self.rows_returned,
+            criteria: format!("{criteria:?}")
+        };
+        debug!("Finished query {} in {} us, reading {} blocks and returning {} rows",
+            trace.id, trace.duration_us, trace.blocks_read, trace.rows_returned);
+        Some(trace)
+    }
+}
+
+/**
+ * Add a query to the slow-query log if it took at least the threshold.  The log has one JSON
+ * entry per line, and like the journal, is rewritten and renamed into place.  The query has
+ * already finished, so a failure is logged rather than returned.
+ */
+pub(crate) fn record_if_slow(backend: &dyn Backend, threshold: Option<Duration>, trace: QueryTrace) {
+    let Some(threshold) = threshold else { return };
+    if Duration::from_micros(trace.duration_us) < threshold {
+        return;
+    }
+
+    if let Err(err) = append(backend, &trace) {
+        error!("Failed to record query {} in the slow-query log: {:?}", trace.id, err);
+    }
+}
+
+/**
+ * The slow-query log, oldest first.
+ */
+pub(crate) fn load(backend: &dyn Backend) -> Result<Vec<QueryTrace>, Error> {
+    if !backend.exists(SLOW_QUERY_LOG_FILENAME) {
+        return Ok(Vec::new());
+    }
+    let data = backend.read(SLOW_QUERY_LOG_FILENAME)?;
+    let mut traces = Vec::new();
+    for line in data.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+        traces.push(serde_json::from_slice(line)?);
+    }
+    Ok(traces)
+}
+
+fn append(backend: &dyn Backend, trace: &QueryTrace) -> Result<(), Error> {
+    let mut data = if backend.exists(SLOW_QUERY_LOG_FILENAME) {
+        backend.read(SLOW_QUERY_LOG_FILENAME)?
+    } else {
+        Vec::new()
+    };
+    serde_json::to_writer(&mut data, trace)?;
+    data.push(b'\n');
+    let temp_filename = format!("{SLOW_QUERY_LOG_FILENAME}.tmp");
+    backend.write(&temp_filename, &data)?;
+    backend.rename(&temp_filename, SLOW_QUERY_LOG_FILENAME)?;
+    Ok(())
+}
@@ -1,44 +1,618 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::rc::Rc;
+use std::time::Duration;
 
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use serde::Serialize;
 
-use crate::{BlockKey, Datum, Error, SegmentNum, TransactionId};
+use crate::{BlockId, BlockKey, BlockNum, Datum, Error, SegmentId, SegmentNum, TransactionId};
 use crate::block::Block;
-use crate::database::Database;
-use crate::scan::Scan;
+use crate::checkpoint::Checkpoints;
+use crate::clock::Stopwatch;
+use crate::database::{Database, MemoryUsage};
+use crate::Error::{ArityMismatch, Conflict, DataError, SchemaError};
+use crate::cursor::{self, Cursor, Page};
+use crate::flush::{FlushSizer, FlushStats};
+use crate::pool::write_objects;
+use crate::prepared::PreparedQuery;
+use crate::query::{Criteria, QueryRow};
+use crate::scan::{Scan, ScanSource, UNSAVED_VERSION, Version};
+use crate::schema::ValueType;
 use crate::segment::Segment;
+use crate::storage::get_segment_name;
+use crate::tombstone::Tombstones;
+use crate::typed::{Field, TypedValue};
+
+/**
+ * What a row validator has decided should happen to a row.
+ */
+pub enum RowAction {
+    Insert,
+    Skip
+}
+
+/**
+ * A callback run on each row passed to `add_row`.  It can modify the row in place, decide to
+ * skip it, or reject it by returning an error.
+ */
+pub type RowValidator<'a> = Box<dyn FnMut(&mut [Datum]) -> Result<RowAction, Error> + 'a>;
+
+/**
+ * What a memory hook has decided should happen when rows are added.
+ */
+pub enum MemoryAction {
+    Continue,
+    Flush
+}
+
+/**
+ * A callback run as rows are added, with the bytes of memory the transaction's unsaved blocks
+ * take.  It can ask for them to be flushed, or stop the ingest by returning an error.  See
+ * `Transaction::set_memory_hook`.
+ */
+pub type MemoryHook<'a> = Box<dyn FnMut(usize) -> Result<MemoryAction, Error> + 'a>;
+
+/**
+ * The result of `Transaction::count`, and how it was found.  `blocks_counted` blocks were
+ * counted from the row counts in their segment info, without being loaded; `blocks_scanned`
+ * blocks had their rows checked one by one.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CountStats {
+    pub rows: usize,
+    pub blocks_counted: usize,
+    pub blocks_scanned: usize
+}
+
+impl CountStats {
+    /**
+     * Whether the count was answered from segment info alone, without scanning any rows.
+     */
+    pub fn metadata_only(&self) -> bool {
+        self.blocks_scanned == 0
+    }
+}
+
+/**
+ * What a transaction saved, from `Transaction::commit`.  `txn_id` is `None` for a transaction
+ * that wrote nothing, which is never given an id.  `rows` counts the rows in the blocks written,
+ * so a row added twice at the same point before a flush counts once, and `bytes` is the size of
+ * the segment files made visible.  `duration` is how long the commit took, including its final
+ * flush.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitSummary {
+    pub txn_id: Option<TransactionId>,
+    pub segments: usize,
+    pub rows: usize,
+    pub bytes: u64,
+    pub duration: Duration
+}
+
+/**
+ * How far `Transaction::commit_with_yield` has got.  While it is `Pending`, `blocks_remaining`
+ * blocks are still to be written; once it is `Ready`, `commit` only has to make the segments
+ * visible.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommitProgress {
+    Pending { blocks_written: usize, blocks_remaining: usize },
+    Ready
+}
+
+/**
+ * A segment a dry-run transaction would have written; see `Transaction::set_dry_run`.  `bytes`
+ * includes any blocks that would have been saved in the pool.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DryRunSegment {
+    pub blocks: usize,
+    pub rows: usize,
+    pub bytes: u64
+}
+
+/**
+ * A block overlapping the criteria of `Transaction::count`, which may not be loaded yet.
+ */
+struct CountedBlock {
+    min_bounds: Vec<Datum>,
+    max_bounds: Vec<Datum>,
+    num_rows: Option<usize>,
+    version: Version,
+    source: CountedSource
+}
+
+enum CountedSource {
+    Saved(BlockId),
+    Unsaved(Rc<Block>)
+}
 
 pub struct Transaction<'db> {
     pub(crate) id: Option<TransactionId>,
     pub(crate) horizon: TransactionId,
     pub(crate) database: &'db mut Database,
     pub(crate) unsaved_blocks: HashMap<BlockKey, Rc<Block>>,
-    pub(crate) uncommitted_segments: Vec<Rc<Segment>>
+    pub(crate) uncommitted_segments: Vec<Rc<Segment>>,
+    /* Criteria passed to `delete_where` since the last flush */
+    unsaved_tombstones: Vec<Criteria>,
+    late_segments: Vec<SegmentId>,
+    uncommitted_size: u64,
+    validator: Option<RowValidator<'db>>,
+    memory_hook: Option<MemoryHook<'db>>,
+    unsaved_memory: usize,
+    pending_checkpoints: BTreeMap<String, String>,
+    conditions: Vec<(Vec<Datum>, usize, Option<Datum>)>,
+    reads: RefCell<Vec<Criteria>>,
+    serializable: bool,
+    flush_sizer: FlushSizer,
+    /* Rows in the blocks written to segments so far, for the commit summary */
+    rows_written: usize,
+    dry_run: bool,
+    dry_run_segments: Vec<DryRunSegment>,
+    /* The chunks `commit_with_yield` has still to write, in scan order */
+    commit_queue: Vec<Vec<Datum>>,
+    blocks_yielded: usize
 }
 
 impl<'db> Transaction<'db> {
-    pub fn new(database: &'db mut Database, horizon: TransactionId) -> Transaction {
+    pub(crate) fn new(database: &'db mut Database, horizon: TransactionId) -> Transaction<'db> {
+        let flush_sizer = FlushSizer::new(database.auto_flush, database.write_amplification());
         Transaction {
             id: None,
             horizon,
             database,
             unsaved_blocks: Default::default(),
-            uncommitted_segments: Vec::new()
+            uncommitted_segments: Vec::new(),
+            unsaved_tombstones: Vec::new(),
+            late_segments: Vec::new(),
+            uncommitted_size: 0,
+            validator: None,
+            memory_hook: None,
+            unsaved_memory: 0,
+            pending_checkpoints: BTreeMap::new(),
+            conditions: Vec::new(),
+            reads: RefCell::new(Vec::new()),
+            serializable: false,
+            flush_sizer,
+            rows_written: 0,
+            dry_run: false,
+            dry_run_segments: Vec::new(),
+            commit_queue: Vec::new(),
+            blocks_yielded: 0
+        }
+    }
+
+    /**
+     * Set a validator to be run on every row subsequently passed to `add_row`, replacing any
+     * previous one.
+     */
+    pub fn set_validator<F>(&mut self, validator: F)
+    where F: FnMut(&mut [Datum]) -> Result<RowAction, Error> + 'db
+    {
+        self.validator = Some(Box::new(validator));
+    }
+
+    /**
+     * Set a hook to be run after each row is added, replacing any previous one, so an embedder
+     * can observe and limit the memory used by ingest.  It is passed the size of the unsaved
+     * blocks, which grow in steps as their vectors are reallocated, and can return
+     * `MemoryAction::Flush` to save them, or an error to stop the ingest: the row that was
+     * just added stays in the transaction, and the error is returned by `add_row`.  For
+     * `add_rows` the hook is run once before the batch is inserted, with the size the blocks
+     * would have with it, so an error leaves none of the batch in the transaction.  A hook can
+     * also consult the host's allocator, such as a `cap::Cap` global allocator, and flush when
+     * the process as a whole nears its limit.
+     */
+    pub fn set_memory_hook<F>(&mut self, hook: F)
+    where F: FnMut(usize) -> Result<MemoryAction, Error> + 'db
+    {
+        self.memory_hook = Some(Box::new(hook));
+    }
+
+    /**
+     * Also fail to commit if another transaction committed after this one began has written
+     * anywhere this one has queried, not just where it has written.  See `commit`.
+     */
+    pub fn set_serializable(&mut self, serializable: bool) {
+        self.serializable = serializable;
+    }
+
+    /**
+     * Make the transaction a dry run, which encodes its blocks into segments as usual when it
+     * flushes, but never writes them, so an ingest and the database's chunking can be tried on
+     * real data without changing anything.  The segments it would have written are listed by
+     * `dry_run_segments`, and `flush_stats` and the summary from `commit` count them as if they
+     * had been.  Flushed rows are discarded, so queries in the transaction no longer see them,
+     * and `commit` saves nothing, not even checkpoints or new labels.
+     */
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /**
+     * The segments a dry run would have written so far, in order.
+     */
+    pub fn dry_run_segments(&self) -> &[DryRunSegment] {
+        &self.dry_run_segments
+    }
+
+    /**
+     * Insert a row, replacing any existing row at the same point.
+     *
+     * If a validator has been set, it is run first and may modify, skip, or reject the row.
+     *
+     * Fails with `ArityMismatch` if the row doesn't have one entry for each dimension and value
+     * in the schema, or `OutOfBounds` if a dimension value lies outside the bounds declared in it.
+     */
+    pub fn add_row(&mut self, values: &[Datum]) -> Result<(), Error> {
+        if let Some(validator) = self.validator.as_mut() {
+            let mut row = values.to_vec();
+            return match validator(&mut row)? {
+                RowAction::Insert => self.insert_row(&row, &[]),
+                RowAction::Skip => Ok(())
+            };
+        }
+
+        self.insert_row(values, &[])
+    }
+
+    /**
+     * Insert a row given as strings for its label dimensions and typed values for its value
+     * columns, as in `add_row_named(&["north".into(), Field::Datum(1200), 21.5.into()])`.
+     * Strings not seen before are given new ids, which are saved when the transaction commits.
+     *
+     * Fails with `ArityMismatch` if the row doesn't have one entry for each dimension and value,
+     * or `SchemaError` if a string is given for a dimension without labels or for a value, or a
+     * typed value for a dimension or a value of another type.  Otherwise it fails as `add_row`
+     * does.
+     */
+    pub fn add_row_named(&mut self, fields: &[Field]) -> Result<(), Error> {
+        let schema = &self.database.schema;
+        let num_dims = schema.dimensions.len();
+        if fields.len() != num_dims + schema.values.len() {
+            error!("Row has {:?} fields but the schema has {:?} columns", fields.len(), num_dims + schema.values.len());
+            return Err(ArityMismatch);
         }
+
+        let mut values = Vec::with_capacity(fields.len());
+        for (col_no, field) in fields.iter().enumerate() {
+            let value = match field {
+                Field::Datum(datum) => *datum,
+                Field::Label(label) if col_no < num_dims && schema.dimensions[col_no].labels =>
+                    self.database.labels.get_or_add(col_no, label),
+                Field::Value(value) if col_no >= num_dims && schema.values[col_no - num_dims].value_type == value.value_type() =>
+                    value.to_datum(),
+                _ => {
+                    error!("Field {:?} of row doesn't suit column {:?}", field, col_no);
+                    return Err(SchemaError);
+                }
+            };
+            values.push(value);
+        }
+
+        self.add_row(&values)
+    }
+
+    /**
+     * A row with its labels and typed values resolved, including labels first used in this
+     * transaction.  See `Database::resolve_row`.
+     */
+    pub fn resolve_row(&self, row: &QueryRow) -> Vec<Field> {
+        self.database.resolve_row(row)
+    }
+
+    /**
+     * Insert a row from a struct, or anything else serializing to a map, whose field names are
+     * the names of the schema's dimensions and values.  Extra fields are ignored.  Label
+     * dimensions take strings, and typed values numbers or booleans of their type.
+     *
+     * Fails with `SchemaError` if a field is missing, or `DataError` if one is not a non-negative
+     * integer, a string for a label dimension, or a value of its column's type.
+     */
+    pub fn add_serialized<T: Serialize>(&mut self, row: &T) -> Result<(), Error> {
+        let serde_json::Value::Object(fields) = serde_json::to_value(row).map_err(|_| DataError)? else {
+            error!("Serialized row is not a map");
+            return Err(DataError);
+        };
+
+        let schema = &self.database.schema;
+        let names = schema.dimensions.iter().map(|d| &d.name)
+            .chain(schema.values.iter().map(|v| &v.name));
+        let mut values = Vec::with_capacity(schema.dimensions.len() + schema.values.len());
+        for (col_no, name) in names.enumerate() {
+            let Some(field) = fields.get(name) else {
+                let is_dimension = col_no < schema.dimensions.len();
+                match fields.keys().find(|old| self.database.renamed_to(old, is_dimension).as_ref() == Some(name)) {
+                    Some(old) => error!("Serialized row has no field {name:?}; field {old:?} has been renamed to it"),
+                    None => error!("Serialized row has no field {name:?}")
+                }
+                return Err(SchemaError);
+            };
+            let value = if col_no < schema.dimensions.len() {
+                match field.as_str() {
+                    Some(label) if schema.dimensions[col_no].labels => Some(self.database.labels.get_or_add(col_no, label)),
+                    _ => field.as_u64().map(|value| value as Datum)
+                }
+            } else {
+                match schema.values[col_no - schema.dimensions.len()].value_type {
+                    ValueType::U64 => field.as_u64().map(TypedValue::U64),
+                    ValueType::I64 => field.as_i64().map(TypedValue::I64),
+                    ValueType::F64 => field.as_f64().map(TypedValue::F64),
+                    ValueType::Bool => field.as_bool().map(TypedValue::Bool)
+                }.map(|value| value.to_datum())
+            };
+            let Some(value) = value else {
+                error!("Field {name:?} is not a value of its column's type: {field}");
+                return Err(DataError);
+            };
+            values.push(value);
+        }
+
+        self.add_row(&values)
+    }
+
+    /**
+     * Insert a batch of rows.  This is equivalent to calling `add_row` on each, but the rows are
+     * first grouped by chunk and sorted within each chunk, so they are inserted in the order that
+     * is cheapest for the blocks.  This makes a large difference for input in random order.
+     *
+     * The validator is run and bounds are checked on every row, and then the memory hook is run
+     * with the size the unsaved blocks would have with the whole batch, before any rows are
+     * inserted, so if any of them fails, none of the batch is.
+     */
+    pub fn add_rows<R: AsRef<[Datum]>>(&mut self, rows: &[R]) -> Result<(), Error> {
+        let mut batch: Vec<Vec<Datum>> = Vec::with_capacity(rows.len());
+        for row in rows {
+            let mut row = row.as_ref().to_vec();
+            if let Some(validator) = self.validator.as_mut() {
+                if let RowAction::Skip = validator(&mut row)? {
+                    continue;
+                }
+            }
+            self.database.schema.check_row(&row)?;
+            batch.push(row);
+        }
+
+        let schema = &self.database.schema;
+        let num_dims = schema.dimensions.len();
+        batch.sort_by_cached_key(|row| (schema.get_chunk_key(row).key_values, row[0..num_dims].to_vec()));
+
+        let row_size = num_dims * size_of::<Datum>() + schema.values.len() * size_of::<Option<Datum>>();
+        self.check_memory(self.unsaved_memory + batch.len() * row_size)?;
+        for row in &batch {
+            self.insert_checked_row(row, &[]);
+        }
+        self.auto_flush()?;
+        Ok(())
     }
 
-    pub fn add_row(&mut self, values: &[Datum]) {
+    /**
+     * Insert a whole chunk's worth of rows at once, given as a dense matrix, such as a rollup
+     * computed from an earlier query.  `dim_axes` has the coordinates along each dimension, in
+     * increasing order, all within one chunk.  `dense_values` has an array for each value
+     * column, with an entry for every combination of the coordinates, the last dimension
+     * varying fastest; a cell is left without a row where every column is `None`.  If the
+     * transaction has no unsaved rows in the chunk, the matrix becomes its block as it is;
+     * otherwise its rows are added to the block, replacing any at the same points.  The
+     * validator is not run.
+     *
+     * Fails with `ArityMismatch` if there isn't an axis for each dimension, an array for each
+     * value, or an entry in each array for every cell; `DataError` if an axis is empty or out of
+     * order, the axes span more than one chunk, or a cell has values in only some columns; and
+     * `OutOfBounds` if an axis lies outside the schema's bounds.
+     */
+    pub fn add_block(&mut self, dim_axes: &[Vec<Datum>], dense_values: &[Vec<Option<Datum>>]) -> Result<(), Error> {
+        let schema = &self.database.schema;
+        let num_dims = schema.dimensions.len();
+        if dim_axes.len() != num_dims || dense_values.len() != schema.values.len() {
+            error!("Block has {:?} axes and {:?} value columns, expected {:?} and {:?}",
+                dim_axes.len(), dense_values.len(), num_dims, schema.values.len());
+            return Err(ArityMismatch);
+        }
+        if dim_axes.iter().any(|axis| axis.is_empty() || !axis.windows(2).all(|pair| pair[0] < pair[1])) {
+            error!("Block axes must be non-empty and in increasing order");
+            return Err(DataError);
+        }
+        let num_cells: usize = dim_axes.iter().map(|axis| axis.len()).product();
+        if let Some(column) = dense_values.iter().find(|column| column.len() != num_cells) {
+            error!("Block value column has {:?} entries, expected {:?}", column.len(), num_cells);
+            return Err(ArityMismatch);
+        }
+        if (0..num_cells).any(|cell| dense_values.iter().any(|column| column[cell].is_some() != dense_values[0][cell].is_some())) {
+            error!("Block has cells with values in only some columns");
+            return Err(DataError);
+        }
+
+        /* The corners of the block, padded with values, are enough to check the chunk and bounds */
+        let mut min_point: Vec<Datum> = dim_axes.iter().map(|axis| axis[0]).collect();
+        let mut max_point: Vec<Datum> = dim_axes.iter().map(|axis| axis[axis.len() - 1]).collect();
+        min_point.resize(num_dims + schema.values.len(), 0);
+        max_point.resize(num_dims + schema.values.len(), 0);
+        schema.check_row(&min_point)?;
+        schema.check_row(&max_point)?;
+        let key = schema.get_chunk_key(&min_point);
+        if key != schema.get_chunk_key(&max_point) {
+            error!("Block {:?}..{:?} spans more than one chunk", &min_point[..num_dims], &max_point[..num_dims]);
+            return Err(DataError);
+        }
+
+        let block = Block::from_dense(dim_axes.to_vec(), dense_values);
+        if block.num_rows() == 0 {
+            return Ok(());
+        }
+        if self.unsaved_blocks.contains_key(&key) {
+            for row in Block::iter(&Rc::new(block)) {
+                self.insert_checked_row(&row, &[]);
+            }
+        } else {
+            let row_size = (num_dims + schema.values.len()) * size_of::<Datum>();
+            let logical_size = (block.num_rows() * row_size) as u64;
+            self.unsaved_memory += block.memory_size();
+            self.database.counters.logical_bytes += logical_size;
+            self.flush_sizer.add(logical_size);
+            self.unsaved_blocks.insert(key, Rc::new(block));
+        }
+        debug!("Added block of {:?} cells", num_cells);
+        self.check_memory(self.unsaved_memory)?;
+        self.auto_flush()
+    }
+
+    /**
+     * Set one value of the row at a point, without the caller having to read the row first.
+     * The new version replaces the existing row when queried, or inserts one if there is none.
+     * The validator is not run.
+     *
+     * With more than one value, the new version is partial, holding only value `value_no`: it
+     * takes the others from the existing row when queried, or is 0 in them if there is none.
+     * Nothing is read when the update is made, so the row isn't recorded as read by a
+     * serializable transaction.
+     *
+     * Fails with `ArityMismatch` if `coords` doesn't have one entry for each dimension,
+     * `SchemaError` if there is no value `value_no`, and `OutOfBounds` if the point lies outside
+     * the schema's bounds.
+     */
+    pub fn update(&mut self, coords: &[Datum], value_no: usize, new_value: Datum) -> Result<(), Error> {
+        let schema = &self.database.schema;
+        if coords.len() != schema.dimensions.len() {
+            error!("Point {:?} has {:?} dimensions, expected {:?}", coords, coords.len(), schema.dimensions.len());
+            return Err(ArityMismatch);
+        }
+        if value_no >= schema.values.len() {
+            error!("Can't update value {:?} at {:?}", value_no, coords);
+            return Err(SchemaError);
+        }
+
+        let num_dims = coords.len();
+        let num_values = schema.values.len();
+        let mut row = coords.to_vec();
+        row.resize(num_dims + num_values, 0);
+        row[num_dims + value_no] = new_value;
+        let missing: Vec<usize> = (0..num_values).filter(|&other_no| other_no != value_no).collect();
+        self.insert_row(&row, &missing)
+    }
+
+    /**
+     * Set value `value_no` at a point, as `update` does, but only if the latest committed value
+     * there is still `expected` when the transaction commits; `None` expects there to be no row.
+     * This is checked against everything committed by then, not just what was visible when
+     * the transaction began, so two writers can't both change the same value unawares.
+     *
+     * If any condition fails, `commit` fails with `Conflict` and the transaction is rolled
+     * back.  The caller can then read the new value in a fresh transaction and try again.
+     */
+    pub fn put_if(&mut self, coords: &[Datum], value_no: usize, expected: Option<Datum>, new_value: Datum) -> Result<(), Error> {
+        self.update(coords, value_no, new_value)?;
+        self.conditions.push((coords.to_vec(), value_no, expected));
+        Ok(())
+    }
+
+    /**
+     * Delete every row matching the criteria, such as the whole history of a sensor or a time
+     * range of bad readings.  The blocks holding the rows aren't rewritten: the criteria are
+     * saved as a tombstone in the info of the next segment flushed, hiding the matching rows
+     * of older transactions and earlier flushes, so this takes the same time however many rows
+     * it deletes.  Rows added after the delete, by this transaction or later ones, are kept.
+     * Unsaved rows matching the criteria are dropped straight away.
+     *
+     * Deleting counts as writing to every chunk overlapping the criteria when checking for
+     * conflicts at commit.
+     *
+     * Fails with `SchemaError` if the criteria refer to more dimensions than the schema has.
+     */
+    pub fn delete_where(&mut self, criteria: &Criteria) -> Result<(), Error> {
+        let num_dims = self.database.schema.dimensions.len();
+        if criteria.num_dims() > num_dims {
+            return Err(SchemaError);
+        }
+
+        for (key, block) in std::mem::take(&mut self.unsaved_blocks) {
+            if !criteria.overlaps_bounds(&block.get_min_bounds(), &block.get_max_bounds()) {
+                self.unsaved_blocks.insert(key, block);
+                continue;
+            }
+            self.unsaved_memory = self.unsaved_memory.saturating_sub(block.memory_size());
+            let mut kept = Block::new(num_dims);
+            for (row, missing) in Block::iter(&block).partial().filter(|(row, _)| !criteria.matches_point(row)) {
+                kept.add_partial_row(&row, &missing);
+            }
+            if kept.get_start_point().is_some() {
+                self.unsaved_memory += kept.memory_size();
+                self.unsaved_blocks.insert(key, Rc::new(kept));
+            }
+        }
+
+        self.unsaved_tombstones.push(criteria.clone());
+        debug!("Deleting rows matching {:?}", criteria);
+        Ok(())
+    }
+
+    /**
+     * Insert a row, without the values numbered in `missing` if it is a partial version.
+     */
+    fn insert_row(&mut self, values: &[Datum], missing: &[usize]) -> Result<(), Error> {
+        self.database.schema.check_row(values)?;
+        self.insert_checked_row(values, missing);
+        self.check_memory(self.unsaved_memory)?;
+        self.auto_flush()
+    }
+
+    fn insert_checked_row(&mut self, values: &[Datum], missing: &[usize]) {
         let key = self.database.schema.get_chunk_key(values);
         let block = self.unsaved_blocks.entry(key)
             .or_insert_with(|| Rc::new(Block::new(self.database.schema.dimensions.len())));
-        let block = block.as_ref();
-        let block = unsafe {
-            let const_ptr = block as *const Block;
-            let mut_ptr = const_ptr as *mut Block;
-            &mut *mut_ptr
-        };
-        block.add_row(values);
+        /* Unsaved blocks are only shared while a query is using them, which would be finished by now. */
+        let block = Rc::make_mut(block);
+        let size_before = block.memory_size();
+        block.add_partial_row(values, missing);
+        self.unsaved_memory = self.unsaved_memory + block.memory_size() - size_before;
+        self.database.counters.logical_bytes += size_of_val(values) as u64;
+        self.flush_sizer.add(size_of_val(values) as u64);
+    }
+
+    /**
+     * Run the memory hook, if there is one, with the given size of the unsaved blocks, flushing
+     * if it asks to.
+     */
+    fn check_memory(&mut self, size: usize) -> Result<(), Error> {
+        let Some(hook) = self.memory_hook.as_mut() else { return Ok(()) };
+        match hook(size)? {
+            MemoryAction::Continue => Ok(()),
+            MemoryAction::Flush => {
+                info!("Flushing {:?} bytes of unsaved blocks for the memory hook", self.unsaved_memory);
+                self.flush()
+            }
+        }
+    }
+
+    /**
+     * Flush if the unsaved rows would fill a segment of the database's automatic flush size.
+     * If the flush fails, the rows stay unsaved and the error is returned.
+     */
+    fn auto_flush(&mut self) -> Result<(), Error> {
+        if !self.flush_sizer.is_due() {
+            return Ok(());
+        }
+        info!("Flushing automatically with {:?} unsaved blocks", self.unsaved_blocks.len());
+        self.flush_sizer.record_auto_flush();
+        self.flush()
+    }
+
+    /**
+     * How the transaction's flushes have gone so far, including the compression ratio that
+     * sizes automatic flushes.
+     */
+    pub fn flush_stats(&self) -> FlushStats {
+        self.flush_sizer.stats()
+    }
+
+    /**
+     * Record a checkpoint for a source, to be saved when this transaction commits.  A loader
+     * can use this to remember how much of a source it has ingested, and see it again through
+     * `Database::checkpoints` the next time it runs.
+     */
+    pub fn set_checkpoint(&mut self, source: &str, position: &str) {
+        self.pending_checkpoints.insert(source.to_string(), position.to_string());
     }
 
     /**
@@ -49,25 +623,154 @@ impl<'db> Transaction<'db> {
      */
     pub fn rollback(mut self) {
         self.unsaved_blocks.clear();
+        self.unsaved_tombstones.clear();
+        self.unsaved_memory = 0;
         self.rollback_segments();
     }
 
     /**
      * Save all changes from this transaction, making them visible for future transactions.
      *
+     * If a transaction committed after this one began wrote to any of the same chunks, this
+     * fails with `Conflict` and rolls back, since one of the two would silently overwrite the
+     * other.  A serializable transaction also conflicts if the other wrote to any chunk
+     * overlapping the criteria of its queries.  Transactions that haven't written anything
+     * never conflict.
+     *
+     * Returns a summary of what was saved; see `CommitSummary`.  To avoid blocking for the
+     * whole of a large commit, see `commit_with_yield`.
+     *
      * Consumes the Transaction, because you can't use it for anything else after this.
      */
-    pub fn commit(mut self) -> Result<(), Error> {
+    pub fn commit(mut self) -> Result<CommitSummary, Error> {
+        let start = Stopwatch::start();
+        self.check_conditions()?;
+        self.check_conflicts()?;
         self.flush()?;
-        self.commit_segments()?;
+        if self.dry_run {
+            let bytes = self.dry_run_segments.iter().map(|segment| segment.bytes).sum();
+            info!("Finished dry run of transaction with id {:?}", self.id);
+            return Ok(CommitSummary {
+                txn_id: self.id, segments: self.dry_run_segments.len(), rows: self.rows_written, bytes, duration: start.elapsed()
+            });
+        }
+        /* Before the segments, so no committed row has a label that wasn't saved */
+        self.database.labels.save(self.database.backend.as_ref())?;
+        let segments = self.uncommitted_segments.len();
+        let checkpoints = self.stage_checkpoints()?;
+        let bytes = match self.commit_segments() {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                self.unstage_checkpoints(checkpoints.is_some());
+                return Err(err);
+            }
+        };
+        if !self.late_segments.is_empty() {
+            self.database.add_late_segments(&self.late_segments);
+        }
+        self.commit_checkpoints(checkpoints);
         info!("Committed transaction with id {:?}", self.id);
-        Ok(())
+        Ok(CommitSummary { txn_id: self.id, segments, rows: self.rows_written, bytes, duration: start.elapsed() })
     }
 
+    /**
+     * Do part of the work of committing: write at most `budget` of the unsaved blocks to
+     * segments, and return, so that a service on a single-threaded async runtime can yield
+     * between calls rather than block it for the whole of a large commit.  Call it until it
+     * returns `Ready`, then call `commit`, which is left only with making the segments visible.
+     * The first call checks for conflicts, as `commit` does, so a transaction that will fail
+     * does so before writing anything.
+     *
+     * Each call writes its blocks to segments of their own, so a transaction committed this way
+     * has more segments than one committed all at once.  Rows added between calls are saved as
+     * usual: in their chunk's turn if it is still to be written, or otherwise by `commit`.  The
+     * summary `commit` returns only times that last call.
+     */
+    pub fn commit_with_yield(&mut self, budget: usize) -> Result<CommitProgress, Error> {
+        if self.commit_queue.is_empty() {
+            if self.unsaved_blocks.is_empty() {
+                return Ok(CommitProgress::Ready);
+            }
+            if !self.dry_run {
+                self.database.check_writable()?;
+            }
+            self.check_conditions()?;
+            self.check_conflicts()?;
+
+            let schema = &self.database.schema;
+            let mut blocks: Vec<&Rc<Block>> = self.unsaved_blocks.values()
+                .filter(|block| block.get_start_point().is_some())
+                .collect();
+            blocks.sort_by_cached_key(|block| schema.block_order_key(block));
+            self.commit_queue = blocks.iter().map(|block| schema.get_chunk_origin(&block.get_min_bounds())).collect();
+            debug!("Committing {:?} blocks with yields", self.commit_queue.len());
+            if self.commit_queue.is_empty() {
+                return Ok(CommitProgress::Ready);
+            }
+        }
+
+        let num_chunks = budget.clamp(1, self.commit_queue.len());
+        let keys: Vec<BlockKey> = self.commit_queue[..num_chunks].iter()
+            .map(|origin| self.database.schema.get_chunk_key(origin))
+            .collect();
+        let blocks: Vec<Rc<Block>> = keys.iter().filter_map(|key| self.unsaved_blocks.get(key).cloned()).collect();
+        self.write_blocks(&blocks)?;
+
+        self.commit_queue.drain(..num_chunks);
+        for key in keys {
+            if let Some(block) = self.unsaved_blocks.remove(&key) {
+                self.unsaved_memory = self.unsaved_memory.saturating_sub(block.memory_size());
+            }
+        }
+        self.blocks_yielded += blocks.len();
+        if self.commit_queue.is_empty() {
+            return Ok(CommitProgress::Ready);
+        }
+        Ok(CommitProgress::Pending { blocks_written: self.blocks_yielded, blocks_remaining: self.commit_queue.len() })
+    }
+
+    /**
+     * Query all the rows.  The scan counts as open, but is exempt from the database's limit on
+     * open scans, so this never fails; `query_with` is subject to it.
+     */
     pub fn query(&'db self) -> Scan<'db> {
-        let source = self.database.get_scan_source();
+        let criteria = Criteria::new();
+        let scan = self.scan(self.database.counted_scan_source(), &criteria);
+        self.record_read(&criteria);
+        scan
+    }
+
+    /**
+     * Query only the rows matching the criteria.
+     *
+     * Fails with `SchemaError` if the criteria refer to more dimensions than the schema has, and
+     * `QuotaExceeded` if the database's limit on open scans has been reached.
+     */
+    pub fn query_with(&'db self, criteria: &Criteria) -> Result<Scan<'db>, Error> {
+        let scan = self.scan_with(criteria)?;
+        self.record_read(criteria);
+        Ok(scan)
+    }
+
+    /**
+     * A scan of the rows matching the criteria, as `query_with` returns, but borrowing the
+     * transaction only for as long as the scan lasts, and not recorded as a read.
+     */
+    fn scan_with(&self, criteria: &Criteria) -> Result<Scan<'_>, Error> {
+        if criteria.num_dims() > self.database.schema.dimensions.len() {
+            return Err(SchemaError);
+        }
+        Ok(self.scan(self.database.open_scan_source()?, criteria))
+    }
+
+    /**
+     * A scan of the rows matching the criteria, which must not refer to more dimensions than
+     * the schema has, reading committed data through the given source.
+     */
+    fn scan<'a>(&'a self, source: Box<dyn ScanSource + 'a>, criteria: &Criteria) -> Scan<'a> {
         let num_dims = self.database.schema.dimensions.len();
         let mut scan = Scan::new(source, num_dims, self.id.unwrap_or(0));
+        scan.set_criteria(criteria.clone());
         for seg_id in self.database.get_visible_committed_segments(self.horizon) {
             debug!("Add committed segment {:?}", seg_id);
             scan.add_segment_id(seg_id);
@@ -77,6 +780,7 @@ impl<'db> Transaction<'db> {
             scan.add_segment(rc.clone());
         }
 
+        scan.add_tombstones(&self.unsaved_tombstones());
         for block in self.unsaved_blocks.values() {
             debug!("Add unsaved block");
             scan.add_block(block.clone());
@@ -85,36 +789,472 @@ impl<'db> Transaction<'db> {
     }
 
     /**
-     * Create a new segment and save all remaining blocks to into.
+     * Fetch one page of up to `page_size` rows matching the criteria.  The first page is fetched
+     * without a cursor; each page returns a cursor for the next, until there are no more rows.
+     *
+     * A cursor can only be used with a transaction having the same horizon, such as one from
+     * `Database::resume_transaction`; otherwise this fails with `DataError`.
+     */
+    pub fn query_paged(&'db self, criteria: &Criteria, cursor: Option<&Cursor>, page_size: usize) -> Result<Page, Error> {
+        cursor::query_page(self, criteria, cursor, page_size)
+    }
+
+    /**
+     * Prepare a query that will be run repeatedly with different parameters, such as a sliding
+     * time range.  The blocks overlapping the criteria are found once here, so each execution
+     * only has to check their bounds against its parameters.
+     *
+     * Fails with `SchemaError` if the criteria refer to more dimensions than the schema has.
+     */
+    pub fn prepare(&'db self, criteria: &Criteria) -> Result<PreparedQuery<'db>, Error> {
+        PreparedQuery::new(self, criteria)
+    }
+
+    /**
+     * List the distinct values of a dimension among the rows matching the criteria, in
+     * ascending order.  For example, all sensor ids with readings in a time range.
+     *
+     * The values are taken from each block's list of dimension values where possible, so rows
+     * are only examined in blocks that straddle the edge of the criteria.
+     */
+    pub fn dimension_values(&'db self, dim_no: usize, criteria: &Criteria) -> Result<Vec<Datum>, Error> {
+        let num_dims = self.database.schema.dimensions.len();
+        if dim_no >= num_dims || criteria.num_dims() > num_dims {
+            return Err(SchemaError);
+        }
+
+        self.record_read(criteria);
+        let tombstones = self.tombstones()?;
+        let mut values = BTreeSet::new();
+        for (block, version) in self.matching_blocks(criteria)? {
+            if tombstones.overlaps(&block.get_min_bounds(), &block.get_max_bounds(), version) {
+                values.extend(Block::iter(&block)
+                    .filter(|row| criteria.matches_point(row) && !tombstones.deletes(row, version))
+                    .map(|row| row[dim_no]));
+            } else {
+                Block::add_distinct_values(&block, dim_no, criteria, &mut values);
+            }
+        }
+        Ok(values.into_iter().collect())
+    }
+
+    /**
+     * Find which chunks contain any data overlapping the criteria, returning the first point of
+     * each chunk in ascending order.  Only segment metadata is read, so this is a cheap way to
+     * find holes in the data, such as hours with no readings.
+     *
+     * Chunks are reported if any block in them overlaps the criteria, even if none of the rows
+     * in it actually match, or they have all been deleted by `delete_where` without a
+     * tombstone covering the whole block.
+     */
+    pub fn coverage(&'db self, criteria: &Criteria) -> Result<Vec<Vec<Datum>>, Error> {
+        let schema = &self.database.schema;
+        if criteria.num_dims() > schema.dimensions.len() {
+            return Err(SchemaError);
+        }
+
+        self.record_read(criteria);
+        let tombstones = self.tombstones()?;
+        let mut chunks = BTreeSet::new();
+        for segment in self.visible_segments()? {
+            chunks.extend(segment.block_info.iter()
+                .filter(|info| criteria.overlaps_bounds(&info.min_bounds, &info.max_bounds))
+                .filter(|info| !tombstones.covers(&info.min_bounds, &info.max_bounds, segment.id))
+                .map(|info| schema.get_chunk_origin(&info.min_bounds)));
+        }
+        for block in self.unsaved_blocks.values() {
+            let min_bounds = block.get_min_bounds();
+            if block.get_start_point().is_some() && criteria.overlaps_bounds(&min_bounds, &block.get_max_bounds()) {
+                chunks.insert(schema.get_chunk_origin(&min_bounds));
+            }
+        }
+        Ok(chunks.into_iter().collect())
+    }
+
+    /**
+     * Count the rows matching the criteria.  Blocks lying entirely within the criteria are
+     * counted from the row counts in their segment info, so only blocks straddling the edge of
+     * the criteria, blocks overlapping others in the same chunk (whose rows may replace each
+     * other), and blocks in segments too old to record counts have to be loaded.  The returned
+     * stats say whether any were.
+     *
+     * Fails with `SchemaError` if the criteria refer to more dimensions than the schema has.
+     */
+    pub fn count(&'db self, criteria: &Criteria) -> Result<CountStats, Error> {
+        let schema = &self.database.schema;
+        let num_dims = schema.dimensions.len();
+        if criteria.num_dims() > num_dims {
+            return Err(SchemaError);
+        }
+
+        self.record_read(criteria);
+        let tombstones = self.tombstones()?;
+        let mut chunks: BTreeMap<Vec<Datum>, Vec<CountedBlock>> = BTreeMap::new();
+        for segment in self.visible_segments()? {
+            for (block_num, info) in segment.block_info.iter().enumerate() {
+                if criteria.overlaps_bounds(&info.min_bounds, &info.max_bounds) {
+                    chunks.entry(schema.get_chunk_origin(&info.min_bounds)).or_default().push(CountedBlock {
+                        min_bounds: info.min_bounds.clone(),
+                        max_bounds: info.max_bounds.clone(),
+                        num_rows: info.counts.map(|(num_rows, _)| num_rows),
+                        version: segment.id,
+                        source: CountedSource::Saved((segment.id.0, segment.id.1, block_num as BlockNum))
+                    });
+                }
+            }
+        }
+        for block in self.unsaved_blocks.values() {
+            let min_bounds = block.get_min_bounds();
+            let max_bounds = block.get_max_bounds();
+            if block.get_start_point().is_some() && criteria.overlaps_bounds(&min_bounds, &max_bounds) {
+                chunks.entry(schema.get_chunk_origin(&min_bounds)).or_default().push(CountedBlock {
+                    min_bounds,
+                    max_bounds,
+                    num_rows: Some(block.num_rows()),
+                    version: UNSAVED_VERSION,
+                    source: CountedSource::Unsaved(block.clone())
+                });
+            }
+        }
+
+        let source = self.database.get_scan_source();
+        let load = |counted: &CountedBlock| match &counted.source {
+            CountedSource::Saved(block_id) => source.get_block(*block_id),
+            CountedSource::Unsaved(block) => Ok(block.clone())
+        };
+
+        let mut stats = CountStats::default();
+        for blocks in chunks.values() {
+            /* Rows in overlapping blocks may have the same key, so those are merged by key */
+            let mut keys = HashSet::new();
+            for (i, counted) in blocks.iter().enumerate() {
+                if tombstones.covers(&counted.min_bounds, &counted.max_bounds, counted.version) {
+                    stats.blocks_counted += 1;
+                    continue;
+                }
+                let overlapping = blocks.iter().enumerate().any(|(j, other)| i != j
+                    && bounds_intersect(&counted.min_bounds, &counted.max_bounds, &other.min_bounds, &other.max_bounds));
+                let deleted = tombstones.overlaps(&counted.min_bounds, &counted.max_bounds, counted.version);
+                match counted.num_rows {
+                    Some(num_rows) if !overlapping && !deleted && criteria.covers_bounds(&counted.min_bounds, &counted.max_bounds) => {
+                        stats.rows += num_rows;
+                        stats.blocks_counted += 1;
+                    },
+                    _ => {
+                        let block = load(counted)?;
+                        let rows = Block::iter(&block)
+                            .filter(|row| criteria.matches_point(row) && !tombstones.deletes(row, counted.version));
+                        if overlapping {
+                            keys.extend(rows.map(|row| row[0..num_dims].to_vec()));
+                        } else {
+                            stats.rows += rows.count();
+                        }
+                        stats.blocks_scanned += 1;
+                    }
+                }
+            }
+            stats.rows += keys.len();
+        }
+        Ok(stats)
+    }
+
+    /**
+     * Remember the criteria of a query, for detecting read-write conflicts at commit.
+     */
+    pub(crate) fn record_read(&self, criteria: &Criteria) {
+        self.reads.borrow_mut().push(criteria.clone());
+    }
+
+    /**
+     * All saved segments visible to this transaction, including its own uncommitted ones.
+     */
+    pub(crate) fn visible_segments(&'db self) -> Result<Vec<Rc<Segment>>, Error> {
+        let source = self.database.get_scan_source();
+        let mut segments = Vec::new();
+        for seg_id in self.database.get_visible_committed_segments(self.horizon) {
+            segments.push(source.get_segment(seg_id)?);
+        }
+        segments.extend(self.uncommitted_segments.iter().cloned());
+        Ok(segments)
+    }
+
+    /**
+     * The tombstones of every segment visible to this transaction, and of its unsaved deletes.
+     */
+    pub(crate) fn tombstones(&'db self) -> Result<Tombstones, Error> {
+        let mut tombstones = self.unsaved_tombstones();
+        for segment in self.visible_segments()? {
+            tombstones.add_segment(&segment);
+        }
+        Ok(tombstones)
+    }
+
+    fn unsaved_tombstones(&self) -> Tombstones {
+        let mut tombstones = Tombstones::default();
+        for criteria in &self.unsaved_tombstones {
+            tombstones.add(UNSAVED_VERSION, criteria);
+        }
+        tombstones
+    }
+
+    /**
+     * All blocks visible to this transaction whose bounds overlap the criteria.
+     */
+    fn matching_blocks(&'db self, criteria: &Criteria) -> Result<Vec<(Rc<Block>, Version)>, Error> {
+        let source = self.database.get_scan_source();
+        let mut blocks = Vec::new();
+        for segment in self.visible_segments()? {
+            for (block_num, block_info) in segment.block_info.iter().enumerate() {
+                if criteria.overlaps_bounds(&block_info.min_bounds, &block_info.max_bounds) {
+                    let block_id = (segment.id.0, segment.id.1, block_num as BlockNum);
+                    blocks.push((source.get_block(block_id)?, segment.id));
+                }
+            }
+        }
+
+        blocks.extend(self.unsaved_blocks.values()
+            .filter(|b| criteria.overlaps_bounds(&b.get_min_bounds(), &b.get_max_bounds()))
+            .map(|b| (b.clone(), UNSAVED_VERSION)));
+        Ok(blocks)
+    }
+
+    /**
+     * Roughly how much memory the database and this transaction's unsaved blocks are using.
+     */
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            unsaved_blocks: self.unsaved_memory,
+            ..self.database.memory_usage()
+        }
+    }
+
+    /**
+     * Create a new segment and save all remaining blocks to into.  In late data mode, blocks
+     * holding only late data are saved to a second segment; see `Database::set_late_data`.
+     *
+     * If the same point is written again after a flush, the last write wins: segments are
+     * numbered in the order they are written, and a row in a later segment replaces one at the
+     * same point in an earlier segment of the same transaction.  The segments written by one
+     * flush never share a point, since each chunk's rows are in a single block.
      */
     pub fn flush(&mut self) -> Result<(), Error> {
-        if self.unsaved_blocks.is_empty() { return Ok(()); }
+        if self.unsaved_blocks.is_empty() && self.unsaved_tombstones.is_empty() { return Ok(()); }
+        if !self.dry_run {
+            self.database.check_writable()?;
+        }
 
-        let txn_id= self.get_transaction_id();
-        let seg_num = self.uncommitted_segments.len() as SegmentNum;
-
-        /* Create a new segment and save all remaining blocks to into. */
-        let moved_blocks = std::mem::take(&mut self.unsaved_blocks);
-        let blocks: Vec<&Rc<Block>> = moved_blocks.values().collect();
-        let mut block_refs = Vec::new();
-        for rc in blocks {
-            let br = unsafe {
-                let x = rc.as_ref() as *const Block;
-                let y = x as *mut Block;
-                &*y
-            };
-            block_refs.push(br);
+        let blocks: Vec<Rc<Block>> = self.unsaved_blocks.values().cloned().collect();
+        self.write_blocks(&blocks)?;
+        self.unsaved_blocks.clear();
+        self.unsaved_memory = 0;
+        self.commit_queue.clear();
+        Ok(())
+    }
+
+    /**
+     * Save some of the unsaved blocks to new segments, along with any unsaved tombstones, as
+     * `flush` does for all of them.  The blocks are kept if they can't be written.
+     */
+    fn write_blocks(&mut self, blocks: &[Rc<Block>]) -> Result<(), Error> {
+        let mut blocks: Vec<Rc<Block>> = match self.database.max_block_size {
+            Some(max_size) => blocks.iter().flat_map(|rc| Block::split(rc, max_size)).collect(),
+            None => blocks.to_vec()
+        };
+
+        /* Save the blocks in scan order, so a scan reads each segment file from start to end */
+        let schema = &self.database.schema;
+        blocks.sort_by_cached_key(|block| schema.block_order_key(block));
+        let num_blocks = blocks.len();
+        let num_rows: usize = blocks.iter().map(|block| block.num_rows()).sum();
+        let size_before = self.uncommitted_size;
+        let (late_blocks, recent_blocks): (Vec<Rc<Block>>, Vec<Rc<Block>>) = match self.database.late_data_cutoff() {
+            Some((dim_no, cutoff)) => blocks.into_iter().partition(|block| block.get_max_bounds()[dim_no] < cutoff),
+            None => (Vec::new(), blocks)
+        };
+
+        /*
+         * Keep the blocks if they can't be written, so the transaction can still be rolled back
+         * or retried.  A retry after the late segment fails writes the recent blocks again, to a
+         * later segment whose identical rows replace the earlier ones.
+         */
+        if !recent_blocks.is_empty() || !self.unsaved_tombstones.is_empty() {
+            self.write_segments(&recent_blocks)?;
+        }
+        if !late_blocks.is_empty() {
+            let seg_ids = self.write_segments(&late_blocks)?;
+            debug!("Saved {:?} late blocks in segments {:?}", late_blocks.len(), seg_ids);
+            self.late_segments.extend(seg_ids);
+        }
+        self.rows_written += num_rows;
+        self.flush_sizer.record_flush(self.uncommitted_size - size_before, num_blocks);
+        Ok(())
+    }
+
+    /**
+     * Save blocks to new uncommitted segments: usually one, but if its file would be larger
+     * than the database's maximum segment size, the blocks are split over as many as needed.
+     * The split is estimated from the sizes of the blocks in the oversized file, and any
+     * group that still encodes too large is split again.
+     */
+    fn write_segments(&mut self, blocks: &[Rc<Block>]) -> Result<Vec<SegmentId>, Error> {
+        let (new_segment, data) = self.encode_segment(blocks)?;
+        match self.database.max_segment_size {
+            Some(max_size) if data.len() as u64 > max_size && blocks.len() > 1 => {
+                let mut groups = new_segment.group_blocks(&data, max_size)?;
+                if groups.len() == 1 {
+                    let middle = blocks.len() / 2;
+                    groups = vec![0..middle, middle..blocks.len()];
+                }
+                debug!("Splitting {:?} bytes of blocks over {:?} segments", data.len(), groups.len());
+                let mut seg_ids = Vec::new();
+                for group in groups {
+                    seg_ids.extend(self.write_segments(&blocks[group])?);
+                }
+                Ok(seg_ids)
+            },
+            _ if self.dry_run => Ok(vec![self.record_dry_run_segment(new_segment, data, blocks)?]),
+            _ => Ok(vec![self.write_segment(new_segment, data)?])
         }
+    }
+
+    /**
+     * Account for an encoded segment as `write_segment` would, without writing anything.
+     */
+    fn record_dry_run_segment(&mut self, new_segment: Segment, data: Vec<u8>, blocks: &[Rc<Block>]) -> Result<SegmentId, Error> {
+        let pooled_size: u64 = new_segment.pool_objects.iter().map(|object| object.data.len() as u64).sum();
+        let bytes = data.len() as u64 + pooled_size;
+        self.database.check_quota(self.uncommitted_size, bytes)?;
+        self.uncommitted_size += data.len() as u64;
+        self.unsaved_tombstones.clear();
+        let rows = blocks.iter().map(|block| block.num_rows()).sum();
+        debug!("Dry run of segment {:?} with {:?} blocks, {:?} rows and {:?} bytes", new_segment.id, blocks.len(), rows, bytes);
+        self.dry_run_segments.push(DryRunSegment { blocks: blocks.len(), rows, bytes });
+        Ok(new_segment.id)
+    }
+
+    /**
+     * Encode blocks, which `flush` has put in scan order, as the next uncommitted segment.
+     */
+    fn encode_segment(&mut self, blocks: &[Rc<Block>]) -> Result<(Segment, Vec<u8>), Error> {
+        let txn_id= self.get_transaction_id();
+        let Ok(seg_num) = SegmentNum::try_from(self.uncommitted_segments.len() + self.dry_run_segments.len()) else {
+            error!("Transaction has too many segments to number another");
+            return Err(DataError);
+        };
 
         let seg_id = (txn_id, seg_num);
-        let new_segment = Segment::create(
-            self.database.path.as_path(),
-            seg_id, &block_refs
-        )?;
+        let mut new_segment = self.database.new_segment(seg_id);
+        new_segment.ordered = true;
+        new_segment.tombstones = self.unsaved_tombstones.clone();
+        let block_refs: Vec<&Block> = blocks.iter().map(|rc| rc.as_ref()).collect();
+        let data = new_segment.encode(&block_refs)?;
+        Ok((new_segment, data))
+    }
 
+    /**
+     * Save an encoded segment to a new uncommitted segment file.
+     */
+    fn write_segment(&mut self, mut new_segment: Segment, data: Vec<u8>) -> Result<SegmentId, Error> {
+        let seg_id = new_segment.id;
+
+        /*
+         * Pool objects are shared and may already exist, so they are saved outside the
+         * transaction, and left for `compact` to remove if it doesn't commit.
+         */
+        let pool_objects = std::mem::take(&mut new_segment.pool_objects);
+        let pooled_size: u64 = pool_objects.iter().map(|object| object.data.len() as u64).sum();
+        self.database.check_quota(self.uncommitted_size, data.len() as u64 + pooled_size)?;
+        let pooled_size = write_objects(self.database.backend.as_ref(), &pool_objects)?;
+        self.database.add_pooled_size(pooled_size);
+        self.database.counters.bytes_written += pooled_size;
+
+        if let Err(err) = self.database.backend.write(&new_segment.name, &data) {
+            error!("Failed to write segment file {:?}: {:?}", new_segment.name, err);
+            self.database.remove_partial_file(&new_segment.name);
+            return Err(err);
+        }
+        debug!("Wrote segment file {:?}", new_segment.name);
+        self.uncommitted_size += data.len() as u64;
+        self.database.counters.bytes_written += data.len() as u64;
+        self.database.counters.segments_created += 1;
+
+        /* Only the first segment of a flush has the tombstones, so they never hide its rows */
+        self.unsaved_tombstones.clear();
         let rc = Rc::new(new_segment);
         self.uncommitted_segments.push(rc);
         //TODO tell database to cache the segment for us
+        Ok(seg_id)
+    }
+
+    /**
+     * Check the conditions from `put_if` against the latest committed data, including any
+     * transactions committed after this one's horizon.
+     */
+    fn check_conditions(&self) -> Result<(), Error> {
+        let num_dims = self.database.schema.dimensions.len();
+        for (coords, value_no, expected) in &self.conditions {
+            let criteria = coords.iter().enumerate()
+                .fold(Criteria::new(), |criteria, (dim_no, &value)| criteria.range(dim_no, value..=value));
+            let mut scan = Scan::new(self.database.get_scan_source(), num_dims, 0);
+            scan.set_criteria(criteria);
+            for seg_id in self.database.get_visible_committed_segments(self.database.next_transaction_id) {
+                scan.add_segment_id(seg_id);
+            }
+
+            let current = scan.next().map(|row| row[num_dims + value_no]);
+            if current != *expected {
+                error!("Conflict at {:?}: expected {:?}, found {:?}", coords, expected, current);
+                return Err(Conflict);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Check whether any transaction committed since this one's horizon wrote to the chunks
+     * this one has written or deleted from, or deleted from the chunks it has written, or for
+     * a serializable transaction, wrote to any it has read.
+     */
+    fn check_conflicts(&self) -> Result<(), Error> {
+        let schema = &self.database.schema;
+        let mut written: BTreeSet<Vec<Datum>> = self.unsaved_blocks.values()
+            .filter(|block| block.get_start_point().is_some())
+            .map(|block| schema.get_chunk_origin(&block.get_min_bounds()))
+            .collect();
+        let mut deleted: Vec<&Criteria> = self.unsaved_tombstones.iter().collect();
+        for segment in &self.uncommitted_segments {
+            written.extend(segment.block_info.iter().map(|info| schema.get_chunk_origin(&info.min_bounds)));
+            deleted.extend(&segment.tombstones);
+        }
+        if written.is_empty() && deleted.is_empty() {
+            return Ok(());
+        }
+
+        let source = self.database.get_scan_source();
+        let reads = self.reads.borrow();
+        for &seg_id in self.database.committed_segments.iter().filter(|seg| seg.0 >= self.horizon) {
+            let segment = source.get_segment(seg_id)?;
+            for info in &segment.block_info {
+                let chunk = schema.get_chunk_origin(&info.min_bounds);
+                if written.contains(&chunk) || deleted.iter().any(|criteria| criteria.overlaps_bounds(&info.min_bounds, &info.max_bounds)) {
+                    error!("Write-write conflict with transaction {:?} in chunk {:?}", seg_id.0, chunk);
+                    return Err(Conflict);
+                }
+                if self.serializable && reads.iter().any(|criteria| criteria.overlaps_bounds(&info.min_bounds, &info.max_bounds)) {
+                    error!("Read-write conflict with transaction {:?} in chunk {:?}", seg_id.0, chunk);
+                    return Err(Conflict);
+                }
+            }
+            for chunk in &written {
+                let chunk_end: Vec<Datum> = chunk.iter().zip(&schema.dimensions)
+                    .map(|(&start, dim)| start.saturating_add(dim.chunk_size - 1))
+                    .collect();
+                if segment.tombstones.iter().any(|criteria| criteria.overlaps_bounds(chunk, &chunk_end)) {
+                    error!("Write-delete conflict with transaction {:?} in chunk {:?}", seg_id.0, chunk);
+                    return Err(Conflict);
+                }
+            }
+        }
         Ok(())
     }
 
@@ -122,17 +1262,99 @@ impl<'db> Transaction<'db> {
      * Rename the segment files so they're visible to other transactions.
      *
      * We do this in reverse order: the database won't see the transaction
-     * until segment 1 is visible.
+     * until segment 0 is visible.  If a rename fails after others have succeeded, the
+     * transaction is partly visible on disk, so the database becomes read-only.
+     *
+     * Returns the total size of the segment files made visible.
      */
-    fn commit_segments(&mut self) -> Result<(), Error>{
-        while !self.uncommitted_segments.is_empty() {
-            let mut rc = self.uncommitted_segments.pop().unwrap();
-            let segment = Rc::get_mut(&mut rc).unwrap();
-            segment.make_visible(&self.database.path)?;
-            self.database.add_committed_segment(segment.id);
-            debug!("Made segment visible {:?}", segment.path);
+    fn commit_segments(&mut self) -> Result<u64, Error>{
+        let mut made_visible = Vec::new();
+        while let Some(rc) = self.uncommitted_segments.pop() {
+            /* The segment itself is discarded after this, so only its file needs renaming. */
+            let backend = self.database.backend.as_ref();
+            let visible_name = get_segment_name(rc.id, true);
+            let result = backend.size(&rc.name)
+                .and_then(|size| backend.rename(&rc.name, &visible_name).map(|_| size));
+            match result {
+                Ok(size) => {
+                    debug!("Made segment visible {:?}", visible_name);
+                    made_visible.push((rc.id, size));
+                },
+                Err(err) => {
+                    error!("Failed to make segment {:?} visible: {:?}", rc.name, err);
+                    /* Put it back so it's deleted with the rest when the transaction is dropped. */
+                    self.uncommitted_segments.push(rc);
+                    if !made_visible.is_empty() {
+                        self.database.set_read_only();
+                    }
+                    return Err(err);
+                }
+            }
         }
-        Ok(())
+
+        let mut total_size = 0;
+        for (seg_id, size) in made_visible {
+            /* Any copy cached by a query in this transaction refers to the old filename. */
+            self.database.cached_segments.borrow_mut().evict(&seg_id);
+            self.database.add_committed_segment(seg_id, size);
+            total_size += size;
+        }
+        Ok(total_size)
+    }
+
+    /**
+     * Save any checkpoints set in this transaction, before its segments are made visible, and
+     * return them to be put in effect once they are.
+     *
+     * If the transaction has segments, the checkpoints are staged along with the ones they
+     * replace, and take effect on opening the database only if the transaction's first segment
+     * is visible; so a crash leaves the checkpoints consistent with the committed rows.  A
+     * transaction without segments has nothing to be consistent with, and saves them outright.
+     */
+    fn stage_checkpoints(&mut self) -> Result<Option<Checkpoints>, Error> {
+        if self.pending_checkpoints.is_empty() { return Ok(None); }
+
+        let mut checkpoints = self.database.checkpoints.clone();
+        for (source, position) in std::mem::take(&mut self.pending_checkpoints) {
+            checkpoints.set(source, position);
+        }
+        let backend = self.database.backend.as_ref();
+        match self.id {
+            /* Only a transaction that has written segments has an id */
+            Some(txn_id) if !self.uncommitted_segments.is_empty() =>
+                checkpoints.save_staged(backend, txn_id, &self.database.checkpoints)?,
+            _ => checkpoints.save(backend)?
+        }
+        debug!("Saved checkpoints");
+        Ok(Some(checkpoints))
+    }
+
+    /**
+     * Restore the checkpoints file after the segments failed to be made visible.  If that fails
+     * too, the database is made read-only: the staged checkpoints would take effect wrongly if
+     * a later transaction committed.
+     */
+    fn unstage_checkpoints(&mut self, staged: bool) {
+        if !staged { return; }
+
+        if let Err(err) = self.database.checkpoints.save(self.database.backend.as_ref()) {
+            error!("Failed to restore checkpoints: {:?}", err);
+            self.database.set_read_only();
+        }
+    }
+
+    /**
+     * Put the checkpoints saved by `stage_checkpoints` in effect, now that the transaction has
+     * committed, and save them as no longer staged.  That last part is only tidying: the staged
+     * ones are taken as in effect when the database is opened.
+     */
+    fn commit_checkpoints(&mut self, checkpoints: Option<Checkpoints>) {
+        let Some(checkpoints) = checkpoints else { return; };
+
+        if let Err(err) = checkpoints.save(self.database.backend.as_ref()) {
+            warn!("Failed to save committed checkpoints: {:?}", err);
+        }
+        self.database.checkpoints = checkpoints;
     }
 
     /**
@@ -140,18 +1362,21 @@ impl<'db> Transaction<'db> {
      */
     fn rollback_segments(&mut self) {
         let moved_segments = std::mem::take(&mut self.uncommitted_segments);
-        for mut rc in moved_segments {
-            let segment = Rc::get_mut(&mut rc).unwrap();
-            let path = segment.path.clone();
-            segment.delete().unwrap();
-            debug!("Deleted cancelled segment {:?}", path);
+        for rc in moved_segments {
+            if let Err(err) = rc.delete(self.database.backend.as_ref()) {
+                error!("Failed to delete cancelled segment {:?}: {:?}", rc.name, err);
+                self.database.set_read_only();
+                continue;
+            }
+            debug!("Deleted cancelled segment {:?}", rc.name);
+            self.database.counters.segments_deleted += 1;
             //TODO tell database to stop caching the segment
         }
     }
 
     fn get_transaction_id(&mut self) -> TransactionId {
-        if self.id.is_some() {
-            self.id.unwrap()
+        if let Some(id) = self.id {
+            id
         } else {
             let id = self.database.get_next_transaction_id();
             self.id = Some(id);
@@ -166,3 +1391,570 @@ impl<'db> Drop for Transaction<'db> {
         self.rollback_segments();
     }
 }
+
+/**
+ * Whether two boxes of points have any point in common.
+ */
+fn bounds_intersect(min1: &[Datum], max1: &[Datum], min2: &[Datum], max2: &[Datum]) -> bool {
+    (0..min1.len()).all(|dim_no| min1[dim_no] <= max2[dim_no] && min2[dim_no] <= max1[dim_no])
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod transaction_tests {
+    use crate::{Aggregate, AggregateResult, CommitProgress, Criteria, Database, Error, MemoryAction, RowAction, Transaction};
+    use crate::test_util::create_database;
+
+    #[test]
+    fn validator() {
+        let mut database = create_database("transaction-validator");
+        let mut txn = database.new_transaction().unwrap();
+        txn.set_validator(|row| {
+            match row[2] {
+                0 => Ok(RowAction::Skip),
+                v if v > 1000 => Err(Error::DataError),
+                _ => {
+                    row[2] *= 10;
+                    Ok(RowAction::Insert)
+                }
+            }
+        });
+
+        txn.add_row(&[1, 1, 5]).unwrap();
+        txn.add_row(&[2, 2, 0]).unwrap();
+        assert!(txn.add_row(&[3, 3, 5000]).is_err());
+
+        let rows: Vec<_> = txn.query().map(|r| r.values_array).collect();
+        assert_eq!(rows, vec![vec![1, 1, 50]]);
+    }
+
+    #[test]
+    fn commit_summary() {
+        let mut database = create_database("transaction-commit-summary");
+        let txn = database.new_transaction().unwrap();
+        let summary = txn.commit().unwrap();
+        assert_eq!((summary.txn_id, summary.segments, summary.rows, summary.bytes), (None, 0, 0, 0));
+
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..20 {
+            txn.add_row(&[i, i, i]).unwrap();
+        }
+        txn.flush().unwrap();
+        txn.add_row(&[30, 30, 1]).unwrap();
+        txn.add_row(&[30, 30, 2]).unwrap();
+        let summary = txn.commit().unwrap();
+        assert!(summary.txn_id.is_some());
+        assert_eq!(summary.segments, 2);
+        assert_eq!(summary.rows, 21);
+        assert!(summary.bytes > 0);
+        assert_eq!(summary.bytes, database.stats().size);
+    }
+
+    #[test]
+    fn dry_run() {
+        let mut database = create_database("transaction-dry-run");
+        let files_before = std::fs::read_dir(&database.path).unwrap().count();
+        let mut txn = database.new_transaction().unwrap();
+        txn.set_dry_run(true);
+        for i in 0..50 {
+            txn.add_row(&[i, i % 5, i]).unwrap();
+        }
+        txn.flush().unwrap();
+        assert_eq!(txn.dry_run_segments().len(), 1);
+        assert_eq!(txn.dry_run_segments()[0].rows, 50);
+        assert_eq!(txn.dry_run_segments()[0].blocks, 5);
+        assert_eq!(txn.query().count(), 0);
+
+        txn.add_row(&[100, 0, 1]).unwrap();
+        txn.set_checkpoint("input", "50");
+        let summary = txn.commit().unwrap();
+        assert_eq!((summary.segments, summary.rows), (2, 51));
+        assert!(summary.bytes > 0);
+
+        assert_eq!(std::fs::read_dir(&database.path).unwrap().count(), files_before);
+        assert_eq!(database.query_snapshot().query().count(), 0);
+        assert!(database.checkpoints().get("input").is_none());
+    }
+
+    #[test]
+    fn commit_with_yield() {
+        let mut database = create_database("transaction-commit-with-yield");
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..30 {
+            txn.add_row(&[i, 0, i]).unwrap();
+        }
+        assert_eq!(txn.commit_with_yield(2).unwrap(), CommitProgress::Pending { blocks_written: 2, blocks_remaining: 1 });
+        assert_eq!(txn.uncommitted_segments.len(), 1);
+
+        /* Rows added meanwhile are saved with their chunk, or by the commit */
+        txn.add_row(&[25, 0, 99]).unwrap();
+        txn.add_row(&[45, 0, 45]).unwrap();
+        assert_eq!(txn.query().count(), 31);
+        assert_eq!(txn.commit_with_yield(2).unwrap(), CommitProgress::Ready);
+        let summary = txn.commit().unwrap();
+        assert_eq!((summary.segments, summary.rows), (3, 31));
+
+        let rows: Vec<_> = database.query_snapshot().query().map(|row| row.values_array).collect();
+        assert_eq!(rows.len(), 31);
+        assert_eq!(rows[25], vec![25, 0, 99]);
+    }
+
+    #[test]
+    fn add_block() {
+        let mut database = create_database("transaction-add-block");
+        let mut txn = database.new_transaction().unwrap();
+        let axes = vec![vec![10, 12], vec![0, 5, 9]];
+        txn.add_block(&axes, &[vec![Some(1), None, Some(3), Some(4), Some(5), None]]).unwrap();
+        let rows: Vec<_> = txn.query().map(|r| r.values_array).collect();
+        assert_eq!(rows, vec![vec![10, 0, 1], vec![10, 9, 3], vec![12, 0, 4], vec![12, 5, 5]]);
+
+        /* Into a chunk with unsaved rows, the block's rows replace those at the same points */
+        txn.add_row(&[20, 1, 100]).unwrap();
+        txn.add_row(&[21, 1, 100]).unwrap();
+        txn.add_block(&[vec![21, 22], vec![1]], &[vec![Some(7), Some(8)]]).unwrap();
+        let rows: Vec<_> = txn.query_with(&Criteria::new().range(0, 20..=29)).unwrap().map(|r| r.values_array).collect();
+        assert_eq!(rows, vec![vec![20, 1, 100], vec![21, 1, 7], vec![22, 1, 8]]);
+
+        assert!(matches!(txn.add_block(&[vec![1]], &[vec![Some(1)]]), Err(Error::ArityMismatch)));
+        assert!(matches!(txn.add_block(&[vec![1], vec![1]], &[vec![Some(1), Some(2)]]), Err(Error::ArityMismatch)));
+        assert!(matches!(txn.add_block(&[vec![2, 1], vec![1]], &[vec![Some(1), Some(2)]]), Err(Error::DataError)));
+        assert!(matches!(txn.add_block(&[vec![9, 10], vec![1]], &[vec![Some(1), Some(2)]]), Err(Error::DataError)));
+        txn.commit().unwrap();
+        assert_eq!(database.query_snapshot().query().count(), 7);
+    }
+
+    #[test]
+    fn memory_hook() {
+        let sizes = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut database = create_database("transaction-memory-hook");
+        let mut txn = database.new_transaction().unwrap();
+        let hook_sizes = sizes.clone();
+        txn.set_memory_hook(move |size| {
+            hook_sizes.borrow_mut().push(size);
+            match size {
+                s if s > 10000 => Err(Error::QuotaExceeded),
+                s if s > 1000 => Ok(MemoryAction::Flush),
+                _ => Ok(MemoryAction::Continue)
+            }
+        });
+
+        for i in 0..100 {
+            txn.add_row(&[i, i, i]).unwrap();
+        }
+        assert!(txn.flush_stats().flushes > 0);
+        assert!(txn.memory_usage().unsaved_blocks <= 1000);
+        assert_eq!(txn.query().count(), 100);
+        drop(txn);
+        assert_eq!(sizes.borrow().len(), 100);
+        assert!(sizes.borrow().iter().any(|&size| size > 1000));
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.set_memory_hook(|size| if size > 0 { Err(Error::QuotaExceeded) } else { Ok(MemoryAction::Continue) });
+        assert!(matches!(txn.add_rows(&[[1, 1, 1], [2, 2, 2]]), Err(Error::QuotaExceeded)));
+        assert_eq!(txn.query().count(), 0);
+
+        /* A batch the hook asks to flush for is inserted after the rows before it are saved */
+        txn.set_memory_hook(|size| Ok(if size > 100 { MemoryAction::Flush } else { MemoryAction::Continue }));
+        txn.add_rows(&[[1, 1, 1], [2, 2, 2]]).unwrap();
+        txn.add_rows(&(3..20).map(|i| [i, i, i]).collect::<Vec<_>>()).unwrap();
+        assert_eq!(txn.flush_stats().flushes, 1);
+        assert_eq!(txn.query().count(), 19);
+    }
+
+    #[test]
+    fn update() {
+        let mut database = create_database("transaction-update");
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(&[[1, 1, 10], [2, 2, 20], [3, 3, 30]]).unwrap();
+        txn.commit().unwrap();
+
+        /* Later versions replace earlier ones, whether committed, flushed or unsaved */
+        let mut txn = database.new_transaction().unwrap();
+        txn.update(&[1, 1], 0, 11).unwrap();
+        txn.update(&[2, 2], 0, 21).unwrap();
+        txn.flush().unwrap();
+        txn.update(&[2, 2], 0, 22).unwrap();
+        txn.update(&[4, 4], 0, 40).unwrap();
+        let rows: Vec<_> = txn.query().map(|r| r.values_array).collect();
+        assert_eq!(rows, vec![vec![1, 1, 11], vec![2, 2, 22], vec![3, 3, 30], vec![4, 4, 40]]);
+
+        assert!(matches!(txn.update(&[1], 0, 5), Err(Error::ArityMismatch)));
+        assert!(txn.update(&[1, 1], 1, 5).is_err());
+        txn.commit().unwrap();
+
+        let txn = database.new_transaction().unwrap();
+        let values: Vec<_> = txn.query().map(|r| (r[2], r.txn_id)).collect();
+        assert_eq!(values, vec![(11, 2), (22, 2), (30, 1), (40, 2)]);
+    }
+
+    #[test]
+    fn provenance() {
+        let mut database = create_database("transaction-provenance");
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[1, 1, 10]).unwrap();
+        txn.commit().unwrap();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[2, 2, 20]).unwrap();
+        let rows: Vec<_> = txn.query().with_provenance().map(|r| (r.txn_id, r.segment)).collect();
+        assert_eq!(rows, vec![(1, Some((1, 0))), (0, None)]);
+
+        txn.flush().unwrap();
+        txn.add_row(&[3, 3, 30]).unwrap();
+        let rows: Vec<_> = txn.query().with_provenance().map(|r| (r.txn_id, r.segment)).collect();
+        assert_eq!(rows, vec![(1, Some((1, 0))), (2, Some((2, 0))), (2, None)]);
+        assert!(txn.query().all(|r| r.segment.is_none()));
+    }
+
+    #[test]
+    fn last_write_wins() {
+        let mut database = create_database("transaction-last-write-wins");
+        database.set_late_data(Some((0, 10)));
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[50, 1, 0]).unwrap();
+        txn.commit().unwrap();
+
+        /* Rewrite one late and one recent point in every flush, so they move between segments */
+        let mut txn = database.new_transaction().unwrap();
+        for value in 1..=5 {
+            txn.add_row(&[1, 1, value]).unwrap();
+            txn.add_row(&[50, 1, value]).unwrap();
+            txn.flush().unwrap();
+            let rows: Vec<_> = txn.query().map(|r| r[2]).collect();
+            assert_eq!(rows, vec![value, value]);
+        }
+        assert_eq!(txn.uncommitted_segments.len(), 10);
+        txn.add_row(&[1, 1, 6]).unwrap();
+        let rows: Vec<_> = txn.query().map(|r| r[2]).collect();
+        assert_eq!(rows, vec![6, 5]);
+        txn.commit().unwrap();
+
+        let txn = database.new_transaction().unwrap();
+        let rows: Vec<_> = txn.query().map(|r| r.values_array).collect();
+        assert_eq!(rows, vec![vec![1, 1, 6], vec![50, 1, 5]]);
+    }
+
+    #[test]
+    fn put_if() {
+        let mut database = create_database("transaction-put-if");
+        let mut txn = database.new_transaction().unwrap();
+        txn.put_if(&[1, 1], 0, None, 10).unwrap();
+        txn.put_if(&[2, 2], 0, None, 20).unwrap();
+        txn.commit().unwrap();
+
+        /* A transaction from before the next commit expects the values it saw */
+        let mut txn = Transaction::new(&mut database, 2);
+        txn.put_if(&[1, 1], 0, Some(10), 11).unwrap();
+        txn.commit().unwrap();
+
+        let mut stale = Transaction::new(&mut database, 2);
+        assert_eq!(stale.query().find(|r| r[0] == 1).unwrap()[2], 10);
+        stale.put_if(&[1, 1], 0, Some(10), 12).unwrap();
+        assert!(matches!(stale.commit(), Err(Error::Conflict)));
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.put_if(&[2, 2], 0, None, 21).unwrap();
+        assert!(matches!(txn.commit(), Err(Error::Conflict)));
+
+        let txn = database.new_transaction().unwrap();
+        let values: Vec<_> = txn.query().map(|r| r[2]).collect();
+        assert_eq!(values, vec![11, 20]);
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut database = create_database("transaction-conflicts");
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_rows(&[[1, 1, 10], [15, 1, 20]]).unwrap();
+        txn.commit().unwrap();
+
+        /* Transaction 2 writes to chunk (0, 0) after transactions 3 and 4 began */
+        let mut txn = Transaction::new(&mut database, 2);
+        txn.add_row(&[2, 2, 30]).unwrap();
+        txn.commit().unwrap();
+
+        let mut txn = Transaction::new(&mut database, 2);
+        txn.add_row(&[3, 3, 40]).unwrap();
+        txn.flush().unwrap();
+        assert!(matches!(txn.commit(), Err(Error::Conflict)));
+
+        /* Writing elsewhere is fine, unless the transaction is serializable and read the chunk */
+        let mut txn = Transaction::new(&mut database, 2);
+        assert_eq!(txn.query_with(&Criteria::new().range(0, 10..=19)).unwrap().count(), 1);
+        txn.add_row(&[16, 1, 50]).unwrap();
+        txn.commit().unwrap();
+
+        let mut txn = Transaction::new(&mut database, 2);
+        txn.set_serializable(true);
+        assert_eq!(txn.query().count(), 2);
+        txn.add_row(&[25, 1, 60]).unwrap();
+        assert!(matches!(txn.commit(), Err(Error::Conflict)));
+
+        let mut txn = Transaction::new(&mut database, 2);
+        txn.set_serializable(true);
+        assert_eq!(txn.query_with(&Criteria::new().range(0, 20..=29)).unwrap().count(), 0);
+        txn.add_row(&[25, 1, 60]).unwrap();
+        txn.commit().unwrap();
+
+        let txn = database.new_transaction().unwrap();
+        let values: Vec<_> = txn.query().map(|r| r[2]).collect();
+        assert_eq!(values, vec![10, 30, 20, 50, 60]);
+    }
+
+    #[test]
+    fn add_rows() {
+        let mut database = create_database("transaction-add-rows");
+        let mut txn = database.new_transaction().unwrap();
+        txn.set_validator(|row| Ok(if row[2] == 0 { RowAction::Skip } else { RowAction::Insert }));
+
+        let mut rows = Vec::new();
+        for i in 0..30 {
+            let x = (i * 17) % 30;
+            rows.push(vec![x, 29 - x, x]);
+        }
+        txn.add_rows(&rows).unwrap();
+
+        let xs: Vec<_> = txn.query().map(|r| r[0]).collect();
+        assert_eq!(xs, (1..30).collect::<Vec<_>>());
+
+        txn.set_validator(|row| if row[2] == 99 { Err(Error::DataError) } else { Ok(RowAction::Insert) });
+        assert!(txn.add_rows(&[[40, 40, 1], [41, 41, 99]]).is_err());
+        assert_eq!(txn.query().count(), 29);
+    }
+
+    #[test]
+    fn add_serialized() {
+        #[derive(serde::Serialize)]
+        struct Reading { value: u32, y: u8, x: u64, note: &'static str }
+
+        let mut database = create_database("transaction-add-serialized");
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_serialized(&Reading { value: 7, y: 2, x: 1, note: "ignored" }).unwrap();
+
+        let rows: Vec<_> = txn.query().map(|r| r.values_array).collect();
+        assert_eq!(rows, vec![vec![1, 2, 7]]);
+
+        #[derive(serde::Serialize)]
+        struct Missing { x: u64, value: u64 }
+        assert!(matches!(txn.add_serialized(&Missing { x: 1, value: 2 }), Err(Error::SchemaError)));
+
+        #[derive(serde::Serialize)]
+        struct Negative { x: i64, y: i64, value: i64 }
+        assert!(matches!(txn.add_serialized(&Negative { x: 1, y: -2, value: 3 }), Err(Error::DataError)));
+        assert!(matches!(txn.add_serialized(&[1, 2, 3]), Err(Error::DataError)));
+    }
+
+    #[test]
+    fn checkpoints() {
+        let mut database = create_database("transaction-checkpoints");
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.set_checkpoint("file1", "100");
+        txn.rollback();
+        assert!(database.checkpoints().is_empty());
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[1, 1, 1]).unwrap();
+        txn.set_checkpoint("file1", "200");
+        txn.set_checkpoint("file2", "abc");
+        txn.commit().unwrap();
+        assert_eq!(database.checkpoints().get("file1"), Some("200"));
+
+        let database = Database::open(database.path.as_path()).unwrap();
+        assert_eq!(database.checkpoints().len(), 2);
+        assert_eq!(database.checkpoints().get("file2"), Some("abc"));
+        assert_eq!(database.checkpoints().get("file3"), None);
+    }
+
+    #[test]
+    fn staged_checkpoints() {
+        let mut database = create_database("transaction-staged-checkpoints");
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[1, 1, 1]).unwrap();
+        txn.set_checkpoint("file1", "100");
+        let committed_id = txn.commit().unwrap().txn_id.unwrap();
+
+        /* As left by a crash before the next transaction's first segment was made visible */
+        let mut staged = database.checkpoints().clone();
+        staged.set("file1".to_string(), "200".to_string());
+        let uncommitted_id = committed_id + 1;
+        staged.save_staged(database.backend.as_ref(), uncommitted_id, database.checkpoints()).unwrap();
+        let database = Database::open(database.path.as_path()).unwrap();
+        assert_eq!(database.checkpoints().get("file1"), Some("100"));
+        let database = Database::open(database.path.as_path()).unwrap();
+        assert_eq!(database.checkpoints().get("file1"), Some("100"));
+
+        /* As left by a crash after it was */
+        staged.save_staged(database.backend.as_ref(), committed_id, database.checkpoints()).unwrap();
+        let database = Database::open(database.path.as_path()).unwrap();
+        assert_eq!(database.checkpoints().get("file1"), Some("200"));
+    }
+
+    #[test]
+    fn query_with_criteria() {
+        let mut database = create_database("transaction-query-with-criteria");
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..50 {
+            txn.add_row(&[i, i % 5, i]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[100, 2, 100]).unwrap();
+
+        let criteria = Criteria::new().range(0, 15..=100).range(1, 2..=2);
+        let rows: Vec<_> = txn.query_with(&criteria).unwrap().map(|r| r[0]).collect();
+        assert_eq!(rows, vec![17, 22, 27, 32, 37, 42, 47, 100]);
+
+        let rows: Vec<_> = txn.query_with(&criteria).unwrap().project(&[0]).unwrap().map(|r| (r[0], r[1])).collect();
+        assert_eq!(rows[0], (17, 17));
+        assert!(txn.query_with(&criteria).unwrap().project(&[1]).is_err());
+
+        let criteria = Criteria::new().range(2, 0..=0);
+        assert!(txn.query_with(&criteria).is_err());
+    }
+
+    #[test]
+    fn aggregate() {
+        let mut database = create_database("transaction-aggregate");
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..50 {
+            txn.add_row(&[i, i % 5, i]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[100, 2, 100]).unwrap();
+        let criteria = Criteria::new().range(0, 15..=100).range(1, 2..=2);
+        let result = txn.query_with(&criteria).unwrap().aggregate(0).run().unwrap();
+        assert_eq!(result, AggregateResult { count: 8, sum: 324, min: 17, max: 100, mean: 40 });
+        assert_eq!(result.get(Aggregate::Max), 100);
+
+        let criteria = Criteria::new().range(0, 200..=300);
+        assert_eq!(txn.query_with(&criteria).unwrap().aggregate(0).run().unwrap(), AggregateResult::default());
+        assert!(matches!(txn.query().aggregate(1).run(), Err(Error::SchemaError)));
+    }
+
+    #[test]
+    fn dimension_values() {
+        let mut database = create_database("transaction-dimension-values");
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..50 {
+            txn.add_row(&[i, i % 7, i]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[60, 42, 0]).unwrap();
+
+        assert_eq!(txn.dimension_values(1, &Criteria::new()).unwrap(), vec![0, 1, 2, 3, 4, 5, 6, 42]);
+        assert_eq!(txn.dimension_values(1, &Criteria::new().range(0, 3..=5)).unwrap(), vec![3, 4, 5]);
+        assert_eq!(txn.dimension_values(1, &Criteria::new().range(0, 10..=19).range(1, 2..=4)).unwrap(), vec![2, 3, 4]);
+        assert_eq!(txn.dimension_values(0, &Criteria::new().range(1, 42..=42)).unwrap(), vec![60]);
+        assert!(txn.dimension_values(2, &Criteria::new()).is_err());
+    }
+
+    #[test]
+    fn count() {
+        let mut database = create_database("transaction-count");
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..50 {
+            txn.add_row(&[i, i % 5, i]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let mut txn = database.new_transaction().unwrap();
+        let stats = txn.count(&Criteria::new()).unwrap();
+        assert_eq!((stats.rows, stats.blocks_counted), (50, 5));
+        assert!(stats.metadata_only());
+        assert!(txn.count(&Criteria::new().range(0, 10..=29)).unwrap().metadata_only());
+
+        let stats = txn.count(&Criteria::new().range(0, 15..=29)).unwrap();
+        assert_eq!((stats.rows, stats.blocks_counted, stats.blocks_scanned), (15, 1, 1));
+
+        /* The first row replaces a committed one, so the blocks in its chunk are merged */
+        txn.add_row(&[12, 2, 999]).unwrap();
+        txn.add_row(&[60, 0, 1]).unwrap();
+        for criteria in [Criteria::new(), Criteria::new().range(0, 10..=19), Criteria::new().range(1, 2..=3)] {
+            let stats = txn.count(&criteria).unwrap();
+            assert_eq!(stats.rows, txn.query_with(&criteria).unwrap().count());
+        }
+        let stats = txn.count(&Criteria::new()).unwrap();
+        assert_eq!((stats.rows, stats.blocks_counted, stats.blocks_scanned), (51, 5, 2));
+
+        assert!(txn.count(&Criteria::new().range(2, 0..=0)).is_err());
+    }
+
+    #[test]
+    fn delete_where() {
+        let mut database = create_database("transaction-delete-where");
+        let mut txn = database.new_transaction().unwrap();
+        for i in 0..50 {
+            txn.add_row(&[i, i % 5, i]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        /* Committed, flushed and unsaved rows are all deleted, but not the row written after */
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[60, 2, 60]).unwrap();
+        txn.flush().unwrap();
+        txn.add_row(&[70, 2, 70]).unwrap();
+        txn.delete_where(&Criteria::new().range(1, 2..=2)).unwrap();
+        txn.add_row(&[12, 2, 999]).unwrap();
+        let sensor_2: Vec<_> = txn.query_with(&Criteria::new().range(1, 2..=2)).unwrap().map(|r| r.values_array).collect();
+        assert_eq!(sensor_2, vec![vec![12, 2, 999]]);
+        assert_eq!(txn.count(&Criteria::new()).unwrap().rows, 41);
+        assert_eq!(txn.dimension_values(1, &Criteria::new().range(0, 20..=29)).unwrap(), vec![0, 1, 3, 4]);
+        txn.commit().unwrap();
+
+        let txn = database.new_transaction().unwrap();
+        let sensor_2: Vec<_> = txn.query_with(&Criteria::new().range(1, 2..=2)).unwrap().map(|r| r[0]).collect();
+        assert_eq!(sensor_2, vec![12]);
+        assert_eq!(txn.count(&Criteria::new()).unwrap().rows, 41);
+        txn.rollback();
+
+        /* Deleting whole blocks leaves them to be counted from the segment info */
+        let mut txn = database.new_transaction().unwrap();
+        txn.delete_where(&Criteria::new().range(0, 0..=19)).unwrap();
+        txn.commit().unwrap();
+        let txn = database.new_transaction().unwrap();
+        assert_eq!(txn.count(&Criteria::new()).unwrap().rows, 24);
+        let stats = txn.count(&Criteria::new().range(0, 0..=19)).unwrap();
+        assert_eq!(stats.rows, 0);
+        assert!(stats.metadata_only());
+        assert_eq!(txn.coverage(&Criteria::new()).unwrap(), vec![vec![20, 0], vec![30, 0], vec![40, 0]]);
+        txn.rollback();
+
+        /* A delete conflicts with writes to the chunks it overlaps, either way round */
+        let mut stale = Transaction::new(&mut database, 3);
+        stale.add_row(&[5, 1, 5]).unwrap();
+        assert!(matches!(stale.commit(), Err(Error::Conflict)));
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[45, 1, 1]).unwrap();
+        txn.commit().unwrap();
+        let mut stale = Transaction::new(&mut database, 4);
+        stale.delete_where(&Criteria::new().range(0, 40..=49)).unwrap();
+        assert!(matches!(stale.commit(), Err(Error::Conflict)));
+
+        database.compact().unwrap();
+        let database = Database::open(database.path.as_path()).unwrap();
+        let rows: Vec<_> = database.query_snapshot().query().map(|r| r[0]).collect();
+        assert_eq!(rows, vec![20, 21, 23, 24, 25, 26, 28, 29, 30, 31, 33, 34, 35, 36, 38, 39, 40, 41, 43, 44, 45, 45, 46, 48, 49]);
+    }
+
+    #[test]
+    fn coverage() {
+        let mut database = create_database("transaction-coverage");
+        let mut txn = database.new_transaction().unwrap();
+        for x in [1, 5, 12, 35, 38] {
+            txn.add_row(&[x, 3, 1]).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let mut txn = database.new_transaction().unwrap();
+        txn.add_row(&[41, 17, 1]).unwrap();
+
+        assert_eq!(txn.coverage(&Criteria::new()).unwrap(), vec![vec![0, 0], vec![10, 0], vec![30, 0], vec![40, 10]]);
+        assert_eq!(txn.coverage(&Criteria::new().range(0, 10..=39)).unwrap(), vec![vec![10, 0], vec![30, 0]]);
+        assert_eq!(txn.coverage(&Criteria::new().range(1, 10..=19)).unwrap(), vec![vec![40, 10]]);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::Datum;
+use crate::schema::ValueType;
+
+/**
+ * A value of a typed value column; see `ValueType`.  Each converts to and from the `Datum`
+ * stored in blocks without loss, so blocks are saved and loaded the same whatever the types of
+ * their columns.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TypedValue {
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Bool(bool)
+}
+
+impl TypedValue {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            TypedValue::U64(_) => ValueType::U64,
+            TypedValue::I64(_) => ValueType::I64,
+            TypedValue::F64(_) => ValueType::F64,
+            TypedValue::Bool(_) => ValueType::Bool
+        }
+    }
+
+    pub fn to_datum(&self) -> Datum {
+        match *self {
+            TypedValue::U64(value) => value as Datum,
+            TypedValue::I64(value) => value as u64 as Datum,
+            TypedValue::F64(value) => value.to_bits() as Datum,
+            TypedValue::Bool(value) => value as Datum
+        }
+    }
+
+    pub fn from_datum(value_type: ValueType, datum: Datum) -> TypedValue {
+        match value_type {
+            ValueType::U64 => TypedValue::U64(datum as u64),
+            ValueType::I64 => TypedValue::I64(datum as u64 as i64),
+            ValueType::F64 => TypedValue::F64(f64::from_bits(datum as u64)),
+            ValueType::Bool => TypedValue::Bool(datum != 0)
+        }
+    }
+}
+
+impl From<u64> for TypedValue {
+    fn from(value: u64) -> TypedValue {
+        TypedValue::U64(value)
+    }
+}
+
+impl From<i64> for TypedValue {
+    fn from(value: i64) -> TypedValue {
+        TypedValue::I64(value)
+    }
+}
+
+impl From<f64> for TypedValue {
+    fn from(value: f64) -> TypedValue {
+        TypedValue::F64(value)
+    }
+}
+
+impl From<bool> for TypedValue {
+    fn from(value: bool) -> TypedValue {
+        TypedValue::Bool(value)
+    }
+}
+
+/**
+ * One entry of a row for `Transaction::add_row_named`, or of a row resolved by
+ * `Database::resolve_row`: a string for a label dimension, a typed value for a value column, or
+ * a plain `Datum` for anything else.  A `Datum` is accepted anywhere, and stored as it is.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Field {
+    Datum(Datum),
+    Label(String),
+    Value(TypedValue)
+}
+
+impl From<Datum> for Field {
+    fn from(value: Datum) -> Field {
+        Field::Datum(value)
+    }
+}
+
+impl From<&str> for Field {
+    fn from(value: &str) -> Field {
+        Field::Label(value.to_string())
+    }
+}
+
+impl From<String> for Field {
+    fn from(value: String) -> Field {
+        Field::Label(value)
+    }
+}
+
+impl From<TypedValue> for Field {
+    fn from(value: TypedValue) -> Field {
+        Field::Value(value)
+    }
+}
+
+impl From<i64> for Field {
+    fn from(value: i64) -> Field {
+        Field::Value(TypedValue::I64(value))
+    }
+}
+
+impl From<f64> for Field {
+    fn from(value: f64) -> Field {
+        Field::Value(TypedValue::F64(value))
+    }
+}
+
+impl From<bool> for Field {
+    fn from(value: bool) -> Field {
+        Field::Value(TypedValue::Bool(value))
+    }
+}
+
+#[cfg(test)]
+mod typed_tests {
+    use crate::ValueType;
+    use super::TypedValue;
+
+    #[test]
+    fn round_trip() {
+        let values = [TypedValue::U64(u64::MAX), TypedValue::I64(-5), TypedValue::F64(-21.375), TypedValue::Bool(true)];
+        for value in values {
+            assert_eq!(TypedValue::from_datum(value.value_type(), value.to_datum()), value);
+        }
+        assert_eq!(TypedValue::from_datum(ValueType::F64, 0), TypedValue::F64(0.0));
+    }
+}
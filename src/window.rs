@@ -0,0 +1,132 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::Datum;
+use crate::query::QueryRow;
+
+/**
+ * A moving window over the rows of a query, ready to have an operation chosen for it.
+ *
+ * Windows move along the first dimension, separately for each combination of the remaining
+ * dimensions.  For example, with dimensions `time` and `sensor_id`, each sensor gets its own
+ * window of its `size` most recent rows.
+ */
+pub struct Window<I> {
+    inner: I,
+    num_dims: usize,
+    size: usize
+}
+
+#[derive(Clone, Copy)]
+enum WindowOp {
+    Mean,
+    Min,
+    Max
+}
+
+/**
+ * An iterator returning each row of a query with its values replaced by an aggregate of the
+ * values in its window.
+ */
+pub struct WindowScan<I> {
+    inner: I,
+    num_dims: usize,
+    size: usize,
+    op: WindowOp,
+    history: HashMap<Vec<Datum>, VecDeque<Vec<Datum>>>
+}
+
+impl<I: Iterator<Item=QueryRow>> Window<I> {
+    pub(crate) fn new(inner: I, num_dims: usize, size: usize) -> Window<I> {
+        Window { inner, num_dims, size: size.max(1) }
+    }
+
+    /**
+     * The mean of each value over the window, rounded down.
+     */
+    pub fn mean(self) -> WindowScan<I> {
+        self.with_op(WindowOp::Mean)
+    }
+
+    pub fn min(self) -> WindowScan<I> {
+        self.with_op(WindowOp::Min)
+    }
+
+    pub fn max(self) -> WindowScan<I> {
+        self.with_op(WindowOp::Max)
+    }
+
+    fn with_op(self, op: WindowOp) -> WindowScan<I> {
+        WindowScan {
+            inner: self.inner,
+            num_dims: self.num_dims,
+            size: self.size,
+            op,
+            history: HashMap::new()
+        }
+    }
+}
+
+impl<I: Iterator<Item=QueryRow>> WindowScan<I> {
+    fn aggregate(&self, window: &VecDeque<Vec<Datum>>, value_no: usize) -> Datum {
+        let values = window.iter().map(|v| v[value_no]);
+        match self.op {
+            WindowOp::Mean => (values.map(|v| v as u128).sum::<u128>() / window.len() as u128) as Datum,
+            WindowOp::Min => values.min().unwrap_or(0),
+            WindowOp::Max => values.max().unwrap_or(0)
+        }
+    }
+}
+
+impl<I: Iterator<Item=QueryRow>> Iterator for WindowScan<I> {
+    type Item = QueryRow;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut row = self.inner.next()?;
+        let num_dims = self.num_dims;
+
+        let group = row.values_array[1..num_dims].to_vec();
+        let values = row.values_array[num_dims..].to_vec();
+        let num_values = values.len();
+
+        let mut window = self.history.remove(&group).unwrap_or_default();
+        if window.len() >= self.size {
+            window.pop_front();
+        }
+        window.push_back(values);
+
+        for value_no in 0..num_values {
+            row.values_array[num_dims + value_no] = self.aggregate(&window, value_no);
+        }
+
+        self.history.insert(group, window);
+        Some(row)
+    }
+}
+
+#[cfg(test)]
+mod window_tests {
+    use super::*;
+
+    fn rows(data: &[[Datum; 3]]) -> impl Iterator<Item=QueryRow> + '_ {
+        data.iter().map(|r| QueryRow { txn_id: 1, segment: None, values_array: r.to_vec() })
+    }
+
+    fn values(scan: WindowScan<impl Iterator<Item=QueryRow>>) -> Vec<Datum> {
+        scan.map(|r| r[2]).collect()
+    }
+
+    #[test]
+    fn mean() {
+        let data = [[0, 1, 10], [1, 1, 20], [2, 1, 30], [3, 1, 40]];
+        assert_eq!(values(Window::new(rows(&data), 2, 2).mean()), vec![10, 15, 25, 35]);
+        assert_eq!(values(Window::new(rows(&data), 2, 3).mean()), vec![10, 15, 20, 30]);
+        assert_eq!(values(Window::new(rows(&data), 2, 1).mean()), vec![10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn max_per_group() {
+        let data = [[0, 1, 10], [0, 2, 100], [1, 1, 5], [1, 2, 200], [2, 1, 1], [2, 2, 50]];
+        assert_eq!(values(Window::new(rows(&data), 2, 2).max()), vec![10, 100, 10, 200, 5, 200]);
+        assert_eq!(values(Window::new(rows(&data), 2, 2).min()), vec![10, 100, 5, 100, 1, 50]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Malformed input must produce errors, never panics: embedders can't recover from an abort
+//! inside the library.
+
+use std::rc::Rc;
+
+use matdb::{Backend, CellOrder, Cursor, Database, Dimension, Error, MemoryBackend, Schema, SegmentEncoder, Value, ValueType};
+
+fn schema() -> Schema {
+    Schema {
+        dimensions: vec![
+            Dimension { name: String::from("time"), chunk_size: 50, bounds: None, time: None, labels: false },
+            Dimension { name: String::from("sensor_id"), chunk_size: 10, bounds: None, time: None, labels: false },
+        ],
+        values: vec![
+            Value { name: String::from("value"), value_type: ValueType::U64 }
+        ],
+        cell_order: CellOrder::RowMajor
+    }
+}
+
+fn create_database() -> Database {
+    Database::create_with_backend(schema(), Box::new(MemoryBackend::new())).unwrap()
+}
+
+/**
+ * A small deterministic generator, so failures can be reproduced.
+ */
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/**
+ * Variations on some valid data: every truncation, and copies with a few bytes changed.
+ */
+fn corruptions(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut results: Vec<Vec<u8>> = (0..data.len()).map(|len| data[..len].to_vec()).collect();
+    let mut random = Random(0x5eed);
+    for _ in 0..500 {
+        let mut corrupt = data.to_vec();
+        for _ in 0..1 + random.below(4) {
+            let pos = random.below(corrupt.len());
+            corrupt[pos] = random.next() as u8;
+        }
+        results.push(corrupt);
+    }
+    results
+}
+
+fn encode_segment(schema: &Schema) -> Vec<u8> {
+    let mut encoder = SegmentEncoder::new(schema);
+    for i in 0..60 {
+        encoder.add_row(&[i, i % 4, i * 3]).unwrap();
+    }
+    encoder.encode().unwrap()
+}
+
+#[test]
+fn corrupt_ingested_segments() {
+    let mut database = create_database();
+    let data = encode_segment(&database.schema);
+    for corrupt in corruptions(&data) {
+        if database.ingest_segment(&corrupt).is_ok() {
+            let txn = database.new_transaction().unwrap();
+            txn.query().count();
+        }
+    }
+}
+
+#[test]
+fn corrupt_committed_segments() {
+    let data = encode_segment(&schema());
+    for corrupt in corruptions(&data).into_iter().step_by(7) {
+        let backend = Rc::new(MemoryBackend::new());
+        drop(Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap());
+        backend.write("00000001.00000000", &corrupt).unwrap();
+
+        let mut database = Database::open_with_backend(Box::new(backend)).unwrap();
+        let txn = database.new_transaction().unwrap();
+        txn.query().count();
+        let _ = txn.dimension_values(1, &Default::default());
+        let _ = txn.coverage(&Default::default());
+    }
+}
+
+#[test]
+fn corrupt_dumps() {
+    let mut database = create_database();
+    let mut txn = database.new_transaction().unwrap();
+    for i in 0..40 {
+        txn.add_row(&[i, i % 3, i]).unwrap();
+    }
+    txn.commit().unwrap();
+    let mut dump = Vec::new();
+    database.dump(&mut dump).unwrap();
+
+    for corrupt in corruptions(&dump).into_iter().step_by(3) {
+        let _ = Database::restore_with_backend(&mut corrupt.as_slice(), Box::new(MemoryBackend::new()));
+    }
+}
+
+#[test]
+fn corrupt_schemas() {
+    let json = br#"{"dimensions":[{"name":"x","chunk_size":10}],"values":[{"name":"value"}]}"#;
+    for schema_json in [
+        &json[..],
+        br#"{"dimensions":[{"name":"x","chunk_size":0}],"values":[]}"#,
+        br#"{"dimensions":[],"values":[]}"#,
+        br#"{"dimensions":[{"name":"x","chunk_size":10,"bounds":[5,4]}],"values":[]}"#,
+        b"not json",
+    ] {
+        let backend = MemoryBackend::new();
+        backend.write("schema.json", schema_json).unwrap();
+        let result = Database::open_with_backend(Box::new(backend));
+        assert_eq!(result.is_ok(), schema_json == &json[..]);
+    }
+}
+
+#[test]
+fn malformed_rows_and_tokens() {
+    let mut database = create_database();
+    let mut txn = database.new_transaction().unwrap();
+    assert!(matches!(txn.add_row(&[]), Err(Error::ArityMismatch)));
+    assert!(matches!(txn.add_row(&[1]), Err(Error::ArityMismatch)));
+    assert!(matches!(txn.add_row(&[1, 2, 3, 4]), Err(Error::ArityMismatch)));
+    assert!(matches!(txn.add_rows(&[vec![1, 2, 3], vec![1, 2]]), Err(Error::ArityMismatch)));
+    assert_eq!(txn.query().count(), 0);
+    txn.rollback();
+
+    for token in ["", ".", "1.", "zz.1", "1.1.x", "ffffffffff.1"] {
+        assert!(Cursor::from_token(token).is_err());
+    }
+}
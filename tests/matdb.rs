@@ -1,28 +1,26 @@
+#![cfg(feature = "fs")]
+
 use std::path::Path;
 use std::time::Instant;
 
-use matdb::{Database, Dimension, Value, Schema, Transaction};
+use matdb::api::{CellOrder, Database, Dimension, OpenMode, OpenOptions, Value, ValueType, Schema, Transaction};
 
 fn create_database() -> Database {
     let mut database_path = std::env::temp_dir();
     database_path.push(Path::new("testdb"));
 
-    let matdb;
-    if database_path.exists() {
-        matdb = Database::open(database_path.as_path()).unwrap();
-    } else {
-        matdb = Database::create(Schema {
-            dimensions: vec![
-                Dimension { name: String::from("time"), chunk_size: 50 },
-                Dimension { name: String::from("sensor_id"), chunk_size: 10 },
-            ],
-            values: vec![
-                Value { name: String::from("value") }
-            ]
-        }, database_path.as_path()).unwrap();
-    }
-
-    matdb
+    let schema = Schema {
+        dimensions: vec![
+            Dimension { name: String::from("time"), chunk_size: 50, bounds: None, time: None, labels: false },
+            Dimension { name: String::from("sensor_id"), chunk_size: 10, bounds: None, time: None, labels: false },
+        ],
+        values: vec![
+            Value { name: String::from("value"), value_type: ValueType::U64 }
+        ],
+        cell_order: CellOrder::RowMajor
+    };
+    let options = OpenOptions::new().mode(OpenMode::CreateIfMissing).schema(schema);
+    Database::open_with(database_path.as_path(), options).unwrap()
 }
 
 fn insert_data(txn: &mut Transaction) {
@@ -33,7 +31,7 @@ fn insert_data(txn: &mut Transaction) {
             txn.flush().unwrap();
         }
         for j in 0..100 {
-            txn.add_row(&[i, j, i*1000 + j]);
+            txn.add_row(&[i, j, i*1000 + j]).unwrap();
             count += 1;
         }
     }
@@ -0,0 +1,290 @@
+//! Deterministic crash simulation.  A scripted workload runs against a simulated backend which
+//! "crashes" at a chosen operation: that operation and everything after it fail, and a write
+//! in progress may leave part of its file behind.  The files that survive are opened again,
+//! and the recovered database is checked for atomicity, durability and consistency of
+//! checkpoints.  Every crash point of every workload is tried.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::rc::Rc;
+
+use matdb::{Backend, CellOrder, Database, Datum, Dimension, Error, ReadSeek, Schema, SegmentEncoder, Value, ValueType};
+
+const NUM_SEEDS: u64 = 25;
+const NUM_TRANSACTIONS: usize = 12;
+const CHECKPOINT_SOURCE: &str = "simulation";
+
+/**
+ * A small deterministic generator, so every schedule can be reproduced from its seed.
+ */
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/**
+ * An in-memory backend that crashes after a set number of writes, renames and removes.
+ */
+struct SimBackend {
+    files: RefCell<BTreeMap<String, Vec<u8>>>,
+    ops_before_crash: Cell<Option<usize>>,
+    has_crashed: Cell<bool>,
+    num_ops: Cell<usize>,
+    random: RefCell<Random>
+}
+
+impl SimBackend {
+    fn new(files: BTreeMap<String, Vec<u8>>, crash_at: Option<usize>, seed: u64) -> SimBackend {
+        SimBackend {
+            files: RefCell::new(files),
+            ops_before_crash: Cell::new(crash_at),
+            has_crashed: Cell::new(false),
+            num_ops: Cell::new(0),
+            random: RefCell::new(Random::new(seed))
+        }
+    }
+
+    /**
+     * Count an operation, returning whether the crash happens during it.  Once crashed,
+     * every later operation fails too.
+     */
+    fn crash_now(&self) -> bool {
+        self.num_ops.set(self.num_ops.get() + 1);
+        match self.ops_before_crash.get() {
+            Some(0) => {
+                self.has_crashed.set(true);
+                true
+            },
+            Some(n) => {
+                self.ops_before_crash.set(Some(n - 1));
+                false
+            },
+            None => false
+        }
+    }
+
+    /**
+     * The files as they would be found after restarting.
+     */
+    fn survivors(&self) -> BTreeMap<String, Vec<u8>> {
+        self.files.borrow().clone()
+    }
+}
+
+impl Backend for SimBackend {
+    fn list(&self) -> Result<Vec<String>, Error> {
+        Ok(self.files.borrow().keys().cloned().collect())
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.files.borrow().contains_key(name)
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ReadSeek>, Error> {
+        let data = self.files.borrow().get(name).cloned().ok_or(Error::IoError)?;
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        let first_failure = !self.has_crashed.get();
+        if self.crash_now() {
+            if first_failure {
+                /* A torn write leaves some prefix of the data behind */
+                let len = self.random.borrow_mut().below(data.len() + 1);
+                self.files.borrow_mut().insert(name.to_string(), data[..len].to_vec());
+            }
+            return Err(Error::IoError);
+        }
+        self.files.borrow_mut().insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        if self.crash_now() {
+            return Err(Error::IoError);
+        }
+        let mut files = self.files.borrow_mut();
+        let data = files.remove(from).ok_or(Error::IoError)?;
+        files.insert(to.to_string(), data);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        if self.crash_now() {
+            return Err(Error::IoError);
+        }
+        self.files.borrow_mut().remove(name).map(|_| ()).ok_or(Error::IoError)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    NotStarted,
+    RolledBack,
+    InFlight,
+    Committed
+}
+
+/**
+ * What the workload did: the number of rows in each transaction, and how it ended.
+ */
+struct History {
+    num_rows: Vec<usize>,
+    outcomes: Vec<Outcome>
+}
+
+fn schema() -> Schema {
+    Schema {
+        dimensions: vec![
+            Dimension { name: String::from("txn"), chunk_size: 4, bounds: None, time: None, labels: false },
+            Dimension { name: String::from("row"), chunk_size: 10, bounds: None, time: None, labels: false },
+        ],
+        values: vec![
+            Value { name: String::from("value"), value_type: ValueType::U64 }
+        ],
+        cell_order: CellOrder::RowMajor
+    }
+}
+
+fn row(txn_no: usize, row_no: usize) -> [Datum; 3] {
+    [txn_no, row_no, txn_no * 1000 + row_no]
+}
+
+fn initial_files() -> BTreeMap<String, Vec<u8>> {
+    let backend = Rc::new(SimBackend::new(BTreeMap::new(), None, 0));
+    drop(Database::create_with_backend(schema(), Box::new(backend.clone())).unwrap());
+    backend.survivors()
+}
+
+/**
+ * Run transactions until they all finish or the backend crashes.  Each transaction inserts
+ * rows at its own points, so that its rows can be told apart from all the others.
+ */
+fn run_workload(backend: Rc<SimBackend>, seed: u64) -> History {
+    let mut random = Random::new(seed);
+    let mut history = History {
+        num_rows: vec![0; NUM_TRANSACTIONS + 1],
+        outcomes: vec![Outcome::NotStarted; NUM_TRANSACTIONS + 1]
+    };
+
+    let Ok(mut database) = Database::open_with_backend(Box::new(backend)) else { return history };
+    for txn_no in 1..=NUM_TRANSACTIONS {
+        let num_rows = 1 + random.below(40);
+        history.num_rows[txn_no] = num_rows;
+        history.outcomes[txn_no] = Outcome::InFlight;
+
+        if random.below(6) == 0 {
+            let mut encoder = SegmentEncoder::new(&database.schema);
+            for row_no in 0..num_rows {
+                encoder.add_row(&row(txn_no, row_no)).unwrap();
+            }
+            if database.ingest_segment(&encoder.encode().unwrap()).is_err() {
+                return history;
+            }
+            history.outcomes[txn_no] = Outcome::Committed;
+            continue;
+        }
+
+        let mut txn = database.new_transaction().unwrap();
+        for row_no in 0..num_rows {
+            txn.add_row(&row(txn_no, row_no)).unwrap();
+            if random.below(15) == 0 && txn.flush().is_err() {
+                return history;
+            }
+        }
+        if random.below(6) == 0 {
+            txn.rollback();
+            history.outcomes[txn_no] = Outcome::RolledBack;
+            continue;
+        }
+        txn.set_checkpoint(CHECKPOINT_SOURCE, &txn_no.to_string());
+        if txn.commit().is_err() {
+            return history;
+        }
+        history.outcomes[txn_no] = Outcome::Committed;
+    }
+    history
+}
+
+/**
+ * Open the files that survived the crash and check them against what the workload did.
+ */
+fn check_recovery(files: BTreeMap<String, Vec<u8>>, history: &History, schedule: &str) {
+    let backend = Rc::new(SimBackend::new(files, None, 0));
+    let mut database = Database::open_with_backend(Box::new(backend.clone()))
+        .unwrap_or_else(|err| panic!("{schedule}: open failed with {err:?}"));
+
+    for name in backend.list().unwrap() {
+        assert!(!name.ends_with(".tmp") || name.ends_with(".json.tmp"),
+            "{schedule}: temporary segment {name} left after recovery");
+    }
+
+    /* Each transaction is either entirely present or entirely absent */
+    let txn = database.new_transaction().unwrap();
+    let mut found: BTreeMap<usize, Vec<Datum>> = BTreeMap::new();
+    for r in txn.query() {
+        assert_eq!(r[2], r[0] * 1000 + r[1], "{schedule}: wrong value in row at {:?}", (r[0], r[1]));
+        found.entry(r[0]).or_default().push(r[1]);
+    }
+    txn.rollback();
+
+    for (txn_no, &outcome) in history.outcomes.iter().enumerate().skip(1) {
+        let rows = found.get(&txn_no).map_or(0, |rows| rows.len());
+        let complete = rows == history.num_rows[txn_no];
+        assert!(rows == 0 || complete, "{schedule}: transaction {txn_no} is partly present, {rows} rows");
+        match outcome {
+            Outcome::Committed => assert!(complete, "{schedule}: committed transaction {txn_no} is missing"),
+            Outcome::RolledBack | Outcome::NotStarted => assert_eq!(rows, 0, "{schedule}: transaction {txn_no} should be absent"),
+            Outcome::InFlight => {}
+        }
+    }
+
+    /* A checkpoint is only saved after its transaction's rows, and is saved before commit returns */
+    let checkpoint = database.checkpoints().get(CHECKPOINT_SOURCE).map(|c| c.parse::<usize>().unwrap());
+    if let Some(txn_no) = checkpoint {
+        assert!(found.contains_key(&txn_no), "{schedule}: checkpoint {txn_no} is ahead of the data");
+    }
+
+    /* The recovered database can be written to again */
+    let mut txn = database.new_transaction().unwrap();
+    txn.add_row(&row(NUM_TRANSACTIONS + 1, 0)).unwrap();
+    txn.commit().unwrap_or_else(|err| panic!("{schedule}: commit after recovery failed with {err:?}"));
+    let txn = database.new_transaction().unwrap();
+    assert_eq!(txn.query().filter(|r| r[0] == NUM_TRANSACTIONS + 1).count(), 1);
+}
+
+#[test]
+fn crash_at_every_operation() {
+    let initial = initial_files();
+    let mut num_schedules = 0;
+    for seed in 0..NUM_SEEDS {
+        /* Run once without crashing, to find out how many operations there are */
+        let backend = Rc::new(SimBackend::new(initial.clone(), None, seed));
+        let history = run_workload(backend.clone(), seed);
+        check_recovery(backend.survivors(), &history, &format!("seed {seed} without crash"));
+        let num_ops = backend.num_ops.get();
+
+        for crash_at in 0..num_ops {
+            let backend = Rc::new(SimBackend::new(initial.clone(), Some(crash_at), seed));
+            let history = run_workload(backend.clone(), seed);
+            check_recovery(backend.survivors(), &history, &format!("seed {seed} crash at {crash_at}"));
+            num_schedules += 1;
+        }
+    }
+    assert!(num_schedules > 1000);
+}